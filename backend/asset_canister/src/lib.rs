@@ -1,16 +1,329 @@
-use candid::{CandidType, Principal};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use candid::{CandidType, Nat, Principal};
 use ic_cdk::api::time;
-use ic_cdk::{caller, query, update};
+use ic_cdk::{call, caller, init, inspect_message, post_upgrade, query, update};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, RbTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell, Storable};
 use serde::{Serialize, Deserialize as SerdeDeserialize};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type AssetStore = StableBTreeMap<u64, Asset, Memory>;
-type AssetIdCounter = StableBTreeMap<u8, u64, Memory>;
+// Shared by ASSETS and the summary view over it
+const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(0);
+type LegacyAssetIdCounter = StableBTreeMap<u8, u64, Memory>;
+type AssetIdCounter = StableCell<u64, Memory>;
 type FileStore = StableBTreeMap<String, Vec<u8>, Memory>;
+type FilePieceStore = StableBTreeMap<FilePieceKey, Vec<u8>, Memory>;
+type UploadSessionStore = StableBTreeMap<u64, UploadSession, Memory>;
+type UploadSessionIdCounter = StableBTreeMap<u8, u64, Memory>;
+type UploadChunkStore = StableBTreeMap<(u64, u64), Vec<u8>, Memory>;
+type FileInfoStore = StableBTreeMap<String, FileInfo, Memory>;
+type AuthorizedMarketplaceStore = StableBTreeMap<Principal, u64, Memory>;
+type ConfigStore = StableBTreeMap<String, String, Memory>;
+type SaleStore = StableBTreeMap<u64, SaleRecord, Memory>;
+type SaleIdCounter = StableBTreeMap<u8, u64, Memory>;
+type PendingPurchaseStore = StableBTreeMap<u64, Principal, Memory>;
+type ApprovalStore = StableBTreeMap<u64, Approval, Memory>;
+type FileRefStore = StableBTreeMap<String, u64, Memory>;
+type FileRefAssetStore = StableBTreeMap<FileRefKey, u64, Memory>;
+type FileSupplierStore = StableBTreeMap<FileSupplierKey, (), Memory>;
+type StorageUsageStore = StableBTreeMap<Principal, u64, Memory>;
+type UserQuotaStore = StableBTreeMap<Principal, u64, Memory>;
+type OfferStore = StableBTreeMap<u64, Offer, Memory>;
+// (asset id, offer id) for every offer
+type AssetOfferIndex = StableBTreeMap<(u64, u64), (), Memory>;
+type OfferIdCounter = StableBTreeMap<u8, u64, Memory>;
+type AuctionStore = StableBTreeMap<u64, Auction, Memory>;
+type FavoriteStore = StableBTreeMap<(Principal, u64), u64, Memory>;
+// (asset id, user) for every favorite
+type AssetFavoriteIndex = StableBTreeMap<(u64, Principal), (), Memory>;
+type PriceAlertStore = StableBTreeMap<(Principal, u64), PriceAlert, Memory>;
+// (asset id, watcher) for every price alert
+type AssetPriceAlertIndex = StableBTreeMap<(u64, Principal), (), Memory>;
+// (holder, asset id) -> when the hold ended or will end
+type ReservationHoldStore = StableBTreeMap<(Principal, u64), u64, Memory>;
+type FavoriteCountStore = StableBTreeMap<u64, u64, Memory>;
+type RatingStore = StableBTreeMap<(u64, Principal), Rating, Memory>;
+type AssetStatsStore = StableBTreeMap<u64, AssetStats, Memory>;
+type EngagementMarkStore = StableBTreeMap<(u64, Principal, u8), u64, Memory>;
+type Icrc7TxIdCounter = StableBTreeMap<u8, u64, Memory>;
+type ReportStore = StableBTreeMap<u64, AssetReport, Memory>;
+type ReportIdCounter = StableBTreeMap<u8, u64, Memory>;
+type ModeratorStore = StableBTreeMap<Principal, u64, Memory>;
+type CollectionStore = StableBTreeMap<u64, Collection, Memory>;
+type CollectionIdCounter = StableBTreeMap<u8, u64, Memory>;
+type CollectionItemStore = StableBTreeMap<(u64, u64), u64, Memory>;
+// (asset id, collection id) for every collection item
+type AssetCollectionIndex = StableBTreeMap<(u64, u64), (), Memory>;
+type OwnerIndex = StableBTreeMap<(Principal, u64), (), Memory>;
+type CategoryStore = StableBTreeMap<u64, Category, Memory>;
+type CategoryIdCounter = StableBTreeMap<u8, u64, Memory>;
+type CategoryIndex = StableBTreeMap<(u64, u64), (), Memory>;
+type TagIndex = StableBTreeMap<TagKey, (), Memory>;
+// Inverted index over the tokens of each asset's name, description, category and tags
+type TextIndex = StableBTreeMap<TokenKey, (), Memory>;
+// Quota charged for reusing a stored file, keyed by "<file_hash>:<principal>"
+type DedupChargeStore = StableBTreeMap<String, u64, Memory>;
+// Global activity feed keyed by sequence number
+type ActivityEventStore = StableBTreeMap<u64, ActivityEvent, Memory>;
+type ActivitySeqCounter = StableBTreeMap<u8, u64, Memory>;
+// URL slug -> asset, including the old slugs of renamed assets
+type SlugIndex = StableBTreeMap<String, SlugEntry, Memory>;
+// (buyer, asset id) for every recorded sale
+type PurchaseIndex = StableBTreeMap<(Principal, u64), (), Memory>;
+// (asset id, sale id) for every recorded sale, in any token
+type AssetSaleIndex = StableBTreeMap<(u64, u64), (), Memory>;
+// External catalog id -> the asset imported for it
+type ExternalIdIndex = StableBTreeMap<String, u64, Memory>;
+type ForSaleIndex = StableBTreeMap<u64, (), Memory>;
+type OwnershipEventStore = StableBTreeMap<u64, OwnershipEvent, Memory>;
+// (asset id, event id) for every ownership event
+type AssetOwnershipEventIndex = StableBTreeMap<(u64, u64), (), Memory>;
+type OwnershipEventIdCounter = StableBTreeMap<u8, u64, Memory>;
+type ListingWindowStore = StableBTreeMap<u64, ListingWindow, Memory>;
+type FileAccessGrantStore = StableBTreeMap<(u64, Principal), u64, Memory>;
+type PreviewImageStore = StableBTreeMap<u64, PreviewImage, Memory>;
+type AssetImageStore = StableBTreeMap<u64, PreviewImage, Memory>;
+type AssetImageDigestStore = StableBTreeMap<u64, Sha256Digest, Memory>;
+type AssetImageIdCounter = StableBTreeMap<u8, u64, Memory>;
+type AssetGalleryStore = StableBTreeMap<u64, AssetGallery, Memory>;
+type AssetVersionStore = StableBTreeMap<(u64, u32), AssetVersion, Memory>;
+type PendingTransferStore = StableBTreeMap<u64, PendingTransfer, Memory>;
+type BundleStore = StableBTreeMap<u64, Bundle, Memory>;
+type BundleIdCounter = StableBTreeMap<u8, u64, Memory>;
+type BundleMemberStore = StableBTreeMap<u64, u64, Memory>;
+type BundleSaleStore = StableBTreeMap<u64, BundleSale, Memory>;
+type ProfileStore = StableBTreeMap<Principal, Profile, Memory>;
+// Lowercased display name -> the principal holding it
+type ProfileNameStore = StableBTreeMap<String, Principal, Memory>;
+type VerificationStore = StableBTreeMap<Principal, Verification, Memory>;
+type VerificationEventStore = StableBTreeMap<u64, VerificationEvent, Memory>;
+type VerificationEventIdCounter = StableBTreeMap<u8, u64, Memory>;
+type EscrowStore = StableBTreeMap<u64, Escrow, Memory>;
+type EscrowIdCounter = StableBTreeMap<u8, u64, Memory>;
+type PendingPayoutStore = StableBTreeMap<u64, PendingPayout, Memory>;
+type PayoutIdCounter = StableBTreeMap<u8, u64, Memory>;
+// Asset id -> its escrow that is still holding funds
+type EscrowedAssetStore = StableBTreeMap<u64, u64, Memory>;
+type GiftStore = StableBTreeMap<u64, Gift, Memory>;
+// SHA-256 of a claim code (hex) -> the gifted asset
+type GiftCodeStore = StableBTreeMap<String, u64, Memory>;
+// Asset id -> the hash of its open gift's claim code
+type GiftCodeHashStore = StableBTreeMap<u64, String, Memory>;
+type RentalListingStore = StableBTreeMap<u64, RentalListing, Memory>;
+type RentalStore = StableBTreeMap<u64, Rental, Memory>;
+type RentalIdCounter = StableBTreeMap<u8, u64, Memory>;
+// (asset id, renter) -> the rental that currently runs longest
+type ActiveRentalStore = StableBTreeMap<(u64, Principal), u64, Memory>;
+// Each follow is stored in both directions, mapped to when it was made
+type FollowingStore = StableBTreeMap<(Principal, Principal), u64, Memory>;
+type FollowerStore = StableBTreeMap<(Principal, Principal), u64, Memory>;
+type FollowerCountStore = StableBTreeMap<Principal, u64, Memory>;
+// (recipient, notification id) so an inbox is one range scan
+type NotificationStore = StableBTreeMap<(Principal, u64), Notification, Memory>;
+type NotificationIdCounter = StableBTreeMap<u8, u64, Memory>;
+// (asset id, price point id); point ids only grow, so a range is oldest first
+type PriceHistoryStore = StableBTreeMap<(u64, u64), PricePoint, Memory>;
+type PricePointIdCounter = StableBTreeMap<u8, u64, Memory>;
+// Paused update methods by name; GLOBAL_PAUSE_KEY pauses all of them
+type PauseStore = StableBTreeMap<String, Pause, Memory>;
+type AssetAttachmentStore = StableBTreeMap<u64, AssetAttachments, Memory>;
+// Latest declining-price auction per asset
+type DutchAuctionStore = StableBTreeMap<u64, DutchAuction, Memory>;
+type DepositStore = StableBTreeMap<Principal, DepositAccount, Memory>;
+// (principal, entry id); entry ids only grow, so a range is oldest first
+type DepositLogStore = StableBTreeMap<(Principal, u64), DepositEntry, Memory>;
+type DepositEntryIdCounter = StableBTreeMap<u8, u64, Memory>;
+// At most one takedown per asset; a reinstated asset keeps its record
+type TakedownStore = StableBTreeMap<u64, Takedown, Memory>;
+type TakedownEventStore = StableBTreeMap<u64, TakedownEvent, Memory>;
+type TakedownEventIdCounter = StableBTreeMap<u8, u64, Memory>;
+// Sale volume per (day, seller) and (day, asset), kept for LEADERBOARD_DAYS
+// so windowed leaderboards never scan the sale log
+type SellerDailyTallyStore = StableBTreeMap<(u64, Principal), SaleTally, Memory>;
+type AssetDailyTallyStore = StableBTreeMap<(u64, u64), SaleTally, Memory>;
+type SellerTallyStore = StableBTreeMap<Principal, SaleTally, Memory>;
+type AssetTallyStore = StableBTreeMap<u64, SaleTally, Memory>;
+// "<seller>:<SHA-256 of the code>" -> coupon, so codes are scoped to a seller
+// and a seller's coupons form one contiguous range
+type CouponStore = StableBTreeMap<String, Coupon, Memory>;
+type CategoryStatsStore = StableBTreeMap<u64, StatCounts, Memory>;
+type OwnerStatsStore = StableBTreeMap<Principal, StatCounts, Memory>;
+type MarketplaceCounts = StableCell<StatCounts, Memory>;
+type MarketplaceSales = StableCell<SaleTally, Memory>;
+type FeaturedCell = StableCell<FeaturedList, Memory>;
+type SupportedLedgerStore = StableBTreeMap<Principal, SupportedLedger, Memory>;
+type EventSubscriptionStore = StableBTreeMap<Principal, EventSubscription, Memory>;
+// (activity seq, callback canister) -> delivery, so deliveries go out in event order
+type EventOutboxStore = StableBTreeMap<(u64, Principal), PendingDelivery, Memory>;
+
+// Newest method surface; see the API v2 section at the end of the endpoints
+const API_VERSION: u32 = 2;
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_IDS_PER_LOOKUP: usize = 200;
+// Keeps file chunk responses safely below the 2MB response limit
+const MAX_FILE_CHUNK_SIZE: u64 = 1_900_000;
+// Stored files are split into pieces of this many bytes; equal to the response
+// chunk so each streamed HTTP chunk is one stored piece
+const FILE_PIECE_SIZE: u64 = MAX_FILE_CHUNK_SIZE;
+// A maintenance run splits files stored whole until it has moved this many bytes
+const FILE_SPLIT_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+// Listing queries stop collecting assets once their estimated encoded size
+// passes this, leaving headroom under the 2MB response limit
+const RESPONSE_SIZE_BUDGET: usize = 1_500_000;
+// Cursor tokens name the store they index so one cannot be replayed against
+// another listing
+const ASSET_CURSOR_TAG: u8 = b'a';
+const SALE_CURSOR_TAG: u8 = b's';
+const EVENT_CURSOR_TAG: u8 = b'e';
+
+const MAX_NAME_LENGTH: usize = 128;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_REVIEW_LENGTH: usize = 2000;
+const MAX_UPLOAD_BATCH_SIZE: usize = 50;
+const MAX_BULK_UPDATE_SIZE: usize = 200;
+const MIGRATION_BATCH_SIZE: usize = 50;
+const MAX_REPORT_REASON_LENGTH: usize = 500;
+const MAX_REPORTS_PER_DAY: usize = 10;
+const REPORT_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+// 10 million ICP; low enough that price * 10_000 (basis points) fits in a u64
+const MAX_PRICE_E8S: u64 = 10_000_000 * 100_000_000;
+const MAX_ROYALTY_BPS: u16 = 1_000;
+const MAX_PLATFORM_FEE_BPS: u16 = 1_000;
+const LOW_CYCLES_THRESHOLD: u128 = 100_000_000_000; // 0.1T cycles
+const WASM_PAGE_SIZE: u64 = 65_536;
+const DEFAULT_STORAGE_QUOTA: u64 = 1024 * 1024 * 1024; // 1 GiB per principal
+// Share of a file's size charged to users whose upload reuses stored bytes
+const DEFAULT_DEDUP_CHARGE_PERCENT: u8 = 0;
+const MIN_BID_INCREMENT_E8S: u64 = 1_000_000; // 0.01 ICP
+const MAX_AUCTION_DURATION_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000; // 30 days
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_RENTAL_DAYS: u32 = 365;
+const DEFAULT_ESCROW_WINDOW_SECONDS: u64 = 3 * 24 * 60 * 60; // 3 days
+const MIN_ESCROW_WINDOW_SECONDS: u64 = 60 * 60; // 1 hour
+const MAX_ESCROW_WINDOW_SECONDS: u64 = 30 * 24 * 60 * 60; // 30 days
+const ENGAGEMENT_DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+const TRENDING_HALF_LIFE_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000; // 3 days
+// Daily sale tallies older than this are dropped; the longest window is 30 days
+const LEADERBOARD_DAYS: u64 = 30;
+const NOTEWORTHY_WINDOW_NANOS: u64 = 14 * 24 * 60 * 60 * 1_000_000_000; // 14 days
+// Ranked search keeps this many candidates beyond the requested page
+const SEARCH_RANK_BUFFER: usize = 50;
+// get_similar_assets scoring: each shared tag counts most, then a shared
+// category, then a price within a factor of two
+const SIMILAR_TAG_WEIGHT: u32 = 3;
+const SIMILAR_CATEGORY_WEIGHT: u32 = 2;
+const SIMILAR_PRICE_WEIGHT: u32 = 1;
+const ICRC7_NAME: &str = "VR Marketplace Assets";
+const ICRC7_SYMBOL: &str = "VRA";
+const ICRC7_MAX_UPDATE_BATCH_SIZE: usize = 20;
+const DEFAULT_ALLOWED_FILE_TYPES: [&str; 4] = ["glb", "gltf", "fbx", "vrm"];
+const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+const MAX_FILE_TYPE_LIMITS: usize = 32;
+const DEFAULT_MAX_PREVIEW_IMAGE_SIZE: u64 = 2 * 1024 * 1024;
+const PREVIEW_IMAGE_CONTENT_TYPES: [&str; 3] = ["image/png", "image/jpeg", "image/webp"];
+const MAX_ASSET_IMAGES: usize = 8;
+const MAX_CHANGELOG_LENGTH: usize = 2000;
+const DEFAULT_MAX_ASSET_ATTACHMENTS: u32 = 16;
+const MAX_ATTACHMENT_LABEL_LENGTH: usize = 64;
+// Attachments may also be any of the allowed model file types
+const ATTACHMENT_FILE_TYPES: [&str; 7] = ["png", "jpg", "jpeg", "webp", "ktx2", "pdf", "zip"];
+const RATE_LIMIT_WINDOW_NANOS: u64 = 60 * 1_000_000_000; // 1 minute
+const DEFAULT_UPLOADS_PER_MINUTE: u32 = 10;
+const DEFAULT_METADATA_UPDATES_PER_MINUTE: u32 = 60;
+const DEFAULT_RESERVATIONS_PER_MINUTE: u32 = 5;
+const MAX_CATEGORY_NAME_LENGTH: usize = 64;
+const MAX_GIFT_MESSAGE_LENGTH: usize = 500;
+const GIFT_CODE_BYTES: usize = 16;
+const MAX_METADATA_FIELDS: usize = 32;
+const MAX_METADATA_KEY_LENGTH: usize = 64;
+const MAX_METADATA_VALUE_BYTES: usize = 256;
+const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_PROFILE_URL_LENGTH: usize = 256;
+const RESERVED_DISPLAY_NAMES: [&str; 2] = ["admin", "system"];
+const MIN_BUNDLE_SIZE: usize = 2;
+const MAX_BUNDLE_SIZE: usize = 50;
+const MAX_NOTIFICATIONS_PER_PRINCIPAL: usize = 500;
+const MAX_PRICE_ALERTS_PER_PRINCIPAL: usize = 100;
+const MAX_PRICE_HISTORY: usize = 200;
+const MAX_LICENSE_URL_LENGTH: usize = 512;
+// The activity feed keeps this many of its most recent events
+const MAX_ACTIVITY_EVENTS: u64 = 1_000_000;
+const MAX_SLUG_LENGTH: usize = 64;
+// Each maintenance sweep looks at no more than this many entries per run
+const MAINTENANCE_BATCH_SIZE: usize = 200;
+const UPLOAD_SESSION_IDLE_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+// prune_orphaned_files leaves unreferenced files this recent in place
+const ORPHAN_GRACE_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+// Text model formats up to this size are stored compressed. Larger files are
+// stored as they are, which bounds the work of decompressing one on a read.
+const MAX_COMPRESSED_FILE_SIZE: u64 = 4 * 1024 * 1024;
+// recompress_existing_files stops a batch after this many files or once it
+// has compressed this many bytes
+const RECOMPRESS_BATCH_SIZE: usize = 50;
+const RECOMPRESS_BATCH_BYTES: u64 = 16 * 1024 * 1024;
+// Gallery images hashed per maintenance pass when backfilling digests
+const IMAGE_DIGEST_BATCH_BYTES: u64 = 16 * 1024 * 1024;
+// /assets.json lists this many of the newest publicly listed assets
+const MAX_ASSET_INDEX_ENTRIES: usize = 1_000;
+const MAX_EXTERNAL_ID_LENGTH: usize = 200;
+const MAX_EXTERNAL_URL_LENGTH: usize = 2048;
+// How long a checkout reservation holds an asset
+const RESERVATION_DURATION_NANOS: u64 = 10 * 60 * 1_000_000_000; // 10 minutes
+// After a hold ends, its holder must wait this long to reserve the same asset
+const RESERVATION_COOLDOWN_NANOS: u64 = 30 * 60 * 1_000_000_000; // 30 minutes
+const MAX_ACTIVE_RESERVATIONS_PER_HOLDER: usize = 3;
+// How long the old slug of a renamed asset keeps resolving to it
+const SLUG_REDIRECT_GRACE_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000; // 30 days
+const DEFAULT_DISPUTE_WINDOW_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000; // 3 days
+const MAX_DISPUTE_WINDOW_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000; // 90 days
+const GLOBAL_PAUSE_KEY: &str = "*";
+const MAX_PAUSE_REASON_LENGTH: usize = 500;
+const MAX_TAKEDOWN_DETAILS_LENGTH: usize = 2000;
+const MAX_FEATURED_ASSETS: usize = 20;
+// Pending payouts tried on each maintenance run
+const PAYOUT_RETRY_BATCH_SIZE: usize = 20;
+const MAX_SUPPORTED_LEDGERS: u64 = 10;
+const MAINNET_ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const MAX_LEDGER_SYMBOL_LENGTH: usize = 16;
+const MAX_EVENT_SUBSCRIPTIONS: u64 = 10;
+// Deliveries queued beyond this are dropped and recorded as failures
+const MAX_PENDING_EVENT_DELIVERIES: u64 = 10_000;
+const EVENT_DELIVERY_BATCH_SIZE: usize = 50;
+// A delivery is dropped after this many failed sends, waiting twice as long
+// after each failure
+const MAX_EVENT_DELIVERY_ATTEMPTS: u32 = 5;
+const EVENT_RETRY_BASE_NANOS: u64 = 30 * 1_000_000_000; // 30 seconds
+// The method subscribers implement; it receives one ActivityEvent
+const EVENT_CALLBACK_METHOD: &str = "on_asset_event";
+const MAX_APPEAL_LENGTH: usize = 2000;
+// A discounted sale always moves some funds to the seller
+const MAX_COUPON_PERCENT_OFF: u8 = 99;
+const MAX_COUPONS_PER_SELLER: usize = 100;
+// Reserved id for the bucket that assets without a matching category land in
+const UNCATEGORIZED_CATEGORY_ID: u64 = 0;
+const UNCATEGORIZED_CATEGORY_NAME: &str = "Uncategorized";
+// The categories the upload form offered before the registry existed
+const DEFAULT_CATEGORIES: [&str; 10] = [
+    "Environments", "Characters", "Vehicles", "Architecture", "Furniture",
+    "Weapons", "Tools", "Nature", "Abstract", "Other",
+];
 
 #[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
 pub struct Asset {
@@ -22,17 +335,64 @@ pub struct Asset {
     pub file_url: String,
     pub file_type: String, // "glb", "gltf", etc.
     pub file_size: u64,
-    pub price: u64, // in e8s (1 ICP = 100_000_000 e8s)
+    // One entry per token the asset can be bought in, each in that ledger's
+    // smallest unit; the entry for the ICP ledger is the price in e8s
+    // (1 ICP = 100_000_000 e8s). Read it through icp_price or price_in.
+    pub prices: Vec<PriceOption>,
     pub is_for_sale: bool,
     pub created_at: u64,
     pub updated_at: u64,
-    pub category: String,
+    pub category_id: u64,
+    pub category: String, // name of the category, kept in step with the registry
     pub tags: Vec<String>,
     pub preview_image_url: Option<String>,
+    pub creator: Principal,
+    pub royalty_bps: u16, // royalty on secondary sales, in basis points
+    // Set while a moderator has hidden the asset. Records stored before this
+    // field existed decode it as None, so no schema version bump is needed.
+    pub hidden: Option<Moderation>,
+    pub visibility: Visibility,
+    // Structured technical details (polygon count, rig type, ...) keyed by name
+    pub metadata: Vec<(String, MetadataValue)>,
+    // When the owner archived the asset. Archived assets are kept for their
+    // sale history but only their owner can see them, and they cannot change
+    // hands until unarchived. Decodes as None for older records, like hidden.
+    pub archived_at: Option<u64>,
+    pub license: License,
+    // URL-safe handle derived from the name. None only for records stored
+    // before slugs existed, until post_upgrade backfills them.
+    pub slug: Option<String>,
+    // Checkout hold. Stays on the record after it expires, so compare
+    // expires_at with the current time before showing it.
+    pub reservation: Option<Reservation>,
+    // Set for assets that did not start as an upload to this canister
+    pub source: Option<AssetSource>,
+    // Compliance hold: the asset stays visible but nothing may change or move it
+    pub frozen: Option<Freeze>,
 }
 
-impl Storable for Asset {
-    fn to_bytes(&self) -> Cow<[u8]> {
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub enum AssetSource {
+    // Migrated from an external catalog by import_external_assets
+    Imported { external_id: String },
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct PriceOption {
+    pub ledger: Principal,
+    pub amount: u64, // in the ledger's smallest unit
+}
+
+// A token ledger sellers may price assets in, besides the ICP ledger
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct SupportedLedger {
+    pub ledger: Principal,
+    pub symbol: String,
+    pub added_at: u64,
+}
+
+impl Storable for SupportedLedger {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -43,348 +403,20890 @@ impl Storable for Asset {
     const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
 }
 
-#[derive(CandidType, Serialize, SerdeDeserialize)]
-pub struct AssetInput {
-    pub name: String,
-    pub description: String,
-    pub file_hash: String,
-    pub file_url: String,
-    pub file_type: String,
-    pub file_size: u64,
-    pub price: u64,
-    pub category: String,
-    pub tags: Vec<String>,
-    pub preview_image_url: Option<String>,
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct Freeze {
+    pub reason: String,
+    pub frozen_by: Principal,
+    pub frozen_at: u64,
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
-    static ASSETS: RefCell<AssetStore> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
-    );
-
-    static ASSET_ID_COUNTER: RefCell<AssetIdCounter> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
-    );
-
-    static FILES: RefCell<FileStore> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
-        )
-    );
+// One slot of the landing page carousel
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct FeaturedEntry {
+    pub asset_id: u64,
+    // Owner when featured; the slot lapses once the asset changes hands
+    pub owner: Principal,
+    pub until: Option<u64>,
+    pub featured_by: Principal,
+    pub featured_at: u64,
 }
 
-fn get_next_asset_id() -> u64 {
-    ASSET_ID_COUNTER.with(|counter| {
-        let mut counter = counter.borrow_mut();
-        let current_id = counter.get(&0).unwrap_or(0);
-        let next_id = current_id + 1;
-        counter.insert(0, next_id);
-        next_id
-    })
+// Featured entries in curated order
+#[derive(CandidType, Serialize, SerdeDeserialize, Default)]
+struct FeaturedList {
+    entries: Vec<FeaturedEntry>,
 }
 
-#[update]
-fn upload_asset(asset_input: AssetInput) -> Result<Asset, String> {
-    let principal = caller();
-    
-    if principal == Principal::anonymous() {
-        return Err("Anonymous users cannot upload assets".to_string());
+impl Storable for FeaturedList {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
 
-    let asset_id = get_next_asset_id();
-    let current_time = time();
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
 
-    let asset = Asset {
-        id: asset_id,
-        name: asset_input.name,
-        description: asset_input.description,
-        owner: principal,
-        file_hash: asset_input.file_hash,
-        file_url: asset_input.file_url,
-        file_type: asset_input.file_type,
-        file_size: asset_input.file_size,
-        price: asset_input.price,
-        is_for_sale: false, // Assets start as not for sale
-        created_at: current_time,
-        updated_at: current_time,
-        category: asset_input.category,
-        tags: asset_input.tags,
-        preview_image_url: asset_input.preview_image_url,
-    };
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
 
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        assets.insert(asset_id, asset.clone());
-    });
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct Reservation {
+    pub holder: Principal,
+    pub reserved_at: u64,
+    pub expires_at: u64,
+}
 
-    Ok(asset)
+// What a buyer may do with the model. Listed from most to least restrictive;
+// custom licenses cannot be ranked against the others.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Eq, Debug)]
+pub enum License {
+    PersonalUse,
+    CommercialUse,
+    #[serde(rename = "CCBY")]
+    CcBy,
+    #[serde(rename = "CC0")]
+    Cc0,
+    Custom { url: String },
 }
 
-#[query]
-fn get_asset(asset_id: u64) -> Option<Asset> {
-    ASSETS.with(|assets| {
-        assets.borrow().get(&asset_id)
-    })
+impl License {
+    fn rank(&self) -> Option<u8> {
+        match self {
+            License::PersonalUse => Some(0),
+            License::CommercialUse => Some(1),
+            License::CcBy => Some(2),
+            License::Cc0 => Some(3),
+            License::Custom { .. } => None,
+        }
+    }
+
+    // Whether moving to `other` only grants buyers more. CC0 waives every
+    // right, so anything may move to it.
+    fn is_broadened_by(&self, other: &License) -> bool {
+        if self == other || *other == License::Cc0 {
+            return true;
+        }
+        matches!((self.rank(), other.rank()), (Some(current), Some(next)) if next > current)
+    }
 }
 
-#[query]
-fn get_user_assets(owner: Principal) -> Vec<Asset> {
-    ASSETS.with(|assets| {
-        assets
-            .borrow()
-            .iter()
-            .filter(|(_, asset)| asset.owner == owner)
-            .map(|(_, asset)| asset)
-            .collect()
-    })
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub enum MetadataValue {
+    Text(String),
+    Nat(u64),
+    Bool(bool),
 }
 
-#[query]
-fn get_all_assets() -> Vec<Asset> {
-    ASSETS.with(|assets| {
-        assets
-            .borrow()
-            .iter()
-            .map(|(_, asset)| asset)
-            .collect()
-    })
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Visibility {
+    Public,   // listed and searchable
+    Unlisted, // reachable by id, but kept out of listings and search
+    Private,  // only visible to the owner
 }
 
-#[query]
-fn get_assets_for_sale() -> Vec<Asset> {
-    ASSETS.with(|assets| {
-        assets
-            .borrow()
-            .iter()
-            .filter(|(_, asset)| asset.is_for_sale)
-            .map(|(_, asset)| asset)
-            .collect()
-    })
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Moderation {
+    pub reason: String,
+    pub hidden_by: Principal,
+    pub hidden_at: u64,
 }
 
-#[update]
-fn update_asset_price(asset_id: u64, new_price: u64) -> Result<Asset, String> {
-    let principal = caller();
-    
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        
-        match assets.get(&asset_id) {
-            Some(mut asset) => {
-                if asset.owner != principal {
-                    return Err("Only the owner can update the asset price".to_string());
-                }
-                
-                asset.price = new_price;
-                asset.updated_at = time();
-                assets.insert(asset_id, asset.clone());
-                Ok(asset)
-            },
-            None => Err("Asset not found".to_string()),
-        }
-    })
+// Schema version 1: the asset layout before creator royalties were introduced
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV1 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
 }
 
-#[update]
-fn set_asset_for_sale(asset_id: u64, for_sale: bool) -> Result<Asset, String> {
-    let principal = caller();
-    
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        
-        match assets.get(&asset_id) {
-            Some(mut asset) => {
-                if asset.owner != principal {
-                    return Err("Only the owner can change sale status".to_string());
-                }
-                
-                asset.is_for_sale = for_sale;
-                asset.updated_at = time();
-                assets.insert(asset_id, asset.clone());
-                Ok(asset)
-            },
-            None => Err("Asset not found".to_string()),
+impl From<AssetV1> for Asset {
+    // The uploader of a legacy asset is the best record we have of its creator
+    fn from(asset: AssetV1) -> Self {
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices: icp_prices(asset.price),
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.owner,
+            royalty_bps: 0,
+            hidden: None,
+            visibility: Visibility::Public,
+            metadata: Vec::new(),
+            archived_at: None,
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
         }
-    })
+    }
 }
 
-#[update]
-fn transfer_asset_ownership(asset_id: u64, new_owner: Principal) -> Result<Asset, String> {
-    let principal = caller();
-    
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        
-        match assets.get(&asset_id) {
-            Some(mut asset) => {
-                if asset.owner != principal {
-                    return Err("Only the owner can transfer ownership".to_string());
-                }
-                
-                asset.owner = new_owner;
-                asset.is_for_sale = false; // Remove from sale after transfer
-                asset.updated_at = time();
-                assets.insert(asset_id, asset.clone());
-                Ok(asset)
-            },
-            None => Err("Asset not found".to_string()),
+// Schema version 2: the asset layout before visibility was introduced
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV2 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
+    creator: Principal,
+    royalty_bps: u16,
+    hidden: Option<Moderation>,
+}
+
+impl From<AssetV2> for Asset {
+    // Every asset was public before visibility could be chosen
+    fn from(asset: AssetV2) -> Self {
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices: icp_prices(asset.price),
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: Visibility::Public,
+            metadata: Vec::new(),
+            archived_at: None,
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
         }
-    })
+    }
 }
 
-#[query]
-fn search_assets(query: String) -> Vec<Asset> {
-    let query_lower = query.to_lowercase();
-    
-    ASSETS.with(|assets| {
-        assets
-            .borrow()
-            .iter()
-            .filter(|(_, asset)| {
-                asset.name.to_lowercase().contains(&query_lower) ||
-                asset.description.to_lowercase().contains(&query_lower) ||
-                asset.category.to_lowercase().contains(&query_lower) ||
-                asset.tags.iter().any(|tag| tag.to_lowercase().contains(&query_lower))
-            })
-            .map(|(_, asset)| asset)
-            .collect()
-    })
+// Schema version 3: the asset layout before categories became registry ids
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV3 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
+    creator: Principal,
+    royalty_bps: u16,
+    hidden: Option<Moderation>,
+    visibility: Visibility,
 }
 
-#[query]
-fn get_assets_by_category(category: String) -> Vec<Asset> {
-    ASSETS.with(|assets| {
-        assets
-            .borrow()
-            .iter()
-            .filter(|(_, asset)| asset.category.to_lowercase() == category.to_lowercase())
-            .map(|(_, asset)| asset)
-            .collect()
-    })
+impl From<AssetV3> for Asset {
+    // The free-text category is matched against the registry by
+    // migrate_asset_categories; until then the asset is uncategorized
+    fn from(asset: AssetV3) -> Self {
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices: icp_prices(asset.price),
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            metadata: Vec::new(),
+            archived_at: None,
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
+        }
+    }
 }
 
-#[query]
-fn get_total_assets() -> u64 {
-    ASSETS.with(|assets| {
-        assets.borrow().len()
-    })
+// Schema version 4: the asset layout before structured metadata was introduced
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV4 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category_id: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
+    creator: Principal,
+    royalty_bps: u16,
+    hidden: Option<Moderation>,
+    visibility: Visibility,
 }
 
-// File upload and storage methods
-#[update]
-fn upload_file(file_hash: String, file_data: Vec<u8>) -> Result<String, String> {
-    let principal = caller();
-    
-    if principal == Principal::anonymous() {
-        return Err("Anonymous users cannot upload files".to_string());
+impl From<AssetV4> for Asset {
+    fn from(asset: AssetV4) -> Self {
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices: icp_prices(asset.price),
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: asset.category_id,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            metadata: Vec::new(),
+            archived_at: None,
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
+        }
     }
+}
+
+// Schema version 5: the asset layout before licenses were introduced
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV5 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category_id: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
+    creator: Principal,
+    royalty_bps: u16,
+    hidden: Option<Moderation>,
+    visibility: Visibility,
+    metadata: Vec<(String, MetadataValue)>,
+    archived_at: Option<u64>,
+}
 
-    // Check if file already exists
-    FILES.with(|files| {
-        let mut files = files.borrow_mut();
-        if files.contains_key(&file_hash) {
-            return Err("File already exists".to_string());
+impl From<AssetV5> for Asset {
+    fn from(asset: AssetV5) -> Self {
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices: icp_prices(asset.price),
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: asset.category_id,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            metadata: asset.metadata,
+            archived_at: asset.archived_at,
+            // The most restrictive license, so no buyer is granted more than
+            // the seller meant to
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
         }
-        
-        files.insert(file_hash.clone(), file_data);
-        Ok(file_hash)
-    })
+    }
 }
 
-#[query]
-fn get_file(file_hash: String) -> Option<Vec<u8>> {
-    FILES.with(|files| {
-        files.borrow().get(&file_hash)
-    })
+// Schema version 6: the asset layout before the ICP price became one of the
+// per-token prices. `prices` and `sold_in_icp` were added to it as optional
+// fields, so version 6 records may lack them.
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+struct AssetV6 {
+    id: u64,
+    name: String,
+    description: String,
+    owner: Principal,
+    file_hash: String,
+    file_url: String,
+    file_type: String,
+    file_size: u64,
+    price: u64,
+    is_for_sale: bool,
+    created_at: u64,
+    updated_at: u64,
+    category_id: u64,
+    category: String,
+    tags: Vec<String>,
+    preview_image_url: Option<String>,
+    creator: Principal,
+    royalty_bps: u16,
+    hidden: Option<Moderation>,
+    visibility: Visibility,
+    metadata: Vec<(String, MetadataValue)>,
+    archived_at: Option<u64>,
+    license: License,
+    slug: Option<String>,
+    reservation: Option<Reservation>,
+    source: Option<AssetSource>,
+    frozen: Option<Freeze>,
+    prices: Option<Vec<PriceOption>>,
+    sold_in_icp: Option<bool>,
 }
 
-#[update]
-fn upload_asset_with_file(asset_input: AssetInput, file_data: Vec<u8>) -> Result<Asset, String> {
-    let principal = caller();
-    
-    if principal == Principal::anonymous() {
-        return Err("Anonymous users cannot upload assets".to_string());
+impl From<AssetV6> for Asset {
+    fn from(asset: AssetV6) -> Self {
+        // sold_in_icp was Some(false) once the owner dropped the ICP price
+        let mut prices = if asset.sold_in_icp == Some(false) {
+            Vec::new()
+        } else {
+            icp_prices(asset.price)
+        };
+        prices.extend(asset.prices.unwrap_or_default());
+        Asset {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            prices,
+            is_for_sale: asset.is_for_sale,
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: asset.category_id,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            metadata: asset.metadata,
+            archived_at: asset.archived_at,
+            license: asset.license,
+            slug: asset.slug,
+            reservation: asset.reservation,
+            source: asset.source,
+            frozen: asset.frozen,
+        }
     }
+}
 
-    // Store the file hash before moving asset_input
-    let file_hash = asset_input.file_hash.clone();
-
-    // First upload the file
-    FILES.with(|files| {
-        let mut files = files.borrow_mut();
-        files.insert(file_hash.clone(), file_data);
-    });
+// Stored asset records are prefixed with ASSET_SCHEMA_TAG and a schema version.
+// Older versions are upgraded to the current layout when read and written back
+// in the new layout on their next update (or eagerly via migrate_assets).
+// Records written before tagging was introduced are plain candid, which always
+// starts with "DIDL" and so cannot be mistaken for a tagged record.
+const ASSET_SCHEMA_TAG: u8 = 0xA5;
+const ASSET_SCHEMA_VERSION: u8 = 7;
 
-    // Then create the asset record
-    let asset_id = get_next_asset_id();
-    let current_time = time();
+// Only used to recover the id of a record that fails to decode
+#[derive(CandidType, SerdeDeserialize)]
+struct StoredAssetId {
+    id: u64,
+}
 
-    let asset = Asset {
-        id: asset_id,
-        name: asset_input.name,
-        description: asset_input.description,
-        owner: principal,
-        file_hash: file_hash.clone(),
-        file_url: format!("canister://{}", file_hash), // Internal canister URL
-        file_type: asset_input.file_type,
-        file_size: asset_input.file_size,
-        price: asset_input.price,
-        is_for_sale: false,
-        created_at: current_time,
-        updated_at: current_time,
-        category: asset_input.category,
-        tags: asset_input.tags,
-        preview_image_url: asset_input.preview_image_url,
+fn decode_asset(bytes: &[u8]) -> Result<Asset, String> {
+    let decoded = match bytes {
+        [ASSET_SCHEMA_TAG, 7, payload @ ..] => candid::decode_one::<Asset>(payload),
+        [ASSET_SCHEMA_TAG, 6, payload @ ..] => candid::decode_one::<AssetV6>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, 5, payload @ ..] => candid::decode_one::<AssetV5>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, 4, payload @ ..] => candid::decode_one::<AssetV4>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, 3, payload @ ..] => candid::decode_one::<AssetV3>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, 2, payload @ ..] => candid::decode_one::<AssetV2>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, 1, payload @ ..] => candid::decode_one::<AssetV1>(payload).map(Asset::from),
+        [ASSET_SCHEMA_TAG, version, ..] => {
+            return Err(format!("Unsupported asset schema version {}", version));
+        },
+        untagged => candid::decode_one::<AssetV2>(untagged)
+            .map(Asset::from)
+            .or_else(|_| candid::decode_one::<AssetV1>(untagged).map(Asset::from)),
     };
 
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        assets.insert(asset_id, asset.clone());
-    });
-
-    Ok(asset)
+    decoded.map_err(|err| {
+        let payload = match bytes {
+            [ASSET_SCHEMA_TAG, _, payload @ ..] => payload,
+            untagged => untagged,
+        };
+        let asset_id = candid::decode_one::<StoredAssetId>(payload)
+            .map_or("<unknown>".to_string(), |stored| stored.id.to_string());
+        format!("Failed to decode stored asset {}: {}", asset_id, err)
+    })
 }
 
-#[update]
-fn marketplace_transfer_asset(asset_id: u64, seller: Principal, buyer: Principal) -> Result<Asset, String> {
-    let _marketplace_principal = caller();
-    
-    // In a production environment, you might want to maintain a list of authorized marketplace canisters
-    // For now, we'll allow any canister to initiate transfers (you can add authorization later)
-    
-    ASSETS.with(|assets| {
-        let mut assets = assets.borrow_mut();
-        
-        match assets.get(&asset_id) {
-            Some(mut asset) => {
-                // Verify the seller is the current owner
-                if asset.owner != seller {
-                    return Err("Seller is not the current owner of the asset".to_string());
-                }
-                
-                // Verify the asset is for sale
-                if !asset.is_for_sale {
-                    return Err("Asset is not for sale".to_string());
-                }
-                
-                // Transfer ownership
-                asset.owner = buyer;
-                asset.is_for_sale = false; // Remove from sale after transfer
-                asset.updated_at = time();
-                assets.insert(asset_id, asset.clone());
-                Ok(asset)
+impl Storable for Asset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![ASSET_SCHEMA_TAG, ASSET_SCHEMA_VERSION];
+        bytes.extend(candid::encode_one(self).unwrap());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_asset(&bytes).unwrap_or_else(|message| ic_cdk::trap(&message))
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Debug, PartialEq)]
+pub enum AssetError {
+    NotFound,
+    NotOwner,
+    NotForSale,
+    AnonymousCaller,
+    InvalidInput(String),
+    FileTooLarge { max: u64 },
+    AlreadyExists,
+    Unauthorized,
+    ListedForSale,
+    TransferInProgress,
+    PaymentFailed(String),
+    ApprovalExpired,
+    QuotaExceeded { remaining: u64 },
+    OfferNotFound,
+    OfferNotActive,
+    OfferExpired,
+    AuctionNotFound,
+    AuctionNotActive,
+    AuctionEnded,
+    BidTooLow { min_bid: u64 },
+    RateLimited { retry_after_seconds: u64 },
+    AssetHidden,
+    CollectionNotFound,
+    NoPendingTransfer,
+    InvalidRecipient,
+    InvalidPrice { max: u64 },
+    BundleNotFound,
+    InBundle { bundle_id: u64 },
+    InvalidFileFormat { expected: String },
+    DisplayNameTaken,
+    EscrowNotFound,
+    RefundWindowClosed,
+    GiftNotFound,
+    NotForRent,
+    AssetArchived,
+    MethodPaused { reason: Option<String> },
+    InvalidCursor,
+    InsufficientBalance { available: u64, required: u64 },
+    TakenDown,
+    CouponNotFound,
+    CouponExpired,
+    CouponUsedUp,
+    CouponNotApplicable,
+    NotArchived,
+    AssetReserved { expires_at: u64 },
+    NoReservation,
+    AssetFrozen,
+    UnsupportedLedger,
+    NotPricedInLedger,
+    TooManyReservations,
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::NotFound => write!(f, "Asset not found"),
+            AssetError::NotOwner => write!(f, "Caller is not the owner of the asset"),
+            AssetError::NotForSale => write!(f, "Asset is not for sale"),
+            AssetError::AnonymousCaller => write!(f, "Anonymous callers are not allowed"),
+            AssetError::InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
+            AssetError::FileTooLarge { max } => write!(f, "File exceeds the maximum size of {} bytes", max),
+            AssetError::AlreadyExists => write!(f, "File already exists"),
+            AssetError::Unauthorized => write!(f, "Caller is not authorized"),
+            AssetError::ListedForSale => write!(f, "Asset is listed for sale"),
+            AssetError::TransferInProgress => write!(f, "A transfer of this asset is already in progress"),
+            AssetError::PaymentFailed(reason) => write!(f, "Payment failed: {}", reason),
+            AssetError::ApprovalExpired => write!(f, "Transfer approval has expired"),
+            AssetError::QuotaExceeded { remaining } => write!(f, "Storage quota exceeded, {} bytes remaining", remaining),
+            AssetError::OfferNotFound => write!(f, "Offer not found"),
+            AssetError::OfferNotActive => write!(f, "Offer is no longer active"),
+            AssetError::OfferExpired => write!(f, "Offer has expired"),
+            AssetError::AuctionNotFound => write!(f, "Auction not found"),
+            AssetError::AuctionNotActive => write!(f, "Auction is not active"),
+            AssetError::AuctionEnded => write!(f, "Auction has already ended"),
+            AssetError::BidTooLow { min_bid } => write!(f, "Bid too low, the minimum bid is {} e8s", min_bid),
+            AssetError::RateLimited { retry_after_seconds } => {
+                write!(f, "Too many requests, try again in {} seconds", retry_after_seconds)
             },
-            None => Err("Asset not found".to_string()),
+            AssetError::AssetHidden => write!(f, "Asset has been hidden by a moderator"),
+            AssetError::CollectionNotFound => write!(f, "Collection not found"),
+            AssetError::NoPendingTransfer => write!(f, "No pending transfer for this asset"),
+            AssetError::InvalidRecipient => write!(f, "Recipient must be another, non-anonymous user principal"),
+            AssetError::InvalidPrice { max } => write!(f, "Price must be greater than zero and at most {} e8s", max),
+            AssetError::BundleNotFound => write!(f, "Bundle not found"),
+            AssetError::InBundle { bundle_id } => write!(f, "Asset is part of bundle {}", bundle_id),
+            AssetError::InvalidFileFormat { expected } => write!(f, "Invalid file format: expected {}", expected),
+            AssetError::DisplayNameTaken => write!(f, "Display name is already taken"),
+            AssetError::EscrowNotFound => write!(f, "No escrowed purchase for this asset"),
+            AssetError::RefundWindowClosed => write!(f, "The refund window for this purchase has closed"),
+            AssetError::GiftNotFound => write!(f, "Gift not found or no longer claimable"),
+            AssetError::NotForRent => write!(f, "Asset is not available for rent"),
+            AssetError::AssetArchived => write!(f, "Asset is archived"),
+            AssetError::MethodPaused { reason } => match reason {
+                Some(reason) => write!(f, "This method is paused: {}", reason),
+                None => write!(f, "This method is paused"),
+            },
+            AssetError::InvalidCursor => write!(f, "Invalid pagination cursor"),
+            AssetError::InsufficientBalance { available, required } => write!(f, "Deposit balance of {} e8s does not cover the {} e8s required", available, required),
+            AssetError::TakenDown => write!(f, "Asset has been taken down by a moderator"),
+            AssetError::CouponNotFound => write!(f, "Coupon not found"),
+            AssetError::CouponExpired => write!(f, "Coupon has expired"),
+            AssetError::CouponUsedUp => write!(f, "Coupon has no uses left"),
+            AssetError::CouponNotApplicable => write!(f, "Coupon does not apply to this asset"),
+            AssetError::NotArchived => write!(f, "Asset must be archived first"),
+            AssetError::AssetReserved { expires_at } => write!(f, "Asset is reserved for checkout until {}", expires_at),
+            AssetError::NoReservation => write!(f, "Asset has no active reservation"),
+            AssetError::AssetFrozen => write!(f, "Asset is frozen pending a moderation review"),
+            AssetError::UnsupportedLedger => write!(f, "Ledger is not on the supported ledger list"),
+            AssetError::NotPricedInLedger => write!(f, "Asset has no price in this ledger"),
+            AssetError::TooManyReservations => write!(f, "Too many assets reserved at once"),
         }
-    })
+    }
 }
 
-// Export Candid interface
-ic_cdk::export_candid!();
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetInput {
+    pub name: String,
+    pub description: String,
+    pub file_hash: String,
+    pub file_url: String,
+    pub file_type: String,
+    pub file_size: u64,
+    pub price: u64,
+    pub category_id: u64,
+    pub tags: Vec<String>,
+    pub preview_image_url: Option<String>,
+    pub royalty_bps: Option<u16>,
+    pub license: License,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct InitArgs {
+    pub ledger_canister_id: Option<Principal>,
+    pub default_storage_quota: Option<u64>,
+    pub max_price_e8s: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct StorageUsage {
+    pub used: u64,
+    pub reserved: u64, // declared size of unfinished chunked uploads
+    pub quota: u64,
+    pub remaining: u64,
+}
+
+// ICRC-1/ICRC-2 ledger types used for direct and escrowed purchases
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct LedgerTransferArgs {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Debug)]
+pub enum LedgerTransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// ICRC-7 NFT types; token ids are asset ids
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct UpdateAssetRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub category_id: Option<u64>,
+    pub tags: Option<Vec<String>>,
+    pub preview_image_url: Option<String>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct UploadSession {
+    pub id: u64,
+    pub owner: Principal,
+    pub file_hash: String,
+    pub file_type: String,
+    pub total_size: u64,
+    pub received_size: u64,
+    pub chunk_count: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for UploadSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct FileInfo {
+    pub file_hash: String,
+    pub total_size: u64,
+    pub content_type: String,
+    pub uploaded_by: Option<Principal>, // charged for the bytes against their quota
+    // How the stored bytes are compressed. total_size, the hash and quota
+    // charges always refer to the uncompressed bytes.
+    pub encoding: Option<ContentEncoding>,
+    // When the bytes were last uploaded, original or duplicate. None for files
+    // stored before this was recorded.
+    pub uploaded_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ContentEncoding {
+    Gzip,
+}
+
+impl Storable for FileInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// `deduplicated` is set when the canister already held these bytes and the
+// upload reused them instead of storing a second copy
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct FileUpload {
+    pub file_hash: String,
+    pub deduplicated: bool,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetUpload {
+    pub asset: Asset,
+    pub deduplicated: bool,
+}
+
+// Asset as the v1 methods returned it when API v2 was introduced. Deployed
+// clients decode these bytes, so this shape is frozen: fields added to Asset
+// since then are only served by the v2 methods, except the reservation,
+// which v1 clients read through get_asset_reservation.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetV1Response {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub owner: Principal,
+    pub file_hash: String,
+    pub file_url: String,
+    pub file_type: String,
+    pub file_size: u64,
+    pub price: u64,
+    pub is_for_sale: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub category_id: u64,
+    pub category: String,
+    pub tags: Vec<String>,
+    pub preview_image_url: Option<String>,
+    pub creator: Principal,
+    pub royalty_bps: u16,
+    pub hidden: Option<Moderation>,
+    pub visibility: Visibility,
+    pub metadata: Vec<(String, MetadataValue)>,
+    pub archived_at: Option<u64>,
+}
+
+// v1 callers only know prices in e8s, so an asset that cannot be bought in
+// ICP shows to them as not for sale
+impl From<Asset> for AssetV1Response {
+    fn from(asset: Asset) -> Self {
+        let icp_price = icp_price(&asset);
+        AssetV1Response {
+            id: asset.id,
+            name: asset.name,
+            description: asset.description,
+            owner: asset.owner,
+            file_hash: asset.file_hash,
+            file_url: asset.file_url,
+            file_type: asset.file_type,
+            file_size: asset.file_size,
+            price: icp_price.unwrap_or(0),
+            is_for_sale: asset.is_for_sale && icp_price.is_some(),
+            created_at: asset.created_at,
+            updated_at: asset.updated_at,
+            category_id: asset.category_id,
+            category: asset.category,
+            tags: asset.tags,
+            preview_image_url: asset.preview_image_url,
+            creator: asset.creator,
+            royalty_bps: asset.royalty_bps,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            metadata: asset.metadata,
+            archived_at: asset.archived_at,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetUploadV1Response {
+    pub asset: AssetV1Response,
+    pub deduplicated: bool,
+}
+
+impl From<AssetUpload> for AssetUploadV1Response {
+    fn from(upload: AssetUpload) -> Self {
+        AssetUploadV1Response {
+            asset: upload.asset.into(),
+            deduplicated: upload.deduplicated,
+        }
+    }
+}
+
+// Preview image stored in the canister itself
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PreviewImage {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+    pub uploaded_by: Principal, // charged for the bytes against their quota
+    pub uploaded_at: u64,
+}
+
+impl Storable for PreviewImage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Image ids of an asset's gallery, in display order
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Default)]
+pub struct AssetGallery {
+    pub image_ids: Vec<u64>,
+}
+
+impl Storable for AssetGallery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetImage {
+    pub id: u64,
+    pub content_type: String,
+    pub size: u64,
+    pub uploaded_at: u64,
+    pub url: String, // HTTP gateway path on this canister
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetVersion {
+    pub version: u32,
+    pub file_hash: String,
+    pub file_size: u64,
+    pub changelog: String,
+    pub timestamp: u64,
+}
+
+// Extra file shipped with an asset, such as a texture pack, an LOD variant or
+// a license. Downloading it needs the same access as the asset's model.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetAttachment {
+    pub label: String,
+    pub file_hash: String,
+    pub file_type: String,
+    pub file_size: u64,
+    pub added_at: u64,
+}
+
+// Attachments of one asset in the order they were added; labels are unique
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Default)]
+pub struct AssetAttachments {
+    pub attachments: Vec<AssetAttachment>,
+}
+
+impl Storable for AssetAttachments {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for AssetVersion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Transfer offered by the owner and waiting for the recipient to claim it
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PendingTransfer {
+    pub asset_id: u64,
+    pub from: Principal,
+    pub to: Principal,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl PendingTransfer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+// An asset set aside for whoever redeems the claim code. Only a hash of the
+// code is kept, so it cannot be read back out of canister state.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Gift {
+    pub asset_id: u64,
+    pub sender: Principal,
+    pub message: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl Gift {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+impl Storable for Gift {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Terms under which an owner offers an asset for rent
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct RentalListing {
+    pub asset_id: u64,
+    pub owner: Principal,
+    pub price_per_day: u64, // in e8s
+    pub max_days: u32,
+    pub created_at: u64,
+}
+
+impl Storable for RentalListing {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// A time-boxed license to download an asset without owning it
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Rental {
+    pub id: u64,
+    pub asset_id: u64,
+    pub owner: Principal, // who was paid; the asset may change hands later
+    pub renter: Principal,
+    pub days: u32,
+    pub price: u64,
+    pub platform_fee: Option<PlatformFee>,
+    pub starts_at: u64,
+    pub expires_at: u64,
+    // Some(false) until the owner's share is paid out of the collected
+    // payment. None for rentals whose owner the renter paid directly.
+    pub owner_paid: Option<bool>,
+}
+
+impl Storable for Rental {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Returned to the sender only; the claim code is not stored anywhere
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct CreatedGift {
+    pub gift: Gift,
+    pub claim_code: String,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CouponScope {
+    Asset(u64),
+    AllMyAssets,
+}
+
+// Only the hash of a code is ever sent at creation or stored; buyers present
+// the code itself when purchasing
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Coupon {
+    pub code_hash: String,
+    pub seller: Principal,
+    pub scope: CouponScope,
+    pub percent_off: u8,
+    pub max_uses: u32,
+    pub remaining_uses: u32,
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Coupon {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+impl Storable for Coupon {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for PendingTransfer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct SaleRecord {
+    pub id: u64,
+    pub asset_id: u64,
+    pub seller: Principal,
+    pub buyer: Principal,
+    pub price: u64, // in e8s
+    pub timestamp: u64,
+    pub royalty: Option<Royalty>,
+    // Sales recorded before platform fees existed have neither of these
+    pub platform_fee: Option<PlatformFee>,
+    pub seller_amount: Option<u64>, // price less royalty and platform fee
+    // The license the buyer got; None for sales recorded before licenses existed
+    pub license: Option<License>,
+    // Some(false) until the seller's share is paid out of the collected
+    // payment. None for sales whose seller the buyer paid directly.
+    pub seller_paid: Option<bool>,
+    // The token the sale settled in; price is in its smallest unit. None for
+    // sales recorded before other ledgers were accepted, which settled in ICP.
+    pub ledger: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum BundleStatus {
+    Active,
+    Sold,
+    Invalidated, // a member changed hands or was deleted outside the bundle
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Bundle {
+    pub id: u64,
+    pub owner: Principal,
+    pub name: String,
+    pub description: String,
+    pub asset_ids: Vec<u64>,
+    pub price: u64, // in e8s, for all member assets together
+    pub is_for_sale: bool,
+    pub status: BundleStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Bundle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// The bundle-level record of a bundle purchase; each member also gets its own
+// SaleRecord for its share of the price
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct BundleSale {
+    pub bundle_id: u64,
+    pub seller: Principal,
+    pub buyer: Principal,
+    pub price: u64,
+    pub sale_ids: Vec<u64>, // in member order
+    pub timestamp: u64,
+}
+
+impl Storable for BundleSale {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct BundlePage {
+    pub bundles: Vec<Bundle>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Profile {
+    pub principal: Principal,
+    pub display_name: String,
+    pub bio: String,
+    pub avatar_url: Option<String>,
+    pub website: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Filled in from the verification store when the profile is read
+    pub verification: Option<Verification>,
+    // Filled in from the follow indexes when the profile is read
+    pub follows: Option<FollowCounts>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub struct FollowCounts {
+    pub followers: u64,
+    pub following: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct FollowList {
+    pub principals: Vec<Principal>,
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum NotificationKind {
+    OfferReceived,
+    OfferAccepted,
+    AssetSold,
+    AssetTransferredToYou,
+    PriceDropOnFavorite,
+    AssetTakenDown,
+    AssetReinstated,
+    AppealRejected,
+    PriceAlertTriggered,
+    TransferReverted,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Notification {
+    pub id: u64,
+    pub kind: NotificationKind,
+    pub asset_id: u64,
+    pub counterparty: Option<Principal>, // bidder, buyer or sender, when there is one
+    pub amount: Option<u64>,             // offer, sale or new price in e8s
+    pub created_at: u64,
+    pub read: bool,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    pub total_count: u64,
+    pub unread_count: u64,
+}
+
+impl Storable for Profile {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetWithCreator {
+    pub asset: Asset,
+    pub owner_display_name: Option<String>,
+    pub owner_verified: bool,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetWithCreatorPage {
+    pub assets: Vec<AssetWithCreator>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Pause {
+    pub reason: Option<String>,
+    pub paused_at: u64,
+}
+
+impl Storable for Pause {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct PauseStatus {
+    pub global: Option<Pause>,
+    pub methods: Vec<(String, Pause)>,
+}
+
+// Pre-funded balance held in the principal's deposit subaccount. `balance` is
+// what the principal may spend; `ledger_balance` is the subaccount balance as
+// last seen on the ledger, so only growth beyond it is ever credited.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Default)]
+pub struct DepositAccount {
+    pub balance: u64,
+    pub ledger_balance: u64,
+    pub in_flight: bool, // a ledger call for this account is awaiting its reply
+}
+
+impl Storable for DepositAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub enum DepositEntryKind {
+    Deposit,
+    Purchase { asset_id: u64 },
+    Withdrawal,
+}
+
+// One credit or debit of a deposit balance
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct DepositEntry {
+    pub id: u64,
+    pub kind: DepositEntryKind,
+    pub credit: u64,
+    pub debit: u64,
+    pub balance_after: u64,
+    pub block_index: Option<Nat>,
+    pub timestamp: u64,
+}
+
+impl Storable for DepositEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct DepositEntryPage {
+    pub entries: Vec<DepositEntry>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Verification {
+    pub principal: Principal,
+    pub verified_by: Principal,
+    pub verified_at: u64,
+}
+
+impl Storable for Verification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct VerificationPage {
+    pub creators: Vec<Verification>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum VerificationEventKind {
+    Granted,
+    Revoked,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct VerificationEvent {
+    pub id: u64,
+    pub principal: Principal,
+    pub kind: VerificationEventKind,
+    pub moderator: Principal,
+    pub timestamp: u64,
+}
+
+impl Storable for VerificationEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct VerificationEventPage {
+    pub events: Vec<VerificationEvent>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TakedownReason {
+    Copyright,
+    Trademark,
+    IllegalContent,
+    Malware,
+    Other,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Appeal {
+    pub statement: String,
+    pub filed_at: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AppealResolution {
+    pub reinstated: bool,
+    pub note: String,
+    pub resolved_by: Principal,
+    pub resolved_at: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Takedown {
+    pub asset_id: u64,
+    pub owner: Principal,
+    pub reason_code: TakedownReason,
+    pub details: String,
+    pub taken_down_by: Principal,
+    pub taken_down_at: u64,
+    pub appeal: Option<Appeal>,
+    pub resolution: Option<AppealResolution>,
+}
+
+impl Takedown {
+    // Upheld takedowns stay in force; only a reinstatement lifts one
+    fn is_active(&self) -> bool {
+        !self.resolution.as_ref().is_some_and(|resolution| resolution.reinstated)
+    }
+}
+
+impl Storable for Takedown {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TakedownEventKind {
+    TakenDown { reason_code: TakedownReason },
+    AppealFiled,
+    Reinstated,
+    Upheld,
+    Frozen,
+    Unfrozen,
+    TransferReverted { event_id: u64 },
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct TakedownEvent {
+    pub id: u64,
+    pub asset_id: u64,
+    pub kind: TakedownEventKind,
+    pub actor: Principal, // the moderator, or the owner for appeals
+    pub note: String,
+    pub timestamp: u64,
+}
+
+impl Storable for TakedownEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TakedownEventPage {
+    pub events: Vec<TakedownEvent>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub enum ActivityKind {
+    // Prices are in e8s; None while the asset cannot be bought in ICP
+    AssetCreated { owner: Principal, price: Option<u64>, is_for_sale: bool },
+    PriceChanged { old_price: Option<u64>, new_price: Option<u64> },
+    Listed { price: Option<u64> },
+    Unlisted,
+    Transferred { from: Principal, to: Principal, kind: OwnershipEventKind, price: Option<u64> },
+    Sold { seller: Principal, buyer: Principal, price: u64 },
+    Deleted,
+}
+
+// One entry in the global activity feed. `actor` is None when the canister
+// made the change itself, e.g. when a listing window expires or a transfer is
+// settled; the payload names the parties involved.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct ActivityEvent {
+    pub seq: u64,
+    pub asset_id: u64,
+    pub actor: Option<Principal>,
+    pub kind: ActivityKind,
+    pub timestamp: u64,
+}
+
+impl Storable for ActivityEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// What a subscriber can ask to be pushed, one per ActivityKind variant
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum EventKind {
+    AssetCreated,
+    PriceChanged,
+    Listed,
+    Unlisted,
+    Transferred,
+    Sold,
+    Deleted,
+}
+
+impl ActivityKind {
+    fn event_kind(&self) -> EventKind {
+        match self {
+            ActivityKind::AssetCreated { .. } => EventKind::AssetCreated,
+            ActivityKind::PriceChanged { .. } => EventKind::PriceChanged,
+            ActivityKind::Listed { .. } => EventKind::Listed,
+            ActivityKind::Unlisted => EventKind::Unlisted,
+            ActivityKind::Transferred { .. } => EventKind::Transferred,
+            ActivityKind::Sold { .. } => EventKind::Sold,
+            ActivityKind::Deleted => EventKind::Deleted,
+        }
+    }
+}
+
+// The latest delivery that was given up on
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct DeliveryFailure {
+    pub event_seq: u64,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+// A canister that gets matching activity events pushed to its on_asset_event
+// method
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct EventSubscription {
+    pub callback_canister: Principal,
+    pub event_kinds: Vec<EventKind>,
+    pub subscribed_by: Principal,
+    pub subscribed_at: u64,
+    pub delivered_count: u64,
+    pub dropped_count: u64,
+    pub last_failure: Option<DeliveryFailure>,
+}
+
+impl Storable for EventSubscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+struct PendingDelivery {
+    event: ActivityEvent,
+    attempts: u32,
+    next_attempt_at: u64,
+}
+
+impl Storable for PendingDelivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Resume from `next_seq`. A start before `oldest_seq` means events were
+// dropped by retention and the reader has to resync from current state.
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct ActivityEventPage {
+    pub events: Vec<ActivityEvent>,
+    pub oldest_seq: u64,
+    pub latest_seq: u64,
+    pub next_seq: u64,
+}
+
+// Where a slug points. The previous slug of a renamed asset becomes a redirect
+// that stops resolving, and can be taken by another asset, once it expires.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct SlugEntry {
+    pub asset_id: u64,
+    pub redirect_until: Option<u64>,
+}
+
+impl Storable for SlugEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// One member's part of a bundle purchase, fixed before the payment is made
+struct BundleShare {
+    asset_id: u64,
+    price: u64,
+    royalty: Option<Royalty>,
+    platform_fee: Option<PlatformFee>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Royalty {
+    pub creator: Principal,
+    pub royalty_bps: u16,
+    pub amount: u64, // in e8s, part of the sale price
+    pub paid: bool,  // whether the amount was paid out through the ledger
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PlatformFee {
+    pub recipient: Principal,
+    pub fee_bps: u16,
+    pub amount: u64, // in e8s, part of the sale price
+    pub paid: bool,  // whether the amount was paid out through the ledger
+}
+
+// Which part of a sale a payout settles
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum PayoutLeg {
+    Seller { sale_id: u64 },
+    Royalty { sale_id: u64 },
+    PlatformFee { sale_id: u64 },
+    RentalOwner { rental_id: u64 },
+    RentalFee { rental_id: u64 },
+}
+
+// The subaccount of this canister that holds the funds for a payout
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum PayoutSource {
+    Settlement,
+    Escrow { escrow_id: u64 },
+    // The buyer's deposit subaccount; the ledger fee comes off its balance too
+    Deposit { buyer: Principal, ledger_fee: u64 },
+}
+
+// A payment the buyer has already made to this canister and that is still
+// owed to someone. It stays queued, and is retried, until the ledger takes it.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct PendingPayout {
+    pub id: u64,
+    pub leg: PayoutLeg,
+    pub ledger: Principal,
+    pub source: PayoutSource,
+    pub to: Principal,
+    pub amount: u64,
+    pub asset_id: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+impl Storable for PendingPayout {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct PendingPayoutPage {
+    pub payouts: Vec<PendingPayout>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum EscrowStatus {
+    Held,
+    Confirmed,
+    AutoConfirmed, // the refund window ran out without a decision from the buyer
+    Refunded,
+}
+
+// A purchase whose payment sits in a subaccount of this canister until the
+// buyer confirms it, asks for a refund, or the refund window runs out
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Escrow {
+    pub id: u64,
+    pub asset_id: u64,
+    pub buyer: Principal,
+    pub seller: Principal,
+    pub price: u64,
+    pub royalty: Option<Royalty>,
+    pub platform_fee: Option<PlatformFee>,
+    pub ledger_fee: u64,
+    pub held: u64, // price plus one ledger fee per payout leg
+    pub status: EscrowStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub settled_at: Option<u64>,
+    pub sale_id: Option<u64>,
+}
+
+impl Storable for Escrow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct PlatformFeeConfig {
+    pub fee_bps: u16,
+    pub recipient: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct CreatorRoyalties {
+    pub creator: Principal,
+    pub sale_count: u64,
+    pub total_accrued: u64,
+    pub total_paid: u64,
+}
+
+// All amounts in e8s, taken from the splits recorded with each sale.
+// net_proceeds is what reached the principal: seller amounts plus royalties.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Default, PartialEq, Debug)]
+pub struct AssetEarnings {
+    pub asset_id: u64,
+    pub gross_sales: u64,
+    pub royalty_income: u64,
+    pub platform_fees_paid: u64,
+    pub net_proceeds: u64,
+    pub sale_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Default, PartialEq, Debug)]
+pub struct EarningsSummary {
+    pub gross_sales: u64,
+    pub royalty_income: u64,
+    pub platform_fees_paid: u64,
+    pub net_proceeds: u64,
+    pub sale_count: u64,
+    pub assets: Vec<AssetEarnings>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum EarningsLegKind {
+    Sale,    // the principal sold the asset
+    Royalty, // the principal created it and was owed a royalty
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct EarningsLeg {
+    pub sale_id: u64,
+    pub asset_id: u64,
+    pub kind: EarningsLegKind,
+    pub sale_price: u64,
+    pub amount: u64, // what this leg brought the principal
+    pub platform_fee: u64,
+    pub paid: bool, // royalties can be accrued but not yet paid out
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct EarningsLegPage {
+    pub legs: Vec<EarningsLeg>,
+    pub total_count: u64,
+}
+
+impl Storable for SaleRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Escrow reservations and refunds do not move the asset; they are logged with
+// the seller as `from` and the buyer as `to` so the whole purchase is traceable
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub enum OwnershipEventKind {
+    Upload,
+    Transfer,
+    MarketplaceSale,
+    Purchase,
+    AuctionSale,
+    OfferAccepted,
+    EscrowReserved,
+    EscrowRefunded,
+    EscrowSale,
+    Gift,
+    // The owner moved their whole library to a new principal
+    Migration,
+    // An admin undid ownership event `event_id`, returning the asset
+    TransferReverted { event_id: u64 },
+    // The asset was created by cloning `source_asset_id`
+    DerivedFrom { source_asset_id: u64 },
+}
+
+// One entry in an asset's chain of custody; `from` is None for uploads and
+// clones
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct OwnershipEvent {
+    pub id: u64,
+    pub asset_id: u64,
+    pub from: Option<Principal>,
+    pub to: Principal,
+    pub kind: OwnershipEventKind,
+    pub price: Option<u64>,
+    pub timestamp: u64,
+    // Set once an admin has reverted this event
+    pub reverted: Option<Reversal>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct Reversal {
+    pub reverted_by: Principal,
+    pub reason: String,
+    pub reverted_at: u64,
+}
+
+impl Storable for OwnershipEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct OwnershipEventPage {
+    pub events: Vec<OwnershipEvent>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct OwnershipEventCursorPage {
+    pub events: Vec<OwnershipEvent>,
+    pub next_cursor: Option<String>,
+}
+
+// Optional sale window for a listed asset. Listings without a window are on
+// sale for as long as is_for_sale is set.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct ListingWindow {
+    pub asset_id: u64,
+    pub starts_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub expired_at: Option<u64>, // set once the listing has been taken down on expiry
+}
+
+impl ListingWindow {
+    fn contains(&self, current_time: u64) -> bool {
+        self.starts_at.is_none_or(|starts_at| current_time >= starts_at)
+            && self.expires_at.is_none_or(|expires_at| current_time < expires_at)
+    }
+}
+
+impl Storable for ListingWindow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct SaleRecordPage {
+    pub sales: Vec<SaleRecord>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct SaleRecordCursorPage {
+    pub sales: Vec<SaleRecord>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetSaleStats {
+    pub asset_id: u64,
+    pub sale_count: u64,
+    pub last_sold_price: Option<u64>,
+    pub last_sold_at: Option<u64>,
+    pub total_volume: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TimeWindow {
+    Last7Days,
+    Last30Days,
+    AllTime,
+}
+
+impl TimeWindow {
+    fn days(&self) -> Option<u64> {
+        match self {
+            TimeWindow::Last7Days => Some(7),
+            TimeWindow::Last30Days => Some(LEADERBOARD_DAYS),
+            TimeWindow::AllTime => None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct SaleTally {
+    pub volume: u64, // in e8s
+    pub sale_count: u64,
+}
+
+impl SaleTally {
+    fn add(&mut self, other: &SaleTally) {
+        self.volume = self.volume.saturating_add(other.volume);
+        self.sale_count += other.sale_count;
+    }
+}
+
+impl Storable for SaleTally {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Running totals kept for a category, an owner and the whole marketplace
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct StatCounts {
+    pub asset_count: u64,
+    pub for_sale_count: u64,
+    pub favorite_count: u64, // favorites received by the counted assets
+}
+
+impl StatCounts {
+    fn of(asset: &Asset) -> Self {
+        StatCounts {
+            asset_count: 1,
+            for_sale_count: asset.is_for_sale as u64,
+            favorite_count: get_favorite_count(asset.id),
+        }
+    }
+
+    fn add(&mut self, other: &StatCounts) {
+        self.asset_count += other.asset_count;
+        self.for_sale_count += other.for_sale_count;
+        self.favorite_count += other.favorite_count;
+    }
+
+    fn subtract(&mut self, other: &StatCounts) {
+        self.asset_count = self.asset_count.saturating_sub(other.asset_count);
+        self.for_sale_count = self.for_sale_count.saturating_sub(other.for_sale_count);
+        self.favorite_count = self.favorite_count.saturating_sub(other.favorite_count);
+    }
+}
+
+impl Storable for StatCounts {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, PartialEq, Debug)]
+pub struct OwnerStats {
+    pub asset_count: u64,
+    pub for_sale_count: u64,
+    pub favorite_count: u64,
+    pub sale_count: u64, // sales made as the seller
+    pub sales_volume: u64, // in e8s
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, PartialEq, Debug)]
+pub struct MarketplaceStats {
+    pub asset_count: u64,
+    pub for_sale_count: u64,
+    pub favorite_count: u64,
+    pub owner_count: u64,
+    pub category_count: u64,
+    pub sale_count: u64,
+    pub sales_volume: u64, // in e8s
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TopSeller {
+    pub seller: Principal,
+    pub volume: u64,
+    pub sale_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TopSellingAsset {
+    pub asset: Asset,
+    pub volume: u64,
+    pub sale_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Approval {
+    pub asset_id: u64,
+    pub owner: Principal,
+    pub spender: Principal,
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Storable for Approval {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq)]
+pub enum OfferStatus {
+    Active,
+    Accepted,
+    Cancelled,
+    Invalidated, // another offer was accepted or the asset changed hands
+    Expired,     // passed its expiry while still active; set by maintenance
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Offer {
+    pub id: u64,
+    pub asset_id: u64,
+    pub bidder: Principal,
+    pub amount: u64, // in e8s
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub status: OfferStatus,
+}
+
+impl Storable for Offer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq)]
+pub enum AuctionStatus {
+    Active,
+    Sold,
+    Unsold, // no bids, or the high bid did not meet the reserve
+    PaymentFailed,
+    Cancelled,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Bid {
+    pub bidder: Principal,
+    pub amount: u64, // in e8s
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Auction {
+    pub asset_id: u64,
+    pub seller: Principal,
+    pub starting_price: u64,
+    pub reserve_price: Option<u64>,
+    pub end_time: u64,
+    pub high_bid: Option<Bid>,
+    pub bid_count: u64,
+    pub status: AuctionStatus,
+    pub created_at: u64,
+    pub settled_at: Option<u64>,
+}
+
+impl Storable for Auction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Declining-price sale: the price falls linearly from start_price to
+// end_price over `duration` nanoseconds, then stays at end_price until the
+// asset sells or the seller cancels.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct DutchAuction {
+    pub asset_id: u64,
+    pub seller: Principal,
+    pub start_price: u64,
+    pub end_price: u64,
+    pub starts_at: u64,
+    pub duration: u64,
+    pub status: AuctionStatus, // Active, Sold or Cancelled
+    pub buyer: Option<Principal>,
+    pub sold_price: Option<u64>,
+    pub ended_at: Option<u64>,
+}
+
+impl DutchAuction {
+    fn price_at(&self, current_time: u64) -> u64 {
+        let elapsed = current_time.saturating_sub(self.starts_at);
+        if elapsed >= self.duration {
+            return self.end_price;
+        }
+        let drop = (self.start_price - self.end_price) as u128 * elapsed as u128 / self.duration as u128;
+        self.start_price - drop as u64
+    }
+}
+
+impl Storable for DutchAuction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Rating {
+    pub asset_id: u64,
+    pub reviewer: Principal,
+    pub stars: u8, // 1-5
+    pub review: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// A watcher's wish price: once the listed asset drops to target_price or
+// below, they are notified and the alert is cleared
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PriceAlert {
+    pub asset_id: u64,
+    pub target_price: u64,
+    pub created_at: u64,
+}
+
+impl Storable for PriceAlert {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for Rating {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetRating {
+    pub asset_id: u64,
+    pub average: f64, // 0.0 when there are no ratings
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct ReviewPage {
+    pub reviews: Vec<Rating>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetStats {
+    pub asset_id: u64,
+    pub view_count: u64,
+    pub download_count: u64,
+    pub trending_score: f64, // decayed as of trending_updated_at
+    pub trending_updated_at: u64,
+    // Filled in from the price history by get_asset_stats
+    pub prices: Option<PriceSummary>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PricePoint {
+    pub price: u64,
+    pub timestamp: u64,
+    pub changed_by: Principal,
+}
+
+impl Storable for PricePoint {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, PartialEq, Debug)]
+pub struct PriceSummary {
+    pub lowest: u64,
+    pub highest: u64,
+    pub previous: Option<u64>, // the price before the current one
+}
+
+impl Storable for AssetStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Copy)]
+enum Engagement {
+    View,
+    Download,
+}
+
+impl Engagement {
+    fn key(self) -> u8 {
+        match self {
+            Engagement::View => 0,
+            Engagement::Download => 1,
+        }
+    }
+
+    fn weight(self) -> f64 {
+        match self {
+            Engagement::View => 1.0,
+            Engagement::Download => 3.0,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetReport {
+    pub id: u64,
+    pub asset_id: u64,
+    pub reporter: Principal,
+    pub reason: String,
+    pub created_at: u64,
+}
+
+impl Storable for AssetReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct ReportedAsset {
+    pub asset_id: u64,
+    pub hidden: Option<Moderation>,
+    pub reports: Vec<AssetReport>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct Collection {
+    pub id: u64,
+    pub owner: Principal,
+    pub name: String,
+    pub description: String,
+    pub cover_image: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Collection {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct CollectionPage {
+    pub collection: Collection,
+    pub assets: Vec<Asset>,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Debug)]
+pub struct Category {
+    pub id: u64,
+    pub name: String,
+    pub parent: Option<u64>,
+    // Archived categories keep their assets but cannot be chosen for new ones
+    pub archived: bool,
+    pub created_at: u64,
+}
+
+impl Storable for Category {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct CategoryNode {
+    pub category: Category,
+    pub children: Vec<CategoryNode>,
+}
+
+// Tag index key; ordered by tag, then asset id, so a prefix range scan walks
+// the tags alphabetically
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TagKey {
+    tag: String,
+    asset_id: u64,
+}
+
+impl Storable for TagKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.tag.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (asset_id, tag) = bytes.split_at(8);
+        TagKey {
+            tag: String::from_utf8(tag.to_vec()).unwrap(),
+            asset_id: u64::from_be_bytes(asset_id.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Text index key; ordered like TagKey, so a token's postings are contiguous
+// and a prefix range scan walks the tokens alphabetically
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TokenKey {
+    token: String,
+    asset_id: u64,
+}
+
+impl Storable for TokenKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.token.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (asset_id, token) = bytes.split_at(8);
+        TokenKey {
+            token: String::from_utf8(token.to_vec()).unwrap(),
+            asset_id: u64::from_be_bytes(asset_id.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// File reference key; ordered by file hash, then asset id, so one file's
+// referencing assets are contiguous
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FileRefKey {
+    file_hash: String,
+    asset_id: u64,
+}
+
+impl Storable for FileRefKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.file_hash.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (asset_id, file_hash) = bytes.split_at(8);
+        FileRefKey {
+            file_hash: String::from_utf8(file_hash.to_vec()).unwrap(),
+            asset_id: u64::from_be_bytes(asset_id.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// File piece key; ordered by file hash, then piece index, so a file's pieces
+// are contiguous and in order
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FilePieceKey {
+    file_hash: String,
+    index: u64,
+}
+
+impl Storable for FilePieceKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.index.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.file_hash.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (index, file_hash) = bytes.split_at(8);
+        FilePieceKey {
+            file_hash: String::from_utf8(file_hash.to_vec()).unwrap(),
+            index: u64::from_be_bytes(index.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// File supplier key; ordered by file hash, then principal
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FileSupplierKey {
+    file_hash: String,
+    principal: Principal,
+}
+
+impl Storable for FileSupplierKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = vec![principal.len() as u8];
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(self.file_hash.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (principal, file_hash) = bytes[1..].split_at(bytes[0] as usize);
+        FileSupplierKey {
+            file_hash: String::from_utf8(file_hash.to_vec()).unwrap(),
+            principal: Principal::from_slice(principal),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct StorageStats {
+    pub total_file_bytes: u64,
+    pub file_count: u64,
+    pub orphan_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct ApiVersion {
+    pub current: u32,
+    pub supported: Vec<u32>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct RateLimits {
+    pub uploads_per_minute: u32,
+    pub metadata_updates_per_minute: u32,
+    // None leaves the current limit in place when setting
+    pub reservations_per_minute: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Debug, PartialEq)]
+pub struct FileTypeLimit {
+    pub file_type: String,
+    pub max_size: u64,
+}
+
+// Per-type limits override max_file_size for files of that type
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Debug, PartialEq)]
+pub struct UploadLimits {
+    pub max_file_size: u64,
+    pub file_type_limits: Vec<FileTypeLimit>,
+    pub max_preview_image_size: u64,
+    pub max_attachments: u32,
+}
+
+// Update calls that count against a per-principal budget
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum RateLimitedAction {
+    Upload,
+    MetadataUpdate,
+    Reservation,
+}
+
+// Sliding-window call log per principal and action. Kept on the heap only:
+// losing it on upgrade merely resets everyone's budget.
+#[derive(Default)]
+struct RateLimiter {
+    calls: BTreeMap<(Principal, RateLimitedAction), VecDeque<u64>>,
+    last_swept_at: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct CanisterMetrics {
+    pub asset_count: u64,
+    pub assets_for_sale: u64,
+    pub owner_count: u64, // distinct principals owning at least one asset
+    pub file_count: u64,
+    pub total_file_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub memory_regions: Vec<MemoryRegionUsage>,
+    pub cycles_balance: u128,
+    pub last_upgrade_at: Option<u64>,
+    pub version: String,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct MemoryRegionUsage {
+    pub memory_id: u8,
+    pub bytes: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, PartialEq)]
+pub enum HealthStatus {
+    Healthy,
+    LowCycles,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub version: String,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct PruneReport {
+    pub files_removed: u64,
+    pub bytes_reclaimed: u64,
+}
+
+// What one maintenance run cleaned up. Auction settlements are only started
+// by the run and finish asynchronously.
+#[derive(CandidType, Serialize, SerdeDeserialize, Default, Debug, PartialEq)]
+pub struct MaintenanceReport {
+    pub upload_sessions_removed: u64,
+    pub offers_expired: u64,
+    pub transfers_removed: u64,
+    pub gifts_removed: u64,
+    pub auctions_settled: u64,
+    pub files_split: u64, // files stored whole, moved into pieces
+    pub images_hashed: u64, // gallery images whose digests were backfilled
+}
+
+// Progress of one recompress_existing_files batch
+#[derive(CandidType, Serialize, SerdeDeserialize, Default, Debug, PartialEq)]
+pub struct RecompressReport {
+    pub files_examined: u64,
+    pub files_compressed: u64,
+    pub bytes_saved: u64,
+    // False while files past this batch remain to be examined
+    pub done: bool,
+}
+
+// Progress of one transfer_all_assets call. Pass `next_cursor` back until
+// `done`; assets in `skipped` stay with the old principal. In a dry run
+// nothing is changed and the fields describe what would move.
+#[derive(CandidType, Serialize, SerdeDeserialize, Debug, PartialEq)]
+pub struct AccountMigration {
+    pub to: Principal,
+    pub dry_run: bool,
+    pub moved: Vec<u64>,
+    pub skipped: Vec<(u64, AssetError)>,
+    // Owned assets past this batch, still to be examined
+    pub remaining: u64,
+    pub next_cursor: Option<u64>,
+    pub profile_moved: bool,
+    pub favorites_moved: u64,
+    pub notifications_moved: u64,
+    pub done: bool,
+}
+
+// Where each maintenance sweep resumes. Kept on the heap: after an upgrade
+// the sweeps simply start again from the first key.
+#[derive(Default)]
+struct MaintenanceCursors {
+    upload_sessions: Option<u64>,
+    offers: Option<u64>,
+    pending_transfers: Option<u64>,
+    gifts: Option<u64>,
+    auctions: Option<u64>,
+    galleries: Option<u64>,
+}
+
+// One page of an asset export, with the id counter a restore needs so new
+// assets do not reuse restored ids
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetExport {
+    pub assets: Vec<Asset>,
+    pub asset_id_counter: u64,
+    pub total_count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct FileManifestPage {
+    pub files: Vec<FileInfo>,
+    pub total_count: u64,
+}
+
+// One asset of an external catalog being migrated in. The file stays at
+// external_url until attach_imported_file stores it here.
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct ExternalAssetRecord {
+    pub external_id: String, // the catalog's own key; importing it again is a no-op
+    pub name: String,
+    pub description: String,
+    pub category: String, // matched to the registry by name, else Uncategorized
+    pub tags: Vec<String>,
+    pub price: u64,
+    pub original_created_at: u64,
+    pub owner: Principal,
+    pub external_url: String,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct RestoreReport {
+    pub assets: u64,
+    pub files: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct FileIntegrityReport {
+    pub file_hash: String,
+    pub computed_hash: String,
+    pub total_size: u64,
+    pub matches: bool,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetFilter {
+    pub category_id: Option<u64>,
+    pub owner: Option<Principal>,
+    pub for_sale_only: Option<bool>,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+    pub file_type: Option<String>,
+    pub tag: Option<String>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    // Every listed field must be present on the asset with exactly this value
+    pub metadata: Option<Vec<(String, MetadataValue)>>,
+    pub verified_only: Option<bool>,
+    // A custom license matches every custom license, whatever its url
+    pub license: Option<License>,
+    // Prices are compared in this token; assets not priced in it never match.
+    // None compares the ICP price, and a price bound or sort then leaves out
+    // assets that cannot be bought in ICP.
+    pub ledger: Option<Principal>,
+}
+
+impl AssetFilter {
+    // The price that min_price, max_price and the price sorts look at
+    fn price_of(&self, asset: &Asset) -> Option<u64> {
+        match self.ledger {
+            Some(ledger) => price_in(asset, ledger),
+            None => icp_price(asset),
+        }
+    }
+
+    // All provided fields must match; hidden and non-public assets never do
+    fn matches(&self, asset: &Asset) -> bool {
+        if !is_listed_publicly(asset) {
+            return false;
+        }
+        if let Some(category_id) = self.category_id {
+            if asset.category_id != category_id {
+                return false;
+            }
+        }
+        if let Some(owner) = &self.owner {
+            if asset.owner != *owner {
+                return false;
+            }
+        }
+        if self.for_sale_only == Some(true) && !asset.is_for_sale {
+            return false;
+        }
+        let price = self.price_of(asset);
+        if self.ledger.is_some() && price.is_none() {
+            return false;
+        }
+        if let Some(min_price) = self.min_price {
+            if price.is_none_or(|price| price < min_price) {
+                return false;
+            }
+        }
+        if let Some(max_price) = self.max_price {
+            if price.is_none_or(|price| price > max_price) {
+                return false;
+            }
+        }
+        if let Some(file_type) = &self.file_type {
+            if !asset.file_type.eq_ignore_ascii_case(file_type) {
+                return false;
+            }
+        }
+        if let Some(tag) = &self.tag {
+            if !asset.tags.iter().any(|asset_tag| asset_tag.eq_ignore_ascii_case(tag)) {
+                return false;
+            }
+        }
+        if let Some(created_after) = self.created_after {
+            if asset.created_at <= created_after {
+                return false;
+            }
+        }
+        if let Some(created_before) = self.created_before {
+            if asset.created_at >= created_before {
+                return false;
+            }
+        }
+        if let Some(metadata) = &self.metadata {
+            if !metadata.iter().all(|(key, value)| metadata_field(asset, key) == Some(value)) {
+                return false;
+            }
+        }
+        if self.verified_only == Some(true) && !is_verified(asset.owner) {
+            return false;
+        }
+        if let Some(license) = &self.license {
+            if std::mem::discriminant(license) != std::mem::discriminant(&asset.license) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy)]
+pub enum SortBy {
+    PriceAsc,
+    PriceDesc,
+    Newest,
+    Oldest,
+    NameAsc,
+}
+
+// HTTP gateway types, see the IC HTTP gateway protocol specification
+#[derive(CandidType, SerdeDeserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, SerdeDeserialize, Clone)]
+pub struct StreamingCallbackToken {
+    pub file_hash: String,
+    pub chunk_index: u64,
+    // Set when the chunks are the stored compressed bytes
+    pub content_encoding: Option<ContentEncoding>,
+}
+
+#[derive(CandidType)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+candid::define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+type Sha256Digest = [u8; 32];
+
+// Body hashes of every certified HTTP path. Kept on the heap and rebuilt from
+// the stable stores after an upgrade.
+struct CertifiedHttp {
+    tree: RbTree<String, Sha256Digest>,
+    // Gallery images certified for each asset, so a gallery change can
+    // uncertify the ones it dropped
+    gallery_images: BTreeMap<u64, Vec<u64>>,
+    // Serialized /assets.json entries of publicly listed assets, by asset id
+    index_entries: BTreeMap<u64, String>,
+    index_body: Vec<u8>,
+    // The hash of index_body, kept with its compressed variant so a gzip
+    // response is certified without decoding it
+    index_body_hash: Sha256Digest,
+    index_body_gzip: Vec<u8>,
+    index_dirty: bool,
+    // Set while a rebuild certifies many paths; the digest is refreshed once
+    // at the end
+    deferred: bool,
+}
+
+impl CertifiedHttp {
+    fn new() -> Self {
+        CertifiedHttp {
+            tree: RbTree::new(),
+            gallery_images: BTreeMap::new(),
+            index_entries: BTreeMap::new(),
+            index_body: Vec::new(),
+            index_body_hash: Sha256::digest(b"").into(),
+            index_body_gzip: Vec::new(),
+            index_dirty: true,
+            deferred: false,
+        }
+    }
+}
+
+// One /assets.json entry. Paths are relative to the canister's HTTP gateway.
+#[derive(Serialize)]
+struct AssetIndexEntry<'a> {
+    id: u64,
+    name: &'a str,
+    slug: Option<&'a str>,
+    category: &'a str,
+    file_type: &'a str,
+    file: String,
+    preview: Option<String>,
+}
+
+// JSON rendering of an asset for engine plugins that fetch over plain HTTP.
+// The field names are part of the HTTP API and stay fixed when the candid
+// Asset record changes; prices are e8s and times are nanoseconds.
+#[derive(Serialize)]
+struct AssetDocument<'a> {
+    id: u64,
+    name: &'a str,
+    description: &'a str,
+    slug: Option<&'a str>,
+    owner: String,
+    creator: String,
+    category_id: u64,
+    category: &'a str,
+    tags: &'a [String],
+    file_type: &'a str,
+    file_size: u64,
+    file: String,
+    preview: Option<String>,
+    price_e8s: Option<u64>, // null while the asset cannot be bought in ICP
+    for_sale: bool,
+    royalty_bps: u16,
+    license: &'a str,
+    license_url: Option<&'a str>,
+    metadata: serde_json::Map<String, serde_json::Value>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(Serialize)]
+struct AssetDocumentPage<'a> {
+    assets: Vec<AssetDocument<'a>>,
+    total_count: u64,
+    offset: u64,
+    limit: u64,
+}
+
+// `truncated` is set when the page was cut short by RESPONSE_SIZE_BUDGET
+// rather than the limit; the remaining assets start at `next_offset`.
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetPage {
+    pub assets: Vec<Asset>,
+    pub total_count: u64,
+    pub truncated: bool,
+    pub next_offset: Option<u64>,
+}
+
+// Strongest last, so the derived ordering ranks matches
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MatchKind {
+    Description, // description or category
+    Tag,
+    NameSubstring,
+    NamePrefix,
+    ExactName,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SearchScore {
+    pub match_kind: MatchKind,
+    pub popularity: u64, // favorites plus counted views and downloads
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct RankedAsset {
+    pub asset: Asset,
+    pub score: Option<SearchScore>, // only when include_scores was set
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct RankedSearchPage {
+    pub results: Vec<RankedAsset>,
+    pub total_count: u64,
+    pub truncated: bool,
+    pub next_offset: Option<u64>,
+}
+
+// Keyset pages for the *_after queries. `next_cursor` is an opaque token for
+// the last key returned and is None once there is nothing left; unlike an
+// offset it stays valid when assets are added or removed between calls.
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetCursorPage {
+    pub assets: Vec<Asset>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetCursorPageV1Response {
+    pub assets: Vec<AssetV1Response>,
+    pub next_cursor: Option<String>,
+}
+
+impl From<AssetCursorPage> for AssetCursorPageV1Response {
+    fn from(page: AssetCursorPage) -> Self {
+        AssetCursorPageV1Response {
+            assets: page.assets.into_iter().map(AssetV1Response::from).collect(),
+            next_cursor: page.next_cursor,
+        }
+    }
+}
+
+// Result of the unpaged listing queries. When the response would have grown
+// past RESPONSE_SIZE_BUDGET, `truncated` is set and `next_cursor` holds the
+// asset id to pass back as the cursor to fetch the rest.
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetChunk {
+    pub assets: Vec<Asset>,
+    pub truncated: bool,
+    pub next_cursor: Option<u64>,
+}
+
+// What grid views and other canisters need of an asset, without the
+// description, tags and metadata
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetSummary {
+    pub id: u64,
+    pub name: String,
+    pub prices: Vec<PriceOption>,
+    pub is_for_sale: bool,
+    pub owner: Principal,
+    pub category: String,
+    pub preview_image_url: Option<String>,
+    pub file_type: String,
+    pub created_at: u64,
+    pub license: License,
+}
+
+impl From<Asset> for AssetSummary {
+    fn from(asset: Asset) -> Self {
+        AssetSummary {
+            id: asset.id,
+            name: asset.name,
+            prices: asset.prices,
+            is_for_sale: asset.is_for_sale,
+            owner: asset.owner,
+            category: asset.category,
+            preview_image_url: asset.preview_image_url,
+            file_type: asset.file_type,
+            created_at: asset.created_at,
+            license: asset.license,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetSummaryCursorPage {
+    pub assets: Vec<AssetSummary>,
+    pub next_cursor: Option<String>,
+}
+
+impl From<AssetCursorPage> for AssetSummaryCursorPage {
+    fn from(page: AssetCursorPage) -> Self {
+        AssetSummaryCursorPage {
+            assets: summarize(page.assets),
+            next_cursor: page.next_cursor,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct AssetSummaryPage {
+    pub assets: Vec<AssetSummary>,
+    pub total_count: u64,
+}
+
+impl From<AssetPage> for AssetSummaryPage {
+    fn from(page: AssetPage) -> Self {
+        AssetSummaryPage {
+            assets: summarize(page.assets),
+            total_count: page.total_count,
+        }
+    }
+}
+
+fn summarize(assets: Vec<Asset>) -> Vec<AssetSummary> {
+    assets.into_iter().map(AssetSummary::from).collect()
+}
+
+// The fields of a stored asset that summaries and their visibility checks
+// read. Decoding one skips over the description, tags, metadata and the rest
+// of the record instead of materializing them.
+#[derive(CandidType, SerdeDeserialize)]
+struct StoredAssetSummary {
+    id: u64,
+    name: String,
+    prices: Vec<PriceOption>,
+    is_for_sale: bool,
+    owner: Principal,
+    category: String,
+    preview_image_url: Option<String>,
+    file_type: String,
+    created_at: u64,
+    license: License,
+    hidden: Option<Moderation>,
+    visibility: Visibility,
+    archived_at: Option<u64>,
+}
+
+// Same rules as is_listed_publicly, is_visible_to and can_view
+impl StoredAssetSummary {
+    fn is_listed_publicly(&self) -> bool {
+        self.hidden.is_none() && self.archived_at.is_none() && self.visibility == Visibility::Public
+    }
+
+    fn is_visible_to(&self, principal: Principal) -> bool {
+        self.owner == principal || self.is_listed_publicly()
+    }
+
+    fn can_view(&self, principal: Principal) -> bool {
+        self.owner == principal || (self.visibility != Visibility::Private && self.archived_at.is_none())
+    }
+}
+
+impl From<Asset> for StoredAssetSummary {
+    fn from(asset: Asset) -> Self {
+        StoredAssetSummary {
+            id: asset.id,
+            name: asset.name,
+            prices: asset.prices,
+            is_for_sale: asset.is_for_sale,
+            owner: asset.owner,
+            category: asset.category,
+            preview_image_url: asset.preview_image_url,
+            file_type: asset.file_type,
+            created_at: asset.created_at,
+            license: asset.license,
+            hidden: asset.hidden,
+            visibility: asset.visibility,
+            archived_at: asset.archived_at,
+        }
+    }
+}
+
+impl From<StoredAssetSummary> for AssetSummary {
+    fn from(summary: StoredAssetSummary) -> Self {
+        AssetSummary {
+            id: summary.id,
+            name: summary.name,
+            prices: summary.prices,
+            is_for_sale: summary.is_for_sale,
+            owner: summary.owner,
+            category: summary.category,
+            preview_image_url: summary.preview_image_url,
+            file_type: summary.file_type,
+            created_at: summary.created_at,
+            license: summary.license,
+        }
+    }
+}
+
+// Records in the current schema decode partially; older ones are upgraded
+// whole first
+impl Storable for StoredAssetSummary {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        unreachable!("summaries are read from the asset store, never written to it")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let decoded = match bytes.as_ref() {
+            [ASSET_SCHEMA_TAG, ASSET_SCHEMA_VERSION, payload @ ..] => {
+                candid::decode_one::<StoredAssetSummary>(payload).map_err(|err| err.to_string())
+            },
+            bytes => decode_asset(bytes).map(StoredAssetSummary::from),
+        };
+        decoded.unwrap_or_else(|message| ic_cdk::trap(&message))
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// A read-only view of the asset store that decodes summaries. Loaded on
+// every call so it sees the store's current root.
+fn asset_summaries() -> StableBTreeMap<u64, StoredAssetSummary, Memory> {
+    StableBTreeMap::load(MEMORY_MANAGER.with(|m| m.borrow().get(ASSETS_MEMORY_ID)))
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct TagPage {
+    pub tags: Vec<TagCount>,
+    pub total_count: u64,
+}
+
+thread_local! {
+    static RATE_LIMITER: RefCell<RateLimiter> = RefCell::new(RateLimiter::default());
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ASSETS: RefCell<AssetStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ASSETS_MEMORY_ID)),
+        )
+    );
+
+    // Releases before the cell kept the last asset id at key 0 of this map
+    static LEGACY_ASSET_ID_COUNTER: RefCell<LegacyAssetIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    static FILES: RefCell<FileStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    static UPLOAD_SESSIONS: RefCell<UploadSessionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    static UPLOAD_SESSION_ID_COUNTER: RefCell<UploadSessionIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Chunks are keyed by (session_id, chunk_index) so a session's chunks
+    // can be read back in order with a single range scan.
+    static UPLOAD_CHUNKS: RefCell<UploadChunkStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static FILE_INFO: RefCell<FileInfoStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // Marketplace canisters allowed to call marketplace_transfer_asset,
+    // mapped to the time they were authorized
+    static AUTHORIZED_MARKETPLACES: RefCell<AuthorizedMarketplaceStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    static CONFIG: RefCell<ConfigStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    static SALES: RefCell<SaleStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    static SALE_ID_COUNTER: RefCell<SaleIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    // Assets locked by an in-flight ledger payment, mapped to the buyer
+    static PENDING_PURCHASES: RefCell<PendingPurchaseStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // At most one approved spender per asset
+    static APPROVALS: RefCell<ApprovalStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    // Number of assets referencing each stored file, either as the model or
+    // as an in-canister preview image
+    static FILE_REFS: RefCell<FileRefStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+
+    // Bytes of stored files charged to each uploader
+    static STORAGE_USAGE: RefCell<StorageUsageStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    // Per-principal overrides of the default storage quota
+    static USER_QUOTAS: RefCell<UserQuotaStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    static OFFERS: RefCell<OfferStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    static OFFER_ID_COUNTER: RefCell<OfferIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // Keyed by asset id; an asset has at most one auction record, the latest
+    static AUCTIONS: RefCell<AuctionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // (user, asset id) -> time the asset was favorited
+    static FAVORITES: RefCell<FavoriteStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    static FAVORITE_COUNTS: RefCell<FavoriteCountStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    static RATINGS: RefCell<RatingStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+
+    static ASSET_STATS: RefCell<AssetStatsStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    // (asset id, caller, engagement kind) -> time it was last counted
+    static ENGAGEMENT_MARKS: RefCell<EngagementMarkStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    static ICRC7_TX_ID_COUNTER: RefCell<Icrc7TxIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+
+    static REPORTS: RefCell<ReportStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+
+    static REPORT_ID_COUNTER: RefCell<ReportIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+
+    // Moderator principal -> time it was added
+    static MODERATORS: RefCell<ModeratorStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+
+    static COLLECTIONS: RefCell<CollectionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        )
+    );
+
+    static COLLECTION_ID_COUNTER: RefCell<CollectionIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+
+    // (collection id, asset id) -> time the asset was added
+    static COLLECTION_ITEMS: RefCell<CollectionItemStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+
+    // Secondary indexes over ASSETS, maintained by index_asset/unindex_asset
+    static OWNER_INDEX: RefCell<OwnerIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+        )
+    );
+
+    // MemoryId 32 held the category index while categories were free text;
+    // it is no longer read and must not be reused
+
+    // Ids of the assets that are listed for sale
+    static FOR_SALE_INDEX: RefCell<ForSaleIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        )
+    );
+
+    // Events are never removed; reverting one only sets its `reverted` field
+    static OWNERSHIP_EVENTS: RefCell<OwnershipEventStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+        )
+    );
+
+    static OWNERSHIP_EVENT_ID_COUNTER: RefCell<OwnershipEventIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+        )
+    );
+
+    static LISTING_WINDOWS: RefCell<ListingWindowStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+        )
+    );
+
+    static FILE_ACCESS_GRANTS: RefCell<FileAccessGrantStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+        )
+    );
+
+    // Single preview per asset, superseded by ASSET_GALLERIES; entries are
+    // moved into galleries on upgrade
+    static PREVIEW_IMAGES: RefCell<PreviewImageStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+        )
+    );
+
+    static ASSET_IMAGES: RefCell<AssetImageStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+        )
+    );
+
+    static ASSET_IMAGE_ID_COUNTER: RefCell<AssetImageIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))),
+        )
+    );
+
+    static ASSET_GALLERIES: RefCell<AssetGalleryStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+        )
+    );
+
+    static ASSET_VERSIONS: RefCell<AssetVersionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+        )
+    );
+
+    static PENDING_TRANSFERS: RefCell<PendingTransferStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))),
+        )
+    );
+
+    // Keyed by the normalized tag
+    static TAG_INDEX: RefCell<TagIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+        )
+    );
+
+    static CATEGORIES: RefCell<CategoryStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))),
+        )
+    );
+
+    static CATEGORY_ID_COUNTER: RefCell<CategoryIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))),
+        )
+    );
+
+    // Keyed by (category id, asset id)
+    static CATEGORY_INDEX: RefCell<CategoryIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))),
+        )
+    );
+
+    static BUNDLES: RefCell<BundleStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))),
+        )
+    );
+
+    static BUNDLE_ID_COUNTER: RefCell<BundleIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+        )
+    );
+
+    // Members of active bundles, mapped to their bundle
+    static BUNDLE_MEMBERS: RefCell<BundleMemberStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+        )
+    );
+
+    // Keyed by bundle id; a bundle is sold at most once
+    static BUNDLE_SALES: RefCell<BundleSaleStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+        )
+    );
+
+    static PROFILES: RefCell<ProfileStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))),
+        )
+    );
+
+    static PROFILE_NAMES: RefCell<ProfileNameStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+        )
+    );
+
+    static VERIFIED_CREATORS: RefCell<VerificationStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+        )
+    );
+
+    static VERIFICATION_EVENTS: RefCell<VerificationEventStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))),
+        )
+    );
+
+    static VERIFICATION_EVENT_ID_COUNTER: RefCell<VerificationEventIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))),
+        )
+    );
+
+    static ESCROWS: RefCell<EscrowStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))),
+        )
+    );
+
+    static ESCROW_ID_COUNTER: RefCell<EscrowIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))),
+        )
+    );
+
+    static ESCROWED_ASSETS: RefCell<EscrowedAssetStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))),
+        )
+    );
+
+    static GIFTS: RefCell<GiftStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))),
+        )
+    );
+
+    static GIFT_CODES: RefCell<GiftCodeStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))),
+        )
+    );
+
+    static RENTAL_LISTINGS: RefCell<RentalListingStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))),
+        )
+    );
+
+    // Every rental ever made, kept after expiry as history
+    static RENTALS: RefCell<RentalStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))),
+        )
+    );
+
+    static RENTAL_ID_COUNTER: RefCell<RentalIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))),
+        )
+    );
+
+    static ACTIVE_RENTALS: RefCell<ActiveRentalStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))),
+        )
+    );
+
+    // (follower, creator)
+    static FOLLOWING: RefCell<FollowingStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))),
+        )
+    );
+
+    // (creator, follower)
+    static FOLLOWERS: RefCell<FollowerStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))),
+        )
+    );
+
+    static FOLLOWER_COUNTS: RefCell<FollowerCountStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))),
+        )
+    );
+
+    static NOTIFICATIONS: RefCell<NotificationStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))),
+        )
+    );
+
+    static NOTIFICATION_ID_COUNTER: RefCell<NotificationIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))),
+        )
+    );
+
+    static PRICE_HISTORY: RefCell<PriceHistoryStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71))),
+        )
+    );
+
+    static PRICE_POINT_ID_COUNTER: RefCell<PricePointIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72))),
+        )
+    );
+
+    static PAUSES: RefCell<PauseStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))),
+        )
+    );
+
+    static ASSET_ATTACHMENTS: RefCell<AssetAttachmentStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))),
+        )
+    );
+
+    static DUTCH_AUCTIONS: RefCell<DutchAuctionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))),
+        )
+    );
+
+    static DEPOSITS: RefCell<DepositStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))),
+        )
+    );
+
+    static DEPOSIT_LOG: RefCell<DepositLogStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))),
+        )
+    );
+
+    static DEPOSIT_ENTRY_ID_COUNTER: RefCell<DepositEntryIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))),
+        )
+    );
+
+    static TAKEDOWNS: RefCell<TakedownStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))),
+        )
+    );
+
+    static TAKEDOWN_EVENTS: RefCell<TakedownEventStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80))),
+        )
+    );
+
+    static TAKEDOWN_EVENT_ID_COUNTER: RefCell<TakedownEventIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))),
+        )
+    );
+
+    static SELLER_DAILY_TALLIES: RefCell<SellerDailyTallyStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))),
+        )
+    );
+
+    static ASSET_DAILY_TALLIES: RefCell<AssetDailyTallyStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))),
+        )
+    );
+
+    static SELLER_TALLIES: RefCell<SellerTallyStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84))),
+        )
+    );
+
+    static ASSET_TALLIES: RefCell<AssetTallyStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))),
+        )
+    );
+
+    static COUPONS: RefCell<CouponStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86))),
+        )
+    );
+
+    static TEXT_INDEX: RefCell<TextIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87))),
+        )
+    );
+
+    // Last allocated asset id
+    static ASSET_ID_COUNTER: RefCell<AssetIdCounter> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88))),
+            0,
+        )
+        .expect("the asset id counter memory holds a u64")
+    );
+
+    static DEDUP_CHARGES: RefCell<DedupChargeStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89))),
+        )
+    );
+
+    static ACTIVITY_EVENTS: RefCell<ActivityEventStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90))),
+        )
+    );
+
+    static ACTIVITY_SEQ_COUNTER: RefCell<ActivitySeqCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91))),
+        )
+    );
+
+    static SLUG_INDEX: RefCell<SlugIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))),
+        )
+    );
+
+    static PURCHASE_INDEX: RefCell<PurchaseIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))),
+        )
+    );
+
+    static EXTERNAL_ID_INDEX: RefCell<ExternalIdIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))),
+        )
+    );
+
+    static CATEGORY_STATS: RefCell<CategoryStatsStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95))),
+        )
+    );
+
+    static OWNER_STATS: RefCell<OwnerStatsStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96))),
+        )
+    );
+
+    static MARKETPLACE_COUNTS: RefCell<MarketplaceCounts> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97))),
+            StatCounts::default(),
+        )
+        .expect("the marketplace counts memory holds stat counts")
+    );
+
+    static MARKETPLACE_SALES: RefCell<MarketplaceSales> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98))),
+            SaleTally::default(),
+        )
+        .expect("the marketplace sales memory holds a sale tally")
+    );
+
+    // (watcher, asset id) -> price alert
+    static PRICE_ALERTS: RefCell<PriceAlertStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99))),
+        )
+    );
+
+    static FEATURED: RefCell<FeaturedCell> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100))),
+            FeaturedList::default(),
+        )
+        .expect("the featured memory holds the featured list")
+    );
+
+    static SUPPORTED_LEDGERS: RefCell<SupportedLedgerStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))),
+        )
+    );
+
+    static EVENT_SUBSCRIPTIONS: RefCell<EventSubscriptionStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102))),
+        )
+    );
+
+    static EVENT_OUTBOX: RefCell<EventOutboxStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))),
+        )
+    );
+
+    static PENDING_PAYOUTS: RefCell<PendingPayoutStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104))),
+        )
+    );
+
+    static PAYOUT_ID_COUNTER: RefCell<PayoutIdCounter> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105))),
+        )
+    );
+
+    // FILE_REFS broken down by the asset holding each reference, so a file's
+    // users are found without scanning every asset
+    static FILE_REF_ASSETS: RefCell<FileRefAssetStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106))),
+        )
+    );
+
+    // Every principal who has supplied the bytes of a stored file, the first
+    // uploader and later uploads of identical bytes alike
+    static FILE_SUPPLIERS: RefCell<FileSupplierStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))),
+        )
+    );
+
+    // Stored file bytes, in FILE_PIECE_SIZE pieces. FILES keeps files stored
+    // whole before pieces existed until maintenance splits them.
+    static FILE_PIECES: RefCell<FilePieceStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108))),
+        )
+    );
+
+    // Recent checkout holds per holder, for the per-holder cap and the
+    // re-reserve cooldown. Entries go once their cooldown has passed.
+    static RESERVATION_HOLDS: RefCell<ReservationHoldStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110))),
+        )
+    );
+
+    static ASSET_SALE_INDEX: RefCell<AssetSaleIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111))),
+        )
+    );
+
+    // SHA-256 of each gallery image's bytes, recorded at upload so
+    // certification never rehashes images
+    static ASSET_IMAGE_DIGESTS: RefCell<AssetImageDigestStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112))),
+        )
+    );
+
+    static ASSET_OWNERSHIP_EVENT_INDEX: RefCell<AssetOwnershipEventIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113))),
+        )
+    );
+
+    static ASSET_OFFER_INDEX: RefCell<AssetOfferIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114))),
+        )
+    );
+
+    static ASSET_PRICE_ALERT_INDEX: RefCell<AssetPriceAlertIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115))),
+        )
+    );
+
+    static ASSET_COLLECTION_INDEX: RefCell<AssetCollectionIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116))),
+        )
+    );
+
+    static ASSET_FAVORITE_INDEX: RefCell<AssetFavoriteIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117))),
+        )
+    );
+
+    static GIFT_CODE_HASHES: RefCell<GiftCodeHashStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118))),
+        )
+    );
+
+    // The part of FOR_SALE_INDEX that is also publicly listed, so for-sale
+    // pages are counted and skipped without decoding asset records
+    static PUBLIC_FOR_SALE_INDEX: RefCell<ForSaleIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109))),
+        )
+    );
+
+    // Payouts whose ledger call is awaiting a reply, so a retry never sends
+    // the same payout twice
+    static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    // When the pending delivery timer fires, if one is set. Timers do not
+    // survive upgrades, so post_upgrade sets it again for a non-empty outbox.
+    static EVENT_DELIVERY_AT: RefCell<Option<u64>> = const { RefCell::new(None) };
+
+    static MAINTENANCE_CURSORS: RefCell<MaintenanceCursors> = RefCell::new(MaintenanceCursors::default());
+
+    static CERTIFIED_HTTP: RefCell<CertifiedHttp> = RefCell::new(CertifiedHttp::new());
+
+    // Where recompress_existing_files resumes; restarts from the first file
+    // after an upgrade, skipping files already compressed
+    static RECOMPRESS_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[init]
+fn init(args: Option<InitArgs>) {
+    apply_init_args(args);
+    seed_categories(time());
+    schedule_engagement_pruning();
+    schedule_listing_expiry();
+    schedule_rental_cleanup();
+    schedule_maintenance();
+    rebuild_certification();
+}
+
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    apply_init_args(args);
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("last_upgrade_at".to_string(), time().to_string());
+    });
+    schedule_engagement_pruning();
+    schedule_listing_expiry();
+    schedule_rental_cleanup();
+    schedule_maintenance();
+    schedule_pending_event_delivery();
+
+    // Reference counts were introduced after assets already existed, and the
+    // per-asset breakdown after them
+    if FILE_REFS.with(|refs| refs.borrow().is_empty())
+        || FILE_REF_ASSETS.with(|refs| refs.borrow().is_empty())
+    {
+        rebuild_file_refs();
+    }
+    // Sale tallies were introduced after sales already existed
+    if SELLER_TALLIES.with(|tallies| tallies.borrow().is_empty())
+        && SALES.with(|sales| !sales.borrow().is_empty())
+    {
+        rebuild_sale_tallies(time());
+    }
+    // The purchase index was introduced after sales already existed
+    if PURCHASE_INDEX.with(|index| index.borrow().is_empty()) && SALES.with(|sales| !sales.borrow().is_empty()) {
+        rebuild_purchase_index();
+    }
+    // The per-asset sale index was introduced after sales already existed
+    if ASSET_SALE_INDEX.with(|index| index.borrow().is_empty()) && SALES.with(|sales| !sales.borrow().is_empty()) {
+        rebuild_asset_sale_index();
+    }
+    // The per-asset ownership event index was introduced after events already existed
+    if ASSET_OWNERSHIP_EVENT_INDEX.with(|index| index.borrow().is_empty())
+        && OWNERSHIP_EVENTS.with(|events| !events.borrow().is_empty())
+    {
+        rebuild_asset_ownership_event_index();
+    }
+    // The per-asset offer and price alert indexes were introduced after both existed
+    if ASSET_OFFER_INDEX.with(|index| index.borrow().is_empty()) && OFFERS.with(|offers| !offers.borrow().is_empty()) {
+        rebuild_asset_offer_index();
+    }
+    if ASSET_PRICE_ALERT_INDEX.with(|index| index.borrow().is_empty())
+        && PRICE_ALERTS.with(|alerts| !alerts.borrow().is_empty())
+    {
+        rebuild_asset_price_alert_index();
+    }
+    // The asset -> collection index was introduced after collections already existed
+    if ASSET_COLLECTION_INDEX.with(|index| index.borrow().is_empty())
+        && COLLECTION_ITEMS.with(|items| !items.borrow().is_empty())
+    {
+        rebuild_asset_collection_index();
+    }
+    // The asset -> favoriter index was introduced after favorites already existed
+    if ASSET_FAVORITE_INDEX.with(|index| index.borrow().is_empty())
+        && FAVORITES.with(|favorites| !favorites.borrow().is_empty())
+    {
+        rebuild_asset_favorite_index();
+    }
+    // Claim code hashes were only kept by code before
+    if GIFT_CODE_HASHES.with(|hashes| hashes.borrow().is_empty()) && GIFT_CODES.with(|codes| !codes.borrow().is_empty()) {
+        rebuild_gift_code_hashes();
+    }
+    // Categories were free text before the registry existed; the category
+    // index is rebuilt below from the migrated records
+    let categories_seeded = seed_categories(time());
+    if categories_seeded {
+        migrate_asset_categories();
+    }
+    // The category, for-sale, tag and text indexes were added after the owner index
+    if categories_seeded
+        || OWNER_INDEX.with(|index| index.borrow().is_empty())
+        || CATEGORY_INDEX.with(|index| index.borrow().is_empty())
+        || (TAG_INDEX.with(|index| index.borrow().is_empty())
+            && ASSETS.with(|assets| assets.borrow().iter().any(|(_, asset)| !asset.tags.is_empty())))
+        || (TEXT_INDEX.with(|index| index.borrow().is_empty())
+            && ASSETS.with(|assets| !assets.borrow().is_empty()))
+        || (PUBLIC_FOR_SALE_INDEX.with(|index| index.borrow().is_empty())
+            && FOR_SALE_INDEX.with(|index| !index.borrow().is_empty()))
+    {
+        rebuild_indexes();
+    }
+
+    // Aggregate counters were introduced after assets already existed; this
+    // runs after the index rebuild, which recounts on its own
+    if OWNER_STATS.with(|stats| stats.borrow().is_empty()) && ASSETS.with(|assets| !assets.borrow().is_empty()) {
+        rebuild_stats();
+    }
+
+    migrate_preview_images();
+    // Slugs were introduced after assets already existed
+    if SLUG_INDEX.with(|index| index.borrow().is_empty()) && ASSETS.with(|assets| !assets.borrow().is_empty()) {
+        backfill_slugs(time());
+    }
+    // Certified data does not survive upgrades
+    rebuild_certification();
+
+    // Timers do not survive upgrades
+    let active_auctions: Vec<Auction> = AUCTIONS.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .filter(|(_, auction)| auction.status == AuctionStatus::Active)
+            .map(|(_, auction)| auction)
+            .collect()
+    });
+    for auction in active_auctions {
+        schedule_auction_settlement(auction.asset_id, auction.end_time);
+    }
+    let held_escrows: Vec<Escrow> = ESCROWS.with(|escrows| {
+        escrows
+            .borrow()
+            .iter()
+            .filter(|(_, escrow)| escrow.status == EscrowStatus::Held)
+            .map(|(_, escrow)| escrow)
+            .collect()
+    });
+    for escrow in held_escrows {
+        schedule_escrow_confirmation(escrow.id, escrow.expires_at);
+    }
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    let args = match args {
+        Some(args) => args,
+        None => return,
+    };
+
+    let previous_icp_ledger = icp_ledger();
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        if let Some(ledger_canister_id) = args.ledger_canister_id {
+            config.insert("ledger_canister_id".to_string(), ledger_canister_id.to_text());
+        }
+        if let Some(default_storage_quota) = args.default_storage_quota {
+            config.insert("default_storage_quota".to_string(), default_storage_quota.to_string());
+        }
+        if let Some(max_price_e8s) = args.max_price_e8s {
+            config.insert("max_price_e8s".to_string(), max_price_e8s.to_string());
+        }
+    });
+    if icp_ledger() != previous_icp_ledger {
+        move_icp_prices(previous_icp_ledger, icp_ledger());
+    }
+}
+
+// ICP prices are keyed by the ICP ledger, so a new ledger takes them over
+fn move_icp_prices(from: Principal, to: Principal) {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let moved: Vec<Asset> = assets
+            .iter()
+            .filter(|(_, asset)| asset.prices.iter().any(|option| option.ledger == from))
+            .map(|(_, mut asset)| {
+                asset.prices.retain(|option| option.ledger != to);
+                for option in asset.prices.iter_mut().filter(|option| option.ledger == from) {
+                    option.ledger = to;
+                }
+                asset
+            })
+            .collect();
+        for asset in moved {
+            assets.insert(asset.id, asset);
+        }
+    });
+}
+
+// Ingress screening. Update calls are checked before they execute, so that
+// anonymous, oversized or unknown calls are dropped without paying for the
+// call. Queries are accepted as is, and so is anything from a controller.
+// Every exposed method must be listed here; the tests compare these tables
+// with the candid interface.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum IngressCaller {
+    Anyone,
+    Authenticated,
+}
+
+const SMALL_ARG_BYTES: u64 = 4 * 1024;
+const METADATA_ARG_BYTES: u64 = 64 * 1024;
+const BATCH_ARG_BYTES: u64 = 1024 * 1024;
+const FILE_ARG_BYTES: u64 = 3 * 1024 * 1024; // above the ingress limit, so only that applies
+
+const INGRESS_UPDATE_METHODS: &[(&str, IngressCaller, u64)] = &[
+    ("abort_upload", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("accept_offer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("add_asset_attachment", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("add_asset_image", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("add_asset_to_collection", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("add_authorized_marketplace", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("add_moderator", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("add_supported_ledger", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("admin_remove_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("approve_transfer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("archive_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("archive_category", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("attach_imported_file", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("begin_upload", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("buy_dutch_auction", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("cancel_auction", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("cancel_dutch_auction", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("cancel_gift", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("cancel_offer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("cancel_transfer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("check_deposit", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("claim_gift", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("claim_transfer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("clone_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("confirm_purchase", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("create_bundle", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("create_category", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("create_collection", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("create_coupon", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("create_dutch_auction", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("create_escrow_purchase", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("create_gift", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("create_rental_listing", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("delete_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("delete_asset_v2", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("delete_collection", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("delete_review", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("dissolve_bundle", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("favorite_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("feature_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("file_appeal", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("finish_restore", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("finish_upload", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("follow_creator", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("freeze_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("grant_file_access", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("hide_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("icrc7_transfer", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("import_assets", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("import_external_assets", IngressCaller::Authenticated, BATCH_ARG_BYTES),
+    ("import_file_chunk", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("initiate_transfer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("list_bundle", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("make_offer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("mark_notifications_read", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("marketplace_transfer_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("migrate_assets", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("pause_method", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("place_bid", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("prune_orphaned_files", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("publish_asset_version", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("purchase_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("purchase_asset_with_balance", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("purchase_asset_with_ledger", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("purchase_bundle", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("rate_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("rebuild_owner_index", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("rebuild_search_index", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("recompress_existing_files", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("recompute_stats", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("record_download", IngressCaller::Anyone, SMALL_ARG_BYTES),
+    ("record_view", IngressCaller::Anyone, SMALL_ARG_BYTES),
+    ("release_reservation", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_asset_attachment", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_asset_from_collection", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_asset_image", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_asset_metadata_field", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("remove_authorized_marketplace", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_moderator", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_price_alert", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("remove_supported_ledger", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("rename_category", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("rent_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("reorder_asset_images", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("replace_chunk", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("report_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("request_refund", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("reserve_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("resolve_appeal", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("resume_method", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("retry_pending_payouts", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("revert_transfer", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("revoke_approval", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("revoke_coupon", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("revoke_file_access", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("revoke_verification", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("run_maintenance_now", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_allowed_file_types", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("set_asset_for_sale", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_asset_for_sale_v2", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_asset_license", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_asset_metadata_field", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("set_asset_prices", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_asset_visibility", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_dedup_charge_percent", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_dispute_window", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_escrow_window", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_file_validation_permissive", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_for_sale_bulk", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("set_my_profile", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("set_paused", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_platform_fee", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_price_alert", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_rate_limits", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_upload_limits", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("set_user_quota", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("start_auction", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("subscribe_events", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("takedown_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("transfer_all_assets", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("transfer_asset_ownership", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("transfer_from", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unarchive_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unfavorite_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unfeature_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unfollow_creator", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unfreeze_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unhide_asset", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unlist_bundle", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("unsubscribe_events", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("update_asset_metadata", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("update_asset_price", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("update_prices_bulk", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("upload_asset", IngressCaller::Authenticated, METADATA_ARG_BYTES),
+    ("upload_asset_with_file", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("upload_asset_with_file_v2", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("upload_assets_batch", IngressCaller::Authenticated, BATCH_ARG_BYTES),
+    ("upload_chunk", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("upload_file", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("upload_preview_image", IngressCaller::Authenticated, FILE_ARG_BYTES),
+    ("verify_creator", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("withdraw_deposit", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+    ("withdraw_rental_listing", IngressCaller::Authenticated, SMALL_ARG_BYTES),
+];
+
+const INGRESS_QUERY_METHODS: &[&str] = &[
+    "export_assets", "export_file_chunk", "export_files_manifest", "file_exists", "get_all_assets",
+    "get_all_assets_summary", "get_all_tags", "get_allowed_file_types", "get_api_version",
+    "get_approval", "get_asset", "get_asset_attachments", "get_asset_by_slug", "get_asset_escrow",
+    "get_asset_file_at_version", "get_asset_images", "get_asset_provenance",
+    "get_asset_provenance_after", "get_asset_rating", "get_asset_rentals", "get_asset_reservation",
+    "get_asset_reviews",
+    "get_asset_sale_history", "get_asset_sale_history_after", "get_asset_sale_stats",
+    "get_asset_stats", "get_asset_v2", "get_asset_versions", "get_assets_after",
+    "get_assets_by_category", "get_assets_by_ids", "get_assets_by_tag", "get_assets_filtered",
+    "get_assets_for_sale", "get_assets_for_sale_after", "get_assets_for_sale_paginated",
+    "get_assets_for_sale_summary", "get_assets_for_sale_v2", "get_assets_paginated",
+    "get_assets_summary", "get_assets_summary_paginated", "get_assets_with_creator_info",
+    "get_auction", "get_bundle", "get_bundle_sale", "get_bundles_for_sale", "get_canister_metrics",
+    "get_categories", "get_category_counts", "get_collection", "get_creator_royalties",
+    "get_current_price", "get_dedup_charge_percent", "get_dispute_window", "get_dutch_auction",
+    "get_escrow", "get_escrow_window", "get_events", "get_favorite_count", "get_featured_assets",
+    "get_file", "get_file_chunk", "get_file_info", "get_followers", "get_following_feed",
+    "get_gift", "get_latest_seq", "get_ledger_canister_id", "get_listing_window",
+    "get_marketplace_stats", "get_my_coupons", "get_my_deposit", "get_my_deposit_account",
+    "get_my_deposit_history", "get_my_earnings", "get_my_earnings_history", "get_my_favorites",
+    "get_my_following", "get_my_notifications", "get_my_offers", "get_my_price_alerts",
+    "get_my_rentals", "get_my_storage_usage", "get_new_noteworthy", "get_offers_for_asset",
+    "get_owned_asset_ids", "get_owner_stats", "get_pause_status", "get_pending_appeals",
+    "get_pending_payouts", "get_pending_transfer", "get_platform_fee", "get_preview_image",
+    "get_price_history", "get_profile", "get_profile_by_name", "get_rate_limits",
+    "get_rental_listing", "get_reported_assets", "get_similar_assets", "get_storage_stats",
+    "get_supported_ledgers", "get_takedown", "get_takedown_events", "get_top_sellers",
+    "get_top_selling_assets", "get_total_assets", "get_trending_assets", "get_upload_limits",
+    "get_upload_session", "get_user_activity", "get_user_activity_after", "get_user_assets",
+    "get_user_assets_after", "get_user_assets_paginated", "get_user_assets_summary",
+    "get_user_assets_v2", "get_user_collections", "get_user_sales", "get_user_sales_after",
+    "get_verification_events", "get_verified_creators", "has_download_access", "has_usage_rights",
+    "health", "http_request", "http_request_streaming_callback", "icrc10_supported_standards",
+    "icrc7_balance_of", "icrc7_name", "icrc7_owner_of", "icrc7_symbol", "icrc7_token_metadata",
+    "icrc7_tokens", "icrc7_tokens_of", "icrc7_total_supply", "is_file_validation_permissive",
+    "list_assets_v2", "list_authorized_marketplaces", "list_moderators", "list_subscriptions",
+    "owns_any_of", "owns_asset", "search_assets", "search_assets_advanced", "search_assets_ranked",
+    "search_assets_summary", "suggest_tags", "verify_file_integrity",
+];
+
+#[inspect_message]
+fn inspect_message() {
+    let principal = caller();
+    let method = ic_cdk::api::call::method_name();
+
+    if !ic_cdk::api::is_controller(&principal) {
+        let arg_bytes = ic_cdk::api::call::arg_data_raw_size() as u64;
+        if let Err(reason) = screen_ingress(&method, principal, arg_bytes) {
+            ic_cdk::trap(&reason);
+        }
+    }
+    ic_cdk::api::call::accept_message();
+}
+
+fn screen_ingress(method: &str, principal: Principal, arg_bytes: u64) -> Result<(), String> {
+    if INGRESS_QUERY_METHODS.contains(&method) {
+        return Ok(());
+    }
+
+    let (_, allowed_caller, max_arg_bytes) = INGRESS_UPDATE_METHODS
+        .iter()
+        .find(|(name, _, _)| *name == method)
+        .ok_or_else(|| format!("Unknown method {}", method))?;
+    if *allowed_caller == IngressCaller::Authenticated && principal == Principal::anonymous() {
+        return Err(format!("{} requires an authenticated caller", method));
+    }
+    if arg_bytes > *max_arg_bytes {
+        return Err(format!("{} arguments exceed {} bytes", method, max_arg_bytes));
+    }
+    Ok(())
+}
+
+// Ids are never reused, so running out of them or handing out one that is
+// already taken means the counter is broken; trapping rolls the call back
+// before anything is overwritten.
+fn get_next_asset_id() -> u64 {
+    let next_id = last_asset_id()
+        .checked_add(1)
+        .unwrap_or_else(|| ic_cdk::trap("asset id counter overflowed"));
+    if ASSETS.with(|assets| assets.borrow().contains_key(&next_id)) {
+        ic_cdk::trap(&format!("asset id counter is corrupt: asset {} already exists", next_id));
+    }
+    set_last_asset_id(next_id);
+    next_id
+}
+
+fn last_asset_id() -> u64 {
+    migrate_legacy_asset_id_counter();
+    ASSET_ID_COUNTER.with(|counter| *counter.borrow().get())
+}
+
+fn set_last_asset_id(id: u64) {
+    migrate_legacy_asset_id_counter();
+    ASSET_ID_COUNTER.with(|counter| {
+        counter.borrow_mut().set(id).expect("a u64 always fits the asset id counter");
+    });
+}
+
+// Moves the value left by older releases into the cell. The legacy entry is
+// removed once copied, so this only does work on first access after upgrade.
+fn migrate_legacy_asset_id_counter() {
+    let Some(legacy_id) = LEGACY_ASSET_ID_COUNTER.with(|counter| counter.borrow_mut().remove(&0)) else {
+        return;
+    };
+    ASSET_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let last_id = (*counter.get()).max(legacy_id);
+        counter.set(last_id).expect("a u64 always fits the asset id counter");
+    });
+}
+
+fn get_next_sale_id() -> u64 {
+    SALE_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_offer_id() -> u64 {
+    OFFER_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_icrc7_tx_id() -> u64 {
+    ICRC7_TX_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_report_id() -> u64 {
+    REPORT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_collection_id() -> u64 {
+    COLLECTION_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_bundle_id() -> u64 {
+    BUNDLE_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_category_id() -> u64 {
+    CATEGORY_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_asset_image_id() -> u64 {
+    ASSET_IMAGE_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_ownership_event_id() -> u64 {
+    OWNERSHIP_EVENT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_verification_event_id() -> u64 {
+    VERIFICATION_EVENT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_escrow_id() -> u64 {
+    ESCROW_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_payout_id() -> u64 {
+    PAYOUT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_price_point_id() -> u64 {
+    PRICE_POINT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_deposit_entry_id() -> u64 {
+    DEPOSIT_ENTRY_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_takedown_event_id() -> u64 {
+    TAKEDOWN_EVENT_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_activity_seq() -> u64 {
+    ACTIVITY_SEQ_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_seq = counter.get(&0).unwrap_or(0);
+        let next_seq = current_seq + 1;
+        counter.insert(0, next_seq);
+        next_seq
+    })
+}
+
+fn get_next_notification_id() -> u64 {
+    NOTIFICATION_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_rental_id() -> u64 {
+    RENTAL_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+fn get_next_upload_session_id() -> u64 {
+    UPLOAD_SESSION_ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+// Recognizes the formats we serve when no explicit file type was recorded
+fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"glTF") {
+        Some("model/gltf-binary")
+    } else if bytes.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn content_type_for(file_type: &str) -> String {
+    match file_type.to_lowercase().as_str() {
+        "glb" => "model/gltf-binary",
+        "gltf" => "model/gltf+json",
+        "json" => "application/json",
+        "obj" => "model/obj",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ktx2" => "image/ktx2",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+// File hashes are lowercase hex SHA-256 digests of the file bytes
+fn verify_declared_hash(declared_hash: &str, computed_hash: &str) -> Result<(), AssetError> {
+    if !declared_hash.eq_ignore_ascii_case(computed_hash) {
+        return Err(AssetError::InvalidInput(format!(
+            "file_hash: declared {} but the uploaded bytes hash to {}",
+            declared_hash, computed_hash
+        )));
+    }
+    Ok(())
+}
+
+fn store_file(file_hash: String, file_type: &str, file_data: Vec<u8>, uploader: Principal, current_time: u64) {
+    let total_size = file_data.len() as u64;
+    let content_type = content_type_for(file_type);
+    let (stored, encoding) = encode_for_storage(&content_type, file_data);
+    let info = FileInfo {
+        file_hash: file_hash.clone(),
+        total_size,
+        content_type,
+        uploaded_by: Some(uploader),
+        encoding,
+        uploaded_at: Some(current_time),
+    };
+
+    charge_storage(uploader, info.total_size);
+    certify_file(&file_hash, info.total_size);
+    record_file_supplier(&file_hash, uploader);
+    write_stored_file(&file_hash, &stored);
+
+    FILE_INFO.with(|file_info| {
+        file_info.borrow_mut().insert(file_hash, info);
+    });
+}
+
+// Content-addressed storage: bytes whose verified hash is already stored are
+// not written again. Returns whether an existing copy was reused.
+fn store_or_reuse_file(
+    file_hash: String,
+    file_type: &str,
+    file_data: Vec<u8>,
+    uploader: Principal,
+    current_time: u64,
+) -> Result<bool, AssetError> {
+    let size = file_data.len() as u64;
+    if !is_file_stored(&file_hash) {
+        ensure_storage_available(uploader, size)?;
+        store_file(file_hash, file_type, file_data, uploader, current_time);
+        return Ok(false);
+    }
+    ensure_matches_stored(&file_hash, &file_data)?;
+    record_file_supplier(&file_hash, uploader);
+    // A fresh upload of orphaned bytes gets the same grace before pruning
+    let stored_by = FILE_INFO.with(|file_info| {
+        let mut file_info = file_info.borrow_mut();
+        let mut info = file_info.get(&file_hash)?;
+        info.uploaded_at = Some(current_time);
+        file_info.insert(file_hash.clone(), info.clone());
+        info.uploaded_by
+    });
+
+    // The original uploader already pays for the bytes, and nobody pays twice
+    let key = dedup_charge_key(&file_hash, uploader);
+    let already_charged = DEDUP_CHARGES.with(|charges| charges.borrow().contains_key(&key));
+    let charge = size * get_dedup_charge_percent() as u64 / 100;
+    if stored_by != Some(uploader) && !already_charged && charge > 0 {
+        ensure_storage_available(uploader, charge)?;
+        charge_storage(uploader, charge);
+        DEDUP_CHARGES.with(|charges| charges.borrow_mut().insert(key, charge));
+    }
+    Ok(true)
+}
+
+// Stored bytes are never overwritten. Callers verify the hash of the new bytes
+// first, so a difference means a collision or a stored copy that does not
+// match its own key; either way the upload is refused.
+fn ensure_matches_stored(file_hash: &String, file_data: &[u8]) -> Result<(), AssetError> {
+    if read_file_bytes(file_hash).is_some_and(|stored| stored != file_data) {
+        return Err(AssetError::InvalidInput(
+            "file_hash: already stored with different bytes".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// The checks every direct upload goes through before its bytes are kept
+fn store_upload(
+    file_hash: &str,
+    file_type: &str,
+    file_data: Vec<u8>,
+    uploader: Principal,
+    current_time: u64,
+) -> Result<bool, AssetError> {
+    ensure_file_size(file_type, file_data.len() as u64)?;
+    verify_declared_hash(file_hash, &sha256_hex(&file_data))?;
+    validate_file_format(file_type, &file_data)?;
+    store_or_reuse_file(file_hash.to_string(), file_type, file_data, uploader, current_time)
+}
+
+fn dedup_charge_key(file_hash: &str, principal: Principal) -> String {
+    format!("{}:{}", file_hash, principal)
+}
+
+// Removes the reuse charges recorded against a file, returning them per principal
+fn take_dedup_charges(file_hash: &str) -> Vec<(Principal, u64)> {
+    let prefix = format!("{}:", file_hash);
+    DEDUP_CHARGES.with(|charges| {
+        let mut charges = charges.borrow_mut();
+        let taken: Vec<(String, u64)> = charges
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .collect();
+        taken
+            .into_iter()
+            .filter_map(|(key, bytes)| {
+                charges.remove(&key);
+                let principal = Principal::from_text(&key[prefix.len()..]).ok()?;
+                Some((principal, bytes))
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn file_exists(file_hash: String) -> bool {
+    is_file_stored(&file_hash)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    matches!(content_type, "model/gltf+json" | "application/json" | "model/obj")
+}
+
+// The bytes to store for a file and their encoding. Compressed bytes are only
+// kept when they are smaller.
+fn encode_for_storage(content_type: &str, file_data: Vec<u8>) -> (Vec<u8>, Option<ContentEncoding>) {
+    if !is_compressible(content_type) || file_data.len() as u64 > MAX_COMPRESSED_FILE_SIZE {
+        return (file_data, None);
+    }
+    let compressed = gzip(&file_data);
+    if compressed.len() < file_data.len() {
+        (compressed, Some(ContentEncoding::Gzip))
+    } else {
+        (file_data, None)
+    }
+}
+
+fn decode_stored_file(stored: Vec<u8>, encoding: Option<ContentEncoding>) -> Vec<u8> {
+    match encoding {
+        None => stored,
+        Some(ContentEncoding::Gzip) => {
+            gunzip(&stored, MAX_COMPRESSED_FILE_SIZE)
+                .unwrap_or_else(|err| ic_cdk::trap(&format!("Stored file is corrupt: {}", err)))
+        },
+    }
+}
+
+fn stored_encoding_of(file_hash: &String) -> Option<ContentEncoding> {
+    FILE_INFO.with(|file_info| file_info.borrow().get(file_hash)).and_then(|info| info.encoding)
+}
+
+// A stored file's bytes as uploaded, decompressed if it is stored compressed
+fn read_file_bytes(file_hash: &String) -> Option<Vec<u8>> {
+    let stored = read_stored_file(file_hash)?;
+    Some(decode_stored_file(stored, stored_encoding_of(file_hash)))
+}
+
+// A range of the bytes a file is served as: as stored when the client takes
+// the stored encoding, otherwise as uploaded. Only the pieces covering the
+// range are read, except for compressed files, which are decoded whole; those
+// are at most MAX_COMPRESSED_FILE_SIZE.
+fn served_range(file_hash: &str, offset: u64, length: u64, as_stored: bool) -> Option<Vec<u8>> {
+    if as_stored || stored_encoding_of(&file_hash.to_string()).is_none() {
+        return read_stored_range(file_hash, offset, length);
+    }
+    read_file_bytes(&file_hash.to_string()).map(|file_data| {
+        let file_len = file_data.len() as u64;
+        let start = offset.min(file_len) as usize;
+        let end = offset.saturating_add(length).min(file_len) as usize;
+        file_data[start..end].to_vec()
+    })
+}
+
+// Length of what served_range reads from
+fn served_len(file_hash: &str, as_stored: bool) -> Option<u64> {
+    match FILE_INFO.with(|file_info| file_info.borrow().get(&file_hash.to_string())) {
+        Some(info) if !(as_stored && info.encoding.is_some()) => Some(info.total_size),
+        _ => stored_file_len(file_hash),
+    }
+}
+
+// Stored file pieces. Every file stored here has a piece 0; files stored whole
+// before pieces existed are read from FILES until maintenance splits them.
+fn piece_key(file_hash: &str, index: u64) -> FilePieceKey {
+    FilePieceKey { file_hash: file_hash.to_string(), index }
+}
+
+fn is_file_stored(file_hash: &str) -> bool {
+    FILE_PIECES.with(|pieces| pieces.borrow().contains_key(&piece_key(file_hash, 0)))
+        || FILES.with(|files| files.borrow().contains_key(&file_hash.to_string()))
+}
+
+// Replaces whatever is stored under the hash
+fn write_stored_file(file_hash: &str, stored: &[u8]) {
+    remove_stored_file(file_hash);
+    FILE_PIECES.with(|pieces| {
+        let mut pieces = pieces.borrow_mut();
+        for (index, piece) in stored.chunks(FILE_PIECE_SIZE as usize).enumerate() {
+            pieces.insert(piece_key(file_hash, index as u64), piece.to_vec());
+        }
+    });
+}
+
+// Appends to a file holding stored_len bytes, topping up its last piece first
+fn append_stored_bytes(file_hash: &str, stored_len: u64, bytes: &[u8]) {
+    FILE_PIECES.with(|pieces| {
+        let mut pieces = pieces.borrow_mut();
+        let mut index = stored_len / FILE_PIECE_SIZE;
+        let mut piece = pieces.get(&piece_key(file_hash, index)).unwrap_or_default();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let taken = (FILE_PIECE_SIZE as usize - piece.len()).min(rest.len());
+            piece.extend_from_slice(&rest[..taken]);
+            rest = &rest[taken..];
+            pieces.insert(piece_key(file_hash, index), std::mem::take(&mut piece));
+            index += 1;
+        }
+    });
+}
+
+// The stored bytes in [offset, offset + length), cut short at the end of the file
+fn read_stored_range(file_hash: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+    if let Some(stored) = FILES.with(|files| files.borrow().get(&file_hash.to_string())) {
+        let start = offset.min(stored.len() as u64) as usize;
+        let end = offset.saturating_add(length).min(stored.len() as u64) as usize;
+        return Some(stored[start..end].to_vec());
+    }
+    if !is_file_stored(file_hash) {
+        return None;
+    }
+
+    let end = offset.saturating_add(length);
+    let mut bytes = Vec::new();
+    if end == offset {
+        return Some(bytes);
+    }
+    let (first, last) = (offset / FILE_PIECE_SIZE, (end - 1) / FILE_PIECE_SIZE);
+    FILE_PIECES.with(|pieces| {
+        for (key, piece) in pieces.borrow().range(piece_key(file_hash, first)..=piece_key(file_hash, last)) {
+            let piece_start = key.index * FILE_PIECE_SIZE;
+            let from = offset.saturating_sub(piece_start).min(piece.len() as u64) as usize;
+            let to = (end - piece_start).min(piece.len() as u64) as usize;
+            bytes.extend_from_slice(&piece[from..to]);
+        }
+    });
+    Some(bytes)
+}
+
+fn read_stored_file(file_hash: &str) -> Option<Vec<u8>> {
+    read_stored_range(file_hash, 0, u64::MAX)
+}
+
+// Length of the stored bytes, read from the last piece alone
+fn stored_file_len(file_hash: &str) -> Option<u64> {
+    if let Some(stored) = FILES.with(|files| files.borrow().get(&file_hash.to_string())) {
+        return Some(stored.len() as u64);
+    }
+    FILE_PIECES.with(|pieces| {
+        let pieces = pieces.borrow();
+        let last = pieces
+            .keys_range(piece_key(file_hash, 0)..=piece_key(file_hash, u64::MAX))
+            .last()?;
+        let last_len = pieces.get(&last).map_or(0, |piece| piece.len() as u64);
+        Some(last.index * FILE_PIECE_SIZE + last_len)
+    })
+}
+
+// Removes the stored bytes, returning how many there were
+fn remove_stored_file(file_hash: &str) -> u64 {
+    let whole = FILES.with(|files| files.borrow_mut().remove(&file_hash.to_string()));
+    let mut removed = whole.map_or(0, |stored| stored.len() as u64);
+    FILE_PIECES.with(|pieces| {
+        let mut pieces = pieces.borrow_mut();
+        let keys: Vec<FilePieceKey> =
+            pieces.keys_range(piece_key(file_hash, 0)..=piece_key(file_hash, u64::MAX)).collect();
+        for key in keys {
+            removed += pieces.remove(&key).map_or(0, |piece| piece.len() as u64);
+        }
+    });
+    removed
+}
+
+// Hashes of every stored file, in order
+fn stored_file_hashes() -> Vec<String> {
+    let mut hashes: Vec<String> = FILE_PIECES.with(|pieces| {
+        pieces.borrow().keys().filter(|key| key.index == 0).map(|key| key.file_hash).collect()
+    });
+    hashes.extend(FILES.with(|files| files.borrow().keys().collect::<Vec<_>>()));
+    hashes.sort();
+    hashes.dedup();
+    hashes
+}
+
+fn has_stored_files() -> bool {
+    FILE_PIECES.with(|pieces| !pieces.borrow().is_empty()) || FILES.with(|files| !files.borrow().is_empty())
+}
+
+// Moves files stored whole into pieces, stopping once FILE_SPLIT_BATCH_BYTES
+// have moved. Returns the number of files split.
+fn split_whole_files() -> u64 {
+    let mut split = 0;
+    let mut moved = 0;
+    while moved < FILE_SPLIT_BATCH_BYTES {
+        let Some((file_hash, stored)) = FILES.with(|files| files.borrow().first_key_value()) else {
+            break;
+        };
+        write_stored_file(&file_hash, &stored);
+        moved += stored.len() as u64;
+        split += 1;
+    }
+    split
+}
+
+// Files an asset keeps alive: its model and, if stored here, its preview image
+fn file_refs_of(asset: &Asset) -> Vec<String> {
+    let mut refs = Vec::new();
+    // Imported assets have no file here until one is attached
+    if !asset.file_hash.is_empty() {
+        refs.push(asset.file_hash.clone());
+    }
+    if let Some(preview_hash) = preview_file_hash(&asset.preview_image_url) {
+        if preview_hash != asset.file_hash {
+            refs.push(preview_hash);
+        }
+    }
+    refs
+}
+
+fn preview_file_hash(preview_image_url: &Option<String>) -> Option<String> {
+    preview_image_url
+        .as_ref()
+        .and_then(|url| url.strip_prefix("canister://"))
+        .map(|file_hash| file_hash.to_string())
+}
+
+fn add_file_ref(file_hash: &str, asset_id: u64) {
+    FILE_REFS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        let count = refs.get(&file_hash.to_string()).unwrap_or(0);
+        refs.insert(file_hash.to_string(), count + 1);
+    });
+    FILE_REF_ASSETS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        let key = FileRefKey { file_hash: file_hash.to_string(), asset_id };
+        let count = refs.get(&key).unwrap_or(0);
+        refs.insert(key, count + 1);
+    });
+}
+
+// Drops one of the asset's references and deletes the file once nothing
+// references it
+fn release_file_ref(file_hash: &str, asset_id: u64) {
+    FILE_REF_ASSETS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        let key = FileRefKey { file_hash: file_hash.to_string(), asset_id };
+        match refs.get(&key).unwrap_or(0).saturating_sub(1) {
+            0 => refs.remove(&key),
+            count => refs.insert(key, count),
+        };
+    });
+    let remaining = FILE_REFS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        let count = refs.get(&file_hash.to_string()).unwrap_or(0).saturating_sub(1);
+        if count == 0 {
+            refs.remove(&file_hash.to_string());
+        } else {
+            refs.insert(file_hash.to_string(), count);
+        }
+        count
+    });
+
+    if remaining == 0 {
+        remove_file(file_hash);
+    }
+}
+
+// Ids of the assets holding a reference to the file
+fn referencing_assets(file_hash: &str) -> Vec<u64> {
+    FILE_REF_ASSETS.with(|refs| {
+        refs.borrow()
+            .range(FileRefKey { file_hash: file_hash.to_string(), asset_id: 0 }..)
+            .take_while(|(key, _)| key.file_hash == file_hash)
+            .map(|(key, _)| key.asset_id)
+            .collect()
+    })
+}
+
+fn record_file_supplier(file_hash: &str, principal: Principal) {
+    let key = FileSupplierKey { file_hash: file_hash.to_string(), principal };
+    FILE_SUPPLIERS.with(|suppliers| suppliers.borrow_mut().insert(key, ()));
+}
+
+fn remove_file_suppliers(file_hash: &str) {
+    FILE_SUPPLIERS.with(|suppliers| {
+        let mut suppliers = suppliers.borrow_mut();
+        let first = FileSupplierKey { file_hash: file_hash.to_string(), principal: Principal::management_canister() };
+        let keys: Vec<FileSupplierKey> = suppliers
+            .range(first..)
+            .take_while(|(key, _)| key.file_hash == file_hash)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            suppliers.remove(&key);
+        }
+    });
+}
+
+// A new reference to a stored file must come from someone who supplied its
+// bytes; otherwise anyone could point a free asset of their own at a paid
+// model's hash and read the model through it. Files stored before suppliers
+// were tracked stay open to owners of assets already using them. Hashes not
+// stored here refer to files kept elsewhere and are not checked.
+fn ensure_may_reference(file_hash: &str, principal: Principal) -> Result<(), AssetError> {
+    let Some(info) = file_info_of(&file_hash.to_string()) else {
+        return Ok(());
+    };
+    let key = FileSupplierKey { file_hash: file_hash.to_string(), principal };
+    let supplied = info.uploaded_by == Some(principal)
+        || FILE_SUPPLIERS.with(|suppliers| suppliers.borrow().contains_key(&key));
+    let legacy_user = info.uploaded_by.is_none()
+        && referencing_assets(file_hash)
+            .into_iter()
+            .filter_map(asset_by_id)
+            .any(|asset| asset.owner == principal);
+    if supplied || legacy_user {
+        Ok(())
+    } else {
+        Err(AssetError::InvalidInput(format!("file_hash: {} was not uploaded by the caller", file_hash)))
+    }
+}
+
+fn remove_file(file_hash: &str) -> u64 {
+    uncertify_path(&format!("/file/{}", file_hash));
+    remove_file_suppliers(file_hash);
+    let size = remove_stored_file(file_hash);
+    let info = FILE_INFO.with(|file_info| file_info.borrow_mut().remove(&file_hash.to_string()));
+
+    if let Some(uploader) = info.and_then(|info| info.uploaded_by) {
+        credit_storage(uploader, size);
+    }
+    for (principal, bytes) in take_dedup_charges(file_hash) {
+        credit_storage(principal, bytes);
+    }
+
+    size
+}
+
+// Storage quotas
+fn charge_storage(principal: Principal, bytes: u64) {
+    STORAGE_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let used = usage.get(&principal).unwrap_or(0);
+        usage.insert(principal, used.saturating_add(bytes));
+    });
+}
+
+fn credit_storage(principal: Principal, bytes: u64) {
+    STORAGE_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let used = usage.get(&principal).unwrap_or(0).saturating_sub(bytes);
+        if used == 0 {
+            usage.remove(&principal);
+        } else {
+            usage.insert(principal, used);
+        }
+    });
+}
+
+fn storage_usage_of(principal: Principal) -> StorageUsage {
+    let used = STORAGE_USAGE.with(|usage| usage.borrow().get(&principal).unwrap_or(0));
+    let reserved = UPLOAD_SESSIONS.with(|sessions| {
+        sessions
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.owner == principal)
+            .map(|(_, session)| session.total_size)
+            .sum()
+    });
+    let quota = USER_QUOTAS
+        .with(|quotas| quotas.borrow().get(&principal))
+        .unwrap_or_else(get_default_storage_quota);
+
+    StorageUsage {
+        used,
+        reserved,
+        quota,
+        remaining: quota.saturating_sub(used).saturating_sub(reserved),
+    }
+}
+
+fn ensure_storage_available(principal: Principal, bytes: u64) -> Result<(), AssetError> {
+    let remaining = storage_usage_of(principal).remaining;
+    if bytes > remaining {
+        return Err(AssetError::QuotaExceeded { remaining });
+    }
+    Ok(())
+}
+
+#[update]
+fn set_dedup_charge_percent(percent: u8) -> Result<u8, String> {
+    ensure_running("set_dedup_charge_percent").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if percent > 100 {
+        return Err("percent: must be between 0 and 100".to_string());
+    }
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("dedup_charge_percent".to_string(), percent.to_string());
+    });
+    Ok(percent)
+}
+
+#[query]
+fn get_dedup_charge_percent() -> u8 {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"dedup_charge_percent".to_string())
+            .and_then(|percent| percent.parse().ok())
+            .unwrap_or(DEFAULT_DEDUP_CHARGE_PERCENT)
+    })
+}
+
+fn get_default_storage_quota() -> u64 {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"default_storage_quota".to_string())
+            .and_then(|quota| quota.parse().ok())
+            .unwrap_or(DEFAULT_STORAGE_QUOTA)
+    })
+}
+
+#[query]
+fn get_my_storage_usage() -> StorageUsage {
+    storage_usage_of(caller())
+}
+
+#[update]
+fn set_user_quota(user: Principal, quota: Option<u64>) -> Result<StorageUsage, String> {
+    ensure_running("set_user_quota").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    USER_QUOTAS.with(|quotas| {
+        let mut quotas = quotas.borrow_mut();
+        match quota {
+            Some(quota) => quotas.insert(user, quota),
+            None => quotas.remove(&user),
+        }
+    });
+
+    Ok(storage_usage_of(user))
+}
+
+fn rebuild_file_refs() {
+    let mut counts: BTreeMap<FileRefKey, u64> = BTreeMap::new();
+    ASSETS.with(|assets| {
+        for (asset_id, asset) in assets.borrow().iter() {
+            for file_hash in file_refs_of(&asset) {
+                *counts.entry(FileRefKey { file_hash, asset_id }).or_insert(0) += 1;
+            }
+            for version in earlier_versions_of(&asset) {
+                *counts.entry(FileRefKey { file_hash: version.file_hash, asset_id }).or_insert(0) += 1;
+            }
+        }
+    });
+    ASSET_ATTACHMENTS.with(|attachments| {
+        for (asset_id, stored) in attachments.borrow().iter() {
+            for attachment in stored.attachments {
+                *counts.entry(FileRefKey { file_hash: attachment.file_hash, asset_id }).or_insert(0) += 1;
+            }
+        }
+    });
+
+    let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+    FILE_REF_ASSETS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        refs.clear_new();
+        for (key, count) in counts {
+            *totals.entry(key.file_hash.clone()).or_insert(0) += count;
+            refs.insert(key, count);
+        }
+    });
+    FILE_REFS.with(|refs| {
+        let mut refs = refs.borrow_mut();
+        refs.clear_new();
+        for (file_hash, count) in totals {
+            refs.insert(file_hash, count);
+        }
+    });
+}
+
+fn file_size_of(file_hash: &String) -> u64 {
+    match FILE_INFO.with(|file_info| file_info.borrow().get(file_hash)) {
+        Some(info) => info.total_size,
+        None => stored_file_len(file_hash).unwrap_or(0),
+    }
+}
+
+fn validate_name(name: &str) -> Result<String, AssetError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AssetError::InvalidInput("name: must not be empty".to_string()));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AssetError::InvalidInput(format!("name: must be at most {} characters", MAX_NAME_LENGTH)));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(AssetError::InvalidInput("name: must not contain control characters".to_string()));
+    }
+    Ok(name.to_string())
+}
+
+fn validate_description(description: &str) -> Result<String, AssetError> {
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "description: must be at most {} characters",
+            MAX_DESCRIPTION_LENGTH
+        )));
+    }
+    Ok(description.trim().to_string())
+}
+
+fn validate_review(review: Option<String>) -> Result<Option<String>, AssetError> {
+    let review = review.map(|review| review.trim().to_string()).filter(|review| !review.is_empty());
+    if let Some(review) = &review {
+        if review.chars().count() > MAX_REVIEW_LENGTH {
+            return Err(AssetError::InvalidInput(format!(
+                "review: must be at most {} characters",
+                MAX_REVIEW_LENGTH
+            )));
+        }
+    }
+    Ok(review)
+}
+
+fn validate_file_type(file_type: &str) -> Result<String, AssetError> {
+    let file_type = normalize_file_type(file_type);
+    let allowed = get_allowed_file_types();
+    if !allowed.contains(&file_type) {
+        return Err(AssetError::InvalidInput(format!(
+            "file_type: must be one of {}",
+            allowed.join(", ")
+        )));
+    }
+    Ok(file_type)
+}
+
+// Older clients send the MIME type instead of the extension
+fn normalize_file_type(file_type: &str) -> String {
+    match file_type.trim().to_lowercase().as_str() {
+        "model/gltf-binary" => "glb".to_string(),
+        "model/gltf+json" => "gltf".to_string(),
+        other => other.trim_start_matches('.').to_string(),
+    }
+}
+
+// Checks that the bytes really are in the claimed format, so that a zip or a
+// texture uploaded as a model is caught here rather than in the viewer. Types
+// we cannot sniff are let through only while validation is permissive.
+fn validate_file_format(file_type: &str, bytes: &[u8]) -> Result<(), AssetError> {
+    match normalize_file_type(file_type).as_str() {
+        // Nothing was claimed, so there is nothing to check against
+        "" => Ok(()),
+        // VRM avatars are glTF binaries with an extension
+        "glb" | "vrm" => validate_glb(bytes),
+        "gltf" => validate_gltf(bytes),
+        _ if is_file_validation_permissive() => Ok(()),
+        other => Err(AssetError::InvalidFileFormat {
+            expected: format!("a file type that can be verified, not {}", other),
+        }),
+    }
+}
+
+// Header: magic "glTF", then little-endian u32 version and total length
+fn validate_glb(bytes: &[u8]) -> Result<(), AssetError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"glTF" {
+        return Err(AssetError::InvalidFileFormat {
+            expected: "a glTF binary starting with the \"glTF\" magic".to_string(),
+        });
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != 2 {
+        return Err(AssetError::InvalidFileFormat {
+            expected: format!("glTF binary version 2, found version {}", version),
+        });
+    }
+    let length = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as u64;
+    if length != bytes.len() as u64 {
+        return Err(AssetError::InvalidFileFormat {
+            expected: format!("a glTF binary of {} bytes as declared in its header, found {}", length, bytes.len()),
+        });
+    }
+    Ok(())
+}
+
+fn validate_gltf(bytes: &[u8]) -> Result<(), AssetError> {
+    let has_asset = serde_json::from_slice::<serde_json::Value>(bytes)
+        .is_ok_and(|json| json.get("asset").is_some_and(|asset| asset.is_object()));
+    if !has_asset {
+        return Err(AssetError::InvalidFileFormat {
+            expected: "glTF JSON with an \"asset\" object".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_tags(tags: &[String]) -> Result<Vec<String>, AssetError> {
+    if tags.len() > MAX_TAGS {
+        return Err(AssetError::InvalidInput(format!("tags: at most {} tags are allowed", MAX_TAGS)));
+    }
+
+    tags.iter()
+        .map(|tag| {
+            let tag = tag.trim();
+            if tag.is_empty() {
+                return Err(AssetError::InvalidInput("tags: tags must not be empty".to_string()));
+            }
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                return Err(AssetError::InvalidInput(format!(
+                    "tags: each tag must be at most {} characters",
+                    MAX_TAG_LENGTH
+                )));
+            }
+            if tag.chars().any(char::is_control) {
+                return Err(AssetError::InvalidInput("tags: tags must not contain control characters".to_string()));
+            }
+            Ok(tag.to_string())
+        })
+        .collect()
+}
+
+fn validate_price(price: u64) -> Result<u64, AssetError> {
+    let max_price = get_max_price();
+    if price > max_price {
+        return Err(AssetError::InvalidInput(format!("price: must be at most {} e8s", max_price)));
+    }
+    Ok(price)
+}
+
+// Unlike an asset's price, which may be zero for free assets, a sale listing
+// needs a positive price
+fn validate_listing_price(price: u64) -> Result<u64, AssetError> {
+    let max_price = get_max_price();
+    if price == 0 || price > max_price {
+        return Err(AssetError::InvalidPrice { max: max_price });
+    }
+    Ok(price)
+}
+
+fn get_max_price() -> u64 {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"max_price_e8s".to_string())
+            .and_then(|max_price| max_price.parse().ok())
+            .unwrap_or(MAX_PRICE_E8S)
+    })
+}
+
+// Transfers must go to a principal that can actually use the asset
+fn validate_recipient(owner: Principal, recipient: Principal) -> Result<Principal, AssetError> {
+    if recipient == Principal::anonymous() || recipient == Principal::management_canister() || recipient == owner {
+        return Err(AssetError::InvalidRecipient);
+    }
+    Ok(recipient)
+}
+
+fn validate_royalty_bps(royalty_bps: u16) -> Result<u16, AssetError> {
+    if royalty_bps > MAX_ROYALTY_BPS {
+        return Err(AssetError::InvalidInput(format!("royalty_bps: must be at most {}", MAX_ROYALTY_BPS)));
+    }
+    Ok(royalty_bps)
+}
+
+fn validate_license(license: License) -> Result<License, AssetError> {
+    let License::Custom { url } = license else {
+        return Ok(license);
+    };
+    let url = url.trim();
+    if !url.starts_with("https://") || url.len() > MAX_LICENSE_URL_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "license: a custom license needs an https:// url of at most {} characters",
+            MAX_LICENSE_URL_LENGTH
+        )));
+    }
+    Ok(License::Custom { url: url.to_string() })
+}
+
+fn validate_asset_input(asset_input: AssetInput) -> Result<AssetInput, AssetError> {
+    if asset_input.file_size == 0 {
+        return Err(AssetError::InvalidInput("file_size: must be greater than zero".to_string()));
+    }
+
+    Ok(AssetInput {
+        name: validate_name(&asset_input.name)?,
+        description: validate_description(&asset_input.description)?,
+        file_type: validate_file_type(&asset_input.file_type)?,
+        tags: validate_tags(&asset_input.tags)?,
+        price: validate_price(asset_input.price)?,
+        royalty_bps: Some(validate_royalty_bps(asset_input.royalty_bps.unwrap_or(0))?),
+        license: validate_license(asset_input.license)?,
+        ..asset_input
+    })
+}
+
+#[update]
+fn upload_asset(asset_input: AssetInput) -> Result<Asset, AssetError> {
+    ensure_running("upload_asset")?;
+    let principal = caller();
+    
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::Upload)?;
+
+    create_asset(asset_input, principal, time())
+}
+
+// Registers many assets in one call. Each input succeeds or fails on its own;
+// the results are returned in input order.
+#[update]
+fn upload_assets_batch(inputs: Vec<AssetInput>) -> Vec<Result<Asset, AssetError>> {
+    if let Err(err) = ensure_running("upload_assets_batch") {
+        return inputs.iter().map(|_| Err(err.clone())).collect();
+    }
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return inputs.iter().map(|_| Err(AssetError::AnonymousCaller)).collect();
+    }
+    if inputs.len() > MAX_UPLOAD_BATCH_SIZE {
+        let err = AssetError::InvalidInput(format!(
+            "batch: at most {} assets can be uploaded per call",
+            MAX_UPLOAD_BATCH_SIZE
+        ));
+        return inputs.iter().map(|_| Err(err.clone())).collect();
+    }
+    if let Err(err) = enforce_rate_limit(RateLimitedAction::Upload) {
+        return inputs.iter().map(|_| Err(err.clone())).collect();
+    }
+
+    let current_time = time();
+    inputs
+        .into_iter()
+        .map(|asset_input| create_asset(asset_input, principal, current_time))
+        .collect()
+}
+
+fn create_asset(asset_input: AssetInput, principal: Principal, current_time: u64) -> Result<Asset, AssetError> {
+    let asset_input = validate_asset_input(asset_input)?;
+    let category = active_category(asset_input.category_id)?;
+    ensure_may_reference(&asset_input.file_hash, principal)?;
+    if let Some(preview_hash) = preview_file_hash(&asset_input.preview_image_url) {
+        ensure_may_reference(&preview_hash, principal)?;
+    }
+
+    let asset_id = get_next_asset_id();
+
+    let mut asset = Asset {
+        id: asset_id,
+        name: asset_input.name,
+        description: asset_input.description,
+        owner: principal,
+        file_hash: asset_input.file_hash,
+        file_url: asset_input.file_url,
+        file_type: asset_input.file_type,
+        file_size: asset_input.file_size,
+        prices: icp_prices(asset_input.price),
+        is_for_sale: false, // Assets start as not for sale
+        created_at: current_time,
+        updated_at: current_time,
+        category_id: category.id,
+        category: category.name,
+        tags: asset_input.tags,
+        preview_image_url: asset_input.preview_image_url,
+        creator: principal,
+        royalty_bps: asset_input.royalty_bps.unwrap_or(0),
+        hidden: None,
+        visibility: Visibility::Public,
+        metadata: Vec::new(),
+        archived_at: None,
+        license: asset_input.license,
+        slug: None,
+        reservation: None,
+        source: None,
+        frozen: None,
+    };
+    assign_slug(&mut asset, current_time);
+
+    for file_hash in file_refs_of(&asset) {
+        add_file_ref(&file_hash, asset.id);
+    }
+    index_asset(&asset);
+    record_ownership_event(asset_id, None, principal, OwnershipEventKind::Upload, None, current_time);
+    record_creation_price(&asset, principal, current_time);
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        assets.insert(asset_id, asset.clone());
+    });
+
+    Ok(asset)
+}
+
+// The read endpoints below are composite queries so that other canisters can
+// fan out to them from their own composite queries. They must stay free of
+// anything only updates may do.
+#[query(composite = true)]
+fn get_asset(asset_id: u64) -> Option<AssetV1Response> {
+    visible_asset(asset_id, caller()).ok().map(AssetV1Response::from)
+}
+
+// The live checkout hold on an asset, for clients of get_asset, whose
+// response cannot carry it
+#[query(composite = true)]
+fn get_asset_reservation(asset_id: u64) -> Option<Reservation> {
+    asset_reservation(asset_id, caller(), time())
+}
+
+fn asset_reservation(asset_id: u64, principal: Principal, current_time: u64) -> Option<Reservation> {
+    let asset = visible_asset(asset_id, principal).ok()?;
+    active_reservation(&asset, current_time).cloned()
+}
+
+fn visible_asset(asset_id: u64, principal: Principal) -> Result<Asset, AssetError> {
+    asset_by_id(asset_id)
+        .filter(|asset| can_view(asset, principal))
+        .ok_or(AssetError::NotFound)
+}
+
+// Resolves current slugs and, during their grace period, the old slugs of
+// renamed assets. Compare the returned asset's slug to detect a redirect.
+#[query(composite = true)]
+fn get_asset_by_slug(slug: String) -> Option<Asset> {
+    let asset_id = resolve_slug(&slug, time())?;
+    visible_asset(asset_id, caller()).ok()
+}
+
+fn resolve_slug(slug: &str, current_time: u64) -> Option<u64> {
+    SLUG_INDEX
+        .with(|index| index.borrow().get(&slug.to_string()))
+        .filter(|entry| entry.redirect_until.is_none_or(|until| current_time < until))
+        .map(|entry| entry.asset_id)
+}
+
+// Lowercase ASCII letters and digits joined by single hyphens. Common Latin
+// accented letters are folded to their base letter; every other character
+// separates words. May return an empty string.
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    let mut pending_hyphen = false;
+    for c in name.chars().flat_map(char::to_lowercase) {
+        let folded = match c {
+            'a'..='z' | '0'..='9' => Some(c.to_string()),
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => Some("a".to_string()),
+            'æ' => Some("ae".to_string()),
+            'ç' => Some("c".to_string()),
+            'è' | 'é' | 'ê' | 'ë' => Some("e".to_string()),
+            'ì' | 'í' | 'î' | 'ï' => Some("i".to_string()),
+            'ñ' => Some("n".to_string()),
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => Some("o".to_string()),
+            'œ' => Some("oe".to_string()),
+            'ß' => Some("ss".to_string()),
+            'ù' | 'ú' | 'û' | 'ü' => Some("u".to_string()),
+            'ý' | 'ÿ' => Some("y".to_string()),
+            _ => None,
+        };
+        match folded {
+            Some(part) => {
+                if pending_hyphen && !slug.is_empty() {
+                    slug.push('-');
+                }
+                pending_hyphen = false;
+                slug.push_str(&part);
+            },
+            None => pending_hyphen = true,
+        }
+        if slug.len() >= MAX_SLUG_LENGTH {
+            break;
+        }
+    }
+    slug.truncate(MAX_SLUG_LENGTH);
+    slug.trim_end_matches('-').to_string()
+}
+
+// The slug an asset would get before collisions are resolved. Names with
+// nothing left after slugify fall back to the id, and purely numeric slugs
+// are prefixed so they cannot be mistaken for an id in a URL.
+fn base_slug(asset: &Asset) -> String {
+    let slug = slugify(&asset.name);
+    if slug.is_empty() {
+        format!("asset-{}", asset.id)
+    } else if slug.bytes().all(|b| b.is_ascii_digit()) {
+        format!("asset-{}", slug)
+    } else {
+        slug
+    }
+}
+
+// Whether `slug` is `base` or `base` followed by a "-<n>" dedupe suffix
+fn slug_matches_base(slug: &str, base: &str) -> bool {
+    slug == base
+        || slug
+            .strip_prefix(base)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|suffix| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Gives the asset the first free slug among its base slug, base-2, base-3, ...
+// A rename that leaves the base unchanged keeps the current slug; otherwise
+// the current slug becomes a redirect for SLUG_REDIRECT_GRACE_NANOS.
+fn assign_slug(asset: &mut Asset, current_time: u64) {
+    let base = base_slug(asset);
+    if asset.slug.as_deref().is_some_and(|slug| slug_matches_base(slug, &base)) {
+        return;
+    }
+
+    SLUG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let available = |slug: &String| match index.get(slug) {
+            None => true,
+            Some(entry) => {
+                entry.asset_id == asset.id || entry.redirect_until.is_some_and(|until| until <= current_time)
+            },
+        };
+        let mut candidate = base.clone();
+        let mut suffix: u64 = 2;
+        while !available(&candidate) {
+            candidate = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+
+        if let Some(previous) = asset.slug.take() {
+            index.insert(
+                previous,
+                SlugEntry { asset_id: asset.id, redirect_until: Some(current_time + SLUG_REDIRECT_GRACE_NANOS) },
+            );
+        }
+        index.insert(candidate.clone(), SlugEntry { asset_id: asset.id, redirect_until: None });
+        asset.slug = Some(candidate);
+    });
+}
+
+// Frees the current slug of a removed asset. Its redirects, if any, expire on
+// their own and resolve to nothing in the meantime.
+fn release_slug(asset: &Asset) {
+    if let Some(slug) = &asset.slug {
+        SLUG_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            if index.get(slug).is_some_and(|entry| entry.asset_id == asset.id) {
+                index.remove(slug);
+            }
+        });
+    }
+}
+
+// Assigns slugs in id order, so the oldest asset keeps the plain base slug
+fn backfill_slugs(current_time: u64) {
+    let ids: Vec<u64> = ASSETS.with(|assets| assets.borrow().iter().map(|(id, _)| id).collect());
+    for asset_id in ids {
+        if let Some(mut asset) = asset_by_id(asset_id) {
+            if asset.slug.is_none() {
+                assign_slug(&mut asset, current_time);
+                ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+            }
+        }
+    }
+}
+
+// Unfiltered lookup for internal use; endpoints apply visibility themselves
+fn asset_by_id(asset_id: u64) -> Option<Asset> {
+    ASSETS.with(|assets| {
+        assets.borrow().get(&asset_id)
+    })
+}
+
+// Results line up with `ids`, with None for ids that do not exist
+#[query(composite = true)]
+fn get_assets_by_ids(ids: Vec<u64>) -> Result<Vec<Option<Asset>>, AssetError> {
+    lookup_assets(&ids, caller())
+}
+
+// Same lookup as get_assets_by_ids, trimmed to what listing pages show so
+// that inter-canister responses stay small
+#[query(composite = true)]
+fn get_assets_summary(ids: Vec<u64>) -> Result<Vec<Option<AssetSummary>>, AssetError> {
+    lookup_summaries(&ids, caller())
+}
+
+fn lookup_summaries(ids: &[u64], principal: Principal) -> Result<Vec<Option<AssetSummary>>, AssetError> {
+    check_lookup_size(ids)?;
+    let summaries = asset_summaries();
+    Ok(ids
+        .iter()
+        .map(|asset_id| {
+            summaries
+                .get(asset_id)
+                .filter(|summary| summary.can_view(principal))
+                .map(AssetSummary::from)
+        })
+        .collect())
+}
+
+fn check_lookup_size(ids: &[u64]) -> Result<(), AssetError> {
+    if ids.len() > MAX_IDS_PER_LOOKUP {
+        return Err(AssetError::InvalidInput(format!(
+            "ids: at most {} ids can be looked up per call",
+            MAX_IDS_PER_LOOKUP
+        )));
+    }
+    Ok(())
+}
+
+fn lookup_assets(ids: &[u64], principal: Principal) -> Result<Vec<Option<Asset>>, AssetError> {
+    check_lookup_size(ids)?;
+
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        Ok(ids
+            .iter()
+            .map(|asset_id| assets.get(asset_id).filter(|asset| can_view(asset, principal)))
+            .collect())
+    })
+}
+
+// Summary variants of the listing queries for grid views. They read the
+// asset store through asset_summaries, so only the summarized fields are
+// decoded. Summaries are a fraction of an asset's size, so the unpaged ones
+// still return everything instead of being held to RESPONSE_SIZE_BUDGET.
+#[query]
+fn get_all_assets_summary() -> Vec<AssetSummary> {
+    asset_summaries()
+        .values()
+        .filter(StoredAssetSummary::is_listed_publicly)
+        .map(AssetSummary::from)
+        .collect()
+}
+
+#[query]
+fn get_assets_summary_paginated(offset: u64, limit: u64) -> AssetSummaryPage {
+    let summaries = asset_summaries();
+    let (page, total_count) = page_of(summaries.values().filter(StoredAssetSummary::is_listed_publicly), offset, limit);
+    AssetSummaryPage {
+        assets: page.into_iter().map(AssetSummary::from).collect(),
+        total_count,
+    }
+}
+
+#[query]
+fn get_assets_for_sale_summary(offset: u64, limit: u64) -> AssetSummaryPage {
+    assets_for_sale_summary(offset, limit, time())
+}
+
+fn assets_for_sale_summary(offset: u64, limit: u64, current_time: u64) -> AssetSummaryPage {
+    let (asset_ids, total_count) = for_sale_page(offset, limit, current_time);
+    let summaries = asset_summaries();
+    AssetSummaryPage {
+        assets: asset_ids.iter().filter_map(|asset_id| summaries.get(asset_id)).map(AssetSummary::from).collect(),
+        total_count,
+    }
+}
+
+#[query]
+fn get_user_assets_summary(owner: Principal) -> Vec<AssetSummary> {
+    user_assets_summary(owner, caller())
+}
+
+fn user_assets_summary(owner: Principal, principal: Principal) -> Vec<AssetSummary> {
+    let summaries = asset_summaries();
+    owned_asset_ids(owner)
+        .into_iter()
+        .filter_map(|asset_id| summaries.get(&asset_id))
+        .filter(|summary| summary.is_visible_to(principal))
+        .map(AssetSummary::from)
+        .collect()
+}
+
+#[query]
+fn search_assets_summary(query: String) -> Vec<AssetSummary> {
+    match text_search_ids(&query) {
+        Some(asset_ids) => {
+            let summaries = asset_summaries();
+            asset_ids
+                .iter()
+                .filter_map(|asset_id| summaries.get(asset_id))
+                .filter(StoredAssetSummary::is_listed_publicly)
+                .map(AssetSummary::from)
+                .collect()
+        },
+        None => get_all_assets_summary(),
+    }
+}
+
+// The unpaged listings resume from `cursor` (an asset id, inclusive) when
+// an earlier response came back truncated
+#[query]
+fn get_user_assets(owner: Principal, cursor: Option<u64>) -> AssetChunk {
+    user_assets(owner, caller(), cursor, RESPONSE_SIZE_BUDGET)
+}
+
+fn user_assets(owner: Principal, principal: Principal, cursor: Option<u64>, budget: usize) -> AssetChunk {
+    let start = cursor.unwrap_or(0);
+    let assets = owned_asset_ids(owner)
+        .into_iter()
+        .filter(|asset_id| *asset_id >= start)
+        .filter_map(asset_by_id)
+        .filter(|asset| is_visible_to(asset, principal));
+    take_within_budget(assets, budget)
+}
+
+#[query]
+fn get_all_assets(cursor: Option<u64>) -> AssetChunk {
+    listed_assets_matching(cursor, RESPONSE_SIZE_BUDGET, |_| true)
+}
+
+// Publicly listed assets from `cursor` on, in id order
+fn listed_assets_matching<F>(cursor: Option<u64>, budget: usize, filter: F) -> AssetChunk
+where
+    F: Fn(&Asset) -> bool,
+{
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        let matching = assets
+            .range(cursor.unwrap_or(0)..)
+            .map(|(_, asset)| asset)
+            .filter(|asset| is_listed_publicly(asset) && filter(asset));
+        take_within_budget(matching, budget)
+    })
+}
+
+#[query]
+fn get_assets_for_sale(offset: u64, limit: u64) -> AssetPage {
+    assets_for_sale(offset, limit, time())
+}
+
+// Pages over ids only; listing windows are checked by id and just the assets
+// inside the page are decoded
+fn assets_for_sale(offset: u64, limit: u64, current_time: u64) -> AssetPage {
+    let (asset_ids, total_count) = for_sale_page(offset, limit, current_time);
+    let page = asset_ids.into_iter().filter_map(asset_by_id).collect();
+    budgeted_page(page, total_count, offset)
+}
+
+// Ids of one page of publicly listed assets that are for sale right now, and
+// how many there are in all
+fn for_sale_page(offset: u64, limit: u64, current_time: u64) -> (Vec<u64>, u64) {
+    PUBLIC_FOR_SALE_INDEX.with(|index| {
+        let index = index.borrow();
+        let asset_ids = index
+            .keys()
+            .filter(|asset_id| listing_window_of(*asset_id).is_none_or(|window| window.contains(current_time)));
+        page_of(asset_ids, offset, limit)
+    })
+}
+
+#[query]
+fn get_assets_paginated(offset: u64, limit: u64) -> AssetPage {
+    paginate_assets(offset, limit, is_listed_publicly)
+}
+
+#[query]
+fn get_user_assets_paginated(owner: Principal, offset: u64, limit: u64) -> AssetPage {
+    let principal = caller();
+    let visible = owned_asset_ids(owner)
+        .into_iter()
+        .filter_map(asset_by_id)
+        .filter(|asset| is_visible_to(asset, principal));
+    let (assets, total_count) = page_of(visible, offset, limit);
+
+    AssetPage {
+        assets,
+        total_count,
+        truncated: false,
+        next_offset: None,
+    }
+}
+
+// Cursor-paged counterparts of the listings above, resuming after the asset
+// id encoded in `cursor`. A None cursor starts from the beginning.
+#[query]
+fn get_assets_after(cursor: Option<String>, limit: u64) -> Result<AssetCursorPageV1Response, AssetError> {
+    Ok(assets_after(cursor, limit)?.into())
+}
+
+#[query]
+fn get_user_assets_after(owner: Principal, cursor: Option<String>, limit: u64) -> Result<AssetCursorPageV1Response, AssetError> {
+    Ok(user_assets_after(owner, caller(), cursor, limit)?.into())
+}
+
+#[query]
+fn get_assets_for_sale_after(cursor: Option<String>, limit: u64) -> Result<AssetCursorPageV1Response, AssetError> {
+    Ok(assets_for_sale_after(cursor, limit, time())?.into())
+}
+
+fn assets_for_sale_after(cursor: Option<String>, limit: u64, current_time: u64) -> Result<AssetCursorPage, AssetError> {
+    let start = cursor_start(cursor, ASSET_CURSOR_TAG)?;
+    let asset_ids: Vec<u64> = FOR_SALE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(asset_id, _)| asset_id)
+            .filter(|asset_id| listing_window_of(*asset_id).is_none_or(|window| window.contains(current_time)))
+            .collect()
+    });
+    Ok(asset_ids_after(asset_ids, limit, is_listed_publicly))
+}
+
+fn assets_after(cursor: Option<String>, limit: u64) -> Result<AssetCursorPage, AssetError> {
+    let start = cursor_start(cursor, ASSET_CURSOR_TAG)?;
+
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        let matching = assets
+            .range((start, Bound::Unbounded))
+            .filter(|(_, asset)| is_listed_publicly(asset));
+        let (assets, next_cursor) = page_after(matching, limit, ASSET_CURSOR_TAG);
+        Ok(AssetCursorPage { assets, next_cursor })
+    })
+}
+
+fn user_assets_after(
+    owner: Principal,
+    principal: Principal,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<AssetCursorPage, AssetError> {
+    let start = match cursor_start(cursor, ASSET_CURSOR_TAG)? {
+        Bound::Excluded(asset_id) => Bound::Excluded((owner, asset_id)),
+        _ => Bound::Included((owner, 0)),
+    };
+
+    let asset_ids: Vec<u64> = OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((start, Bound::Included((owner, u64::MAX))))
+            .map(|((_, asset_id), _)| asset_id)
+            .collect()
+    });
+    Ok(asset_ids_after(asset_ids, limit, |asset| is_visible_to(asset, principal)))
+}
+
+// Pages index entries that are already past the cursor, decoding assets only
+// until the page is full
+fn asset_ids_after<F>(asset_ids: Vec<u64>, limit: u64, filter: F) -> AssetCursorPage
+where
+    F: Fn(&Asset) -> bool,
+{
+    let matching = asset_ids
+        .into_iter()
+        .filter_map(asset_by_id)
+        .filter(|asset| filter(asset))
+        .map(|asset| (asset.id, asset));
+    let (assets, next_cursor) = page_after(matching, limit, ASSET_CURSOR_TAG);
+    AssetCursorPage { assets, next_cursor }
+}
+
+#[query]
+fn get_assets_for_sale_paginated(offset: u64, limit: u64) -> AssetPage {
+    get_assets_for_sale(offset, limit)
+}
+
+#[query(composite = true)]
+fn get_assets_filtered(filter: AssetFilter, sort: SortBy, offset: u64, limit: u64) -> AssetPage {
+    // Map order is already oldest first, so no buffering is needed
+    if let SortBy::Oldest = sort {
+        return paginate_assets(offset, limit, |asset| filter.matches(asset));
+    }
+
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let keep = offset.saturating_add(limit) as usize;
+
+    // Ties fall back to the asset id so pages stay stable between calls
+    let (asset_ids, total_count) = match sort {
+        // Assets without a price in the compared token go last either way
+        SortBy::PriceAsc => first_matches_by(&filter, keep, |asset| {
+            let price = filter.price_of(asset);
+            (price.is_none(), price, asset.id)
+        }),
+        SortBy::PriceDesc => first_matches_by(&filter, keep, |asset| (Reverse(filter.price_of(asset)), asset.id)),
+        SortBy::Newest => first_matches_by(&filter, keep, |asset| Reverse((asset.created_at, asset.id))),
+        SortBy::Oldest => first_matches_by(&filter, keep, |asset| asset.id),
+        SortBy::NameAsc => first_matches_by(&filter, keep, |asset| (asset.name.to_lowercase(), asset.id)),
+    };
+
+    AssetPage {
+        assets: asset_ids.into_iter().skip(offset as usize).filter_map(asset_by_id).collect(),
+        total_count,
+        truncated: false,
+        next_offset: None,
+    }
+}
+
+// Ids of the first `keep` matches in `key` order, and how many match in all.
+// Only the sort keys of the current leaders are held while scanning, never
+// every matching asset.
+fn first_matches_by<K: Ord>(filter: &AssetFilter, keep: usize, key: impl Fn(&Asset) -> K) -> (Vec<u64>, u64) {
+    let mut leaders: BinaryHeap<(K, u64)> = BinaryHeap::new();
+    let mut total_count = 0;
+    ASSETS.with(|assets| {
+        for asset in assets.borrow().values().filter(|asset| filter.matches(asset)) {
+            total_count += 1;
+            if keep == 0 {
+                continue;
+            }
+            let entry = (key(&asset), asset.id);
+            if leaders.len() < keep {
+                leaders.push(entry);
+            } else if leaders.peek().is_some_and(|last| entry < *last) {
+                leaders.pop();
+                leaders.push(entry);
+            }
+        }
+    });
+    (leaders.into_sorted_vec().into_iter().map(|(_, asset_id)| asset_id).collect(), total_count)
+}
+
+fn paginate_assets<F>(offset: u64, limit: u64, filter: F) -> AssetPage
+where
+    F: Fn(&Asset) -> bool,
+{
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        let matching = assets.values().filter(|asset| filter(asset));
+        let (page, total_count) = page_of(matching, offset, limit);
+
+        AssetPage {
+            assets: page,
+            total_count,
+            truncated: false,
+            next_offset: None,
+        }
+    })
+}
+
+// Counts every item but keeps only those inside the page. Items outside the
+// page are dropped as soon as they are counted instead of being collected and
+// then skipped over. Given ids, only the page is decoded afterwards.
+fn page_of<T>(items: impl Iterator<Item = T>, offset: u64, limit: u64) -> (Vec<T>, u64) {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let mut page = Vec::new();
+    let mut total_count = 0;
+
+    for item in items {
+        if total_count >= offset && (page.len() as u64) < limit {
+            page.push(item);
+        }
+        total_count += 1;
+    }
+    (page, total_count)
+}
+
+#[update]
+fn update_asset_price(asset_id: u64, new_price: u64) -> Result<Asset, AssetError> {
+    ensure_running("update_asset_price")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    set_asset_price(asset_id, caller(), new_price, time())
+}
+
+// Sets the ICP price, keeping the prices in other tokens
+fn set_asset_price(asset_id: u64, principal: Principal, new_price: u64, current_time: u64) -> Result<Asset, AssetError> {
+    reprice_asset(asset_id, principal, current_time, |asset| {
+        validate_icp_price_for(asset, new_price)?;
+        set_icp_price(asset, new_price);
+        Ok(())
+    })
+}
+
+// A listed asset must keep a price it can be bought at
+fn validate_icp_price_for(asset: &Asset, price: u64) -> Result<u64, AssetError> {
+    if asset.is_for_sale {
+        validate_listing_price(price)
+    } else {
+        validate_price(price)
+    }
+}
+
+// Ownership and lock checks shared by the price setters. The ICP price
+// history and alerts follow whatever the change does to the ICP price.
+fn reprice_asset(
+    asset_id: u64,
+    principal: Principal,
+    current_time: u64,
+    reprice: impl FnOnce(&mut Asset) -> Result<(), AssetError>,
+) -> Result<Asset, AssetError> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or(AssetError::NotFound)?;
+        if asset.owner != principal {
+            return Err(AssetError::NotOwner);
+        }
+        if is_transfer_in_progress(asset_id) {
+            return Err(AssetError::TransferInProgress);
+        }
+        check_not_frozen(&asset)?;
+
+        let before = asset.clone();
+        reprice(&mut asset)?;
+        record_price_change(&before, icp_price(&asset), principal, current_time);
+        asset.updated_at = current_time;
+        // The JSON document shows the price
+        certify_asset(&asset);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })
+}
+
+// Replaces every price of the asset. An entry for the ICP ledger sets the ICP
+// price; without one the asset can no longer be bought in ICP until an ICP
+// price is set again. Every other entry must be for a supported ledger.
+#[update]
+fn set_asset_prices(asset_id: u64, prices: Vec<PriceOption>) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_prices")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    set_asset_prices_as(asset_id, caller(), prices, time())
+}
+
+fn set_asset_prices_as(
+    asset_id: u64,
+    principal: Principal,
+    prices: Vec<PriceOption>,
+    current_time: u64,
+) -> Result<Asset, AssetError> {
+    if prices.is_empty() {
+        return Err(AssetError::InvalidInput("prices: at least one price is required".to_string()));
+    }
+    for (index, option) in prices.iter().enumerate() {
+        if prices[..index].iter().any(|earlier| earlier.ledger == option.ledger) {
+            return Err(AssetError::InvalidInput("prices: each ledger may appear only once".to_string()));
+        }
+        if is_icp_ledger(option.ledger) {
+            continue;
+        } else if !is_supported_ledger(option.ledger) {
+            return Err(AssetError::UnsupportedLedger);
+        } else if option.amount == 0 {
+            return Err(AssetError::InvalidInput("prices: amounts must be greater than zero".to_string()));
+        }
+    }
+
+    reprice_asset(asset_id, principal, current_time, |asset| {
+        if let Some(option) = prices.iter().find(|option| is_icp_ledger(option.ledger)) {
+            validate_icp_price_for(asset, option.amount)?;
+        }
+        asset.prices = prices;
+        Ok(())
+    })
+}
+
+// Oldest first, up to the last MAX_PRICE_HISTORY changes
+#[query]
+fn get_price_history(asset_id: u64) -> Vec<PricePoint> {
+    match asset_by_id(asset_id) {
+        Some(asset) if can_view(&asset, caller()) => price_history_of(asset_id),
+        _ => Vec::new(),
+    }
+}
+
+// Called with the asset as it was before the change and its new ICP price.
+// Favorite holders hear about drops on listed assets, including an ICP price
+// set again after the asset could not be bought in ICP.
+fn record_price_change(asset: &Asset, new_price: Option<u64>, changed_by: Principal, current_time: u64) {
+    let old_price = icp_price(asset);
+    if new_price == old_price {
+        return;
+    }
+    record_activity(
+        asset.id,
+        Some(changed_by),
+        ActivityKind::PriceChanged { old_price, new_price },
+        current_time,
+    );
+    let Some(new_price) = new_price else {
+        return;
+    };
+    if asset.is_for_sale && old_price.is_none_or(|old_price| new_price < old_price) {
+        notify_favoriters(asset.id, new_price, current_time);
+        trigger_price_alerts(asset.id, new_price, current_time);
+    }
+    record_price_point(asset.id, new_price, changed_by, current_time);
+}
+
+// Starts the price history and announces the asset in the activity feed
+fn record_creation_price(asset: &Asset, owner: Principal, current_time: u64) {
+    let price = icp_price(asset);
+    if let Some(price) = price {
+        record_price_point(asset.id, price, owner, current_time);
+    }
+    record_activity(
+        asset.id,
+        Some(owner),
+        ActivityKind::AssetCreated { owner, price, is_for_sale: asset.is_for_sale },
+        current_time,
+    );
+}
+
+fn record_price_point(asset_id: u64, price: u64, changed_by: Principal, current_time: u64) {
+    let point = PricePoint {
+        price,
+        timestamp: current_time,
+        changed_by,
+    };
+
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        history.insert((asset_id, get_next_price_point_id()), point);
+
+        let keys: Vec<(u64, u64)> = history
+            .range((asset_id, 0)..=(asset_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let excess = keys.len().saturating_sub(MAX_PRICE_HISTORY);
+        for key in keys.into_iter().take(excess) {
+            history.remove(&key);
+        }
+    });
+}
+
+fn price_history_of(asset_id: u64) -> Vec<PricePoint> {
+    PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((asset_id, 0)..=(asset_id, u64::MAX))
+            .map(|(_, point)| point)
+            .collect()
+    })
+}
+
+fn price_summary_of(asset_id: u64) -> Option<PriceSummary> {
+    let prices: Vec<u64> = price_history_of(asset_id).iter().map(|point| point.price).collect();
+    Some(PriceSummary {
+        lowest: *prices.iter().min()?,
+        highest: *prices.iter().max()?,
+        previous: prices.len().checked_sub(2).map(|index| prices[index]),
+    })
+}
+
+fn remove_price_history(asset_id: u64) {
+    PRICE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u64)> = history
+            .range((asset_id, 0)..=(asset_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+#[update]
+fn set_asset_for_sale(asset_id: u64, for_sale: bool) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_for_sale")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    list_asset(asset_id, caller(), for_sale, time())
+}
+
+// Bulk edits for large catalogs. Each id succeeds or fails on its own, so an
+// asset the caller does not own only fails its own entry; the results are
+// returned in input order.
+#[update]
+fn update_prices_bulk(updates: Vec<(u64, u64)>) -> Vec<Result<Asset, AssetError>> {
+    if let Err(err) = ensure_running("update_prices_bulk") {
+        return updates.iter().map(|_| Err(err.clone())).collect();
+    }
+    if let Err(err) = enforce_rate_limit(RateLimitedAction::MetadataUpdate) {
+        return updates.iter().map(|_| Err(err.clone())).collect();
+    }
+    set_prices_bulk(updates, caller(), time())
+}
+
+#[update]
+fn set_for_sale_bulk(asset_ids: Vec<u64>, for_sale: bool) -> Vec<Result<Asset, AssetError>> {
+    if let Err(err) = ensure_running("set_for_sale_bulk") {
+        return asset_ids.iter().map(|_| Err(err.clone())).collect();
+    }
+    if let Err(err) = enforce_rate_limit(RateLimitedAction::MetadataUpdate) {
+        return asset_ids.iter().map(|_| Err(err.clone())).collect();
+    }
+    list_assets_bulk(asset_ids, caller(), for_sale, time())
+}
+
+fn set_prices_bulk(updates: Vec<(u64, u64)>, principal: Principal, current_time: u64) -> Vec<Result<Asset, AssetError>> {
+    if let Err(err) = validate_bulk_size(updates.len()) {
+        return updates.iter().map(|_| Err(err.clone())).collect();
+    }
+    updates
+        .into_iter()
+        .map(|(asset_id, price)| set_asset_price(asset_id, principal, price, current_time))
+        .collect()
+}
+
+fn list_assets_bulk(
+    asset_ids: Vec<u64>,
+    principal: Principal,
+    for_sale: bool,
+    current_time: u64,
+) -> Vec<Result<Asset, AssetError>> {
+    if let Err(err) = validate_bulk_size(asset_ids.len()) {
+        return asset_ids.iter().map(|_| Err(err.clone())).collect();
+    }
+    asset_ids
+        .into_iter()
+        .map(|asset_id| list_asset(asset_id, principal, for_sale, current_time))
+        .collect()
+}
+
+fn validate_bulk_size(len: usize) -> Result<(), AssetError> {
+    if len > MAX_BULK_UPDATE_SIZE {
+        return Err(AssetError::InvalidInput(format!(
+            "batch: at most {} assets can be updated per call",
+            MAX_BULK_UPDATE_SIZE
+        )));
+    }
+    Ok(())
+}
+
+fn list_asset(asset_id: u64, principal: Principal, for_sale: bool, current_time: u64) -> Result<Asset, AssetError> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+
+                if for_sale && asset.hidden.is_some() {
+                    return Err(AssetError::AssetHidden);
+                }
+                if for_sale && asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                if for_sale {
+                    // Only the ICP price has a cap; without one the asset
+                    // must still be buyable in some other token
+                    match icp_price(&asset) {
+                        Some(price) => {
+                            validate_listing_price(price)?;
+                        },
+                        None if !asset.prices.iter().any(|option| price_in(&asset, option.ledger).is_some()) => {
+                            return Err(AssetError::InvalidPrice { max: get_max_price() });
+                        },
+                        None => {},
+                    }
+                    ensure_not_bundled(asset_id)?;
+                }
+                
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                asset.is_for_sale = for_sale;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, Some(principal), current_time);
+                clear_listing_window(asset_id);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+#[update]
+fn set_asset_visibility(asset_id: u64, visibility: Visibility) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_visibility")?;
+    let principal = caller();
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+
+                unindex_asset(&asset);
+                asset.visibility = visibility;
+                asset.updated_at = time();
+                index_asset(&asset);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+// Lists an asset at `price`, optionally only between starts_at and expires_at.
+// Listing an already listed asset replaces its price and window.
+#[update]
+fn set_asset_for_sale_v2(
+    asset_id: u64,
+    price: u64,
+    starts_at: Option<u64>,
+    expires_at: Option<u64>,
+) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_for_sale_v2")?;
+    let principal = caller();
+    let current_time = time();
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+
+    let price = validate_listing_price(price)?;
+    if let (Some(starts_at), Some(expires_at)) = (starts_at, expires_at) {
+        if expires_at <= starts_at {
+            return Err(AssetError::InvalidInput("expires_at: must be after starts_at".to_string()));
+        }
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= current_time) {
+        return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+    }
+    // A start time in the past simply means the listing is active right away
+    let starts_at = starts_at.filter(|starts_at| *starts_at > current_time);
+
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                if asset.hidden.is_some() {
+                    return Err(AssetError::AssetHidden);
+                }
+                if asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                ensure_not_bundled(asset_id)?;
+
+                record_price_change(&asset, Some(price), principal, current_time);
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                set_icp_price(&mut asset, price);
+                asset.is_for_sale = true;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, Some(principal), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    clear_listing_window(asset_id);
+    if starts_at.is_some() || expires_at.is_some() {
+        LISTING_WINDOWS.with(|windows| {
+            windows.borrow_mut().insert(asset_id, ListingWindow {
+                asset_id,
+                starts_at,
+                expires_at,
+                expired_at: None,
+            });
+        });
+    }
+
+    Ok(asset)
+}
+
+#[query]
+fn get_listing_window(asset_id: u64) -> Option<ListingWindow> {
+    listing_window_of(asset_id)
+}
+
+fn listing_window_of(asset_id: u64) -> Option<ListingWindow> {
+    LISTING_WINDOWS.with(|windows| windows.borrow().get(&asset_id))
+}
+
+fn clear_listing_window(asset_id: u64) {
+    LISTING_WINDOWS.with(|windows| {
+        windows.borrow_mut().remove(&asset_id);
+    });
+}
+
+// Listed, and within its sale window if it has one. Expired listings are only
+// taken down periodically, so reads must not rely on is_for_sale alone.
+fn is_on_sale(asset: &Asset, current_time: u64) -> bool {
+    asset.is_for_sale && listing_window_of(asset.id).is_none_or(|window| window.contains(current_time))
+}
+
+fn schedule_listing_expiry() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(5 * 60), expire_listings);
+}
+
+fn expire_listings() {
+    let current_time = time();
+
+    let expired: Vec<ListingWindow> = LISTING_WINDOWS.with(|windows| {
+        windows
+            .borrow()
+            .iter()
+            .map(|(_, window)| window)
+            .filter(|window| window.expired_at.is_none() && window.expires_at.is_some_and(|expires_at| current_time >= expires_at))
+            .collect()
+    });
+
+    for mut window in expired {
+        // Never pull an asset out from under an in-flight purchase; the next run
+        // retries it
+        if is_transfer_in_progress(window.asset_id) {
+            continue;
+        }
+
+        ASSETS.with(|assets| {
+            let mut assets = assets.borrow_mut();
+            if let Some(mut asset) = assets.get(&window.asset_id) {
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                asset.is_for_sale = false;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, None, current_time);
+                assets.insert(asset.id, asset);
+            }
+        });
+
+        // The window is kept as a record of the expiry until the asset is relisted
+        window.expired_at = Some(current_time);
+        LISTING_WINDOWS.with(|windows| {
+            windows.borrow_mut().insert(window.asset_id, window);
+        });
+    }
+}
+
+#[update]
+fn update_asset_metadata(asset_id: u64, request: UpdateAssetRequest) -> Result<Asset, AssetError> {
+    ensure_running("update_asset_metadata")?;
+    let principal = caller();
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+
+    if let Some(preview_hash) = preview_file_hash(&request.preview_image_url) {
+        let already_used = asset_by_id(asset_id).is_some_and(|asset| file_refs_of(&asset).contains(&preview_hash));
+        if !already_used {
+            ensure_may_reference(&preview_hash, principal)?;
+        }
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+
+                // Buyers see the listed metadata; it must not change under a pending purchase
+                if asset.is_for_sale {
+                    return Err(AssetError::ListedForSale);
+                }
+
+                let previous = asset.clone();
+
+                apply_asset_update(&mut asset, request)?;
+                if asset.preview_image_url != previous.preview_image_url {
+                    for file_hash in file_refs_of(&asset) {
+                        add_file_ref(&file_hash, asset_id);
+                    }
+                    for file_hash in file_refs_of(&previous) {
+                        release_file_ref(&file_hash, asset_id);
+                    }
+                }
+
+                let current_time = time();
+                asset.updated_at = current_time;
+                if asset.name != previous.name {
+                    assign_slug(&mut asset, current_time);
+                }
+                unindex_asset(&previous);
+                index_asset(&asset);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+// Validates and applies the fields set in `request`. File references are
+// left to the caller.
+fn apply_asset_update(asset: &mut Asset, request: UpdateAssetRequest) -> Result<(), AssetError> {
+    if let Some(name) = request.name {
+        asset.name = validate_name(&name)?;
+    }
+    if let Some(description) = request.description {
+        asset.description = validate_description(&description)?;
+    }
+    if let Some(category_id) = request.category_id {
+        if category_id != asset.category_id {
+            let category = active_category(category_id)?;
+            asset.category_id = category.id;
+            asset.category = category.name;
+        }
+    }
+    if let Some(tags) = request.tags {
+        asset.tags = validate_tags(&tags)?;
+    }
+    if let Some(preview_image_url) = request.preview_image_url {
+        asset.preview_image_url = Some(preview_image_url);
+    }
+    Ok(())
+}
+
+// Starts a new asset from one the caller owns, for uploading variants of a
+// model without re-entering its details. The clone shares the source's files
+// and keeps its creator and royalty; it starts unlisted and not for sale.
+#[update]
+fn clone_asset(asset_id: u64, overrides: UpdateAssetRequest) -> Result<Asset, AssetError> {
+    ensure_running("clone_asset")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::Upload)?;
+
+    clone_owned_asset(asset_id, overrides, principal, time())
+}
+
+fn clone_owned_asset(
+    asset_id: u64,
+    overrides: UpdateAssetRequest,
+    principal: Principal,
+    current_time: u64,
+) -> Result<Asset, AssetError> {
+    let source = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if source.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    // A clone would otherwise bring moderated content back into view
+    if source.hidden.is_some() {
+        return Err(AssetError::AssetHidden);
+    }
+
+    let mut asset = Asset {
+        is_for_sale: false,
+        created_at: current_time,
+        updated_at: current_time,
+        hidden: None,
+        visibility: Visibility::Unlisted,
+        archived_at: None,
+        slug: None,
+        reservation: None,
+        source: None,
+        ..source
+    };
+    apply_asset_update(&mut asset, overrides)?;
+    // A buyer owns the source but never supplied its files
+    for file_hash in file_refs_of(&asset) {
+        ensure_may_reference(&file_hash, principal)?;
+    }
+    asset.id = get_next_asset_id();
+    assign_slug(&mut asset, current_time);
+
+    for file_hash in file_refs_of(&asset) {
+        add_file_ref(&file_hash, asset.id);
+    }
+    index_asset(&asset);
+    record_ownership_event(
+        asset.id,
+        None,
+        principal,
+        OwnershipEventKind::DerivedFrom { source_asset_id: asset_id },
+        None,
+        current_time,
+    );
+    record_creation_price(&asset, principal, current_time);
+
+    ASSETS.with(|assets| {
+        assets.borrow_mut().insert(asset.id, asset.clone());
+    });
+
+    Ok(asset)
+}
+
+// Structured metadata, one field at a time. Setting an existing key replaces
+// its value.
+#[update]
+fn set_asset_metadata_field(asset_id: u64, key: String, value: MetadataValue) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_metadata_field")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    set_metadata_field(asset_id, caller(), &key, value, time())
+}
+
+#[update]
+fn remove_asset_metadata_field(asset_id: u64, key: String) -> Result<Asset, AssetError> {
+    ensure_running("remove_asset_metadata_field")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    remove_metadata_field(asset_id, caller(), &key, time())
+}
+
+fn set_metadata_field(
+    asset_id: u64,
+    principal: Principal,
+    key: &str,
+    value: MetadataValue,
+    current_time: u64,
+) -> Result<Asset, AssetError> {
+    let key = validate_metadata_key(key)?;
+    let value = validate_metadata_value(value)?;
+
+    edit_owned_asset(asset_id, principal, current_time, |asset| {
+        match asset.metadata.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, existing)) => *existing = value,
+            None => {
+                if asset.metadata.len() >= MAX_METADATA_FIELDS {
+                    return Err(AssetError::InvalidInput(format!(
+                        "metadata: at most {} fields are allowed",
+                        MAX_METADATA_FIELDS
+                    )));
+                }
+                asset.metadata.push((key, value));
+            },
+        }
+        Ok(())
+    })
+}
+
+fn remove_metadata_field(asset_id: u64, principal: Principal, key: &str, current_time: u64) -> Result<Asset, AssetError> {
+    let key = key.trim();
+    edit_owned_asset(asset_id, principal, current_time, |asset| {
+        let count = asset.metadata.len();
+        asset.metadata.retain(|(existing, _)| existing != key);
+        if asset.metadata.len() == count {
+            return Err(AssetError::InvalidInput(format!("metadata: no field named {}", key)));
+        }
+        Ok(())
+    })
+}
+
+// Applies an owner's edit to an asset that is not locked by a transfer
+fn edit_owned_asset(
+    asset_id: u64,
+    principal: Principal,
+    current_time: u64,
+    edit: impl FnOnce(&mut Asset) -> Result<(), AssetError>,
+) -> Result<Asset, AssetError> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+
+                let before = asset.clone();
+                edit(&mut asset)?;
+                asset.updated_at = current_time;
+                // Indexed and certified from the final record, so the served
+                // document matches its certified hash
+                unindex_asset(&before);
+                index_asset(&asset);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+// Until the asset first sells the owner may pick any license. After that
+// buyers hold the license they paid for, so it can only be relaxed.
+#[update]
+fn set_asset_license(asset_id: u64, license: License) -> Result<Asset, AssetError> {
+    ensure_running("set_asset_license")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    change_license(asset_id, caller(), license, time())
+}
+
+fn change_license(asset_id: u64, principal: Principal, license: License, current_time: u64) -> Result<Asset, AssetError> {
+    let license = validate_license(license)?;
+    let sold = has_sales(asset_id);
+    edit_owned_asset(asset_id, principal, current_time, |asset| {
+        if sold && !asset.license.is_broadened_by(&license) {
+            return Err(AssetError::InvalidInput(
+                "license: an asset that has sold can only move to a broader license".to_string(),
+            ));
+        }
+        asset.license = license;
+        Ok(())
+    })
+}
+
+// Sales in any token count, not only the ones tallied in e8s
+fn has_sales(asset_id: u64) -> bool {
+    ASSET_SALE_INDEX.with(|index| index.borrow().range((asset_id, 0)..=(asset_id, u64::MAX)).next().is_some())
+}
+
+fn metadata_field<'a>(asset: &'a Asset, key: &str) -> Option<&'a MetadataValue> {
+    asset.metadata.iter().find(|(existing, _)| existing == key).map(|(_, value)| value)
+}
+
+fn validate_metadata_key(key: &str) -> Result<String, AssetError> {
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(AssetError::InvalidInput("metadata: field names must not be empty".to_string()));
+    }
+    if key.chars().count() > MAX_METADATA_KEY_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "metadata: field names must be at most {} characters",
+            MAX_METADATA_KEY_LENGTH
+        )));
+    }
+    Ok(key.to_string())
+}
+
+fn validate_metadata_value(value: MetadataValue) -> Result<MetadataValue, AssetError> {
+    if let MetadataValue::Text(text) = &value {
+        if text.len() > MAX_METADATA_VALUE_BYTES {
+            return Err(AssetError::InvalidInput(format!(
+                "metadata: text values must be at most {} bytes",
+                MAX_METADATA_VALUE_BYTES
+            )));
+        }
+    }
+    Ok(value)
+}
+
+#[update]
+fn transfer_asset_ownership(asset_id: u64, new_owner: Principal) -> Result<Asset, AssetError> {
+    ensure_running("transfer_asset_ownership")?;
+    transfer_owned_asset(asset_id, caller(), new_owner)
+}
+
+// Two-phase transfer: the owner names a recipient, who must claim the asset
+// before it changes hands, so a mistyped principal cannot lose it
+#[update]
+fn initiate_transfer(asset_id: u64, to: Principal, expires_at: Option<u64>) -> Result<PendingTransfer, AssetError> {
+    ensure_running("initiate_transfer")?;
+    let principal = caller();
+    let current_time = time();
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    validate_recipient(principal, to)?;
+    if expires_at.is_some_and(|expires_at| expires_at <= current_time) {
+        return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+    }
+    remove_expired_transfer(asset_id, current_time);
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    check_reservation(&asset, to, current_time)?;
+    if asset.archived_at.is_some() {
+        return Err(AssetError::AssetArchived);
+    }
+    if is_taken_down(asset_id) {
+        return Err(AssetError::TakenDown);
+    }
+
+    let transfer = PendingTransfer {
+        asset_id,
+        from: principal,
+        to,
+        created_at: current_time,
+        expires_at,
+    };
+    PENDING_TRANSFERS.with(|transfers| {
+        transfers.borrow_mut().insert(asset_id, transfer.clone());
+    });
+    Ok(transfer)
+}
+
+#[update]
+fn claim_transfer(asset_id: u64) -> Result<Asset, AssetError> {
+    ensure_running("claim_transfer")?;
+    let principal = caller();
+    let current_time = time();
+
+    remove_expired_transfer(asset_id, current_time);
+    let transfer = pending_transfer_of(asset_id).ok_or(AssetError::NoPendingTransfer)?;
+    if transfer.to != principal {
+        return Err(AssetError::Unauthorized);
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or(AssetError::NotFound)?;
+        // Guards against the asset having changed hands some other way
+        if asset.owner != transfer.from {
+            return Err(AssetError::NotOwner);
+        }
+        check_not_frozen(&asset)?;
+
+        PENDING_TRANSFERS.with(|transfers| {
+            transfers.borrow_mut().remove(&asset_id);
+        });
+        change_owner(&mut asset, principal, OwnershipEventKind::Transfer, None, current_time);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })
+}
+
+#[update]
+fn cancel_transfer(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("cancel_transfer")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+
+    PENDING_TRANSFERS
+        .with(|transfers| transfers.borrow_mut().remove(&asset_id))
+        .map(|_| ())
+        .ok_or(AssetError::NoPendingTransfer)
+}
+
+#[query]
+fn get_pending_transfer(asset_id: u64) -> Option<PendingTransfer> {
+    let current_time = time();
+    pending_transfer_of(asset_id).filter(|transfer| !transfer.is_expired(current_time))
+}
+
+fn pending_transfer_of(asset_id: u64) -> Option<PendingTransfer> {
+    PENDING_TRANSFERS.with(|transfers| transfers.borrow().get(&asset_id))
+}
+
+// Expired transfers are only removed when something touches the asset again
+fn remove_expired_transfer(asset_id: u64, now: u64) {
+    if pending_transfer_of(asset_id).is_some_and(|transfer| transfer.is_expired(now)) {
+        PENDING_TRANSFERS.with(|transfers| {
+            transfers.borrow_mut().remove(&asset_id);
+        });
+    }
+}
+
+// Gifts lock the asset until someone redeems the claim code, the sender
+// cancels, or the gift expires
+#[update]
+async fn create_gift(asset_id: u64, message: String, expires_at: Option<u64>) -> Result<CreatedGift, AssetError> {
+    ensure_running("create_gift")?;
+    let principal = caller();
+
+    // Checked before and after fetching randomness, since the asset may change
+    // while the management canister call is in flight
+    check_giftable(asset_id, principal, &message, expires_at, time())?;
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| AssetError::InvalidInput(format!("Randomness unavailable ({:?}): {}", code, message)))?;
+    let claim_code = to_hex(&random_bytes[..GIFT_CODE_BYTES]);
+
+    let gift = open_gift(asset_id, principal, &message, expires_at, &claim_code, time())?;
+    Ok(CreatedGift { gift, claim_code })
+}
+
+#[update]
+fn claim_gift(code: String) -> Result<Asset, AssetError> {
+    ensure_running("claim_gift")?;
+    redeem_gift(&code, caller(), time())
+}
+
+#[update]
+fn cancel_gift(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("cancel_gift")?;
+    let gift = gift_of(asset_id).ok_or(AssetError::GiftNotFound)?;
+    if gift.sender != caller() {
+        return Err(AssetError::NotOwner);
+    }
+    remove_gift(asset_id);
+    Ok(())
+}
+
+#[query]
+fn get_gift(asset_id: u64) -> Option<Gift> {
+    let current_time = time();
+    gift_of(asset_id).filter(|gift| !gift.is_expired(current_time))
+}
+
+fn check_giftable(
+    asset_id: u64,
+    principal: Principal,
+    message: &str,
+    expires_at: Option<u64>,
+    current_time: u64,
+) -> Result<(), AssetError> {
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    if message.chars().count() > MAX_GIFT_MESSAGE_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "message: must be at most {} characters",
+            MAX_GIFT_MESSAGE_LENGTH
+        )));
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= current_time) {
+        return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+    }
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    remove_expired_gift(asset_id, current_time);
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    // Whoever redeems the gift is not known yet, so no hold can be honoured
+    check_reservation(&asset, principal, current_time)?;
+    if asset.archived_at.is_some() {
+        return Err(AssetError::AssetArchived);
+    }
+    if is_taken_down(asset_id) {
+        return Err(AssetError::TakenDown);
+    }
+    Ok(())
+}
+
+fn open_gift(
+    asset_id: u64,
+    principal: Principal,
+    message: &str,
+    expires_at: Option<u64>,
+    claim_code: &str,
+    current_time: u64,
+) -> Result<Gift, AssetError> {
+    check_giftable(asset_id, principal, message, expires_at, current_time)?;
+
+    let gift = Gift {
+        asset_id,
+        sender: principal,
+        message: message.trim().to_string(),
+        created_at: current_time,
+        expires_at,
+    };
+    GIFTS.with(|gifts| gifts.borrow_mut().insert(asset_id, gift.clone()));
+    let code_hash = gift_code_hash(claim_code);
+    GIFT_CODES.with(|codes| codes.borrow_mut().insert(code_hash.clone(), asset_id));
+    GIFT_CODE_HASHES.with(|hashes| hashes.borrow_mut().insert(asset_id, code_hash));
+    Ok(gift)
+}
+
+fn redeem_gift(code: &str, principal: Principal, current_time: u64) -> Result<Asset, AssetError> {
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let asset_id = GIFT_CODES
+        .with(|codes| codes.borrow().get(&gift_code_hash(code)))
+        .ok_or(AssetError::GiftNotFound)?;
+    remove_expired_gift(asset_id, current_time);
+    let gift = gift_of(asset_id).ok_or(AssetError::GiftNotFound)?;
+    if gift.sender == principal {
+        return Err(AssetError::InvalidRecipient);
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or(AssetError::NotFound)?;
+        // Guards against the asset having changed hands some other way
+        if asset.owner != gift.sender {
+            return Err(AssetError::NotOwner);
+        }
+        check_not_frozen(&asset)?;
+
+        remove_gift(asset_id);
+        change_owner(&mut asset, principal, OwnershipEventKind::Gift, None, current_time);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })
+}
+
+fn gift_of(asset_id: u64) -> Option<Gift> {
+    GIFTS.with(|gifts| gifts.borrow().get(&asset_id))
+}
+
+fn gift_code_hash(code: &str) -> String {
+    sha256_hex(code.trim().to_lowercase().as_bytes())
+}
+
+fn remove_gift(asset_id: u64) {
+    GIFTS.with(|gifts| gifts.borrow_mut().remove(&asset_id));
+    if let Some(code_hash) = GIFT_CODE_HASHES.with(|hashes| hashes.borrow_mut().remove(&asset_id)) {
+        GIFT_CODES.with(|codes| codes.borrow_mut().remove(&code_hash));
+    }
+}
+
+fn rebuild_gift_code_hashes() {
+    let entries: Vec<(String, u64)> = GIFT_CODES.with(|codes| codes.borrow().iter().collect());
+    GIFT_CODE_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        for (code_hash, asset_id) in entries {
+            hashes.insert(asset_id, code_hash);
+        }
+    });
+}
+
+// Like pending transfers, expired gifts are only removed when something
+// touches the asset again; until then they no longer lock it
+fn remove_expired_gift(asset_id: u64, now: u64) {
+    if gift_of(asset_id).is_some_and(|gift| gift.is_expired(now)) {
+        remove_gift(asset_id);
+    }
+}
+
+// Ownership rules shared by transfer_asset_ownership and icrc7_transfer
+fn transfer_owned_asset(asset_id: u64, principal: Principal, new_owner: Principal) -> Result<Asset, AssetError> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                if asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                if is_taken_down(asset_id) {
+                    return Err(AssetError::TakenDown);
+                }
+                validate_recipient(principal, new_owner)?;
+                let current_time = time();
+                check_reservation(&asset, new_owner, current_time)?;
+                
+                change_owner(&mut asset, new_owner, OwnershipEventKind::Transfer, None, current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+// Every ownership change goes through here so that sale status and
+// outstanding approvals never carry over to the new owner, and so that the
+// provenance log is written in the same call as the change itself
+fn change_owner(
+    asset: &mut Asset,
+    new_owner: Principal,
+    kind: OwnershipEventKind,
+    price: Option<u64>,
+    current_time: u64,
+) {
+    match kind {
+        OwnershipEventKind::Transfer | OwnershipEventKind::Gift => {
+            notify(new_owner, NotificationKind::AssetTransferredToYou, asset.id, Some(asset.owner), None, current_time);
+        },
+        // The seller accepted the offer themselves, so only the bidder hears about it
+        OwnershipEventKind::OfferAccepted => {
+            notify(new_owner, NotificationKind::OfferAccepted, asset.id, Some(asset.owner), price, current_time);
+        },
+        _ if price.is_some() => {
+            notify(asset.owner, NotificationKind::AssetSold, asset.id, Some(new_owner), price, current_time);
+        },
+        _ => {},
+    }
+    record_ownership_event(asset.id, Some(asset.owner), new_owner, kind.clone(), price, current_time);
+    record_activity(
+        asset.id,
+        None,
+        ActivityKind::Transferred { from: asset.owner, to: new_owner, kind, price },
+        current_time,
+    );
+    remove_from_collections(asset.id, Some(asset.owner));
+    let was_for_sale = asset.is_for_sale;
+    unindex_asset(asset);
+    asset.owner = new_owner;
+    asset.is_for_sale = false; // Remove from sale after transfer
+    if let Some(reservation) = asset.reservation.take() {
+        end_reservation_hold(reservation.holder, asset.id, current_time);
+    }
+    clear_listing_window(asset.id);
+    asset.updated_at = current_time;
+    index_asset(asset);
+    record_listing_activity(was_for_sale, asset, None, current_time);
+    clear_approval(asset.id);
+    invalidate_offers(asset.id, current_time);
+    invalidate_bundle_of(asset.id, current_time);
+    // Watchers were waiting on the old owner's listing
+    remove_price_alerts(asset.id);
+    // Rentals already paid for stay valid; the old owner's terms do not carry over
+    RENTAL_LISTINGS.with(|listings| listings.borrow_mut().remove(&asset.id));
+}
+
+// Account migration, for users rotating to a new Internet Identity anchor.
+// Each call moves up to MIGRATION_BATCH_SIZE of the caller's assets, clearing
+// listings, approvals and offered transfers or gifts as any change of owner
+// does. Assets mid-sale, frozen, taken down or held for another buyer stay
+// behind. Once no assets are left, the profile, favorites and notifications
+// follow, again in bounded batches.
+#[update]
+fn transfer_all_assets(to: Principal, cursor: Option<u64>, dry_run: bool) -> Result<AccountMigration, AssetError> {
+    ensure_running("transfer_all_assets")?;
+    migrate_account(caller(), to, cursor, dry_run, time())
+}
+
+fn migrate_account(
+    principal: Principal,
+    to: Principal,
+    cursor: Option<u64>,
+    dry_run: bool,
+    current_time: u64,
+) -> Result<AccountMigration, AssetError> {
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let to = validate_recipient(principal, to)?;
+
+    let owned_after = |cursor: Option<u64>| {
+        let start = cursor.map_or(0, |asset_id| asset_id.saturating_add(1));
+        OWNER_INDEX.with(|index| {
+            index
+                .borrow()
+                .range((principal, start)..=(principal, u64::MAX))
+                .map(|((_, asset_id), _)| asset_id)
+                .collect::<Vec<u64>>()
+        })
+    };
+    let batch: Vec<u64> = owned_after(cursor).into_iter().take(MIGRATION_BATCH_SIZE).collect();
+
+    let mut migration = AccountMigration {
+        to,
+        dry_run,
+        moved: Vec::new(),
+        skipped: Vec::new(),
+        remaining: 0,
+        next_cursor: cursor,
+        profile_moved: false,
+        favorites_moved: 0,
+        notifications_moved: 0,
+        done: false,
+    };
+    for &asset_id in &batch {
+        match migrate_asset(asset_id, principal, to, dry_run, current_time) {
+            Ok(()) => migration.moved.push(asset_id),
+            Err(err) => migration.skipped.push((asset_id, err)),
+        }
+        migration.next_cursor = Some(asset_id);
+    }
+    migration.remaining = owned_after(migration.next_cursor).len() as u64;
+    if migration.remaining > 0 {
+        return Ok(migration);
+    }
+
+    if dry_run {
+        migration.profile_moved = profile_movable(principal, to);
+        migration.favorites_moved = FAVORITES.with(|favorites| {
+            favorites.borrow().range((principal, 0)..=(principal, u64::MAX)).count() as u64
+        });
+        migration.notifications_moved = NOTIFICATIONS.with(|notifications| {
+            notifications.borrow().range((principal, 0)..=(principal, u64::MAX)).count() as u64
+        });
+        migration.done = true;
+        return Ok(migration);
+    }
+
+    migration.profile_moved = migrate_profile(principal, to, current_time);
+    migration.notifications_moved = migrate_notifications(principal, to);
+    migration.favorites_moved = migrate_favorites(principal, to);
+    migration.done = migration.favorites_moved < MIGRATION_BATCH_SIZE as u64;
+    Ok(migration)
+}
+
+// Handoffs the old principal offered are withdrawn rather than blocking the
+// move; sales already under way are not
+fn migrate_asset(
+    asset_id: u64,
+    principal: Principal,
+    to: Principal,
+    dry_run: bool,
+    current_time: u64,
+) -> Result<(), AssetError> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or(AssetError::NotFound)?;
+        if asset.owner != principal {
+            return Err(AssetError::NotOwner);
+        }
+        if is_sale_in_progress(asset_id) {
+            return Err(AssetError::TransferInProgress);
+        }
+        check_not_frozen(&asset)?;
+        if is_taken_down(asset_id) {
+            return Err(AssetError::TakenDown);
+        }
+        check_reservation(&asset, to, current_time)?;
+        if dry_run {
+            return Ok(());
+        }
+
+        PENDING_TRANSFERS.with(|transfers| {
+            transfers.borrow_mut().remove(&asset_id);
+        });
+        remove_gift(asset_id);
+        change_owner(&mut asset, to, OwnershipEventKind::Migration, None, current_time);
+        assets.insert(asset_id, asset);
+        Ok(())
+    })
+}
+
+// A profile the new principal already set up is kept over the old one
+fn profile_movable(principal: Principal, to: Principal) -> bool {
+    PROFILES.with(|profiles| {
+        let profiles = profiles.borrow();
+        profiles.contains_key(&principal) && !profiles.contains_key(&to)
+    })
+}
+
+fn migrate_profile(principal: Principal, to: Principal, current_time: u64) -> bool {
+    if !profile_movable(principal, to) {
+        return false;
+    }
+    let Some(mut profile) = PROFILES.with(|profiles| profiles.borrow_mut().remove(&principal)) else {
+        return false;
+    };
+    profile.principal = to;
+    profile.updated_at = current_time;
+    PROFILE_NAMES.with(|names| names.borrow_mut().insert(display_name_key(&profile.display_name), to));
+    PROFILES.with(|profiles| profiles.borrow_mut().insert(to, profile));
+    true
+}
+
+fn migrate_notifications(principal: Principal, to: Principal) -> u64 {
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let keys: Vec<(Principal, u64)> = notifications
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            if let Some(notification) = notifications.remove(key) {
+                notifications.insert((to, notification.id), notification);
+            }
+        }
+        trim_inbox(&mut notifications, to);
+        keys.len() as u64
+    })
+}
+
+// One batch; favoriting keeps its original time
+fn migrate_favorites(principal: Principal, to: Principal) -> u64 {
+    let batch: Vec<(u64, u64)> = FAVORITES.with(|favorites| {
+        favorites
+            .borrow()
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|((_, asset_id), favorited_at)| (asset_id, favorited_at))
+            .take(MIGRATION_BATCH_SIZE)
+            .collect()
+    });
+    for &(asset_id, favorited_at) in &batch {
+        remove_favorite(principal, asset_id);
+        if let Some(asset) = asset_by_id(asset_id) {
+            add_favorite(to, &asset, favorited_at);
+        }
+    }
+    batch.len() as u64
+}
+
+// Checkout reservations. A buyer, or a marketplace canister for its buyer,
+// holds a listed asset while it arranges payment. Until the hold is released
+// or expires, purchases and transfers to anyone but the holder are refused.
+// Buyers are rate limited, may hold only a few assets at once and must wait
+// out a cooldown before holding the same asset again; authorized marketplaces
+// hold for many buyers and apply their own limits.
+#[update]
+fn reserve_asset(asset_id: u64) -> Result<Reservation, AssetError> {
+    ensure_running("reserve_asset")?;
+    let holder = caller();
+    if !is_authorized_marketplace(&holder) {
+        enforce_rate_limit(RateLimitedAction::Reservation)?;
+    }
+    let (reservation, placed) = place_reservation(asset_id, holder, time())?;
+
+    // The caller's existing hold already has its timers
+    if placed {
+        let delay = Duration::from_nanos(reservation.expires_at.saturating_sub(time()));
+        ic_cdk_timers::set_timer(delay, move || expire_reservation(asset_id, time()));
+        let forget_after = delay + Duration::from_nanos(RESERVATION_COOLDOWN_NANOS);
+        ic_cdk_timers::set_timer(forget_after, move || forget_reservation_holds(holder, time()));
+    }
+    Ok(reservation)
+}
+
+#[update]
+fn release_reservation(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("release_reservation")?;
+    clear_reservation(asset_id, caller(), time())
+}
+
+// Returns the hold and whether this call placed it; asking again only
+// returns the caller's existing hold
+fn place_reservation(
+    asset_id: u64,
+    holder: Principal,
+    current_time: u64,
+) -> Result<(Reservation, bool), AssetError> {
+    if holder == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let mut asset = asset_by_id(asset_id).filter(|asset| can_view(asset, holder)).ok_or(AssetError::NotFound)?;
+    if !is_on_sale(&asset, current_time) {
+        return Err(AssetError::NotForSale);
+    }
+    if asset.owner == holder {
+        return Err(AssetError::InvalidInput("Cannot reserve your own asset".to_string()));
+    }
+    // Reserving again does not extend the hold, so nobody can keep an asset
+    // off the market by renewing it
+    if let Some(reservation) = active_reservation(&asset, current_time) {
+        if reservation.holder == holder {
+            return Ok((reservation.clone(), false));
+        }
+        return Err(AssetError::AssetReserved { expires_at: reservation.expires_at });
+    }
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    let capped = !is_authorized_marketplace(&holder);
+    if capped {
+        check_reservation_allowance(asset_id, holder, current_time)?;
+    }
+
+    let reservation = Reservation {
+        holder,
+        reserved_at: current_time,
+        expires_at: current_time.saturating_add(RESERVATION_DURATION_NANOS),
+    };
+    asset.reservation = Some(reservation.clone());
+    ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+    if capped {
+        RESERVATION_HOLDS.with(|holds| holds.borrow_mut().insert((holder, asset_id), reservation.expires_at));
+    }
+    Ok((reservation, true))
+}
+
+// Refuses a holder already at the cap of live holds, or still cooling down
+// from an earlier hold on this asset
+fn check_reservation_allowance(asset_id: u64, holder: Principal, current_time: u64) -> Result<(), AssetError> {
+    forget_reservation_holds(holder, current_time);
+    if let Some(ended_at) = RESERVATION_HOLDS.with(|holds| holds.borrow().get(&(holder, asset_id))) {
+        let available_at = ended_at.saturating_add(RESERVATION_COOLDOWN_NANOS);
+        return Err(AssetError::RateLimited { retry_after_seconds: retry_after_seconds(available_at, current_time) });
+    }
+    let active = RESERVATION_HOLDS.with(|holds| {
+        holds
+            .borrow()
+            .range((holder, 0)..=(holder, u64::MAX))
+            .filter(|(_, ends_at)| current_time < *ends_at)
+            .count()
+    });
+    if active >= MAX_ACTIVE_RESERVATIONS_PER_HOLDER {
+        return Err(AssetError::TooManyReservations);
+    }
+    Ok(())
+}
+
+// Drops the holder's holds whose cooldown has passed
+fn forget_reservation_holds(holder: Principal, current_time: u64) {
+    RESERVATION_HOLDS.with(|holds| {
+        let mut holds = holds.borrow_mut();
+        let done: Vec<(Principal, u64)> = holds
+            .range((holder, 0)..=(holder, u64::MAX))
+            .filter(|(_, ends_at)| ends_at.saturating_add(RESERVATION_COOLDOWN_NANOS) <= current_time)
+            .map(|(key, _)| key)
+            .collect();
+        for key in done {
+            holds.remove(&key);
+        }
+    });
+}
+
+// Marks a hold as over now, so it stops counting against the holder's cap;
+// its cooldown runs from here
+fn end_reservation_hold(holder: Principal, asset_id: u64, current_time: u64) {
+    RESERVATION_HOLDS.with(|holds| {
+        let mut holds = holds.borrow_mut();
+        if holds.get(&(holder, asset_id)).is_some_and(|ends_at| current_time < ends_at) {
+            holds.insert((holder, asset_id), current_time);
+        }
+    });
+}
+
+fn clear_reservation(asset_id: u64, holder: Principal, current_time: u64) -> Result<(), AssetError> {
+    let mut asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    match active_reservation(&asset, current_time) {
+        Some(reservation) if reservation.holder == holder => {},
+        Some(_) => return Err(AssetError::Unauthorized),
+        None => return Err(AssetError::NoReservation),
+    }
+    asset.reservation = None;
+    ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+    end_reservation_hold(holder, asset_id, current_time);
+    Ok(())
+}
+
+// Drops a lapsed hold from the record. The checks never rely on this: an
+// expired reservation is ignored whether or not it has been removed.
+fn expire_reservation(asset_id: u64, current_time: u64) {
+    if let Some(mut asset) = asset_by_id(asset_id) {
+        if asset.reservation.is_some() && active_reservation(&asset, current_time).is_none() {
+            asset.reservation = None;
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+    }
+}
+
+fn active_reservation(asset: &Asset, current_time: u64) -> Option<&Reservation> {
+    asset.reservation.as_ref().filter(|reservation| current_time < reservation.expires_at)
+}
+
+// Refuses to move an asset to anyone but the holder of its live reservation
+fn check_reservation(asset: &Asset, recipient: Principal, current_time: u64) -> Result<(), AssetError> {
+    match active_reservation(asset, current_time) {
+        Some(reservation) if reservation.holder != recipient => {
+            Err(AssetError::AssetReserved { expires_at: reservation.expires_at })
+        },
+        _ => Ok(()),
+    }
+}
+
+fn check_not_frozen(asset: &Asset) -> Result<(), AssetError> {
+    if asset.frozen.is_some() {
+        return Err(AssetError::AssetFrozen);
+    }
+    Ok(())
+}
+
+// Transfer approvals let the owner delegate a single transfer of an asset
+#[update]
+fn approve_transfer(asset_id: u64, spender: Principal, expires_at: Option<u64>) -> Result<Approval, AssetError> {
+    ensure_running("approve_transfer")?;
+    let principal = caller();
+    let current_time = time();
+
+    if spender == Principal::anonymous() || spender == principal {
+        return Err(AssetError::InvalidInput("spender: must be another, non-anonymous principal".to_string()));
+    }
+
+    if let Some(expires_at) = expires_at {
+        if expires_at <= current_time {
+            return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+        }
+    }
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+
+    let approval = Approval {
+        asset_id,
+        owner: principal,
+        spender,
+        expires_at,
+        created_at: current_time,
+    };
+
+    APPROVALS.with(|approvals| {
+        approvals.borrow_mut().insert(asset_id, approval.clone());
+    });
+
+    Ok(approval)
+}
+
+#[update]
+fn revoke_approval(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("revoke_approval")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+
+    clear_approval(asset_id);
+    Ok(())
+}
+
+#[query]
+fn get_approval(asset_id: u64) -> Option<Approval> {
+    APPROVALS.with(|approvals| {
+        approvals.borrow().get(&asset_id)
+    })
+}
+
+#[update]
+fn transfer_from(asset_id: u64, to: Principal) -> Result<Asset, AssetError> {
+    ensure_running("transfer_from")?;
+    let spender = caller();
+    let current_time = time();
+
+    let approval = match get_approval(asset_id) {
+        Some(approval) if approval.spender == spender => approval,
+        _ => return Err(AssetError::Unauthorized),
+    };
+
+    if let Some(expires_at) = approval.expires_at {
+        if current_time >= expires_at {
+            clear_approval(asset_id);
+            return Err(AssetError::ApprovalExpired);
+        }
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                // An approval granted by a previous owner is never valid
+                if asset.owner != approval.owner {
+                    return Err(AssetError::Unauthorized);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                if asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                if is_taken_down(asset_id) {
+                    return Err(AssetError::TakenDown);
+                }
+                check_reservation(&asset, to, current_time)?;
+
+                // Consumes the approval
+                change_owner(&mut asset, to, OwnershipEventKind::Transfer, None, current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+fn clear_approval(asset_id: u64) {
+    APPROVALS.with(|approvals| {
+        approvals.borrow_mut().remove(&asset_id);
+    });
+}
+
+// Offers let buyers bid below the asking price of a listed asset. The amount
+// is paid in ICP when the owner accepts, so the bidder must have approved this
+// canister (icrc2_approve) for the amount plus four ledger fees by then.
+#[update]
+fn make_offer(asset_id: u64, amount_e8s: u64, expires_at: Option<u64>) -> Result<Offer, AssetError> {
+    ensure_running("make_offer")?;
+    let bidder = caller();
+    let current_time = time();
+
+    if bidder == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner == bidder {
+        return Err(AssetError::InvalidInput("Cannot make an offer on your own asset".to_string()));
+    }
+    if !is_on_sale(&asset, current_time) {
+        return Err(AssetError::NotForSale);
+    }
+
+    if amount_e8s == 0 {
+        return Err(AssetError::InvalidInput("amount_e8s: must be greater than zero".to_string()));
+    }
+    validate_price(amount_e8s)?;
+
+    if let Some(expires_at) = expires_at {
+        if expires_at <= current_time {
+            return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+        }
+    }
+
+    let offer = Offer {
+        id: get_next_offer_id(),
+        asset_id,
+        bidder,
+        amount: amount_e8s,
+        expires_at,
+        created_at: current_time,
+        updated_at: current_time,
+        status: OfferStatus::Active,
+    };
+
+    ASSET_OFFER_INDEX.with(|index| index.borrow_mut().insert((asset_id, offer.id), ()));
+    OFFERS.with(|offers| {
+        offers.borrow_mut().insert(offer.id, offer.clone());
+    });
+    notify(asset.owner, NotificationKind::OfferReceived, asset_id, Some(bidder), Some(amount_e8s), current_time);
+
+    Ok(offer)
+}
+
+#[update]
+fn cancel_offer(offer_id: u64) -> Result<Offer, AssetError> {
+    ensure_running("cancel_offer")?;
+    let principal = caller();
+
+    OFFERS.with(|offers| {
+        let mut offers = offers.borrow_mut();
+
+        match offers.get(&offer_id) {
+            Some(mut offer) => {
+                if offer.bidder != principal {
+                    return Err(AssetError::Unauthorized);
+                }
+                if offer.status != OfferStatus::Active {
+                    return Err(AssetError::OfferNotActive);
+                }
+                // The owner may be collecting this offer's payment right now
+                if PENDING_PURCHASES.with(|pending| pending.borrow().get(&offer.asset_id)) == Some(principal) {
+                    return Err(AssetError::TransferInProgress);
+                }
+
+                offer.status = OfferStatus::Cancelled;
+                offer.updated_at = time();
+                offers.insert(offer_id, offer.clone());
+                Ok(offer)
+            },
+            None => Err(AssetError::OfferNotFound),
+        }
+    })
+}
+
+// Collects the offered amount from the bidder before the asset changes hands;
+// royalty and platform fee come out of it as for a direct purchase
+#[update]
+async fn accept_offer(offer_id: u64) -> Result<SaleRecord, AssetError> {
+    ensure_running("accept_offer")?;
+    let seller = caller();
+    let ledger_canister_id = get_ledger_canister_principal().map_err(AssetError::PaymentFailed)?;
+
+    let (offer, terms) = reserve_offer_sale(offer_id, seller, time())?;
+    let legs = payout_leg_count(&terms.royalty, &terms.platform_fee);
+    let transfer_result = collect_payment(ledger_canister_id, offer.bidder, terms.price, legs, offer.asset_id).await;
+
+    let current_time = time();
+    let sale = finish_offer_sale(offer, terms, transfer_result, ledger_canister_id, current_time)?;
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, PayoutSource::Settlement, current_time)).await;
+
+    Ok(sale)
+}
+
+// Validates an offer acceptance and locks the asset for the bidder while the
+// payment is awaited. finish_offer_sale releases the lock.
+fn reserve_offer_sale(
+    offer_id: u64,
+    seller: Principal,
+    current_time: u64,
+) -> Result<(Offer, ReservedPurchase), AssetError> {
+    let offer = OFFERS.with(|offers| offers.borrow().get(&offer_id)).ok_or(AssetError::OfferNotFound)?;
+    if offer.status != OfferStatus::Active {
+        return Err(AssetError::OfferNotActive);
+    }
+    if offer.expires_at.is_some_and(|expires_at| current_time >= expires_at) {
+        return Err(AssetError::OfferExpired);
+    }
+
+    let asset = asset_by_id(offer.asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != seller {
+        return Err(AssetError::NotOwner);
+    }
+    if is_transfer_in_progress(asset.id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    if asset.archived_at.is_some() {
+        return Err(AssetError::AssetArchived);
+    }
+    if is_taken_down(asset.id) {
+        return Err(AssetError::TakenDown);
+    }
+    check_reservation(&asset, offer.bidder, current_time)?;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset.id, offer.bidder);
+    });
+    let terms = ReservedPurchase {
+        seller,
+        price: offer.amount,
+        royalty: royalty_for(&asset, seller, offer.amount),
+        platform_fee: platform_fee_for(offer.amount),
+        coupon: None,
+    };
+    Ok((offer, terms))
+}
+
+// Releases the lock taken by reserve_offer_sale and, once the payment is
+// held, hands the asset to the bidder. A failed payment leaves the offer open.
+fn finish_offer_sale(
+    mut offer: Offer,
+    terms: ReservedPurchase,
+    transfer_result: Result<(), String>,
+    ledger_canister_id: Principal,
+    current_time: u64,
+) -> Result<SaleRecord, AssetError> {
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&offer.asset_id);
+    });
+    transfer_result.map_err(AssetError::PaymentFailed)?;
+
+    // Mark the offer accepted first so the ownership change only invalidates
+    // the competing offers
+    offer.status = OfferStatus::Accepted;
+    offer.updated_at = current_time;
+    OFFERS.with(|offers| {
+        offers.borrow_mut().insert(offer.id, offer.clone());
+    });
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&offer.asset_id) {
+            Some(mut asset) => {
+                change_owner(&mut asset, offer.bidder, OwnershipEventKind::OfferAccepted, Some(offer.amount), current_time);
+                assets.insert(asset.id, asset);
+                Ok(())
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    let ReservedPurchase { seller, price, royalty, platform_fee, .. } = terms;
+    Ok(store_sale(collected_sale(
+        sale_record(offer.asset_id, seller, offer.bidder, price, royalty, platform_fee, current_time),
+        ledger_canister_id,
+    )))
+}
+
+#[query]
+fn get_offers_for_asset(asset_id: u64) -> Vec<Offer> {
+    OFFERS.with(|offers| {
+        let offers = offers.borrow();
+        offer_ids_of(asset_id).into_iter().filter_map(|offer_id| offers.get(&offer_id)).collect()
+    })
+}
+
+// Offer ids made on an asset, oldest first
+fn offer_ids_of(asset_id: u64) -> Vec<u64> {
+    ASSET_OFFER_INDEX.with(|index| {
+        index.borrow().keys_range((asset_id, 0)..=(asset_id, u64::MAX)).map(|(_, offer_id)| offer_id).collect()
+    })
+}
+
+fn rebuild_asset_offer_index() {
+    let keys: Vec<(u64, u64)> =
+        OFFERS.with(|offers| offers.borrow().iter().map(|(id, offer)| (offer.asset_id, id)).collect());
+    ASSET_OFFER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+#[query]
+fn get_my_offers() -> Vec<Offer> {
+    let principal = caller();
+
+    OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .filter(|(_, offer)| offer.bidder == principal)
+            .map(|(_, offer)| offer)
+            .collect()
+    })
+}
+
+fn invalidate_offers(asset_id: u64, current_time: u64) {
+    OFFERS.with(|offers| {
+        let mut offers = offers.borrow_mut();
+        let active: Vec<Offer> = offer_ids_of(asset_id)
+            .into_iter()
+            .filter_map(|offer_id| offers.get(&offer_id))
+            .filter(|offer| offer.status == OfferStatus::Active)
+            .collect();
+
+        for mut offer in active {
+            offer.status = OfferStatus::Invalidated;
+            offer.updated_at = current_time;
+            offers.insert(offer.id, offer);
+        }
+    });
+}
+
+// English auctions: bids only record intent, the high bidder is charged through
+// the ledger when the auction settles. Bidders must icrc2_approve this canister
+// for their bid plus four ledger fees before the auction ends: one for the
+// pull and one per payout leg, as with purchase_asset.
+#[update]
+fn start_auction(
+    asset_id: u64,
+    starting_price: u64,
+    reserve_price: Option<u64>,
+    end_time: u64,
+) -> Result<Auction, AssetError> {
+    ensure_running("start_auction")?;
+    let principal = caller();
+    let current_time = time();
+
+    if starting_price == 0 {
+        return Err(AssetError::InvalidInput("starting_price: must be greater than zero".to_string()));
+    }
+    validate_price(starting_price)?;
+    if let Some(reserve_price) = reserve_price {
+        validate_price(reserve_price)?;
+    }
+    if end_time <= current_time {
+        return Err(AssetError::InvalidInput("end_time: must be in the future".to_string()));
+    }
+    if end_time - current_time > MAX_AUCTION_DURATION_NANOS {
+        return Err(AssetError::InvalidInput("end_time: auctions can run for at most 30 days".to_string()));
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                if asset.hidden.is_some() {
+                    return Err(AssetError::AssetHidden);
+                }
+                if asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                ensure_not_bundled(asset_id)?;
+
+                // The auction replaces any fixed-price listing
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                asset.is_for_sale = false;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, Some(principal), current_time);
+                assets.insert(asset_id, asset);
+                Ok(())
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+    invalidate_offers(asset_id, current_time);
+
+    let auction = Auction {
+        asset_id,
+        seller: principal,
+        starting_price,
+        reserve_price,
+        end_time,
+        high_bid: None,
+        bid_count: 0,
+        status: AuctionStatus::Active,
+        created_at: current_time,
+        settled_at: None,
+    };
+
+    AUCTIONS.with(|auctions| {
+        auctions.borrow_mut().insert(asset_id, auction.clone());
+    });
+    schedule_auction_settlement(asset_id, end_time);
+
+    Ok(auction)
+}
+
+#[update]
+fn place_bid(asset_id: u64, amount: u64) -> Result<Auction, AssetError> {
+    ensure_running("place_bid")?;
+    let bidder = caller();
+    let current_time = time();
+
+    if bidder == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    validate_price(amount)?;
+
+    AUCTIONS.with(|auctions| {
+        let mut auctions = auctions.borrow_mut();
+
+        match auctions.get(&asset_id) {
+            Some(mut auction) => {
+                if auction.status != AuctionStatus::Active {
+                    return Err(AssetError::AuctionNotActive);
+                }
+                if current_time >= auction.end_time {
+                    return Err(AssetError::AuctionEnded);
+                }
+                if auction.seller == bidder {
+                    return Err(AssetError::InvalidInput("Cannot bid on your own auction".to_string()));
+                }
+
+                let min_bid = min_next_bid(&auction);
+                if amount < min_bid {
+                    return Err(AssetError::BidTooLow { min_bid });
+                }
+
+                auction.high_bid = Some(Bid {
+                    bidder,
+                    amount,
+                    timestamp: current_time,
+                });
+                auction.bid_count += 1;
+                auctions.insert(asset_id, auction.clone());
+                Ok(auction)
+            },
+            None => Err(AssetError::AuctionNotFound),
+        }
+    })
+}
+
+#[update]
+fn cancel_auction(asset_id: u64) -> Result<Auction, AssetError> {
+    ensure_running("cancel_auction")?;
+    let principal = caller();
+
+    AUCTIONS.with(|auctions| {
+        let mut auctions = auctions.borrow_mut();
+
+        match auctions.get(&asset_id) {
+            Some(mut auction) => {
+                if auction.seller != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if auction.status != AuctionStatus::Active {
+                    return Err(AssetError::AuctionNotActive);
+                }
+                if auction.high_bid.is_some() {
+                    return Err(AssetError::InvalidInput("Cannot cancel an auction that has bids".to_string()));
+                }
+
+                // The settlement timer stays registered but finds nothing to do
+                auction.status = AuctionStatus::Cancelled;
+                auction.settled_at = Some(time());
+                auctions.insert(asset_id, auction.clone());
+                Ok(auction)
+            },
+            None => Err(AssetError::AuctionNotFound),
+        }
+    })
+}
+
+#[query]
+fn get_auction(asset_id: u64) -> Option<Auction> {
+    AUCTIONS.with(|auctions| auctions.borrow().get(&asset_id))
+}
+
+fn min_next_bid(auction: &Auction) -> u64 {
+    match &auction.high_bid {
+        Some(high_bid) => high_bid.amount.saturating_add(MIN_BID_INCREMENT_E8S),
+        None => auction.starting_price,
+    }
+}
+
+fn is_auction_active(asset_id: u64) -> bool {
+    AUCTIONS.with(|auctions| {
+        auctions
+            .borrow()
+            .get(&asset_id)
+            .is_some_and(|auction| auction.status == AuctionStatus::Active)
+    })
+}
+
+fn schedule_auction_settlement(asset_id: u64, end_time: u64) {
+    let delay = Duration::from_nanos(end_time.saturating_sub(time()));
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(settle_auction(asset_id)));
+}
+
+async fn settle_auction(asset_id: u64) {
+    let current_time = time();
+
+    let auction = match AUCTIONS.with(|auctions| auctions.borrow().get(&asset_id)) {
+        Some(auction) => auction,
+        None => return,
+    };
+    // Timers left over from a cancelled auction may fire during a newer one
+    if auction.status != AuctionStatus::Active || current_time < auction.end_time {
+        return;
+    }
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return;
+    }
+
+    let high_bid = match auction.high_bid.clone() {
+        Some(high_bid) if auction.reserve_price.is_none_or(|reserve| high_bid.amount >= reserve) => high_bid,
+        _ => {
+            finish_auction(asset_id, AuctionStatus::Unsold, current_time);
+            return;
+        },
+    };
+
+    let ledger_canister_id = match get_ledger_canister_principal() {
+        Ok(ledger_canister_id) => ledger_canister_id,
+        Err(_) => {
+            finish_auction(asset_id, AuctionStatus::PaymentFailed, current_time);
+            return;
+        },
+    };
+
+    let platform_fee = platform_fee_for(high_bid.amount);
+    let royalty = match asset_by_id(asset_id) {
+        Some(asset) => royalty_for(&asset, auction.seller, high_bid.amount),
+        None => {
+            finish_auction(asset_id, AuctionStatus::Cancelled, current_time);
+            return;
+        },
+    };
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, high_bid.bidder);
+    });
+
+    let legs = payout_leg_count(&royalty, &platform_fee);
+    let transfer_result = collect_payment(ledger_canister_id, high_bid.bidder, high_bid.amount, legs, asset_id).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+
+    let current_time = time();
+    if transfer_result.is_err() {
+        finish_auction(asset_id, AuctionStatus::PaymentFailed, current_time);
+        return;
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        if let Some(mut asset) = assets.get(&asset_id) {
+            change_owner(&mut asset, high_bid.bidder, OwnershipEventKind::AuctionSale, Some(high_bid.amount), current_time);
+            assets.insert(asset_id, asset);
+        }
+    });
+    finish_auction(asset_id, AuctionStatus::Sold, current_time);
+
+    let sale = store_sale(collected_sale(
+        sale_record(asset_id, auction.seller, high_bid.bidder, high_bid.amount, royalty, platform_fee, current_time),
+        ledger_canister_id,
+    ));
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, PayoutSource::Settlement, current_time)).await;
+}
+
+fn finish_auction(asset_id: u64, status: AuctionStatus, current_time: u64) {
+    AUCTIONS.with(|auctions| {
+        let mut auctions = auctions.borrow_mut();
+        if let Some(mut auction) = auctions.get(&asset_id) {
+            auction.status = status;
+            auction.settled_at = Some(current_time);
+            auctions.insert(asset_id, auction);
+        }
+    });
+}
+
+// Dutch auctions sell to the first buyer at the price of the moment, paid
+// through the ledger like purchase_asset. Buyers approve this canister for
+// the current price plus four ledger fees.
+#[update]
+fn create_dutch_auction(
+    asset_id: u64,
+    start_price: u64,
+    end_price: u64,
+    duration: u64,
+) -> Result<DutchAuction, AssetError> {
+    ensure_running("create_dutch_auction")?;
+    open_dutch_auction(asset_id, caller(), start_price, end_price, duration, time())
+}
+
+#[query]
+fn get_dutch_auction(asset_id: u64) -> Option<DutchAuction> {
+    DUTCH_AUCTIONS.with(|auctions| auctions.borrow().get(&asset_id))
+}
+
+// Price a buyer would pay right now, or None when no Dutch auction is running
+#[query]
+fn get_current_price(asset_id: u64) -> Option<u64> {
+    get_dutch_auction(asset_id)
+        .filter(|auction| auction.status == AuctionStatus::Active)
+        .map(|auction| auction.price_at(time()))
+}
+
+#[update]
+async fn buy_dutch_auction(asset_id: u64) -> Result<Asset, AssetError> {
+    ensure_running("buy_dutch_auction")?;
+    let buyer = caller();
+
+    if buyer == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    // The price is fixed and the asset locked before awaiting the ledger, so
+    // the amount charged is exactly the price checked here
+    let (seller, price, royalty, platform_fee) = reserve_dutch_purchase(asset_id, buyer, time())?;
+
+    let legs = payout_leg_count(&royalty, &platform_fee);
+    let transfer_result = collect_payment(ledger_canister_id, buyer, price, legs, asset_id).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+    transfer_result.map_err(AssetError::PaymentFailed)?;
+
+    let current_time = time();
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                change_owner(&mut asset, buyer, OwnershipEventKind::AuctionSale, Some(price), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+    finish_dutch_auction(asset_id, AuctionStatus::Sold, Some((buyer, price)), current_time);
+
+    let sale = store_sale(collected_sale(
+        sale_record(asset_id, seller, buyer, price, royalty, platform_fee, current_time),
+        ledger_canister_id,
+    ));
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, PayoutSource::Settlement, current_time)).await;
+
+    Ok(asset)
+}
+
+#[update]
+fn cancel_dutch_auction(asset_id: u64) -> Result<DutchAuction, AssetError> {
+    ensure_running("cancel_dutch_auction")?;
+    close_dutch_auction(asset_id, caller(), time())
+}
+
+fn open_dutch_auction(
+    asset_id: u64,
+    principal: Principal,
+    start_price: u64,
+    end_price: u64,
+    duration: u64,
+    current_time: u64,
+) -> Result<DutchAuction, AssetError> {
+    validate_price(start_price)?;
+    validate_price(end_price)?;
+    if end_price == 0 {
+        return Err(AssetError::InvalidInput("end_price: must be greater than zero".to_string()));
+    }
+    if start_price <= end_price {
+        return Err(AssetError::InvalidInput("start_price: must be above end_price".to_string()));
+    }
+    if duration == 0 {
+        return Err(AssetError::InvalidInput("duration: must be greater than zero".to_string()));
+    }
+    if duration > MAX_AUCTION_DURATION_NANOS {
+        return Err(AssetError::InvalidInput("duration: auctions can run for at most 30 days".to_string()));
+    }
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                if asset.hidden.is_some() {
+                    return Err(AssetError::AssetHidden);
+                }
+                if asset.archived_at.is_some() {
+                    return Err(AssetError::AssetArchived);
+                }
+                ensure_not_bundled(asset_id)?;
+
+                // Like English auctions, this replaces any fixed-price listing
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                asset.is_for_sale = false;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, Some(principal), current_time);
+                assets.insert(asset_id, asset);
+                Ok(())
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+    invalidate_offers(asset_id, current_time);
+
+    let auction = DutchAuction {
+        asset_id,
+        seller: principal,
+        start_price,
+        end_price,
+        starts_at: current_time,
+        duration,
+        status: AuctionStatus::Active,
+        buyer: None,
+        sold_price: None,
+        ended_at: None,
+    };
+    DUTCH_AUCTIONS.with(|auctions| {
+        auctions.borrow_mut().insert(asset_id, auction.clone());
+    });
+
+    Ok(auction)
+}
+
+// Checks the auction can be bought by `buyer` and locks the asset at the
+// current price. The caller must clear PENDING_PURCHASES once the payment
+// has settled either way.
+fn reserve_dutch_purchase(
+    asset_id: u64,
+    buyer: Principal,
+    current_time: u64,
+) -> Result<(Principal, u64, Option<Royalty>, Option<PlatformFee>), AssetError> {
+    let auction = get_dutch_auction(asset_id)
+        .filter(|auction| auction.status == AuctionStatus::Active)
+        .ok_or(AssetError::AuctionNotActive)?;
+    if auction.seller == buyer {
+        return Err(AssetError::InvalidInput("Cannot buy your own auction".to_string()));
+    }
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+
+    let price = auction.price_at(current_time);
+    let royalty = royalty_for(&asset, auction.seller, price);
+    let platform_fee = platform_fee_for(price);
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, buyer);
+    });
+
+    Ok((auction.seller, price, royalty, platform_fee))
+}
+
+fn close_dutch_auction(asset_id: u64, principal: Principal, current_time: u64) -> Result<DutchAuction, AssetError> {
+    let auction = get_dutch_auction(asset_id).ok_or(AssetError::AuctionNotFound)?;
+    if auction.seller != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if auction.status != AuctionStatus::Active {
+        return Err(AssetError::AuctionNotActive);
+    }
+    // A purchase already paying for the asset has to finish first
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+
+    finish_dutch_auction(asset_id, AuctionStatus::Cancelled, None, current_time);
+    get_dutch_auction(asset_id).ok_or(AssetError::AuctionNotFound)
+}
+
+fn finish_dutch_auction(asset_id: u64, status: AuctionStatus, sale: Option<(Principal, u64)>, current_time: u64) {
+    DUTCH_AUCTIONS.with(|auctions| {
+        let mut auctions = auctions.borrow_mut();
+        if let Some(mut auction) = auctions.get(&asset_id) {
+            auction.status = status;
+            auction.buyer = sale.map(|(buyer, _)| buyer);
+            auction.sold_price = sale.map(|(_, price)| price);
+            auction.ended_at = Some(current_time);
+            auctions.insert(asset_id, auction);
+        }
+    });
+}
+
+fn is_dutch_auction_active(asset_id: u64) -> bool {
+    DUTCH_AUCTIONS.with(|auctions| {
+        auctions
+            .borrow()
+            .get(&asset_id)
+            .is_some_and(|auction| auction.status == AuctionStatus::Active)
+    })
+}
+
+#[update]
+fn favorite_asset(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("favorite_asset")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+
+    add_favorite(principal, &asset, time());
+    Ok(())
+}
+
+#[update]
+fn unfavorite_asset(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("unfavorite_asset")?;
+    let principal = caller();
+
+    remove_favorite(principal, asset_id);
+    Ok(())
+}
+
+fn add_favorite(principal: Principal, asset: &Asset, current_time: u64) {
+    let already_favorited = FAVORITES.with(|favorites| {
+        favorites.borrow_mut().insert((principal, asset.id), current_time).is_some()
+    });
+    if already_favorited {
+        return;
+    }
+    ASSET_FAVORITE_INDEX.with(|index| index.borrow_mut().insert((asset.id, principal), ()));
+    FAVORITE_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = counts.get(&asset.id).unwrap_or(0);
+        counts.insert(asset.id, count + 1);
+    });
+    update_stats(asset, |stats| stats.favorite_count += 1);
+}
+
+fn remove_favorite(principal: Principal, asset_id: u64) {
+    let was_favorited = FAVORITES.with(|favorites| {
+        favorites.borrow_mut().remove(&(principal, asset_id)).is_some()
+    });
+    if !was_favorited {
+        return;
+    }
+    ASSET_FAVORITE_INDEX.with(|index| index.borrow_mut().remove(&(asset_id, principal)));
+    FAVORITE_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        match counts.get(&asset_id).unwrap_or(0) {
+            0 | 1 => counts.remove(&asset_id),
+            count => counts.insert(asset_id, count - 1),
+        };
+    });
+    if let Some(asset) = asset_by_id(asset_id) {
+        update_stats(&asset, |stats| stats.favorite_count = stats.favorite_count.saturating_sub(1));
+    }
+}
+
+#[query]
+fn get_my_favorites(offset: u64, limit: u64) -> AssetPage {
+    let principal = caller();
+
+    // Skip ids whose asset no longer exists rather than failing the whole page
+    let (assets, total_count) = FAVORITES.with(|favorites| {
+        let favorites = favorites.borrow();
+        let visible = favorites
+            .keys_range((principal, 0)..=(principal, u64::MAX))
+            .filter_map(|(_, asset_id)| asset_by_id(asset_id))
+            .filter(|asset| can_view(asset, principal));
+        page_of(visible, offset, limit)
+    });
+
+    AssetPage {
+        assets,
+        total_count,
+        truncated: false,
+        next_offset: None,
+    }
+}
+
+#[query]
+fn get_favorite_count(asset_id: u64) -> u64 {
+    FAVORITE_COUNTS.with(|counts| counts.borrow().get(&asset_id).unwrap_or(0))
+}
+
+fn remove_favorites(asset_id: u64) {
+    let holders = favoriters_of(asset_id);
+    FAVORITES.with(|favorites| {
+        let mut favorites = favorites.borrow_mut();
+        for holder in &holders {
+            favorites.remove(&(*holder, asset_id));
+        }
+    });
+    ASSET_FAVORITE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for holder in holders {
+            index.remove(&(asset_id, holder));
+        }
+    });
+
+    FAVORITE_COUNTS.with(|counts| {
+        counts.borrow_mut().remove(&asset_id);
+    });
+}
+
+// Principals who favorited an asset
+fn favoriters_of(asset_id: u64) -> Vec<Principal> {
+    ASSET_FAVORITE_INDEX.with(|index| {
+        index
+            .borrow()
+            .keys_range((asset_id, Principal::management_canister())..)
+            .take_while(|(favorited_id, _)| *favorited_id == asset_id)
+            .map(|(_, principal)| principal)
+            .collect()
+    })
+}
+
+fn rebuild_asset_favorite_index() {
+    let keys: Vec<(u64, Principal)> = FAVORITES
+        .with(|favorites| favorites.borrow().keys().map(|(principal, asset_id)| (asset_id, principal)).collect());
+    ASSET_FAVORITE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Price alerts. A watcher names a wish price for an asset they can see;
+// when its owner lowers the listed price to that price or below, the
+// watcher gets a PriceAlertTriggered notification and the alert is spent.
+#[update]
+fn set_price_alert(asset_id: u64, target_price: u64) -> Result<PriceAlert, AssetError> {
+    ensure_running("set_price_alert")?;
+    place_price_alert(asset_id, target_price, caller(), time())
+}
+
+#[update]
+fn remove_price_alert(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("remove_price_alert")?;
+    let principal = caller();
+    let removed = PRICE_ALERTS.with(|alerts| alerts.borrow_mut().remove(&(principal, asset_id)));
+    ASSET_PRICE_ALERT_INDEX.with(|index| index.borrow_mut().remove(&(asset_id, principal)));
+    removed.map(|_| ()).ok_or(AssetError::NotFound)
+}
+
+#[query]
+fn get_my_price_alerts() -> Vec<PriceAlert> {
+    price_alerts_of(caller())
+}
+
+fn place_price_alert(
+    asset_id: u64,
+    target_price: u64,
+    principal: Principal,
+    current_time: u64,
+) -> Result<PriceAlert, AssetError> {
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let asset = visible_asset(asset_id, principal)?;
+    if asset.owner == principal {
+        return Err(AssetError::InvalidInput("Cannot set a price alert on your own asset".to_string()));
+    }
+    let target_price = validate_price(target_price)?;
+    if asset.is_for_sale && icp_price(&asset).is_some_and(|price| price <= target_price) {
+        return Err(AssetError::InvalidInput(
+            "target_price: the asset is already listed at or below it".to_string(),
+        ));
+    }
+
+    PRICE_ALERTS.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let replacing = alerts.contains_key(&(principal, asset_id));
+        let held = alerts.range((principal, 0)..=(principal, u64::MAX)).count();
+        if !replacing && held >= MAX_PRICE_ALERTS_PER_PRINCIPAL {
+            return Err(AssetError::InvalidInput(format!(
+                "asset_id: at most {} price alerts may be active",
+                MAX_PRICE_ALERTS_PER_PRINCIPAL
+            )));
+        }
+        let alert = PriceAlert {
+            asset_id,
+            target_price,
+            created_at: current_time,
+        };
+        alerts.insert((principal, asset_id), alert.clone());
+        ASSET_PRICE_ALERT_INDEX.with(|index| index.borrow_mut().insert((asset_id, principal), ()));
+        Ok(alert)
+    })
+}
+
+// Alerts on assets the watcher can no longer see are kept but not shown
+fn price_alerts_of(principal: Principal) -> Vec<PriceAlert> {
+    PRICE_ALERTS.with(|alerts| {
+        alerts
+            .borrow()
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|(_, alert)| alert)
+            .filter(|alert| asset_by_id(alert.asset_id).is_some_and(|asset| can_view(&asset, principal)))
+            .collect()
+    })
+}
+
+fn trigger_price_alerts(asset_id: u64, new_price: u64, current_time: u64) {
+    let triggered: Vec<Principal> = PRICE_ALERTS.with(|alerts| {
+        let alerts = alerts.borrow();
+        watchers_of(asset_id)
+            .into_iter()
+            .filter(|watcher| alerts.get(&(*watcher, asset_id)).is_some_and(|alert| new_price <= alert.target_price))
+            .collect()
+    });
+    for watcher in triggered {
+        PRICE_ALERTS.with(|alerts| alerts.borrow_mut().remove(&(watcher, asset_id)));
+        ASSET_PRICE_ALERT_INDEX.with(|index| index.borrow_mut().remove(&(asset_id, watcher)));
+        notify(watcher, NotificationKind::PriceAlertTriggered, asset_id, None, Some(new_price), current_time);
+    }
+}
+
+fn remove_price_alerts(asset_id: u64) {
+    let watchers = watchers_of(asset_id);
+    PRICE_ALERTS.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        for watcher in &watchers {
+            alerts.remove(&(*watcher, asset_id));
+        }
+    });
+    ASSET_PRICE_ALERT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for watcher in watchers {
+            index.remove(&(asset_id, watcher));
+        }
+    });
+}
+
+// Principals with a price alert on an asset
+fn watchers_of(asset_id: u64) -> Vec<Principal> {
+    ASSET_PRICE_ALERT_INDEX.with(|index| {
+        index
+            .borrow()
+            .keys_range((asset_id, Principal::management_canister())..)
+            .take_while(|(alert_asset_id, _)| *alert_asset_id == asset_id)
+            .map(|(_, watcher)| watcher)
+            .collect()
+    })
+}
+
+fn rebuild_asset_price_alert_index() {
+    let keys: Vec<(u64, Principal)> = PRICE_ALERTS
+        .with(|alerts| alerts.borrow().iter().map(|((watcher, asset_id), _)| (asset_id, watcher)).collect());
+    ASSET_PRICE_ALERT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Only principals who have bought the asset may rate it, once each; rating
+// again replaces the earlier rating
+#[update]
+fn rate_asset(asset_id: u64, stars: u8, review: Option<String>) -> Result<Rating, AssetError> {
+    ensure_running("rate_asset")?;
+    let principal = caller();
+    let current_time = time();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    if !(1..=5).contains(&stars) {
+        return Err(AssetError::InvalidInput("stars: must be between 1 and 5".to_string()));
+    }
+    let review = validate_review(review)?;
+
+    if asset_by_id(asset_id).is_none() {
+        return Err(AssetError::NotFound);
+    }
+    if !has_bought(asset_id, principal) {
+        return Err(AssetError::Unauthorized);
+    }
+
+    RATINGS.with(|ratings| {
+        let mut ratings = ratings.borrow_mut();
+        let created_at = ratings
+            .get(&(asset_id, principal))
+            .map_or(current_time, |rating| rating.created_at);
+
+        let rating = Rating {
+            asset_id,
+            reviewer: principal,
+            stars,
+            review,
+            created_at,
+            updated_at: current_time,
+        };
+        ratings.insert((asset_id, principal), rating.clone());
+        Ok(rating)
+    })
+}
+
+#[update]
+fn delete_review(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("delete_review")?;
+    let principal = caller();
+
+    RATINGS.with(|ratings| {
+        match ratings.borrow_mut().remove(&(asset_id, principal)) {
+            Some(_) => Ok(()),
+            None => Err(AssetError::NotFound),
+        }
+    })
+}
+
+#[query]
+fn get_asset_rating(asset_id: u64) -> AssetRating {
+    let ratings = ratings_of(asset_id);
+    let count = ratings.len() as u64;
+    let total: u64 = ratings.iter().map(|rating| rating.stars as u64).sum();
+
+    AssetRating {
+        asset_id,
+        average: if count == 0 { 0.0 } else { total as f64 / count as f64 },
+        count,
+    }
+}
+
+#[query]
+fn get_asset_reviews(asset_id: u64, offset: u64, limit: u64) -> ReviewPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let reviews: Vec<Rating> = ratings_of(asset_id)
+        .into_iter()
+        .filter(|rating| rating.review.is_some())
+        .collect();
+
+    ReviewPage {
+        total_count: reviews.len() as u64,
+        reviews: reviews.into_iter().skip(offset as usize).take(limit as usize).collect(),
+    }
+}
+
+fn ratings_of(asset_id: u64) -> Vec<Rating> {
+    RATINGS.with(|ratings| {
+        ratings
+            .borrow()
+            .range((asset_id, Principal::management_canister())..)
+            .take_while(|((rated_id, _), _)| *rated_id == asset_id)
+            .map(|(_, rating)| rating)
+            .collect()
+    })
+}
+
+fn has_bought(asset_id: u64, principal: Principal) -> bool {
+    PURCHASE_INDEX.with(|index| index.borrow().contains_key(&(principal, asset_id)))
+}
+
+fn rebuild_purchase_index() {
+    let purchases: Vec<(Principal, u64)> =
+        SALES.with(|sales| sales.borrow().iter().map(|(_, sale)| (sale.buyer, sale.asset_id)).collect());
+    PURCHASE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for purchase in purchases {
+            index.insert(purchase, ());
+        }
+    });
+}
+
+fn rebuild_asset_sale_index() {
+    let sales: Vec<(u64, u64)> =
+        SALES.with(|sales| sales.borrow().iter().map(|(id, sale)| (sale.asset_id, id)).collect());
+    ASSET_SALE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in sales {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Sale ids of an asset, oldest first
+fn sale_ids_of(asset_id: u64) -> Vec<u64> {
+    ASSET_SALE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((asset_id, 0)..=(asset_id, u64::MAX))
+            .map(|((_, sale_id), _)| sale_id)
+            .collect()
+    })
+}
+
+fn remove_ratings(asset_id: u64) {
+    for rating in ratings_of(asset_id) {
+        RATINGS.with(|ratings| {
+            ratings.borrow_mut().remove(&(asset_id, rating.reviewer));
+        });
+    }
+}
+
+// Engagement is counted at most once per caller per asset per day. Anonymous
+// callers share a single identity, so they count once per day between them.
+#[update]
+fn record_view(asset_id: u64) -> Result<AssetStats, AssetError> {
+    ensure_running("record_view")?;
+    record_engagement(asset_id, Engagement::View)
+}
+
+#[update]
+fn record_download(asset_id: u64) -> Result<AssetStats, AssetError> {
+    ensure_running("record_download")?;
+    record_engagement(asset_id, Engagement::Download)
+}
+
+#[query]
+fn get_asset_stats(asset_id: u64) -> AssetStats {
+    let mut stats = ASSET_STATS.with(|stats| stats.borrow().get(&asset_id))
+        .unwrap_or_else(|| empty_asset_stats(asset_id));
+    stats.prices = price_summary_of(asset_id);
+    stats
+}
+
+#[query]
+fn get_trending_assets(limit: u64) -> Vec<Asset> {
+    let current_time = time();
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+
+    let mut scored: Vec<(f64, u64)> = ASSET_STATS.with(|stats| {
+        stats
+            .borrow()
+            .iter()
+            .map(|(asset_id, stats)| (decayed_score(&stats, current_time), asset_id))
+            .filter(|(score, _)| *score > 0.0)
+            .collect()
+    });
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    scored
+        .into_iter()
+        .filter_map(|(_, asset_id)| asset_by_id(asset_id))
+        .filter(is_listed_publicly)
+        .take(limit)
+        .collect()
+}
+
+fn record_engagement(asset_id: u64, engagement: Engagement) -> Result<AssetStats, AssetError> {
+    let principal = caller();
+    let current_time = time();
+
+    if asset_by_id(asset_id).is_none() {
+        return Err(AssetError::NotFound);
+    }
+
+    let mut stats = ASSET_STATS.with(|stats| stats.borrow().get(&asset_id))
+        .unwrap_or_else(|| empty_asset_stats(asset_id));
+
+    let mark = (asset_id, principal, engagement.key());
+    let last_counted = ENGAGEMENT_MARKS.with(|marks| marks.borrow().get(&mark));
+    if last_counted.is_some_and(|last_counted| current_time - last_counted < ENGAGEMENT_DEDUP_WINDOW_NANOS) {
+        return Ok(stats);
+    }
+
+    match engagement {
+        Engagement::View => stats.view_count += 1,
+        Engagement::Download => stats.download_count += 1,
+    }
+    stats.trending_score = decayed_score(&stats, current_time) + engagement.weight();
+    stats.trending_updated_at = current_time;
+
+    ENGAGEMENT_MARKS.with(|marks| {
+        marks.borrow_mut().insert(mark, current_time);
+    });
+    ASSET_STATS.with(|asset_stats| {
+        asset_stats.borrow_mut().insert(asset_id, stats.clone());
+    });
+
+    Ok(stats)
+}
+
+fn empty_asset_stats(asset_id: u64) -> AssetStats {
+    AssetStats {
+        asset_id,
+        view_count: 0,
+        download_count: 0,
+        trending_score: 0.0,
+        trending_updated_at: 0,
+        prices: None,
+    }
+}
+
+// Halves the score for every TRENDING_HALF_LIFE_NANOS without engagement
+fn decayed_score(stats: &AssetStats, current_time: u64) -> f64 {
+    let elapsed = current_time.saturating_sub(stats.trending_updated_at) as f64;
+    stats.trending_score * 0.5f64.powf(elapsed / TRENDING_HALF_LIFE_NANOS as f64)
+}
+
+fn schedule_engagement_pruning() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), prune_engagement_marks);
+}
+
+fn prune_engagement_marks() {
+    let current_time = time();
+
+    ENGAGEMENT_MARKS.with(|marks| {
+        let mut marks = marks.borrow_mut();
+        let stale: Vec<(u64, Principal, u8)> = marks
+            .iter()
+            .filter(|(_, counted_at)| current_time - counted_at >= ENGAGEMENT_DEDUP_WINDOW_NANOS)
+            .map(|(mark, _)| mark)
+            .collect();
+
+        for mark in stale {
+            marks.remove(&mark);
+        }
+    });
+}
+
+// Collections group a seller's own assets; an asset may be in several
+#[update]
+fn create_collection(name: String, description: String, cover_image: Option<String>) -> Result<Collection, AssetError> {
+    ensure_running("create_collection")?;
+    let principal = caller();
+    let current_time = time();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let collection = Collection {
+        id: get_next_collection_id(),
+        owner: principal,
+        name: validate_name(&name)?,
+        description: validate_description(&description)?,
+        cover_image,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    COLLECTIONS.with(|collections| {
+        collections.borrow_mut().insert(collection.id, collection.clone());
+    });
+
+    Ok(collection)
+}
+
+#[update]
+fn add_asset_to_collection(collection_id: u64, asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("add_asset_to_collection")?;
+    let principal = caller();
+    get_owned_collection(collection_id, principal)?;
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+
+    add_collection_item(collection_id, asset_id, time());
+    touch_collection(collection_id);
+
+    Ok(())
+}
+
+#[update]
+fn remove_asset_from_collection(collection_id: u64, asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("remove_asset_from_collection")?;
+    get_owned_collection(collection_id, caller())?;
+
+    if !remove_collection_item(collection_id, asset_id) {
+        return Err(AssetError::NotFound);
+    }
+    touch_collection(collection_id);
+
+    Ok(())
+}
+
+#[update]
+fn delete_collection(collection_id: u64) -> Result<(), AssetError> {
+    ensure_running("delete_collection")?;
+    get_owned_collection(collection_id, caller())?;
+
+    for asset_id in collection_asset_ids(collection_id) {
+        remove_collection_item(collection_id, asset_id);
+    }
+    COLLECTIONS.with(|collections| {
+        collections.borrow_mut().remove(&collection_id);
+    });
+
+    Ok(())
+}
+
+#[query]
+fn get_collection(collection_id: u64, offset: u64, limit: u64) -> Option<CollectionPage> {
+    let principal = caller();
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let collection = COLLECTIONS.with(|collections| collections.borrow().get(&collection_id))?;
+
+    let assets: Vec<Asset> = collection_asset_ids(collection_id)
+        .into_iter()
+        .filter_map(asset_by_id)
+        .filter(|asset| is_visible_to(asset, principal))
+        .collect();
+
+    Some(CollectionPage {
+        collection,
+        total_count: assets.len() as u64,
+        assets: assets.into_iter().skip(offset as usize).take(limit as usize).collect(),
+    })
+}
+
+#[query]
+fn get_user_collections(owner: Principal) -> Vec<Collection> {
+    COLLECTIONS.with(|collections| {
+        collections
+            .borrow()
+            .iter()
+            .filter(|(_, collection)| collection.owner == owner)
+            .map(|(_, collection)| collection)
+            .collect()
+    })
+}
+
+fn get_owned_collection(collection_id: u64, principal: Principal) -> Result<Collection, AssetError> {
+    let collection = COLLECTIONS.with(|collections| collections.borrow().get(&collection_id))
+        .ok_or(AssetError::CollectionNotFound)?;
+    if collection.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    Ok(collection)
+}
+
+fn touch_collection(collection_id: u64) {
+    COLLECTIONS.with(|collections| {
+        let mut collections = collections.borrow_mut();
+        if let Some(mut collection) = collections.get(&collection_id) {
+            collection.updated_at = time();
+            collections.insert(collection_id, collection);
+        }
+    });
+}
+
+fn collection_asset_ids(collection_id: u64) -> Vec<u64> {
+    COLLECTION_ITEMS.with(|items| {
+        items
+            .borrow()
+            .range((collection_id, 0)..=(collection_id, u64::MAX))
+            .map(|((_, asset_id), _)| asset_id)
+            .collect()
+    })
+}
+
+fn add_collection_item(collection_id: u64, asset_id: u64, current_time: u64) {
+    COLLECTION_ITEMS.with(|items| {
+        let mut items = items.borrow_mut();
+        if !items.contains_key(&(collection_id, asset_id)) {
+            items.insert((collection_id, asset_id), current_time);
+        }
+    });
+    ASSET_COLLECTION_INDEX.with(|index| index.borrow_mut().insert((asset_id, collection_id), ()));
+}
+
+// Returns whether the asset was in the collection
+fn remove_collection_item(collection_id: u64, asset_id: u64) -> bool {
+    ASSET_COLLECTION_INDEX.with(|index| index.borrow_mut().remove(&(asset_id, collection_id)));
+    COLLECTION_ITEMS.with(|items| items.borrow_mut().remove(&(collection_id, asset_id))).is_some()
+}
+
+fn rebuild_asset_collection_index() {
+    let keys: Vec<(u64, u64)> = COLLECTION_ITEMS
+        .with(|items| items.borrow().keys().map(|(collection_id, asset_id)| (asset_id, collection_id)).collect());
+    ASSET_COLLECTION_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Removes an asset from every collection, or only from those of `owner`
+fn remove_from_collections(asset_id: u64, owner: Option<Principal>) {
+    let collection_ids: Vec<u64> = ASSET_COLLECTION_INDEX.with(|index| {
+        index
+            .borrow()
+            .keys_range((asset_id, 0)..=(asset_id, u64::MAX))
+            .map(|(_, collection_id)| collection_id)
+            .collect()
+    });
+
+    for collection_id in collection_ids {
+        let owned_by = COLLECTIONS.with(|collections| collections.borrow().get(&collection_id))
+            .map(|collection| collection.owner);
+        if owner.is_some() && owned_by != owner {
+            continue;
+        }
+        remove_collection_item(collection_id, asset_id);
+    }
+}
+
+// Archiving takes an asset off the market and out of browsing results while
+// keeping its record, so sale history and provenance still resolve
+#[update]
+fn archive_asset(asset_id: u64) -> Result<Asset, AssetError> {
+    ensure_running("archive_asset")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    archive_owned_asset(asset_id, caller(), time())
+}
+
+#[update]
+fn unarchive_asset(asset_id: u64) -> Result<Asset, AssetError> {
+    ensure_running("unarchive_asset")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    unarchive_owned_asset(asset_id, caller(), time())
+}
+
+fn unarchive_owned_asset(asset_id: u64, principal: Principal, current_time: u64) -> Result<Asset, AssetError> {
+    edit_owned_asset(asset_id, principal, current_time, |asset| {
+        if asset.archived_at.is_none() {
+            return Err(AssetError::InvalidInput("Asset is not archived".to_string()));
+        }
+        // Comes back unlisted; the owner relists it explicitly
+        asset.archived_at = None;
+        Ok(())
+    })
+}
+
+fn archive_owned_asset(asset_id: u64, principal: Principal, current_time: u64) -> Result<Asset, AssetError> {
+    let asset = edit_owned_asset(asset_id, principal, current_time, |asset| {
+        if asset.archived_at.is_some() {
+            return Err(AssetError::AssetArchived);
+        }
+        ensure_not_bundled(asset_id)?;
+
+        let was_for_sale = asset.is_for_sale;
+        asset.is_for_sale = false;
+        asset.archived_at = Some(current_time);
+        record_listing_activity(was_for_sale, asset, Some(principal), current_time);
+        Ok(())
+    })?;
+
+    clear_listing_window(asset_id);
+    clear_approval(asset_id);
+    invalidate_offers(asset_id, current_time);
+    RENTAL_LISTINGS.with(|listings| listings.borrow_mut().remove(&asset_id));
+    Ok(asset)
+}
+
+// Deleting is the second step after archiving
+#[update]
+fn delete_asset(asset_id: u64) -> Result<(), String> {
+    ensure_running("delete_asset").map_err(|err| err.to_string())?;
+    delete_owned_asset(asset_id, caller()).map_err(delete_error_v1)?;
+    close_marketplace_listings(asset_id);
+    Ok(())
+}
+
+fn delete_owned_asset(asset_id: u64, principal: Principal) -> Result<(), AssetError> {
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(asset) => {
+                if asset.owner != principal {
+                    return Err(AssetError::NotOwner);
+                }
+                if asset.archived_at.is_none() {
+                    return Err(AssetError::NotArchived);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+
+                // Removing the record drops the listing kept here. Marketplace
+                // canisters keep their own, which the endpoints close through
+                // close_marketplace_listings once this succeeds.
+                assets.remove(&asset_id);
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    purge_asset_data(&asset);
+    record_activity(asset_id, Some(principal), ActivityKind::Deleted, time());
+
+    Ok(())
+}
+
+// Drops everything that refers to an asset once its record has been removed
+fn purge_asset_data(asset: &Asset) {
+    unindex_asset(asset);
+    release_slug(asset);
+    clear_listing_window(asset.id);
+    clear_approval(asset.id);
+    invalidate_offers(asset.id, time());
+    remove_favorites(asset.id);
+    remove_price_alerts(asset.id);
+    remove_ratings(asset.id);
+    remove_reports(asset.id);
+    remove_file_access_grants(asset.id);
+    remove_asset_images(asset.id);
+    remove_asset_versions(asset);
+    remove_asset_attachments(asset.id);
+    remove_price_history(asset.id);
+    TAKEDOWNS.with(|takedowns| {
+        takedowns.borrow_mut().remove(&asset.id);
+    });
+    PENDING_TRANSFERS.with(|transfers| {
+        transfers.borrow_mut().remove(&asset.id);
+    });
+    remove_gift(asset.id);
+    remove_rentals_of(asset.id);
+    invalidate_bundle_of(asset.id, time());
+    remove_from_collections(asset.id, None);
+    ASSET_STATS.with(|stats| {
+        stats.borrow_mut().remove(&asset.id);
+    });
+
+    for file_hash in file_refs_of(asset) {
+        release_file_ref(&file_hash, asset.id);
+    }
+}
+
+// Every word of the query must appear in the asset's text; the last one may
+// be a prefix, so results narrow as the user types
+#[query]
+fn search_assets(query: String, cursor: Option<u64>) -> AssetChunk {
+    listed_text_matches(&query, cursor, RESPONSE_SIZE_BUDGET)
+}
+
+fn listed_text_matches(query: &str, cursor: Option<u64>, budget: usize) -> AssetChunk {
+    match text_search_ids(query) {
+        Some(asset_ids) => {
+            let matching = asset_ids
+                .range(cursor.unwrap_or(0)..)
+                .filter_map(|asset_id| asset_by_id(*asset_id))
+                .filter(is_listed_publicly);
+            take_within_budget(matching, budget)
+        },
+        None => listed_assets_matching(cursor, budget, |_| true),
+    }
+}
+
+// Ids of the assets containing every token of the query, matching the last
+// token as a prefix. None when the query is blank and so matches everything.
+fn text_search_ids(query: &str) -> Option<BTreeSet<u64>> {
+    let tokens: Vec<String> = tokenize(query).collect();
+    let Some((last, rest)) = tokens.split_last() else {
+        return if query.trim().is_empty() { None } else { Some(BTreeSet::new()) };
+    };
+
+    let mut asset_ids = token_postings(last, true);
+    for token in rest {
+        if asset_ids.is_empty() {
+            break;
+        }
+        let postings = token_postings(token, false);
+        asset_ids.retain(|asset_id| postings.contains(asset_id));
+    }
+    Some(asset_ids)
+}
+
+fn token_postings(token: &str, as_prefix: bool) -> BTreeSet<u64> {
+    TEXT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(TokenKey { token: token.to_string(), asset_id: 0 }..)
+            .take_while(|(key, _)| if as_prefix { key.token.starts_with(token) } else { key.token == token })
+            .map(|(key, _)| key.asset_id)
+            .collect()
+    })
+}
+
+// Lowercased runs of letters and digits; single characters are too common to
+// be worth indexing
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| token.chars().count() >= 2)
+        .map(|token| token.to_lowercase())
+}
+
+fn token_keys(asset: &Asset) -> Vec<TokenKey> {
+    let tokens: BTreeSet<String> = tokenize(&asset.name)
+        .chain(tokenize(&asset.description))
+        .chain(tokenize(&asset.category))
+        .chain(asset.tags.iter().flat_map(|tag| tokenize(tag)))
+        .collect();
+    tokens.into_iter().map(|token| TokenKey { token, asset_id: asset.id }).collect()
+}
+
+// For migrating existing catalogs; rebuild_owner_index also rebuilds it
+#[update]
+fn rebuild_search_index() -> Result<u64, String> {
+    ensure_running("rebuild_search_index").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(rebuild_text_index())
+}
+
+fn rebuild_text_index() -> u64 {
+    TEXT_INDEX.with(|index| index.borrow_mut().clear_new());
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        TEXT_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (_, asset) in assets.iter() {
+                for key in token_keys(&asset) {
+                    index.insert(key, ());
+                }
+            }
+        });
+        assets.len()
+    })
+}
+
+// All provided criteria must match. Price bounds are inclusive and every tag
+// in `tags` must be present on the asset.
+#[query]
+#[allow(clippy::too_many_arguments)]
+fn search_assets_advanced(
+    text: Option<String>,
+    category_id: Option<u64>,
+    min_price: Option<u64>,
+    max_price: Option<u64>,
+    tags: Vec<String>,
+    for_sale_only: bool,
+    offset: u64,
+    limit: u64,
+) -> AssetPage {
+    let text_matches = text.as_deref().and_then(text_search_ids);
+
+    paginate_assets(offset, limit, |asset| {
+        is_listed_publicly(asset)
+            && (!for_sale_only || asset.is_for_sale)
+            && text_matches.as_ref().is_none_or(|asset_ids| asset_ids.contains(&asset.id))
+            && category_id.is_none_or(|category_id| asset.category_id == category_id)
+            && min_price.is_none_or(|min_price| icp_price(asset).is_some_and(|price| price >= min_price))
+            && max_price.is_none_or(|max_price| icp_price(asset).is_some_and(|price| price <= max_price))
+            && tags.iter().all(|tag| asset.tags.iter().any(|asset_tag| asset_tag.eq_ignore_ascii_case(tag)))
+    })
+}
+
+// Relevance-ordered search: exact name hits first, then name prefixes, name
+// substrings, tags and finally description or category matches. Within a
+// kind, more popular assets rank first and newer ones break remaining ties.
+#[query]
+fn search_assets_ranked(query: String, offset: u64, limit: u64, include_scores: bool) -> RankedSearchPage {
+    ranked_search(&query, offset, limit, include_scores)
+}
+
+fn ranked_search(query: &str, offset: u64, limit: u64, include_scores: bool) -> RankedSearchPage {
+    let query_lower = query.trim().to_lowercase();
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let Some(asset_ids) = text_search_ids(&query_lower) else {
+        return RankedSearchPage {
+            results: Vec::new(),
+            total_count: 0,
+            truncated: false,
+            next_offset: None,
+        };
+    };
+
+    // Only the best `keep` candidates can make it onto the page, so the rest
+    // are dropped as the scan goes instead of sorting every match
+    let keep = offset as usize + limit as usize + SEARCH_RANK_BUFFER;
+    let mut candidates: Vec<(SearchScore, Asset)> = Vec::new();
+    let mut total_count = 0;
+    for asset in asset_ids.into_iter().filter_map(asset_by_id).filter(is_listed_publicly) {
+        total_count += 1;
+        candidates.push((search_score(&asset, &query_lower), asset));
+        if candidates.len() >= keep * 2 {
+            candidates.sort_by(compare_ranked);
+            candidates.truncate(keep);
+        }
+    }
+    candidates.sort_by(compare_ranked);
+
+    let mut results = Vec::new();
+    let mut used = 0;
+    let mut truncated = false;
+    for (score, asset) in candidates.into_iter().skip(offset as usize).take(limit as usize) {
+        let size = estimated_size(&asset);
+        if used + size > RESPONSE_SIZE_BUDGET {
+            truncated = true;
+            break;
+        }
+        used += size;
+        results.push(RankedAsset {
+            asset,
+            score: include_scores.then_some(score),
+        });
+    }
+
+    RankedSearchPage {
+        next_offset: truncated.then_some(offset + results.len() as u64),
+        results,
+        total_count,
+        truncated,
+    }
+}
+
+// Candidates come from the text index, so anything that does not match the
+// query as a whole still matched all of its words somewhere
+fn search_score(asset: &Asset, query_lower: &str) -> SearchScore {
+    let name = asset.name.to_lowercase();
+    let match_kind = if name == query_lower {
+        MatchKind::ExactName
+    } else if name.starts_with(query_lower) {
+        MatchKind::NamePrefix
+    } else if name.contains(query_lower) {
+        MatchKind::NameSubstring
+    } else if asset.tags.iter().any(|tag| tag.to_lowercase().contains(query_lower)) {
+        MatchKind::Tag
+    } else {
+        MatchKind::Description
+    };
+
+    let engagement = ASSET_STATS
+        .with(|stats| stats.borrow().get(&asset.id))
+        .map_or(0, |stats| stats.view_count + stats.download_count);
+    SearchScore {
+        match_kind,
+        popularity: get_favorite_count(asset.id) + engagement,
+    }
+}
+
+fn compare_ranked(a: &(SearchScore, Asset), b: &(SearchScore, Asset)) -> std::cmp::Ordering {
+    b.0.match_kind
+        .cmp(&a.0.match_kind)
+        .then(b.0.popularity.cmp(&a.0.popularity))
+        .then(b.1.created_at.cmp(&a.1.created_at))
+        .then(b.1.id.cmp(&a.1.id))
+}
+
+#[query]
+fn get_assets_by_category(category_id: u64, offset: u64, limit: u64) -> AssetPage {
+    paginate_asset_ids(category_asset_ids(category_id), offset, limit)
+}
+
+fn category_asset_ids(category_id: u64) -> Vec<u64> {
+    CATEGORY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((category_id, 0)..=(category_id, u64::MAX))
+            .map(|((_, asset_id), _)| asset_id)
+            .collect()
+    })
+}
+
+// Category registry. Controllers curate the categories; assets reference one
+// by id and carry its current name for display.
+#[update]
+fn create_category(name: String, parent: Option<u64>) -> Result<Category, String> {
+    ensure_running("create_category").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    add_category(&name, parent, time())
+}
+
+fn add_category(name: &str, parent: Option<u64>, now: u64) -> Result<Category, String> {
+    let name = validate_category_name(name, None)?;
+    if let Some(parent_id) = parent {
+        match category_by_id(parent_id) {
+            Some(parent) if !parent.archived => {},
+            Some(_) => return Err("parent: category is archived".to_string()),
+            None => return Err("parent: category not found".to_string()),
+        }
+    }
+
+    let category = Category {
+        id: get_next_category_id(),
+        name,
+        parent,
+        archived: false,
+        created_at: now,
+    };
+    save_category(&category);
+    Ok(category)
+}
+
+#[update]
+fn rename_category(category_id: u64, name: String) -> Result<Category, String> {
+    ensure_running("rename_category").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    rename_category_to(category_id, &name)
+}
+
+// Asset records carry the category name, so every asset in the category is
+// rewritten with the new one
+fn rename_category_to(category_id: u64, name: &str) -> Result<Category, String> {
+    if category_id == UNCATEGORIZED_CATEGORY_ID {
+        return Err(format!("The {} category cannot be renamed", UNCATEGORIZED_CATEGORY_NAME));
+    }
+    let mut category = category_by_id(category_id).ok_or_else(|| "Category not found".to_string())?;
+    category.name = validate_category_name(name, Some(category_id))?;
+    save_category(&category);
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        for asset_id in category_asset_ids(category_id) {
+            if let Some(mut asset) = assets.get(&asset_id) {
+                // The category name is part of the asset's searchable text
+                unindex_asset(&asset);
+                asset.category = category.name.clone();
+                index_asset(&asset);
+                assets.insert(asset_id, asset);
+            }
+        }
+    });
+    Ok(category)
+}
+
+// Archived categories keep their assets but are no longer offered for new ones
+#[update]
+fn archive_category(category_id: u64) -> Result<Category, String> {
+    ensure_running("archive_category").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if category_id == UNCATEGORIZED_CATEGORY_ID {
+        return Err(format!("The {} category cannot be archived", UNCATEGORIZED_CATEGORY_NAME));
+    }
+    let mut category = category_by_id(category_id).ok_or_else(|| "Category not found".to_string())?;
+    category.archived = true;
+    save_category(&category);
+    Ok(category)
+}
+
+// (category name, assets, assets for sale) for every registered category,
+// read from running counters rather than a scan of the assets
+#[query]
+fn get_category_counts() -> Vec<(String, u64, u64)> {
+    category_counts()
+}
+
+fn category_counts() -> Vec<(String, u64, u64)> {
+    CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .iter()
+            .map(|(category_id, category)| {
+                let counts = CATEGORY_STATS.with(|stats| stats.borrow().get(&category_id)).unwrap_or_default();
+                (category.name, counts.asset_count, counts.for_sale_count)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_owner_stats(owner: Principal) -> OwnerStats {
+    owner_stats(owner)
+}
+
+fn owner_stats(owner: Principal) -> OwnerStats {
+    let counts = OWNER_STATS.with(|stats| stats.borrow().get(&owner)).unwrap_or_default();
+    let sales = SELLER_TALLIES.with(|tallies| tallies.borrow().get(&owner)).unwrap_or_default();
+    OwnerStats {
+        asset_count: counts.asset_count,
+        for_sale_count: counts.for_sale_count,
+        favorite_count: counts.favorite_count,
+        sale_count: sales.sale_count,
+        sales_volume: sales.volume,
+    }
+}
+
+#[query]
+fn get_marketplace_stats() -> MarketplaceStats {
+    marketplace_stats()
+}
+
+// The registry as a tree of root categories; archived ones are included and
+// flagged so existing assets can still be labelled
+#[query]
+fn get_categories() -> Vec<CategoryNode> {
+    let categories: Vec<Category> = CATEGORIES.with(|categories| {
+        categories.borrow().iter().map(|(_, category)| category).collect()
+    });
+    category_nodes(&categories, None)
+}
+
+fn category_nodes(categories: &[Category], parent: Option<u64>) -> Vec<CategoryNode> {
+    categories
+        .iter()
+        .filter(|category| category.parent == parent)
+        .map(|category| CategoryNode {
+            category: category.clone(),
+            children: category_nodes(categories, Some(category.id)),
+        })
+        .collect()
+}
+
+fn category_by_id(category_id: u64) -> Option<Category> {
+    CATEGORIES.with(|categories| categories.borrow().get(&category_id))
+}
+
+fn save_category(category: &Category) {
+    CATEGORIES.with(|categories| {
+        categories.borrow_mut().insert(category.id, category.clone());
+    });
+}
+
+// The category a new or edited asset may be placed in
+fn active_category(category_id: u64) -> Result<Category, AssetError> {
+    match category_by_id(category_id) {
+        Some(category) if !category.archived => Ok(category),
+        Some(_) => Err(AssetError::InvalidInput("category_id: category is archived".to_string())),
+        None => Err(AssetError::InvalidInput("category_id: category not found".to_string())),
+    }
+}
+
+fn find_category_by_name(name: &str) -> Option<Category> {
+    let name = normalize_category(name);
+    CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .iter()
+            .map(|(_, category)| category)
+            .find(|category| normalize_category(&category.name) == name)
+    })
+}
+
+// Names are unique ignoring case and surrounding whitespace
+fn validate_category_name(name: &str, category_id: Option<u64>) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("name: must not be empty".to_string());
+    }
+    if name.chars().count() > MAX_CATEGORY_NAME_LENGTH {
+        return Err(format!("name: must be at most {} characters", MAX_CATEGORY_NAME_LENGTH));
+    }
+    if find_category_by_name(name).is_some_and(|existing| Some(existing.id) != category_id) {
+        return Err("name: a category with this name already exists".to_string());
+    }
+    Ok(name.to_string())
+}
+
+// Creates the Uncategorized bucket and the default categories when the
+// registry is empty. Returns whether it was.
+fn seed_categories(now: u64) -> bool {
+    if !CATEGORIES.with(|categories| categories.borrow().is_empty()) {
+        return false;
+    }
+
+    save_category(&Category {
+        id: UNCATEGORIZED_CATEGORY_ID,
+        name: UNCATEGORIZED_CATEGORY_NAME.to_string(),
+        parent: None,
+        archived: false,
+        created_at: now,
+    });
+    for name in DEFAULT_CATEGORIES {
+        add_category(name, None, now).expect("default categories are valid");
+    }
+    true
+}
+
+// Assigns registry ids to assets that still carry a free-text category by
+// matching names case-insensitively; anything unmatched is Uncategorized.
+// Indexes are left to the caller to rebuild.
+fn migrate_asset_categories() -> u64 {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let records: Vec<Asset> = assets
+            .iter()
+            .map(|(_, asset)| asset)
+            .filter(|asset| {
+                asset.category_id == UNCATEGORIZED_CATEGORY_ID && asset.category != UNCATEGORIZED_CATEGORY_NAME
+            })
+            .collect();
+        let count = records.len() as u64;
+
+        for mut asset in records {
+            let category = find_category_by_name(&asset.category)
+                .filter(|category| !category.archived)
+                .or_else(|| category_by_id(UNCATEGORIZED_CATEGORY_ID))
+                .expect("the Uncategorized category is seeded");
+            asset.category_id = category.id;
+            asset.category = category.name;
+            assets.insert(asset.id, asset);
+        }
+        count
+    })
+}
+
+#[query]
+fn get_assets_by_tag(tag: String, offset: u64, limit: u64) -> AssetPage {
+    paginate_asset_ids(tagged_asset_ids(&normalize_tag(&tag)), offset, limit)
+}
+
+// "More like this": candidates come from the tag index, topped up from the
+// asset's category when its tags alone do not fill the list
+#[query]
+fn get_similar_assets(asset_id: u64, limit: u64, exclude_same_owner: bool) -> Vec<AssetSummary> {
+    match asset_by_id(asset_id).filter(|asset| can_view(asset, caller())) {
+        Some(asset) => summarize(similar_assets(&asset, limit, exclude_same_owner)),
+        None => Vec::new(),
+    }
+}
+
+fn similar_assets(source: &Asset, limit: u64, exclude_same_owner: bool) -> Vec<Asset> {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    let tags: BTreeSet<String> = source
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .filter(|tag| !tag.is_empty())
+        .collect();
+
+    let mut candidates: BTreeSet<u64> = tags.iter().flat_map(|tag| tagged_asset_ids(tag)).collect();
+    if candidates.len() <= limit {
+        candidates.extend(category_asset_ids(source.category_id));
+    }
+
+    let mut scored: Vec<(u32, Asset)> = candidates
+        .into_iter()
+        .filter(|asset_id| *asset_id != source.id)
+        .filter_map(asset_by_id)
+        .filter(|asset| is_listed_publicly(asset) && !(exclude_same_owner && asset.owner == source.owner))
+        .map(|asset| (similarity(source, &tags, &asset), asset))
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.id.cmp(&a.1.id)));
+
+    scored.into_iter().take(limit).map(|(_, asset)| asset).collect()
+}
+
+fn similarity(source: &Asset, source_tags: &BTreeSet<String>, asset: &Asset) -> u32 {
+    let shared_tags = asset
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .collect::<BTreeSet<String>>()
+        .intersection(source_tags)
+        .count() as u32;
+    let same_category = source.category_id == asset.category_id;
+    // Compared in e8s, so assets without an ICP price are never in the band
+    let same_price_band = match (icp_price(source), icp_price(asset)) {
+        (Some(source_price), Some(price)) => {
+            let (low, high) = (source_price.min(price), source_price.max(price));
+            if low == 0 { high == 0 } else { high / 2 <= low }
+        },
+        _ => false,
+    };
+
+    shared_tags * SIMILAR_TAG_WEIGHT
+        + same_category as u32 * SIMILAR_CATEGORY_WEIGHT
+        + same_price_band as u32 * SIMILAR_PRICE_WEIGHT
+}
+
+// Tags with the number of publicly listed assets carrying them, in
+// alphabetical order
+#[query]
+fn get_all_tags(offset: u64, limit: u64) -> TagPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let tags = tag_counts("");
+
+    TagPage {
+        total_count: tags.len() as u64,
+        tags: tags.into_iter().skip(offset as usize).take(limit as usize).collect(),
+    }
+}
+
+// Autocomplete: tags starting with the prefix, most used first
+#[query]
+fn suggest_tags(prefix: String, limit: u64) -> Vec<TagCount> {
+    let prefix = normalize_tag(&prefix);
+    if prefix.is_empty() {
+        return Vec::new();
+    }
+
+    let mut tags = tag_counts(&prefix);
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    tags.truncate(limit.min(MAX_PAGE_SIZE) as usize);
+    tags
+}
+
+fn tagged_asset_ids(tag: &str) -> Vec<u64> {
+    TAG_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(TagKey { tag: tag.to_string(), asset_id: 0 }..)
+            .take_while(|(key, _)| key.tag == tag)
+            .map(|(key, _)| key.asset_id)
+            .collect()
+    })
+}
+
+// Counts only assets that are publicly listed so hidden assets do not leak
+// their tags
+fn tag_counts(prefix: &str) -> Vec<TagCount> {
+    let mut counts: Vec<TagCount> = Vec::new();
+    TAG_INDEX.with(|index| {
+        let index = index.borrow();
+        let entries = index
+            .range(TagKey { tag: prefix.to_string(), asset_id: 0 }..)
+            .take_while(|(key, _)| key.tag.starts_with(prefix));
+        for (key, _) in entries {
+            if !asset_by_id(key.asset_id).is_some_and(|asset| is_listed_publicly(&asset)) {
+                continue;
+            }
+            match counts.last_mut() {
+                Some(last) if last.tag == key.tag => last.count += 1,
+                _ => counts.push(TagCount { tag: key.tag, count: 1 }),
+            }
+        }
+    });
+    counts
+}
+
+// Pages over index results, skipping assets that are not publicly listed
+fn paginate_asset_ids(asset_ids: impl IntoIterator<Item = u64>, offset: u64, limit: u64) -> AssetPage {
+    let (page, total_count) = ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        let listed = asset_ids
+            .into_iter()
+            .filter_map(|asset_id| assets.get(&asset_id))
+            .filter(is_listed_publicly);
+        page_of(listed, offset, limit)
+    });
+    budgeted_page(page, total_count, offset)
+}
+
+// Trims a page to the response budget, pointing next_offset at what was cut
+fn budgeted_page(page: Vec<Asset>, total_count: u64, offset: u64) -> AssetPage {
+    let page_len = page.len();
+    let chunk = take_within_budget(page.into_iter(), RESPONSE_SIZE_BUDGET);
+    let returned = chunk.assets.len();
+    AssetPage {
+        assets: chunk.assets,
+        total_count,
+        truncated: returned < page_len,
+        next_offset: (returned < page_len).then_some(offset + returned as u64),
+    }
+}
+
+// Rough upper bound on an asset's candid encoding: the variable-length fields
+// plus a fixed allowance for the numbers, principals and record framing
+fn estimated_size(asset: &Asset) -> usize {
+    const FIXED_FIELDS_SIZE: usize = 512;
+
+    let tags: usize = asset.tags.iter().map(|tag| tag.len() + 8).sum();
+    let metadata: usize = asset
+        .metadata
+        .iter()
+        .map(|(key, value)| {
+            key.len()
+                + 16
+                + match value {
+                    MetadataValue::Text(text) => text.len(),
+                    MetadataValue::Nat(_) | MetadataValue::Bool(_) => 8,
+                }
+        })
+        .sum();
+
+    FIXED_FIELDS_SIZE
+        + asset.name.len()
+        + asset.description.len()
+        + asset.file_hash.len()
+        + asset.file_url.len()
+        + asset.file_type.len()
+        + asset.category.len()
+        + asset.preview_image_url.as_ref().map_or(0, String::len)
+        + asset.hidden.as_ref().map_or(0, |moderation| moderation.reason.len())
+        + tags
+        + metadata
+}
+
+// Cursors are the hex of a store tag followed by the big-endian key, which
+// clients should treat as opaque
+fn encode_cursor(tag: u8, key: u64) -> String {
+    let mut bytes = vec![tag];
+    bytes.extend_from_slice(&key.to_be_bytes());
+    to_hex(&bytes)
+}
+
+fn decode_cursor(cursor: &str, tag: u8) -> Result<u64, AssetError> {
+    if cursor.len() != 18 || !cursor.is_ascii() {
+        return Err(AssetError::InvalidCursor);
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| AssetError::InvalidCursor)?;
+    if bytes[0] != tag {
+        return Err(AssetError::InvalidCursor);
+    }
+
+    let mut key = [0u8; 8];
+    key.copy_from_slice(&bytes[1..]);
+    Ok(u64::from_be_bytes(key))
+}
+
+// Lower bound of a keyset page: just past the cursor's key, or the start
+fn cursor_start(cursor: Option<String>, tag: u8) -> Result<Bound<u64>, AssetError> {
+    match cursor {
+        Some(cursor) => Ok(Bound::Excluded(decode_cursor(&cursor, tag)?)),
+        None => Ok(Bound::Unbounded),
+    }
+}
+
+// Takes up to `limit` keyed items and returns a cursor for the last one
+// taken when more remain
+fn page_after<T>(items: impl Iterator<Item = (u64, T)>, limit: u64, tag: u8) -> (Vec<T>, Option<String>) {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    let mut items = items.peekable();
+    let mut page = Vec::new();
+    let mut last_key = None;
+
+    while page.len() < limit {
+        match items.next() {
+            Some((key, item)) => {
+                last_key = Some(key);
+                page.push(item);
+            }
+            None => break,
+        }
+    }
+
+    let next_cursor = match items.peek() {
+        Some(_) => last_key.map(|key| encode_cursor(tag, key)),
+        None => None,
+    };
+    (page, next_cursor)
+}
+
+// Takes assets in order until the next one would push the estimated
+// response past `budget`, and reports that asset's id as the cursor
+fn take_within_budget(assets: impl Iterator<Item = Asset>, budget: usize) -> AssetChunk {
+    let mut chunk = AssetChunk {
+        assets: Vec::new(),
+        truncated: false,
+        next_cursor: None,
+    };
+    let mut used = 0;
+
+    for asset in assets {
+        let size = estimated_size(&asset);
+        if used + size > budget {
+            chunk.truncated = true;
+            chunk.next_cursor = Some(asset.id);
+            break;
+        }
+        used += size;
+        chunk.assets.push(asset);
+    }
+
+    chunk
+}
+
+#[query]
+fn get_total_assets() -> u64 {
+    ASSETS.with(|assets| {
+        assets.borrow().len()
+    })
+}
+
+// File upload and storage methods
+
+// The file type is optional; when given, the bytes are checked against it and
+// the file is served with the matching content type
+#[update]
+fn upload_file(file_hash: String, file_data: Vec<u8>, file_type: Option<String>) -> Result<FileUpload, AssetError> {
+    ensure_running("upload_file")?;
+    let principal = caller();
+    
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::Upload)?;
+    upload_file_as(file_hash, file_data, file_type, principal, time())
+}
+
+fn upload_file_as(
+    file_hash: String,
+    file_data: Vec<u8>,
+    file_type: Option<String>,
+    principal: Principal,
+    current_time: u64,
+) -> Result<FileUpload, AssetError> {
+    let file_type = match file_type {
+        Some(file_type) => validate_file_type(&file_type)?,
+        None => String::new(),
+    };
+
+    // Untyped uploads are served as a generic binary
+    let deduplicated = store_upload(&file_hash, &file_type, file_data, principal, current_time)?;
+    Ok(FileUpload { file_hash, deduplicated })
+}
+
+#[query]
+fn get_file(file_hash: String) -> Option<Vec<u8>> {
+    if !can_read_file(&file_hash, caller()) {
+        return None;
+    }
+
+    read_file_bytes(&file_hash)
+}
+
+#[query]
+fn get_file_chunk(file_hash: String, offset: u64, length: u64) -> Option<Vec<u8>> {
+    if !can_read_file(&file_hash, caller()) {
+        return None;
+    }
+
+    read_file_chunk(&file_hash, offset, length)
+}
+
+fn read_file_chunk(file_hash: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+    served_range(file_hash, offset, length.min(MAX_FILE_CHUNK_SIZE), false)
+}
+
+#[query]
+fn get_file_info(file_hash: String) -> Option<FileInfo> {
+    file_info_of(&file_hash)
+}
+
+fn file_info_of(file_hash: &String) -> Option<FileInfo> {
+    if let Some(info) = FILE_INFO.with(|file_info| file_info.borrow().get(file_hash)) {
+        return Some(info);
+    }
+
+    // Files stored before file info was tracked only have their raw bytes
+    stored_file_len(file_hash).map(|total_size| FileInfo {
+        file_hash: file_hash.clone(),
+        total_size,
+        content_type: content_type_for(""),
+        uploaded_by: None,
+        encoding: None,
+        uploaded_at: None,
+    })
+}
+
+// Asset image gallery. Images are stored in the canister and shown in the
+// order the owner chooses; the first one is the asset's preview.
+#[update]
+fn add_asset_image(asset_id: u64, bytes: Vec<u8>, content_type: String) -> Result<AssetImage, AssetError> {
+    ensure_running("add_asset_image")?;
+    let principal = caller();
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if gallery_of(asset_id).image_ids.len() >= MAX_ASSET_IMAGES {
+        return Err(AssetError::InvalidInput(format!(
+            "images: an asset can have at most {} images",
+            MAX_ASSET_IMAGES
+        )));
+    }
+    let content_type = validate_asset_image(&bytes, content_type)?;
+    ensure_storage_available(principal, bytes.len() as u64)?;
+
+    let image_id = store_asset_image(principal, bytes, content_type);
+    ASSET_GALLERIES.with(|galleries| {
+        let mut galleries = galleries.borrow_mut();
+        let mut gallery = galleries.get(&asset_id).unwrap_or_default();
+        gallery.image_ids.push(image_id);
+        galleries.insert(asset_id, gallery);
+    });
+    sync_preview_image_url(asset_id);
+
+    asset_images_of(asset_id)
+        .into_iter()
+        .find(|image| image.id == image_id)
+        .ok_or(AssetError::NotFound)
+}
+
+#[update]
+fn remove_asset_image(asset_id: u64, image_index: u32) -> Result<Vec<AssetImage>, AssetError> {
+    ensure_running("remove_asset_image")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+
+    let mut gallery = gallery_of(asset_id);
+    if image_index as usize >= gallery.image_ids.len() {
+        return Err(AssetError::InvalidInput("image_index: out of range".to_string()));
+    }
+    let image_id = gallery.image_ids.remove(image_index as usize);
+    delete_asset_image(image_id);
+    save_gallery(asset_id, gallery);
+    sync_preview_image_url(asset_id);
+
+    Ok(asset_images_of(asset_id))
+}
+
+// new_order lists the current image indexes in their new order
+#[update]
+fn reorder_asset_images(asset_id: u64, new_order: Vec<u32>) -> Result<Vec<AssetImage>, AssetError> {
+    ensure_running("reorder_asset_images")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+
+    let gallery = gallery_of(asset_id);
+    let mut sorted = new_order.clone();
+    sorted.sort_unstable();
+    if !sorted.iter().copied().eq(0..gallery.image_ids.len() as u32) {
+        return Err(AssetError::InvalidInput(
+            "new_order: must list every image index exactly once".to_string(),
+        ));
+    }
+
+    let image_ids = new_order
+        .iter()
+        .map(|index| gallery.image_ids[*index as usize])
+        .collect();
+    save_gallery(asset_id, AssetGallery { image_ids });
+    sync_preview_image_url(asset_id);
+
+    Ok(asset_images_of(asset_id))
+}
+
+#[query]
+fn get_asset_images(asset_id: u64) -> Vec<AssetImage> {
+    match asset_by_id(asset_id) {
+        Some(asset) if can_view(&asset, caller()) => asset_images_of(asset_id),
+        _ => Vec::new(),
+    }
+}
+
+// Replaces the first gallery image, or adds one if the gallery is empty.
+// Kept for clients written before galleries existed.
+#[update]
+fn upload_preview_image(asset_id: u64, bytes: Vec<u8>, content_type: String) -> Result<(), AssetError> {
+    ensure_running("upload_preview_image")?;
+    let principal = caller();
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    let content_type = validate_asset_image(&bytes, content_type)?;
+
+    // The replaced image's bytes no longer count against its uploader
+    let mut gallery = gallery_of(asset_id);
+    let replaced = gallery
+        .image_ids
+        .first()
+        .and_then(|image_id| ASSET_IMAGES.with(|images| images.borrow().get(image_id)));
+    let freed = replaced
+        .filter(|image| image.uploaded_by == principal)
+        .map_or(0, |image| image.bytes.len() as u64);
+    let size = bytes.len() as u64;
+    if size > freed {
+        ensure_storage_available(principal, size - freed)?;
+    }
+
+    let image_id = store_asset_image(principal, bytes, content_type);
+    match gallery.image_ids.first_mut() {
+        Some(first) => {
+            delete_asset_image(*first);
+            *first = image_id;
+        },
+        None => gallery.image_ids.push(image_id),
+    }
+    save_gallery(asset_id, gallery);
+    sync_preview_image_url(asset_id);
+    Ok(())
+}
+
+#[query]
+fn get_preview_image(asset_id: u64) -> Option<PreviewImage> {
+    let asset = asset_by_id(asset_id).filter(|asset| can_view(asset, caller()))?;
+    let image_id = gallery_of(asset.id).image_ids.first().copied()?;
+    ASSET_IMAGES.with(|images| images.borrow().get(&image_id))
+}
+
+fn validate_asset_image(bytes: &[u8], content_type: String) -> Result<String, AssetError> {
+    let content_type = content_type.trim().to_lowercase();
+    if !PREVIEW_IMAGE_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(AssetError::InvalidInput(format!(
+            "content_type: must be one of {}",
+            PREVIEW_IMAGE_CONTENT_TYPES.join(", ")
+        )));
+    }
+    let max = get_upload_limits().max_preview_image_size;
+    if bytes.len() as u64 > max {
+        return Err(AssetError::FileTooLarge { max });
+    }
+    if sniff_content_type(bytes) != Some(content_type.as_str()) {
+        return Err(AssetError::InvalidInput("bytes: do not match content_type".to_string()));
+    }
+    Ok(content_type)
+}
+
+fn store_asset_image(principal: Principal, bytes: Vec<u8>, content_type: String) -> u64 {
+    let image_id = get_next_asset_image_id();
+    charge_storage(principal, bytes.len() as u64);
+    ASSET_IMAGE_DIGESTS.with(|digests| digests.borrow_mut().insert(image_id, Sha256::digest(&bytes).into()));
+    ASSET_IMAGES.with(|images| {
+        images.borrow_mut().insert(image_id, PreviewImage {
+            content_type,
+            bytes,
+            uploaded_by: principal,
+            uploaded_at: time(),
+        });
+    });
+    image_id
+}
+
+fn delete_asset_image(image_id: u64) {
+    if let Some(image) = ASSET_IMAGES.with(|images| images.borrow_mut().remove(&image_id)) {
+        credit_storage(image.uploaded_by, image.bytes.len() as u64);
+    }
+    ASSET_IMAGE_DIGESTS.with(|digests| digests.borrow_mut().remove(&image_id));
+}
+
+fn gallery_of(asset_id: u64) -> AssetGallery {
+    ASSET_GALLERIES.with(|galleries| galleries.borrow().get(&asset_id).unwrap_or_default())
+}
+
+fn save_gallery(asset_id: u64, gallery: AssetGallery) {
+    ASSET_GALLERIES.with(|galleries| {
+        let mut galleries = galleries.borrow_mut();
+        if gallery.image_ids.is_empty() {
+            galleries.remove(&asset_id);
+        } else {
+            galleries.insert(asset_id, gallery);
+        }
+    });
+}
+
+fn asset_images_of(asset_id: u64) -> Vec<AssetImage> {
+    ASSET_IMAGES.with(|images| {
+        let images = images.borrow();
+        gallery_of(asset_id)
+            .image_ids
+            .into_iter()
+            .filter_map(|image_id| {
+                images.get(&image_id).map(|image| AssetImage {
+                    id: image_id,
+                    content_type: image.content_type,
+                    size: image.bytes.len() as u64,
+                    uploaded_at: image.uploaded_at,
+                    url: format!("/asset/{}/image/{}", asset_id, image_id),
+                })
+            })
+            .collect()
+    })
+}
+
+fn remove_asset_images(asset_id: u64) {
+    for image_id in gallery_of(asset_id).image_ids {
+        delete_asset_image(image_id);
+    }
+    ASSET_GALLERIES.with(|galleries| {
+        galleries.borrow_mut().remove(&asset_id);
+    });
+    certify_gallery(asset_id);
+}
+
+fn gallery_preview_url(asset_id: u64) -> String {
+    format!("https://{}.raw.icp0.io/asset/{}/preview", ic_cdk::id(), asset_id)
+}
+
+// Keeps preview_image_url pointing at the first gallery image so clients that
+// only know about the single preview keep working
+fn sync_preview_image_url(asset_id: u64) {
+    update_preview_image_url(asset_id);
+    certify_gallery(asset_id);
+}
+
+fn update_preview_image_url(asset_id: u64) {
+    let has_images = !gallery_of(asset_id).image_ids.is_empty();
+    let gallery_url = gallery_preview_url(asset_id);
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = match assets.get(&asset_id) {
+            Some(asset) => asset,
+            None => return,
+        };
+
+        let preview_image_url = if has_images {
+            Some(gallery_url)
+        } else if asset.preview_image_url.as_ref() == Some(&gallery_url) {
+            None
+        } else {
+            return;
+        };
+        if asset.preview_image_url == preview_image_url {
+            return;
+        }
+
+        let previous_refs = file_refs_of(&asset);
+        asset.preview_image_url = preview_image_url;
+        asset.updated_at = time();
+        let current_refs = file_refs_of(&asset);
+        for file_hash in previous_refs.iter().filter(|file_hash| !current_refs.contains(file_hash)) {
+            release_file_ref(file_hash, asset_id);
+        }
+        assets.insert(asset_id, asset);
+    });
+}
+
+// Moves previews stored before galleries existed into the first gallery slot
+fn migrate_preview_images() {
+    let legacy: Vec<(u64, PreviewImage)> = PREVIEW_IMAGES.with(|images| images.borrow().iter().collect());
+
+    for (asset_id, image) in legacy {
+        let image_id = get_next_asset_image_id();
+        ASSET_IMAGES.with(|images| {
+            images.borrow_mut().insert(image_id, image);
+        });
+        let mut gallery = gallery_of(asset_id);
+        gallery.image_ids.insert(0, image_id);
+        gallery.image_ids.truncate(MAX_ASSET_IMAGES);
+        save_gallery(asset_id, gallery);
+        PREVIEW_IMAGES.with(|images| {
+            images.borrow_mut().remove(&asset_id);
+        });
+        sync_preview_image_url(asset_id);
+    }
+}
+
+// Replaces the asset's model with a new version. Links, sales and ratings stay
+// with the asset, and earlier versions remain downloadable.
+#[update]
+fn publish_asset_version(
+    asset_id: u64,
+    file_hash: String,
+    file_data: Vec<u8>,
+    changelog: String,
+) -> Result<AssetVersion, AssetError> {
+    ensure_running("publish_asset_version")?;
+    let principal = caller();
+    let current_time = time();
+
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+
+    let changelog = changelog.trim().to_string();
+    if changelog.chars().count() > MAX_CHANGELOG_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "changelog: must be at most {} characters",
+            MAX_CHANGELOG_LENGTH
+        )));
+    }
+    if file_data.is_empty() {
+        return Err(AssetError::InvalidInput("file_data: must not be empty".to_string()));
+    }
+    verify_declared_hash(&file_hash, &sha256_hex(&file_data))?;
+    if file_hash.eq_ignore_ascii_case(&asset.file_hash) {
+        return Err(AssetError::InvalidInput("file_hash: matches the current version".to_string()));
+    }
+
+    let file_size = file_data.len() as u64;
+    if !is_file_stored(&file_hash) {
+        ensure_storage_available(principal, file_size)?;
+        store_file(file_hash.clone(), &asset.file_type, file_data, principal, current_time);
+    } else {
+        ensure_matches_stored(&file_hash, &file_data)?;
+        record_file_supplier(&file_hash, principal);
+    }
+
+    let mut versions = versions_of(&asset);
+    let version = AssetVersion {
+        version: versions.last().map_or(1, |latest| latest.version + 1),
+        file_hash: file_hash.clone(),
+        file_size,
+        changelog,
+        timestamp: current_time,
+    };
+    versions.push(version.clone());
+    ASSET_VERSIONS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for version in versions {
+            stored.insert((asset_id, version.version), version);
+        }
+    });
+
+    // The replaced file stays referenced by its version record
+    add_file_ref(&file_hash, asset_id);
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        if let Some(mut asset) = assets.get(&asset_id) {
+            asset.file_url = format!("canister://{}", file_hash);
+            asset.file_hash = file_hash;
+            asset.file_size = file_size;
+            asset.updated_at = current_time;
+            assets.insert(asset_id, asset);
+        }
+    });
+
+    Ok(version)
+}
+
+#[query]
+fn get_asset_versions(asset_id: u64) -> Result<Vec<AssetVersion>, AssetError> {
+    match asset_by_id(asset_id) {
+        Some(asset) if can_view(&asset, caller()) => Ok(versions_of(&asset)),
+        _ => Err(AssetError::NotFound),
+    }
+}
+
+#[query]
+fn get_asset_file_at_version(asset_id: u64, version: u32) -> Result<Vec<u8>, AssetError> {
+    let principal = caller();
+
+    let asset = asset_by_id(asset_id)
+        .filter(|asset| can_view(asset, principal))
+        .ok_or(AssetError::NotFound)?;
+    if !can_download(&asset, principal) {
+        return Err(AssetError::Unauthorized);
+    }
+
+    let version = versions_of(&asset)
+        .into_iter()
+        .find(|stored| stored.version == version)
+        .ok_or(AssetError::NotFound)?;
+    read_file_bytes(&version.file_hash).ok_or(AssetError::NotFound)
+}
+
+// Version history of an asset, oldest first. Assets that were never updated
+// only have their original upload, which is not stored as a version record.
+fn versions_of(asset: &Asset) -> Vec<AssetVersion> {
+    let versions: Vec<AssetVersion> = ASSET_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .range((asset.id, 0)..=(asset.id, u32::MAX))
+            .map(|(_, version)| version)
+            .collect()
+    });
+    if !versions.is_empty() {
+        return versions;
+    }
+
+    vec![AssetVersion {
+        version: 1,
+        file_hash: asset.file_hash.clone(),
+        file_size: asset.file_size,
+        changelog: String::new(),
+        timestamp: asset.created_at,
+    }]
+}
+
+// Versions other than the current one; each holds a reference to its file
+fn earlier_versions_of(asset: &Asset) -> Vec<AssetVersion> {
+    let mut versions = versions_of(asset);
+    versions.pop();
+    versions
+}
+
+fn remove_asset_versions(asset: &Asset) {
+    for version in earlier_versions_of(asset) {
+        release_file_ref(&version.file_hash, asset.id);
+    }
+    ASSET_VERSIONS.with(|versions| {
+        let mut versions = versions.borrow_mut();
+        let keys: Vec<(u64, u32)> = versions
+            .range((asset.id, 0)..=(asset.id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            versions.remove(&key);
+        }
+    });
+}
+
+// Attachments sit alongside the asset's model and are downloaded by hash like
+// any other file. Files already stored are shared rather than stored again.
+#[update]
+fn add_asset_attachment(
+    asset_id: u64,
+    label: String,
+    file_hash: String,
+    file_data: Vec<u8>,
+    file_type: String,
+) -> Result<AssetAttachment, AssetError> {
+    ensure_running("add_asset_attachment")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::Upload)?;
+
+    attach_file(asset_id, principal, &label, file_hash, file_data, &file_type, time())
+}
+
+#[update]
+fn remove_asset_attachment(asset_id: u64, label: String) -> Result<Vec<AssetAttachment>, AssetError> {
+    ensure_running("remove_asset_attachment")?;
+    detach_file(asset_id, caller(), &label)
+}
+
+#[query]
+fn get_asset_attachments(asset_id: u64) -> Vec<AssetAttachment> {
+    match asset_by_id(asset_id) {
+        Some(asset) if can_view(&asset, caller()) => attachments_of(asset_id),
+        _ => Vec::new(),
+    }
+}
+
+fn attach_file(
+    asset_id: u64,
+    principal: Principal,
+    label: &str,
+    file_hash: String,
+    file_data: Vec<u8>,
+    file_type: &str,
+    current_time: u64,
+) -> Result<AssetAttachment, AssetError> {
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+
+    let label = validate_attachment_label(label)?;
+    let mut attachments = attachments_of(asset_id);
+    if attachments.iter().any(|attachment| attachment.label == label) {
+        return Err(AssetError::AlreadyExists);
+    }
+    let max_attachments = get_upload_limits().max_attachments;
+    if attachments.len() >= max_attachments as usize {
+        return Err(AssetError::InvalidInput(format!(
+            "attachments: an asset can have at most {} attachments",
+            max_attachments
+        )));
+    }
+    if file_data.is_empty() {
+        return Err(AssetError::InvalidInput("file_data: must not be empty".to_string()));
+    }
+    let file_type = validate_attachment_type(file_type)?;
+    ensure_file_size(&file_type, file_data.len() as u64)?;
+    verify_declared_hash(&file_hash, &sha256_hex(&file_data))?;
+    if get_allowed_file_types().contains(&file_type) {
+        validate_file_format(&file_type, &file_data)?;
+    }
+
+    let file_size = file_data.len() as u64;
+    store_or_reuse_file(file_hash.clone(), &file_type, file_data, principal, current_time)?;
+    add_file_ref(&file_hash, asset_id);
+
+    let attachment = AssetAttachment {
+        label,
+        file_hash,
+        file_type,
+        file_size,
+        added_at: current_time,
+    };
+    attachments.push(attachment.clone());
+    save_attachments(asset_id, attachments);
+
+    Ok(attachment)
+}
+
+fn detach_file(asset_id: u64, principal: Principal, label: &str) -> Result<Vec<AssetAttachment>, AssetError> {
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+
+    let mut attachments = attachments_of(asset_id);
+    let index = attachments
+        .iter()
+        .position(|attachment| attachment.label == label.trim())
+        .ok_or(AssetError::NotFound)?;
+    let removed = attachments.remove(index);
+    save_attachments(asset_id, attachments.clone());
+    release_file_ref(&removed.file_hash, asset_id);
+
+    Ok(attachments)
+}
+
+fn validate_attachment_label(label: &str) -> Result<String, AssetError> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(AssetError::InvalidInput("label: must not be empty".to_string()));
+    }
+    if label.chars().count() > MAX_ATTACHMENT_LABEL_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "label: must be at most {} characters",
+            MAX_ATTACHMENT_LABEL_LENGTH
+        )));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(AssetError::InvalidInput("label: must not contain control characters".to_string()));
+    }
+    Ok(label.to_string())
+}
+
+fn validate_attachment_type(file_type: &str) -> Result<String, AssetError> {
+    let file_type = normalize_file_type(file_type);
+    let mut allowed = get_allowed_file_types();
+    allowed.extend(ATTACHMENT_FILE_TYPES.iter().map(|file_type| file_type.to_string()));
+    if !allowed.contains(&file_type) {
+        return Err(AssetError::InvalidInput(format!(
+            "file_type: must be one of {}",
+            allowed.join(", ")
+        )));
+    }
+    Ok(file_type)
+}
+
+fn attachments_of(asset_id: u64) -> Vec<AssetAttachment> {
+    ASSET_ATTACHMENTS.with(|attachments| {
+        attachments
+            .borrow()
+            .get(&asset_id)
+            .unwrap_or_default()
+            .attachments
+    })
+}
+
+fn save_attachments(asset_id: u64, attachments: Vec<AssetAttachment>) {
+    ASSET_ATTACHMENTS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        if attachments.is_empty() {
+            stored.remove(&asset_id);
+        } else {
+            stored.insert(asset_id, AssetAttachments { attachments });
+        }
+    });
+}
+
+fn remove_asset_attachments(asset_id: u64) {
+    let removed = ASSET_ATTACHMENTS.with(|attachments| attachments.borrow_mut().remove(&asset_id));
+    for attachment in removed.unwrap_or_default().attachments {
+        release_file_ref(&attachment.file_hash, asset_id);
+    }
+}
+
+#[query]
+fn verify_file_integrity(file_hash: String) -> Option<FileIntegrityReport> {
+    read_file_bytes(&file_hash).map(|file_data| {
+        let computed_hash = sha256_hex(&file_data);
+        FileIntegrityReport {
+            matches: file_hash.eq_ignore_ascii_case(&computed_hash),
+            file_hash: file_hash.clone(),
+            computed_hash,
+            total_size: file_data.len() as u64,
+        }
+    })
+}
+
+// Chunked upload sessions for files that do not fit in a single ingress message
+#[update]
+fn begin_upload(file_hash: String, total_size: u64, file_type: String) -> Result<u64, String> {
+    ensure_running("begin_upload").map_err(|err| err.to_string())?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous users cannot upload files".to_string());
+    }
+    enforce_rate_limit(RateLimitedAction::Upload).map_err(|err| err.to_string())?;
+
+    if total_size == 0 {
+        return Err("Total size must be greater than zero".to_string());
+    }
+
+    ensure_file_size(&file_type, total_size).map_err(|err| err.to_string())?;
+
+    if is_file_stored(&file_hash) {
+        return Err("File already exists".to_string());
+    }
+
+    // The declared size stays reserved against the quota until the session ends
+    ensure_storage_available(principal, total_size).map_err(|err| err.to_string())?;
+
+    let session_id = get_next_upload_session_id();
+    let current_time = time();
+
+    let session = UploadSession {
+        id: session_id,
+        owner: principal,
+        file_hash,
+        file_type,
+        total_size,
+        received_size: 0,
+        chunk_count: 0,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session_id, session);
+    });
+
+    Ok(session_id)
+}
+
+#[update]
+fn upload_chunk(session_id: u64, chunk_index: u64, bytes: Vec<u8>) -> Result<UploadSession, String> {
+    ensure_running("upload_chunk").map_err(|err| err.to_string())?;
+    let mut session = get_owned_upload_session(session_id)?;
+
+    if chunk_index < session.chunk_count {
+        return Err(format!(
+            "Chunk {} was already uploaded; use replace_chunk to overwrite it",
+            chunk_index
+        ));
+    }
+
+    if chunk_index > session.chunk_count {
+        return Err(format!(
+            "Chunk {} is out of order, expected chunk {}",
+            chunk_index, session.chunk_count
+        ));
+    }
+
+    if bytes.is_empty() {
+        return Err("Chunk must not be empty".to_string());
+    }
+
+    let received_size = session.received_size + bytes.len() as u64;
+    if received_size > session.total_size {
+        return Err("Chunk exceeds the declared total size".to_string());
+    }
+
+    UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow_mut().insert((session_id, chunk_index), bytes);
+    });
+
+    session.received_size = received_size;
+    session.chunk_count += 1;
+    session.updated_at = time();
+
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session_id, session.clone());
+    });
+
+    Ok(session)
+}
+
+#[update]
+fn replace_chunk(session_id: u64, chunk_index: u64, bytes: Vec<u8>) -> Result<UploadSession, String> {
+    ensure_running("replace_chunk").map_err(|err| err.to_string())?;
+    let mut session = get_owned_upload_session(session_id)?;
+
+    if bytes.is_empty() {
+        return Err("Chunk must not be empty".to_string());
+    }
+
+    let previous_len = UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow().get(&(session_id, chunk_index)).map(|chunk| chunk.len() as u64)
+    });
+
+    let previous_len = match previous_len {
+        Some(len) => len,
+        None => return Err(format!("Chunk {} has not been uploaded yet", chunk_index)),
+    };
+
+    let received_size = session.received_size - previous_len + bytes.len() as u64;
+    if received_size > session.total_size {
+        return Err("Chunk exceeds the declared total size".to_string());
+    }
+
+    UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow_mut().insert((session_id, chunk_index), bytes);
+    });
+
+    session.received_size = received_size;
+    session.updated_at = time();
+
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session_id, session.clone());
+    });
+
+    Ok(session)
+}
+
+#[update]
+fn finish_upload(session_id: u64) -> Result<String, String> {
+    ensure_running("finish_upload").map_err(|err| err.to_string())?;
+    let session = get_owned_upload_session(session_id)?;
+
+    if session.received_size != session.total_size {
+        return Err(format!(
+            "Upload incomplete: received {} of {} bytes",
+            session.received_size, session.total_size
+        ));
+    }
+
+    // The limits may have been lowered since the session began
+    ensure_file_size(&session.file_type, session.total_size).map_err(|err| err.to_string())?;
+
+    if is_file_stored(&session.file_hash) {
+        return Err("File already exists".to_string());
+    }
+
+    let mut file_data = Vec::with_capacity(session.total_size as usize);
+    let mut hasher = Sha256::new();
+    UPLOAD_CHUNKS.with(|chunks| {
+        for (_, chunk) in chunks.borrow().range((session_id, 0)..=(session_id, u64::MAX)) {
+            hasher.update(&chunk);
+            file_data.extend_from_slice(&chunk);
+        }
+    });
+
+    if file_data.len() as u64 != session.total_size {
+        return Err(format!(
+            "Assembled size {} does not match declared size {}",
+            file_data.len(),
+            session.total_size
+        ));
+    }
+
+    verify_declared_hash(&session.file_hash, &to_hex(&hasher.finalize()))
+        .map_err(|err| err.to_string())?;
+    validate_file_format(&session.file_type, &file_data).map_err(|err| err.to_string())?;
+
+    // Dropping the session releases its quota reservation before the bytes are charged
+    remove_upload_session(session_id);
+
+    store_file(session.file_hash.clone(), &session.file_type, file_data, session.owner, time());
+
+    Ok(session.file_hash)
+}
+
+#[update]
+fn abort_upload(session_id: u64) -> Result<(), String> {
+    ensure_running("abort_upload").map_err(|err| err.to_string())?;
+    get_owned_upload_session(session_id)?;
+    remove_upload_session(session_id);
+    Ok(())
+}
+
+#[query]
+fn get_upload_session(session_id: u64) -> Option<UploadSession> {
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow().get(&session_id)
+    })
+}
+
+fn get_owned_upload_session(session_id: u64) -> Result<UploadSession, String> {
+    let principal = caller();
+
+    match UPLOAD_SESSIONS.with(|sessions| sessions.borrow().get(&session_id)) {
+        Some(session) => {
+            if session.owner != principal {
+                return Err("Only the session owner can modify this upload".to_string());
+            }
+            Ok(session)
+        },
+        None => Err("Upload session not found".to_string()),
+    }
+}
+
+fn remove_upload_session(session_id: u64) {
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let chunk_keys: Vec<(u64, u64)> = chunks
+            .range((session_id, 0)..=(session_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in chunk_keys {
+            chunks.remove(&key);
+        }
+    });
+
+    UPLOAD_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&session_id);
+    });
+}
+
+#[update]
+fn upload_asset_with_file(asset_input: AssetInput, file_data: Vec<u8>) -> Result<AssetUploadV1Response, String> {
+    ensure_running("upload_asset_with_file").map_err(|err| err.to_string())?;
+    create_asset_with_file(asset_input, file_data)
+        .map(AssetUploadV1Response::from)
+        .map_err(upload_error_v1)
+}
+
+fn create_asset_with_file(asset_input: AssetInput, file_data: Vec<u8>) -> Result<AssetUpload, AssetError> {
+    let principal = caller();
+    
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::Upload)?;
+    create_asset_with_file_as(asset_input, file_data, principal, time())
+}
+
+fn create_asset_with_file_as(
+    asset_input: AssetInput,
+    file_data: Vec<u8>,
+    principal: Principal,
+    current_time: u64,
+) -> Result<AssetUpload, AssetError> {
+    let asset_input = validate_asset_input(asset_input)?;
+
+    // Store the file hash before moving asset_input
+    let file_hash = asset_input.file_hash.clone();
+    let category = active_category(asset_input.category_id)?;
+    if let Some(preview_hash) = preview_file_hash(&asset_input.preview_image_url) {
+        ensure_may_reference(&preview_hash, principal)?;
+    }
+
+    // First upload the file; identical bytes already stored are shared, never
+    // overwritten
+    let deduplicated = store_upload(&file_hash, &asset_input.file_type, file_data, principal, current_time)?;
+
+    // Then create the asset record
+    let asset_id = get_next_asset_id();
+
+    let mut asset = Asset {
+        id: asset_id,
+        name: asset_input.name,
+        description: asset_input.description,
+        owner: principal,
+        file_hash: file_hash.clone(),
+        file_url: format!("canister://{}", file_hash), // Internal canister URL
+        file_type: asset_input.file_type,
+        file_size: asset_input.file_size,
+        prices: icp_prices(asset_input.price),
+        is_for_sale: false,
+        created_at: current_time,
+        updated_at: current_time,
+        category_id: category.id,
+        category: category.name,
+        tags: asset_input.tags,
+        preview_image_url: asset_input.preview_image_url,
+        creator: principal,
+        royalty_bps: asset_input.royalty_bps.unwrap_or(0),
+        hidden: None,
+        visibility: Visibility::Public,
+        metadata: Vec::new(),
+        archived_at: None,
+        license: asset_input.license,
+        slug: None,
+        reservation: None,
+        source: None,
+        frozen: None,
+    };
+    assign_slug(&mut asset, current_time);
+
+    for file_hash in file_refs_of(&asset) {
+        add_file_ref(&file_hash, asset.id);
+    }
+    index_asset(&asset);
+    record_ownership_event(asset_id, None, principal, OwnershipEventKind::Upload, None, current_time);
+    record_creation_price(&asset, principal, current_time);
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        assets.insert(asset_id, asset.clone());
+    });
+
+    Ok(AssetUpload { asset, deduplicated })
+}
+
+#[update]
+fn marketplace_transfer_asset(asset_id: u64, seller: Principal, buyer: Principal, price: u64) -> Result<Asset, AssetError> {
+    ensure_running("marketplace_transfer_asset")?;
+    transfer_for_marketplace(caller(), asset_id, seller, buyer, price, time())
+}
+
+fn transfer_for_marketplace(
+    marketplace: Principal,
+    asset_id: u64,
+    seller: Principal,
+    buyer: Principal,
+    price: u64,
+    current_time: u64,
+) -> Result<Asset, AssetError> {
+    if !is_authorized_marketplace(&marketplace) {
+        return Err(AssetError::Unauthorized);
+    }
+
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                // Verify the seller is the current owner
+                if asset.owner != seller {
+                    return Err(AssetError::NotOwner);
+                }
+                
+                // Verify the asset is for sale
+                if !is_on_sale(&asset, current_time) {
+                    return Err(AssetError::NotForSale);
+                }
+
+                if is_transfer_in_progress(asset_id) {
+                    return Err(AssetError::TransferInProgress);
+                }
+                check_not_frozen(&asset)?;
+                // A hold taken by the marketplace itself covers any of its buyers
+                check_reservation(&asset, marketplace, current_time)
+                    .or_else(|_| check_reservation(&asset, buyer, current_time))?;
+                
+                // Transfer ownership
+                change_owner(&mut asset, buyer, OwnershipEventKind::MarketplaceSale, Some(price), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    // The marketplace settles payment itself, so the royalty is only recorded here
+    record_sale(asset_id, seller, buyer, price, royalty_for(&asset, seller, price), None, current_time);
+
+    Ok(asset)
+}
+
+fn record_sale(
+    asset_id: u64,
+    seller: Principal,
+    buyer: Principal,
+    price: u64,
+    royalty: Option<Royalty>,
+    platform_fee: Option<PlatformFee>,
+    current_time: u64,
+) -> SaleRecord {
+    store_sale(sale_record(asset_id, seller, buyer, price, royalty, platform_fee, current_time))
+}
+
+// Builds a sale settled in ICP; callers settling in another token set the
+// ledger before storing it
+fn sale_record(
+    asset_id: u64,
+    seller: Principal,
+    buyer: Principal,
+    price: u64,
+    royalty: Option<Royalty>,
+    platform_fee: Option<PlatformFee>,
+    current_time: u64,
+) -> SaleRecord {
+    let seller_amount = price
+        - royalty.as_ref().map_or(0, |royalty| royalty.amount)
+        - platform_fee.as_ref().map_or(0, |fee| fee.amount);
+    SaleRecord {
+        id: get_next_sale_id(),
+        asset_id,
+        seller,
+        buyer,
+        price,
+        timestamp: current_time,
+        royalty,
+        platform_fee,
+        seller_amount: Some(seller_amount),
+        license: asset_by_id(asset_id).map(|asset| asset.license),
+        seller_paid: None,
+        ledger: get_ledger_canister_principal().ok(),
+    }
+}
+
+fn store_sale(sale: SaleRecord) -> SaleRecord {
+    let (asset_id, seller, buyer, price, current_time) =
+        (sale.asset_id, sale.seller, sale.buyer, sale.price, sale.timestamp);
+    SALES.with(|sales| {
+        sales.borrow_mut().insert(sale.id, sale.clone());
+    });
+    PURCHASE_INDEX.with(|index| index.borrow_mut().insert((buyer, asset_id), ()));
+    ASSET_SALE_INDEX.with(|index| index.borrow_mut().insert((asset_id, sale.id), ()));
+    tally_sale(&sale);
+    prune_sale_tallies(current_time);
+    record_activity(asset_id, Some(buyer), ActivityKind::Sold { seller, buyer, price }, current_time);
+
+    sale
+}
+
+// Direct purchases paid through the ICP ledger. The buyer must first approve
+// this canister (icrc2_approve) for at least the asset price plus four ledger
+// fees: one for pulling the payment in and one each for paying out the seller,
+// creator royalty and platform fee. The asset only changes hands once the
+// whole payment is held. A coupon code from the seller lowers the price before
+// royalty and fee are taken.
+#[update]
+async fn purchase_asset(asset_id: u64, coupon_code: Option<String>) -> Result<Asset, AssetError> {
+    ensure_running("purchase_asset")?;
+    purchase_in(asset_id, None, coupon_code).await
+}
+
+// Pays in another supported token at the owner's price for it. The approval
+// works as for purchase_asset, but on the chosen ledger. Coupons only apply
+// to ICP prices.
+#[update]
+async fn purchase_asset_with_ledger(
+    asset_id: u64,
+    ledger: Principal,
+    coupon_code: Option<String>,
+) -> Result<Asset, AssetError> {
+    ensure_running("purchase_asset_with_ledger")?;
+    purchase_in(asset_id, Some(ledger), coupon_code).await
+}
+
+// A ledger of None, or the ICP ledger itself, pays the ICP price
+async fn purchase_in(
+    asset_id: u64,
+    ledger: Option<Principal>,
+    coupon_code: Option<String>,
+) -> Result<Asset, AssetError> {
+    let buyer = caller();
+
+    if buyer == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let icp_ledger = get_ledger_canister_principal().ok();
+    let ledger = ledger.filter(|ledger| Some(*ledger) != icp_ledger);
+    let ledger_canister_id = match ledger {
+        Some(ledger) if is_supported_ledger(ledger) => ledger,
+        Some(_) => return Err(AssetError::UnsupportedLedger),
+        None => get_ledger_canister_principal().map_err(AssetError::PaymentFailed)?,
+    };
+
+    let ReservedPurchase { seller, price, royalty, platform_fee, coupon } =
+        reserve_purchase_in(asset_id, buyer, ledger, coupon_code.as_deref(), time())?;
+
+    let legs = payout_leg_count(&royalty, &platform_fee);
+    let transfer_result = collect_payment(ledger_canister_id, buyer, price, legs, asset_id).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+
+    // A failed or rejected call means the ledger did not commit the transfer, so
+    // in every failure case the asset is still untouched and no funds have moved
+    if let Err(err) = transfer_result {
+        release_coupon(coupon.as_deref());
+        return Err(AssetError::PaymentFailed(err));
+    }
+
+    let current_time = time();
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(price), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    let sale = store_sale(collected_sale(
+        sale_record(asset_id, seller, buyer, price, royalty, platform_fee, current_time),
+        ledger_canister_id,
+    ));
+
+    // The whole payment is already held, so a leg the ledger refuses now is
+    // retried later rather than undoing the sale
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, PayoutSource::Settlement, current_time)).await;
+
+    Ok(asset)
+}
+
+// Terms of a fixed-price purchase, fixed before the payment is made
+struct ReservedPurchase {
+    seller: Principal,
+    price: u64,
+    royalty: Option<Royalty>,
+    platform_fee: Option<PlatformFee>,
+    coupon: Option<String>, // key of the coupon whose use was taken, if any
+}
+
+// Validates a fixed-price purchase and locks the asset before the payment is
+// awaited, so the owner cannot reprice, delist or transfer it while the
+// payment is in flight. The caller must clear PENDING_PURCHASES afterwards,
+// and give the coupon use back with release_coupon if the payment fails.
+fn reserve_purchase(
+    asset_id: u64,
+    buyer: Principal,
+    coupon_code: Option<&str>,
+    current_time: u64,
+) -> Result<ReservedPurchase, AssetError> {
+    reserve_purchase_in(asset_id, buyer, None, coupon_code, current_time)
+}
+
+// As reserve_purchase, at the asset's price in a token other than ICP when a
+// ledger is given
+fn reserve_purchase_in(
+    asset_id: u64,
+    buyer: Principal,
+    ledger: Option<Principal>,
+    coupon_code: Option<&str>,
+    current_time: u64,
+) -> Result<ReservedPurchase, AssetError> {
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if !is_on_sale(&asset, current_time) {
+        return Err(AssetError::NotForSale);
+    }
+    if asset.owner == buyer {
+        return Err(AssetError::InvalidInput("Cannot buy your own asset".to_string()));
+    }
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    check_reservation(&asset, buyer, current_time)?;
+
+    let list_price = match ledger {
+        Some(ledger) => price_in(&asset, ledger).ok_or(AssetError::NotPricedInLedger)?,
+        None => icp_price(&asset).ok_or(AssetError::NotPricedInLedger)?,
+    };
+
+    // Taken last, so a purchase that fails validation never uses up a coupon
+    let (price, coupon) = match coupon_code.filter(|code| !code.trim().is_empty()) {
+        Some(_) if ledger.is_some() => return Err(AssetError::CouponNotApplicable),
+        Some(code) => {
+            let key = coupon_key(asset.owner, &coupon_code_hash(code));
+            (use_coupon(&key, &asset, list_price, current_time)?, Some(key))
+        },
+        None => (list_price, None),
+    };
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, buyer);
+    });
+    // The fee is fixed here so that a fee change cannot affect a purchase
+    // whose payment is already in flight
+    Ok(ReservedPurchase {
+        seller: asset.owner,
+        price,
+        royalty: royalty_for(&asset, asset.owner, price),
+        platform_fee: platform_fee_for(price),
+        coupon,
+    })
+}
+
+#[update]
+fn create_coupon(
+    scope: CouponScope,
+    code_hash: String,
+    percent_off: u8,
+    max_uses: u32,
+    expires_at: Option<u64>,
+) -> Result<Coupon, AssetError> {
+    ensure_running("create_coupon")?;
+    open_coupon(caller(), scope, &code_hash, percent_off, max_uses, expires_at, time())
+}
+
+#[query]
+fn get_my_coupons() -> Vec<Coupon> {
+    coupons_of(caller())
+}
+
+#[update]
+fn revoke_coupon(code_hash: String) -> Result<(), AssetError> {
+    ensure_running("revoke_coupon")?;
+    COUPONS
+        .with(|coupons| coupons.borrow_mut().remove(&coupon_key(caller(), &code_hash)))
+        .map(|_| ())
+        .ok_or(AssetError::CouponNotFound)
+}
+
+fn open_coupon(
+    seller: Principal,
+    scope: CouponScope,
+    code_hash: &str,
+    percent_off: u8,
+    max_uses: u32,
+    expires_at: Option<u64>,
+    current_time: u64,
+) -> Result<Coupon, AssetError> {
+    if seller == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let code_hash = code_hash.trim().to_lowercase();
+    if code_hash.len() != 64 || !code_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AssetError::InvalidInput("code_hash: must be a hex-encoded SHA-256 digest".to_string()));
+    }
+    if percent_off == 0 || percent_off > MAX_COUPON_PERCENT_OFF {
+        return Err(AssetError::InvalidInput(format!(
+            "percent_off: must be between 1 and {}",
+            MAX_COUPON_PERCENT_OFF
+        )));
+    }
+    if max_uses == 0 {
+        return Err(AssetError::InvalidInput("max_uses: must be at least 1".to_string()));
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= current_time) {
+        return Err(AssetError::InvalidInput("expires_at: must be in the future".to_string()));
+    }
+    if let CouponScope::Asset(asset_id) = scope {
+        let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+        if asset.owner != seller {
+            return Err(AssetError::NotOwner);
+        }
+    }
+
+    let key = coupon_key(seller, &code_hash);
+    if COUPONS.with(|coupons| coupons.borrow().contains_key(&key)) {
+        return Err(AssetError::AlreadyExists);
+    }
+    if coupons_of(seller).len() >= MAX_COUPONS_PER_SELLER {
+        return Err(AssetError::InvalidInput(format!(
+            "A seller can have at most {} coupons",
+            MAX_COUPONS_PER_SELLER
+        )));
+    }
+
+    let coupon = Coupon {
+        code_hash,
+        seller,
+        scope,
+        percent_off,
+        max_uses,
+        remaining_uses: max_uses,
+        expires_at,
+        created_at: current_time,
+    };
+    COUPONS.with(|coupons| coupons.borrow_mut().insert(key, coupon.clone()));
+    Ok(coupon)
+}
+
+// Takes one use of the coupon and returns the discounted price
+fn use_coupon(key: &str, asset: &Asset, price: u64, current_time: u64) -> Result<u64, AssetError> {
+    COUPONS.with(|coupons| {
+        let mut coupons = coupons.borrow_mut();
+        let mut coupon = coupons.get(&key.to_string()).ok_or(AssetError::CouponNotFound)?;
+        if coupon.is_expired(current_time) {
+            return Err(AssetError::CouponExpired);
+        }
+        if coupon.remaining_uses == 0 {
+            return Err(AssetError::CouponUsedUp);
+        }
+        if coupon.scope != CouponScope::Asset(asset.id) && coupon.scope != CouponScope::AllMyAssets {
+            return Err(AssetError::CouponNotApplicable);
+        }
+
+        coupon.remaining_uses -= 1;
+        let discount = (price as u128 * coupon.percent_off as u128 / 100) as u64;
+        coupons.insert(key.to_string(), coupon);
+        Ok(price - discount)
+    })
+}
+
+fn release_coupon(key: Option<&str>) {
+    let Some(key) = key else {
+        return;
+    };
+    COUPONS.with(|coupons| {
+        let mut coupons = coupons.borrow_mut();
+        if let Some(mut coupon) = coupons.get(&key.to_string()) {
+            coupon.remaining_uses = (coupon.remaining_uses + 1).min(coupon.max_uses);
+            coupons.insert(key.to_string(), coupon);
+        }
+    });
+}
+
+fn coupons_of(seller: Principal) -> Vec<Coupon> {
+    let prefix = coupon_key(seller, "");
+    COUPONS.with(|coupons| {
+        coupons
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, coupon)| coupon)
+            .collect()
+    })
+}
+
+fn coupon_key(seller: Principal, code_hash: &str) -> String {
+    format!("{}:{}", seller.to_text(), code_hash.trim().to_lowercase())
+}
+
+// Codes are matched the same way as gift claim codes
+fn coupon_code_hash(code: &str) -> String {
+    sha256_hex(code.trim().to_lowercase().as_bytes())
+}
+
+// Escrowed purchases. The buyer's payment is pulled into a subaccount of this
+// canister and only released to the seller once the buyer confirms the file,
+// or the refund window runs out. The buyer must approve this canister for the
+// asset price plus one ledger fee for the pull and one per payout leg.
+#[update]
+async fn create_escrow_purchase(asset_id: u64) -> Result<Escrow, AssetError> {
+    ensure_running("create_escrow_purchase")?;
+    let buyer = caller();
+
+    if buyer == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    let mut escrow = prepare_escrow(asset_id, buyer, time())?;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, buyer);
+    });
+
+    let funded = fund_escrow(ledger_canister_id, &mut escrow).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+
+    funded.map_err(AssetError::PaymentFailed)?;
+
+    open_escrow(&escrow);
+    schedule_escrow_confirmation(escrow.id, escrow.expires_at);
+    Ok(escrow)
+}
+
+// The buyer may confirm at any time. Once the window is over the seller may
+// also confirm, which covers an automatic confirmation that failed.
+#[update]
+async fn confirm_purchase(asset_id: u64) -> Result<Asset, AssetError> {
+    ensure_running("confirm_purchase")?;
+    let principal = caller();
+    let escrow = active_escrow_of(asset_id).ok_or(AssetError::EscrowNotFound)?;
+
+    let window_over = time() >= escrow.expires_at;
+    if principal != escrow.buyer && !(principal == escrow.seller && window_over) {
+        return Err(AssetError::Unauthorized);
+    }
+
+    release_escrow(escrow, EscrowStatus::Confirmed).await
+}
+
+#[update]
+async fn request_refund(asset_id: u64) -> Result<Escrow, AssetError> {
+    ensure_running("request_refund")?;
+    let buyer = caller();
+    let escrow = active_escrow_of(asset_id).ok_or(AssetError::EscrowNotFound)?;
+
+    if escrow.buyer != buyer {
+        return Err(AssetError::Unauthorized);
+    }
+    if time() >= escrow.expires_at {
+        return Err(AssetError::RefundWindowClosed);
+    }
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, buyer);
+    });
+
+    // Everything held comes back, less the fee for this transfer
+    let refund_result = ledger_transfer(
+        ledger_canister_id,
+        escrow_subaccount(escrow.id),
+        buyer,
+        escrow.held - escrow.ledger_fee,
+        asset_id,
+    ).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+
+    refund_result.map_err(AssetError::PaymentFailed)?;
+
+    Ok(complete_refund(escrow, time()))
+}
+
+#[query]
+fn get_escrow(escrow_id: u64) -> Option<Escrow> {
+    escrow_by_id(escrow_id)
+}
+
+// The escrow currently reserving an asset, if any
+#[query]
+fn get_asset_escrow(asset_id: u64) -> Option<Escrow> {
+    active_escrow_of(asset_id)
+}
+
+fn prepare_escrow(asset_id: u64, buyer: Principal, current_time: u64) -> Result<Escrow, AssetError> {
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if !is_on_sale(&asset, current_time) {
+        return Err(AssetError::NotForSale);
+    }
+    if asset.owner == buyer {
+        return Err(AssetError::InvalidInput("Cannot buy your own asset".to_string()));
+    }
+    if is_transfer_in_progress(asset_id) {
+        return Err(AssetError::TransferInProgress);
+    }
+    check_not_frozen(&asset)?;
+    check_reservation(&asset, buyer, current_time)?;
+    let price = icp_price(&asset).ok_or(AssetError::NotPricedInLedger)?;
+
+    Ok(Escrow {
+        id: get_next_escrow_id(),
+        asset_id,
+        buyer,
+        seller: asset.owner,
+        price,
+        royalty: royalty_for(&asset, asset.owner, price),
+        platform_fee: platform_fee_for(price),
+        ledger_fee: 0,
+        held: 0,
+        status: EscrowStatus::Held,
+        created_at: current_time,
+        expires_at: current_time.saturating_add(get_escrow_window().saturating_mul(1_000_000_000)),
+        settled_at: None,
+        sale_id: None,
+    })
+}
+
+// Pulls the price and the fees for every later payout into the escrow's
+// subaccount
+async fn fund_escrow(ledger_canister_id: Principal, escrow: &mut Escrow) -> Result<(), String> {
+    let ledger_fee = ledger_fee(ledger_canister_id).await?;
+    let held = escrow_payout_legs(escrow)
+        .checked_mul(ledger_fee)
+        .and_then(|fees| fees.checked_add(escrow.price))
+        .ok_or_else(|| "Escrow amount overflows".to_string())?;
+
+    ledger_transfer_from_account(
+        ledger_canister_id,
+        escrow.buyer,
+        Account { owner: ic_cdk::id(), subaccount: Some(escrow_subaccount(escrow.id)) },
+        held,
+        escrow.asset_id,
+    ).await?;
+
+    escrow.ledger_fee = ledger_fee;
+    escrow.held = held;
+    Ok(())
+}
+
+fn open_escrow(escrow: &Escrow) {
+    save_escrow(escrow);
+    ESCROWED_ASSETS.with(|escrowed| escrowed.borrow_mut().insert(escrow.asset_id, escrow.id));
+    record_ownership_event(
+        escrow.asset_id,
+        Some(escrow.seller),
+        escrow.buyer,
+        OwnershipEventKind::EscrowReserved,
+        Some(escrow.price),
+        escrow.created_at,
+    );
+}
+
+async fn release_escrow(escrow: Escrow, status: EscrowStatus) -> Result<Asset, AssetError> {
+    let asset_id = escrow.asset_id;
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+    // The escrow stays held while frozen; the buyer can still take a refund
+    if let Some(asset) = asset_by_id(asset_id) {
+        check_not_frozen(&asset)?;
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().insert(asset_id, escrow.buyer);
+    });
+
+    let payment_result = ledger_transfer(
+        ledger_canister_id,
+        escrow_subaccount(escrow.id),
+        escrow.seller,
+        escrow_seller_amount(&escrow),
+        asset_id,
+    ).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+
+    // The escrow stays held, so a failed payout can simply be confirmed again
+    payment_result.map_err(AssetError::PaymentFailed)?;
+
+    let escrow_id = escrow.id;
+    let current_time = time();
+    let (asset, sale) = complete_escrow_sale(escrow, status, current_time)?;
+    let source = PayoutSource::Escrow { escrow_id };
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, source, current_time)).await;
+    Ok(asset)
+}
+
+// Runs once the seller has been paid out of the escrow
+fn complete_escrow_sale(mut escrow: Escrow, status: EscrowStatus, current_time: u64) -> Result<(Asset, SaleRecord), AssetError> {
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&escrow.asset_id) {
+            Some(mut asset) => {
+                change_owner(&mut asset, escrow.buyer, OwnershipEventKind::EscrowSale, Some(escrow.price), current_time);
+                assets.insert(asset.id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    })?;
+
+    let sale = record_sale(
+        escrow.asset_id,
+        escrow.seller,
+        escrow.buyer,
+        escrow.price,
+        escrow.royalty.clone(),
+        escrow.platform_fee.clone(),
+        current_time,
+    );
+
+    escrow.status = status;
+    escrow.settled_at = Some(current_time);
+    escrow.sale_id = Some(sale.id);
+    close_escrow(&escrow);
+    Ok((asset, sale))
+}
+
+// Runs once the buyer has been paid back; the asset stays with the seller and
+// keeps its listing
+fn complete_refund(mut escrow: Escrow, current_time: u64) -> Escrow {
+    escrow.status = EscrowStatus::Refunded;
+    escrow.settled_at = Some(current_time);
+    close_escrow(&escrow);
+    record_ownership_event(
+        escrow.asset_id,
+        Some(escrow.seller),
+        escrow.buyer,
+        OwnershipEventKind::EscrowRefunded,
+        Some(escrow.price),
+        current_time,
+    );
+    escrow
+}
+
+fn close_escrow(escrow: &Escrow) {
+    save_escrow(escrow);
+    ESCROWED_ASSETS.with(|escrowed| escrowed.borrow_mut().remove(&escrow.asset_id));
+}
+
+fn schedule_escrow_confirmation(escrow_id: u64, expires_at: u64) {
+    let delay = Duration::from_nanos(expires_at.saturating_sub(time()));
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(confirm_expired_escrow(escrow_id)));
+}
+
+async fn confirm_expired_escrow(escrow_id: u64) {
+    let escrow = match escrow_by_id(escrow_id) {
+        Some(escrow) => escrow,
+        None => return,
+    };
+    if escrow.status != EscrowStatus::Held || time() < escrow.expires_at {
+        return;
+    }
+    // A failure leaves the escrow held for the seller to confirm
+    let _ = release_escrow(escrow, EscrowStatus::AutoConfirmed).await;
+}
+
+fn escrow_by_id(escrow_id: u64) -> Option<Escrow> {
+    ESCROWS.with(|escrows| escrows.borrow().get(&escrow_id))
+}
+
+fn save_escrow(escrow: &Escrow) {
+    ESCROWS.with(|escrows| escrows.borrow_mut().insert(escrow.id, escrow.clone()));
+}
+
+fn active_escrow_of(asset_id: u64) -> Option<Escrow> {
+    ESCROWED_ASSETS
+        .with(|escrowed| escrowed.borrow().get(&asset_id))
+        .and_then(escrow_by_id)
+}
+
+fn is_escrowed(asset_id: u64) -> bool {
+    ESCROWED_ASSETS.with(|escrowed| escrowed.borrow().contains_key(&asset_id))
+}
+
+// The seller leg plus the royalty and platform fee legs that are actually paid
+fn escrow_payout_legs(escrow: &Escrow) -> u64 {
+    1 + escrow.royalty.as_ref().is_some_and(|royalty| royalty.amount > 0) as u64 + escrow.platform_fee.is_some() as u64
+}
+
+fn escrow_seller_amount(escrow: &Escrow) -> u64 {
+    escrow.price
+        - escrow.royalty.as_ref().map_or(0, |royalty| royalty.amount)
+        - escrow.platform_fee.as_ref().map_or(0, |fee| fee.amount)
+}
+
+fn escrow_subaccount(escrow_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0; 32];
+    subaccount[..6].copy_from_slice(b"escrow");
+    subaccount[24..].copy_from_slice(&escrow_id.to_be_bytes());
+    subaccount
+}
+
+// Deposits let buyers pre-fund purchases by sending ICP to their own
+// subaccount of this canister instead of approving it. check_deposit credits
+// whatever arrived since the last check; purchases and withdrawals pay out of
+// the subaccount. Only one ledger call per deposit account runs at a time, so
+// the last seen ledger balance always matches what was credited and spent.
+#[query]
+fn get_my_deposit_account() -> Account {
+    Account { owner: ic_cdk::id(), subaccount: Some(deposit_subaccount(caller())) }
+}
+
+#[query]
+fn get_my_deposit() -> DepositAccount {
+    deposit_of(caller())
+}
+
+// Credits and debits of the caller's deposit balance, newest first
+#[query]
+fn get_my_deposit_history(offset: u64, limit: u64) -> DepositEntryPage {
+    let principal = caller();
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    DEPOSIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entries: Vec<DepositEntry> = log
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|(_, entry)| entry)
+            .collect();
+        DepositEntryPage {
+            total_count: entries.len() as u64,
+            entries: entries.into_iter().rev().skip(offset as usize).take(limit as usize).collect(),
+        }
+    })
+}
+
+#[update]
+async fn check_deposit() -> Result<DepositAccount, AssetError> {
+    ensure_running("check_deposit")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    lock_deposit(principal)?;
+    let account = Account { owner: ic_cdk::id(), subaccount: Some(deposit_subaccount(principal)) };
+    let observed = ledger_balance_of(ledger_canister_id, account).await;
+    unlock_deposit(principal);
+
+    Ok(credit_deposit(principal, observed.map_err(AssetError::PaymentFailed)?, time()))
+}
+
+// Buys a listed asset out of the caller's deposit balance, which must cover
+// the price plus one ledger fee per payout leg
+#[update]
+async fn purchase_asset_with_balance(asset_id: u64, coupon_code: Option<String>) -> Result<Asset, AssetError> {
+    ensure_running("purchase_asset_with_balance")?;
+    let buyer = caller();
+
+    if buyer == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+    let ledger_fee = ledger_fee(ledger_canister_id).await.map_err(AssetError::PaymentFailed)?;
+
+    let deposit = lock_deposit(buyer)?;
+    let ReservedPurchase { seller, price, royalty, platform_fee, coupon } =
+        match reserve_purchase(asset_id, buyer, coupon_code.as_deref(), time()) {
+            Ok(reserved) => reserved,
+            Err(err) => {
+                unlock_deposit(buyer);
+                return Err(err);
+            },
+        };
+    let legs = 1 + royalty.as_ref().is_some_and(|royalty| royalty.amount > 0) as u64 + platform_fee.is_some() as u64;
+    let required = price.saturating_add(legs.saturating_mul(ledger_fee));
+    if deposit.balance < required {
+        PENDING_PURCHASES.with(|pending| {
+            pending.borrow_mut().remove(&asset_id);
+        });
+        release_coupon(coupon.as_deref());
+        unlock_deposit(buyer);
+        return Err(AssetError::InsufficientBalance { available: deposit.balance, required });
+    }
+
+    let seller_amount = price
+        - royalty.as_ref().map_or(0, |royalty| royalty.amount)
+        - platform_fee.as_ref().map_or(0, |fee| fee.amount);
+    let transfer_result = ledger_transfer(ledger_canister_id, deposit_subaccount(buyer), seller, seller_amount, asset_id).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow_mut().remove(&asset_id);
+    });
+    let block_index = match transfer_result {
+        Ok(block_index) => block_index,
+        Err(err) => {
+            release_coupon(coupon.as_deref());
+            unlock_deposit(buyer);
+            return Err(AssetError::PaymentFailed(err));
+        },
+    };
+
+    // The buyer is charged the full cost now. The royalty and fee legs stay in
+    // the subaccount, off the spendable balance, until they are paid out.
+    let current_time = time();
+    debit_deposit(
+        buyer,
+        required,
+        seller_amount + ledger_fee,
+        DepositEntryKind::Purchase { asset_id },
+        Some(block_index),
+        current_time,
+    );
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(price), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err(AssetError::NotFound),
+        }
+    });
+    if asset.is_err() {
+        unlock_deposit(buyer);
+        return asset;
+    }
+
+    let sale = record_sale(asset_id, seller, buyer, price, royalty, platform_fee, current_time);
+    unlock_deposit(buyer);
+    let source = PayoutSource::Deposit { buyer, ledger_fee };
+    pay_out(queue_sale_payouts(&sale, ledger_canister_id, source, current_time)).await;
+
+    asset
+}
+
+// Sends `amount` of the caller's unspent deposit to their default account;
+// the ledger fee comes out of the balance as well
+#[update]
+async fn withdraw_deposit(amount: u64) -> Result<DepositAccount, AssetError> {
+    ensure_running("withdraw_deposit")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    if amount == 0 {
+        return Err(AssetError::InvalidInput("amount: must be greater than zero".to_string()));
+    }
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+    let ledger_fee = ledger_fee(ledger_canister_id).await.map_err(AssetError::PaymentFailed)?;
+
+    let deposit = lock_deposit(principal)?;
+    let required = amount.saturating_add(ledger_fee);
+    if deposit.balance < required {
+        unlock_deposit(principal);
+        return Err(AssetError::InsufficientBalance { available: deposit.balance, required });
+    }
+
+    // Withdrawals are not tied to an asset, so the memo carries id 0
+    let transfer_result = ledger_transfer(ledger_canister_id, deposit_subaccount(principal), principal, amount, 0).await;
+    unlock_deposit(principal);
+
+    let block_index = transfer_result.map_err(AssetError::PaymentFailed)?;
+    Ok(debit_deposit(principal, required, required, DepositEntryKind::Withdrawal, Some(block_index), time()))
+}
+
+fn deposit_subaccount(principal: Principal) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"deposit");
+    hasher.update(principal.as_slice());
+    hasher.finalize().to_vec()
+}
+
+fn deposit_of(principal: Principal) -> DepositAccount {
+    DEPOSITS.with(|deposits| deposits.borrow().get(&principal).unwrap_or_default())
+}
+
+fn save_deposit(principal: Principal, deposit: DepositAccount) {
+    DEPOSITS.with(|deposits| {
+        deposits.borrow_mut().insert(principal, deposit);
+    });
+}
+
+// Marks a ledger call as in flight for the account, failing if one already is
+fn lock_deposit(principal: Principal) -> Result<DepositAccount, AssetError> {
+    let mut deposit = deposit_of(principal);
+    if deposit.in_flight {
+        return Err(AssetError::TransferInProgress);
+    }
+    deposit.in_flight = true;
+    save_deposit(principal, deposit.clone());
+    Ok(deposit)
+}
+
+fn unlock_deposit(principal: Principal) {
+    let mut deposit = deposit_of(principal);
+    deposit.in_flight = false;
+    save_deposit(principal, deposit);
+}
+
+// Credits the growth of the subaccount since it was last seen. Seeing the
+// same ledger balance twice credits nothing the second time.
+fn credit_deposit(principal: Principal, observed: u64, current_time: u64) -> DepositAccount {
+    let mut deposit = deposit_of(principal);
+    let credit = observed.saturating_sub(deposit.ledger_balance);
+    deposit.ledger_balance = observed;
+    deposit.balance = deposit.balance.saturating_add(credit);
+    save_deposit(principal, deposit.clone());
+
+    if credit > 0 {
+        log_deposit_entry(principal, DepositEntryKind::Deposit, credit, 0, deposit.balance, None, current_time);
+    }
+    deposit
+}
+
+// `debit` comes off the spendable balance, `paid_out` off the last seen
+// ledger balance; they differ while part of a payment is still to be sent
+fn debit_deposit(
+    principal: Principal,
+    debit: u64,
+    paid_out: u64,
+    kind: DepositEntryKind,
+    block_index: Option<Nat>,
+    current_time: u64,
+) -> DepositAccount {
+    let mut deposit = deposit_of(principal);
+    deposit.balance = deposit.balance.saturating_sub(debit);
+    deposit.ledger_balance = deposit.ledger_balance.saturating_sub(paid_out);
+    save_deposit(principal, deposit.clone());
+
+    log_deposit_entry(principal, kind, 0, debit, deposit.balance, block_index, current_time);
+    deposit
+}
+
+fn log_deposit_entry(
+    principal: Principal,
+    kind: DepositEntryKind,
+    credit: u64,
+    debit: u64,
+    balance_after: u64,
+    block_index: Option<Nat>,
+    current_time: u64,
+) {
+    let id = get_next_deposit_entry_id();
+    DEPOSIT_LOG.with(|log| {
+        log.borrow_mut().insert((principal, id), DepositEntry {
+            id,
+            kind,
+            credit,
+            debit,
+            balance_after,
+            block_index,
+            timestamp: current_time,
+        });
+    });
+}
+
+fn record_deposit_payout(principal: Principal, paid_out: u64) {
+    let mut deposit = deposit_of(principal);
+    deposit.ledger_balance = deposit.ledger_balance.saturating_sub(paid_out);
+    save_deposit(principal, deposit);
+}
+
+async fn ledger_balance_of(ledger_canister_id: Principal, account: Account) -> Result<u64, String> {
+    let balance_result: Result<(Nat,), _> = call(ledger_canister_id, "icrc1_balance_of", (account,)).await;
+
+    match balance_result {
+        Ok((balance,)) => u64::try_from(&balance.0).map_err(|_| "Ledger balance does not fit in 64 bits".to_string()),
+        Err((code, message)) => Err(format!("Ledger call rejected ({:?}): {}", code, message)),
+    }
+}
+
+async fn ledger_transfer_from_account(
+    ledger_canister_id: Principal,
+    from: Principal,
+    to: Account,
+    amount: u64,
+    asset_id: u64,
+) -> Result<Nat, String> {
+    let transfer_args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: from, subaccount: None },
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(asset_id.to_be_bytes().to_vec()),
+        created_at_time: Some(time()),
+    };
+
+    let transfer_result: Result<(Result<Nat, TransferFromError>,), _> = call(
+        ledger_canister_id,
+        "icrc2_transfer_from",
+        (transfer_args,),
+    ).await;
+
+    match transfer_result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(transfer_err),)) => Err(format!("{:?}", transfer_err)),
+        Err((code, message)) => Err(format!("Ledger call rejected ({:?}): {}", code, message)),
+    }
+}
+
+// Pays out of one of this canister's own subaccounts
+async fn ledger_transfer(
+    ledger_canister_id: Principal,
+    from_subaccount: Vec<u8>,
+    to: Principal,
+    amount: u64,
+    asset_id: u64,
+) -> Result<Nat, String> {
+    let transfer_args = LedgerTransferArgs {
+        from_subaccount: Some(from_subaccount),
+        to: Account { owner: to, subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(asset_id.to_be_bytes().to_vec()),
+        created_at_time: Some(time()),
+    };
+
+    let transfer_result: Result<(Result<Nat, LedgerTransferError>,), _> = call(
+        ledger_canister_id,
+        "icrc1_transfer",
+        (transfer_args,),
+    ).await;
+
+    match transfer_result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(transfer_err),)) => Err(format!("{:?}", transfer_err)),
+        Err((code, message)) => Err(format!("Ledger call rejected ({:?}): {}", code, message)),
+    }
+}
+
+async fn ledger_fee(ledger_canister_id: Principal) -> Result<u64, String> {
+    let fee_result: Result<(Nat,), _> = call(ledger_canister_id, "icrc1_fee", ()).await;
+
+    match fee_result {
+        Ok((fee,)) => u64::try_from(&fee.0).map_err(|_| "Ledger fee does not fit in 64 bits".to_string()),
+        Err((code, message)) => Err(format!("Ledger call rejected ({:?}): {}", code, message)),
+    }
+}
+
+#[query]
+fn get_ledger_canister_id() -> Option<String> {
+    CONFIG.with(|config| {
+        config.borrow().get(&"ledger_canister_id".to_string())
+    })
+}
+
+fn get_ledger_canister_principal() -> Result<Principal, String> {
+    CONFIG.with(|config| {
+        match config.borrow().get(&"ledger_canister_id".to_string()) {
+            Some(canister_id) => {
+                Principal::from_text(canister_id).map_err(|_| "Invalid ledger canister ID format".to_string())
+            },
+            None => Err("Ledger canister ID not configured".to_string()),
+        }
+    })
+}
+
+// Besides the ICP ledger, which is always accepted
+#[update]
+fn add_supported_ledger(ledger: Principal, symbol: String) -> Result<SupportedLedger, String> {
+    ensure_running("add_supported_ledger").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    allow_ledger(ledger, &symbol, time())
+}
+
+// Assets already priced in the token keep the price but cannot be bought with
+// it, filtered or sorted by it until the ledger is added back
+#[update]
+fn remove_supported_ledger(ledger: Principal) -> Result<(), String> {
+    ensure_running("remove_supported_ledger").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    SUPPORTED_LEDGERS
+        .with(|ledgers| ledgers.borrow_mut().remove(&ledger))
+        .map(|_| ())
+        .ok_or_else(|| "Ledger is not supported".to_string())
+}
+
+#[query]
+fn get_supported_ledgers() -> Vec<SupportedLedger> {
+    SUPPORTED_LEDGERS.with(|ledgers| ledgers.borrow().values().collect())
+}
+
+fn allow_ledger(ledger: Principal, symbol: &str, current_time: u64) -> Result<SupportedLedger, String> {
+    let symbol = symbol.trim();
+    if symbol.is_empty() || symbol.chars().count() > MAX_LEDGER_SYMBOL_LENGTH {
+        return Err(format!("symbol: must be 1 to {} characters", MAX_LEDGER_SYMBOL_LENGTH));
+    }
+    if ledger == Principal::anonymous() || is_icp_ledger(ledger) {
+        return Err("ledger: must be a token ledger other than the ICP ledger".to_string());
+    }
+    SUPPORTED_LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        if !ledgers.contains_key(&ledger) && ledgers.len() >= MAX_SUPPORTED_LEDGERS {
+            return Err(format!("At most {} ledgers can be supported", MAX_SUPPORTED_LEDGERS));
+        }
+        let entry = SupportedLedger {
+            ledger,
+            symbol: symbol.to_string(),
+            added_at: current_time,
+        };
+        ledgers.insert(ledger, entry.clone());
+        Ok(entry)
+    })
+}
+
+// Until a ledger is configured, ICP prices are recorded against the mainnet
+// ICP ledger; apply_init_args moves them over once one is
+fn icp_ledger() -> Principal {
+    get_ledger_canister_principal()
+        .unwrap_or_else(|_| Principal::from_text(MAINNET_ICP_LEDGER).expect("the mainnet ICP ledger id is valid"))
+}
+
+fn is_icp_ledger(ledger: Principal) -> bool {
+    ledger == icp_ledger()
+}
+
+// Sales recorded before other tokens were accepted have no ledger
+fn is_icp_sale(sale: &SaleRecord) -> bool {
+    sale.ledger.is_none_or(is_icp_ledger)
+}
+
+// The price in e8s, unless the owner priced the asset in other tokens only
+fn icp_price(asset: &Asset) -> Option<u64> {
+    asset.prices.iter().find(|option| is_icp_ledger(option.ledger)).map(|option| option.amount)
+}
+
+fn icp_prices(amount: u64) -> Vec<PriceOption> {
+    vec![PriceOption { ledger: icp_ledger(), amount }]
+}
+
+// Replaces the ICP entry, keeping the prices in other tokens
+fn set_icp_price(asset: &mut Asset, amount: u64) {
+    asset.prices.retain(|option| !is_icp_ledger(option.ledger));
+    asset.prices.insert(0, PriceOption { ledger: icp_ledger(), amount });
+}
+
+fn is_supported_ledger(ledger: Principal) -> bool {
+    SUPPORTED_LEDGERS.with(|ledgers| ledgers.borrow().contains_key(&ledger))
+}
+
+// The asset's price in the given token, if it can be bought with it
+fn price_in(asset: &Asset, ledger: Principal) -> Option<u64> {
+    if is_icp_ledger(ledger) {
+        return icp_price(asset);
+    }
+    if !is_supported_ledger(ledger) {
+        return None;
+    }
+    asset
+        .prices
+        .iter()
+        .find(|option| option.ledger == ledger)
+        .map(|option| option.amount)
+}
+
+// Assets are locked while a ledger payment is in flight, while they are up for
+// auction, reserved by an escrowed purchase or waiting to be claimed as a gift,
+// so the owner cannot change or move them underneath a buyer or recipient
+fn is_transfer_in_progress(asset_id: u64) -> bool {
+    is_sale_in_progress(asset_id)
+        || gift_of(asset_id).is_some_and(|gift| gift.expires_at.is_none() || !gift.is_expired(time()))
+        || pending_transfer_of(asset_id).is_some_and(|transfer| {
+            transfer.expires_at.is_none() || !transfer.is_expired(time())
+        })
+}
+
+// Purchases, auctions and escrows; unlike offered transfers and gifts the
+// owner cannot withdraw these alone
+fn is_sale_in_progress(asset_id: u64) -> bool {
+    PENDING_PURCHASES.with(|pending| {
+        pending.borrow().contains_key(&asset_id)
+    }) || is_auction_active(asset_id)
+        || is_dutch_auction_active(asset_id)
+        || is_escrowed(asset_id)
+}
+
+// Royalties only apply to secondary sales, i.e. when the creator is not the seller
+fn royalty_for(asset: &Asset, seller: Principal, price: u64) -> Option<Royalty> {
+    if asset.royalty_bps == 0 || asset.creator == seller {
+        return None;
+    }
+
+    let amount = (price as u128 * asset.royalty_bps as u128 / 10_000) as u64;
+    Some(Royalty {
+        creator: asset.creator,
+        royalty_bps: asset.royalty_bps,
+        amount,
+        paid: false,
+    })
+}
+
+// Purchases paid through the ledger pull the whole price, plus a ledger fee
+// for each payout leg, into this subaccount before anything changes hands. The
+// legs are then paid out of it through the payout queue.
+fn settlement_subaccount() -> Vec<u8> {
+    let mut subaccount = vec![0; 32];
+    subaccount[..10].copy_from_slice(b"settlement");
+    subaccount
+}
+
+fn payout_subaccount(source: PayoutSource) -> Vec<u8> {
+    match source {
+        PayoutSource::Settlement => settlement_subaccount(),
+        PayoutSource::Escrow { escrow_id } => escrow_subaccount(escrow_id),
+        PayoutSource::Deposit { buyer, .. } => deposit_subaccount(buyer),
+    }
+}
+
+// The seller leg plus the royalty and platform fee legs that are actually paid
+fn payout_leg_count(royalty: &Option<Royalty>, platform_fee: &Option<PlatformFee>) -> u64 {
+    1 + royalty.as_ref().is_some_and(|royalty| royalty.amount > 0) as u64 + platform_fee.is_some() as u64
+}
+
+// Pulls `price` and one ledger fee per payout leg from the buyer into the
+// settlement subaccount. The buyer's approval must also cover the fee of the
+// pull itself.
+async fn collect_payment(ledger_canister_id: Principal, buyer: Principal, price: u64, legs: u64, memo: u64) -> Result<(), String> {
+    let ledger_fee = ledger_fee(ledger_canister_id).await?;
+    let amount = legs
+        .checked_mul(ledger_fee)
+        .and_then(|fees| fees.checked_add(price))
+        .ok_or_else(|| "Payment amount overflows".to_string())?;
+
+    ledger_transfer_from_account(
+        ledger_canister_id,
+        buyer,
+        Account { owner: ic_cdk::id(), subaccount: Some(settlement_subaccount()) },
+        amount,
+        memo,
+    ).await.map(|_| ())
+}
+
+// Marks a sale whose payment collect_payment holds, so that its seller is
+// paid out of it along with the other legs
+fn collected_sale(sale: SaleRecord, ledger_canister_id: Principal) -> SaleRecord {
+    SaleRecord { seller_paid: Some(false), ledger: Some(ledger_canister_id), ..sale }
+}
+
+// Queues every unpaid leg of the sale to be paid out of `source`
+fn queue_sale_payouts(sale: &SaleRecord, ledger_canister_id: Principal, source: PayoutSource, current_time: u64) -> Vec<u64> {
+    let mut legs = Vec::new();
+    if sale.seller_paid == Some(false) {
+        legs.push((PayoutLeg::Seller { sale_id: sale.id }, sale.seller, sale.seller_amount.unwrap_or(sale.price)));
+    }
+    if let Some(royalty) = sale.royalty.as_ref().filter(|royalty| royalty.amount > 0 && !royalty.paid) {
+        legs.push((PayoutLeg::Royalty { sale_id: sale.id }, royalty.creator, royalty.amount));
+    }
+    if let Some(fee) = sale.platform_fee.as_ref().filter(|fee| !fee.paid) {
+        legs.push((PayoutLeg::PlatformFee { sale_id: sale.id }, fee.recipient, fee.amount));
+    }
+
+    legs.into_iter()
+        .map(|(leg, to, amount)| {
+            queue_payout(PendingPayout {
+                id: get_next_payout_id(),
+                leg,
+                ledger: ledger_canister_id,
+                source,
+                to,
+                amount,
+                asset_id: sale.asset_id,
+                attempts: 0,
+                last_error: None,
+                created_at: current_time,
+            })
+        })
+        .collect()
+}
+
+fn queue_payout(payout: PendingPayout) -> u64 {
+    let payout_id = payout.id;
+    PENDING_PAYOUTS.with(|payouts| payouts.borrow_mut().insert(payout_id, payout));
+    payout_id
+}
+
+async fn pay_out(payout_ids: Vec<u64>) {
+    for payout_id in payout_ids {
+        pay_pending_payout(payout_id).await;
+    }
+}
+
+// Returns whether the payout went through. One that is already being paid is
+// left alone.
+async fn pay_pending_payout(payout_id: u64) -> bool {
+    let Some(payout) = PENDING_PAYOUTS.with(|payouts| payouts.borrow().get(&payout_id)) else {
+        return false;
+    };
+    // Deposit payouts wait for any other ledger call on the account, so the
+    // last seen ledger balance stays in step with what was paid out
+    let deposit_buyer = match payout.source {
+        PayoutSource::Deposit { buyer, .. } => Some(buyer),
+        _ => None,
+    };
+    if let Some(buyer) = deposit_buyer {
+        if lock_deposit(buyer).is_err() {
+            return false;
+        }
+    }
+    if !PAYOUTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(payout_id)) {
+        if let Some(buyer) = deposit_buyer {
+            unlock_deposit(buyer);
+        }
+        return false;
+    }
+
+    let result = ledger_transfer(
+        payout.ledger,
+        payout_subaccount(payout.source),
+        payout.to,
+        payout.amount,
+        payout.asset_id,
+    ).await;
+
+    PAYOUTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&payout_id));
+    if let Some(buyer) = deposit_buyer {
+        unlock_deposit(buyer);
+    }
+    record_payout_result(payout_id, result.map(|_| ()))
+}
+
+fn record_payout_result(payout_id: u64, result: Result<(), String>) -> bool {
+    let Some(mut payout) = PENDING_PAYOUTS.with(|payouts| payouts.borrow().get(&payout_id)) else {
+        return false;
+    };
+    match result {
+        Ok(()) => {
+            PENDING_PAYOUTS.with(|payouts| payouts.borrow_mut().remove(&payout_id));
+            if let PayoutSource::Deposit { buyer, ledger_fee } = payout.source {
+                record_deposit_payout(buyer, payout.amount + ledger_fee);
+            }
+            match payout.leg {
+                PayoutLeg::Seller { sale_id } => mark_seller_paid(sale_id),
+                PayoutLeg::Royalty { sale_id } => mark_royalty_paid(sale_id),
+                PayoutLeg::PlatformFee { sale_id } => mark_platform_fee_paid(sale_id),
+                PayoutLeg::RentalOwner { rental_id } => mark_rental_owner_paid(rental_id),
+                PayoutLeg::RentalFee { rental_id } => mark_rental_fee_paid(rental_id),
+            }
+            true
+        },
+        Err(err) => {
+            payout.attempts = payout.attempts.saturating_add(1);
+            payout.last_error = Some(err);
+            PENDING_PAYOUTS.with(|payouts| payouts.borrow_mut().insert(payout_id, payout));
+            false
+        },
+    }
+}
+
+// Payouts are otherwise retried on each maintenance run
+#[update]
+async fn retry_pending_payouts() -> Result<u64, String> {
+    ensure_running("retry_pending_payouts").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(retry_payout_batch().await)
+}
+
+// Oldest first
+#[query]
+fn get_pending_payouts(offset: u64, limit: u64) -> Result<PendingPayoutPage, String> {
+    ensure_controller()?;
+    let limit = limit.min(MAX_PAGE_SIZE);
+    Ok(PENDING_PAYOUTS.with(|payouts| {
+        let payouts = payouts.borrow();
+        PendingPayoutPage {
+            total_count: payouts.len(),
+            payouts: payouts.values().skip(offset as usize).take(limit as usize).collect(),
+        }
+    }))
+}
+
+// Runs with maintenance. The funds stay held, so payouts are retried for as
+// long as the ledger refuses them.
+async fn retry_payout_batch() -> u64 {
+    let payout_ids: Vec<u64> = PENDING_PAYOUTS.with(|payouts| {
+        payouts.borrow().keys().take(PAYOUT_RETRY_BATCH_SIZE).collect()
+    });
+    let mut paid = 0;
+    for payout_id in payout_ids {
+        if pay_pending_payout(payout_id).await {
+            paid += 1;
+        }
+    }
+    paid
+}
+
+fn mark_seller_paid(sale_id: u64) {
+    SALES.with(|sales| {
+        let mut sales = sales.borrow_mut();
+        if let Some(mut sale) = sales.get(&sale_id) {
+            sale.seller_paid = Some(true);
+            sales.insert(sale_id, sale);
+        }
+    });
+}
+
+fn mark_platform_fee_paid(sale_id: u64) {
+    SALES.with(|sales| {
+        let mut sales = sales.borrow_mut();
+        if let Some(mut sale) = sales.get(&sale_id) {
+            if let Some(fee) = sale.platform_fee.as_mut() {
+                fee.paid = true;
+            }
+            sales.insert(sale_id, sale);
+        }
+    });
+}
+
+#[update]
+fn set_platform_fee(fee_bps: u16, recipient: Principal) -> Result<PlatformFeeConfig, String> {
+    ensure_running("set_platform_fee").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if fee_bps > MAX_PLATFORM_FEE_BPS {
+        return Err(format!("fee_bps: must be at most {}", MAX_PLATFORM_FEE_BPS));
+    }
+    if recipient == Principal::anonymous() {
+        return Err("recipient: cannot be the anonymous principal".to_string());
+    }
+
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        config.insert("platform_fee_bps".to_string(), fee_bps.to_string());
+        config.insert("platform_fee_recipient".to_string(), recipient.to_text());
+    });
+    Ok(get_platform_fee())
+}
+
+#[query]
+fn get_platform_fee() -> PlatformFeeConfig {
+    CONFIG.with(|config| {
+        let config = config.borrow();
+        PlatformFeeConfig {
+            fee_bps: config
+                .get(&"platform_fee_bps".to_string())
+                .and_then(|fee_bps| fee_bps.parse().ok())
+                .unwrap_or(0),
+            recipient: config
+                .get(&"platform_fee_recipient".to_string())
+                .and_then(|recipient| Principal::from_text(recipient).ok()),
+        }
+    })
+}
+
+// No fee leg at all when the fee is zero, so no ledger transfer is made for it
+fn platform_fee_for(price: u64) -> Option<PlatformFee> {
+    let config = get_platform_fee();
+    let recipient = config.recipient?;
+    let amount = (price as u128 * config.fee_bps as u128 / 10_000) as u64;
+    if amount == 0 {
+        return None;
+    }
+
+    Some(PlatformFee {
+        recipient,
+        fee_bps: config.fee_bps,
+        amount,
+        paid: false,
+    })
+}
+
+fn mark_royalty_paid(sale_id: u64) {
+    SALES.with(|sales| {
+        let mut sales = sales.borrow_mut();
+        if let Some(mut sale) = sales.get(&sale_id) {
+            if let Some(royalty) = sale.royalty.as_mut() {
+                royalty.paid = true;
+            }
+            sales.insert(sale_id, sale);
+        }
+    });
+}
+
+// Bundles sell several assets of one owner together at a single price. An
+// asset belongs to at most one active bundle, cannot be listed on its own
+// while it does, and invalidates the bundle if it changes hands or is deleted
+// outside of it.
+#[update]
+fn create_bundle(name: String, description: String, asset_ids: Vec<u64>, price: u64) -> Result<Bundle, AssetError> {
+    ensure_running("create_bundle")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+
+    create_bundle_for(principal, &name, &description, asset_ids, price, time())
+}
+
+fn create_bundle_for(
+    owner: Principal,
+    name: &str,
+    description: &str,
+    asset_ids: Vec<u64>,
+    price: u64,
+    current_time: u64,
+) -> Result<Bundle, AssetError> {
+    let name = validate_name(name)?;
+    let description = validate_description(description)?;
+    let price = validate_price(price)?;
+
+    if asset_ids.len() < MIN_BUNDLE_SIZE || asset_ids.len() > MAX_BUNDLE_SIZE {
+        return Err(AssetError::InvalidInput(format!(
+            "asset_ids: a bundle holds between {} and {} assets",
+            MIN_BUNDLE_SIZE, MAX_BUNDLE_SIZE
+        )));
+    }
+    if asset_ids.iter().collect::<BTreeSet<_>>().len() != asset_ids.len() {
+        return Err(AssetError::InvalidInput("asset_ids: must not contain duplicates".to_string()));
+    }
+    for asset_id in &asset_ids {
+        let asset = asset_by_id(*asset_id).ok_or(AssetError::NotFound)?;
+        if asset.owner != owner {
+            return Err(AssetError::NotOwner);
+        }
+        if asset.is_for_sale {
+            return Err(AssetError::ListedForSale);
+        }
+        if asset.hidden.is_some() {
+            return Err(AssetError::AssetHidden);
+        }
+        if is_transfer_in_progress(*asset_id) {
+            return Err(AssetError::TransferInProgress);
+        }
+        check_not_frozen(&asset)?;
+        if asset.archived_at.is_some() {
+            return Err(AssetError::AssetArchived);
+        }
+        ensure_not_bundled(*asset_id)?;
+    }
+
+    let bundle = Bundle {
+        id: get_next_bundle_id(),
+        owner,
+        name,
+        description,
+        asset_ids,
+        price,
+        is_for_sale: false,
+        status: BundleStatus::Active,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    BUNDLE_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        for asset_id in &bundle.asset_ids {
+            members.insert(*asset_id, bundle.id);
+        }
+    });
+    save_bundle(&bundle);
+
+    Ok(bundle)
+}
+
+#[update]
+fn list_bundle(bundle_id: u64) -> Result<Bundle, AssetError> {
+    ensure_running("list_bundle")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    set_bundle_for_sale(bundle_id, caller(), true, time())
+}
+
+#[update]
+fn unlist_bundle(bundle_id: u64) -> Result<Bundle, AssetError> {
+    ensure_running("unlist_bundle")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    set_bundle_for_sale(bundle_id, caller(), false, time())
+}
+
+fn set_bundle_for_sale(bundle_id: u64, principal: Principal, for_sale: bool, current_time: u64) -> Result<Bundle, AssetError> {
+    let mut bundle = active_bundle(bundle_id)?;
+    if bundle.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if bundle.asset_ids.iter().any(|asset_id| is_transfer_in_progress(*asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+    if for_sale {
+        validate_listing_price(bundle.price)?;
+    }
+
+    bundle.is_for_sale = for_sale;
+    bundle.updated_at = current_time;
+    save_bundle(&bundle);
+    Ok(bundle)
+}
+
+// Releases the members so they can be listed or bundled again
+#[update]
+fn dissolve_bundle(bundle_id: u64) -> Result<Bundle, AssetError> {
+    ensure_running("dissolve_bundle")?;
+    let principal = caller();
+
+    let bundle = active_bundle(bundle_id)?;
+    if bundle.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if bundle.asset_ids.iter().any(|asset_id| is_transfer_in_progress(*asset_id)) {
+        return Err(AssetError::TransferInProgress);
+    }
+
+    Ok(end_bundle(bundle, BundleStatus::Invalidated, time()))
+}
+
+#[query]
+fn get_bundle(bundle_id: u64) -> Option<Bundle> {
+    bundle_by_id(bundle_id)
+}
+
+#[query]
+fn get_bundle_sale(bundle_id: u64) -> Option<BundleSale> {
+    BUNDLE_SALES.with(|sales| sales.borrow().get(&bundle_id))
+}
+
+// Listed bundles whose members are all still visible in listings
+#[query]
+fn get_bundles_for_sale(offset: u64, limit: u64) -> BundlePage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let bundles: Vec<Bundle> = BUNDLES.with(|bundles| {
+        bundles
+            .borrow()
+            .iter()
+            .map(|(_, bundle)| bundle)
+            .filter(|bundle| bundle.is_for_sale && bundle.status == BundleStatus::Active)
+            .filter(|bundle| {
+                bundle.asset_ids.iter().all(|asset_id| asset_by_id(*asset_id).is_some_and(|asset| is_listed_publicly(&asset)))
+            })
+            .collect()
+    });
+
+    BundlePage {
+        total_count: bundles.len() as u64,
+        bundles: bundles.into_iter().skip(offset as usize).take(limit as usize).collect(),
+    }
+}
+
+// Paid like purchase_asset: the buyer approves this canister for the bundle
+// price plus one ledger fee for the pull and one for each seller, royalty and
+// platform fee leg of the members. Every member moves to the buyer in the
+// same call once the payment is held, so the purchase is all or nothing.
+#[update]
+async fn purchase_bundle(bundle_id: u64) -> Result<Bundle, AssetError> {
+    ensure_running("purchase_bundle")?;
+    let buyer = caller();
+
+    if buyer == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    let (bundle, shares) = prepare_bundle_sale(bundle_id, buyer, time())?;
+
+    // Lock every member while the payment is in flight
+    PENDING_PURCHASES.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        for asset_id in &bundle.asset_ids {
+            pending.insert(*asset_id, buyer);
+        }
+    });
+
+    let legs: u64 = shares.iter().map(|share| payout_leg_count(&share.royalty, &share.platform_fee)).sum();
+    let transfer_result = collect_payment(ledger_canister_id, buyer, bundle.price, legs, bundle_id).await;
+
+    PENDING_PURCHASES.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        for asset_id in &bundle.asset_ids {
+            pending.remove(asset_id);
+        }
+    });
+
+    transfer_result.map_err(AssetError::PaymentFailed)?;
+
+    let current_time = time();
+    let (bundle, sales) = complete_bundle_sale(bundle, buyer, shares, ledger_canister_id, current_time);
+
+    // As with single purchases, legs the ledger refuses are retried later
+    let payout_ids = sales
+        .iter()
+        .flat_map(|sale| queue_sale_payouts(sale, ledger_canister_id, PayoutSource::Settlement, current_time))
+        .collect();
+    pay_out(payout_ids).await;
+
+    Ok(bundle)
+}
+
+fn prepare_bundle_sale(
+    bundle_id: u64,
+    buyer: Principal,
+    current_time: u64,
+) -> Result<(Bundle, Vec<BundleShare>), AssetError> {
+    let bundle = bundle_by_id(bundle_id).ok_or(AssetError::BundleNotFound)?;
+    if !bundle.is_for_sale || bundle.status != BundleStatus::Active {
+        return Err(AssetError::NotForSale);
+    }
+    if bundle.owner == buyer {
+        return Err(AssetError::InvalidInput("Cannot buy your own bundle".to_string()));
+    }
+    validate_listing_price(bundle.price)?;
+
+    let shares = bundle
+        .asset_ids
+        .iter()
+        .zip(split_bundle_price(bundle.price, bundle.asset_ids.len()))
+        .map(|(asset_id, price)| {
+            let asset = asset_by_id(*asset_id).ok_or(AssetError::NotFound)?;
+            if asset.owner != bundle.owner {
+                return Err(AssetError::NotOwner);
+            }
+            if asset.hidden.is_some() {
+                return Err(AssetError::AssetHidden);
+            }
+            if is_transfer_in_progress(*asset_id) {
+                return Err(AssetError::TransferInProgress);
+            }
+            check_not_frozen(&asset)?;
+            check_reservation(&asset, buyer, current_time)?;
+            Ok(BundleShare {
+                asset_id: *asset_id,
+                price,
+                royalty: royalty_for(&asset, bundle.owner, price),
+                platform_fee: platform_fee_for(price),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((bundle, shares))
+}
+
+// Moves every member to the buyer and records the sale, one SaleRecord per
+// member plus the bundle-level record
+fn complete_bundle_sale(
+    bundle: Bundle,
+    buyer: Principal,
+    shares: Vec<BundleShare>,
+    ledger_canister_id: Principal,
+    current_time: u64,
+) -> (Bundle, Vec<SaleRecord>) {
+    let seller = bundle.owner;
+    // Ending the bundle first keeps the ownership changes below from
+    // invalidating it
+    let bundle = end_bundle(bundle, BundleStatus::Sold, current_time);
+
+    let sales: Vec<SaleRecord> = shares
+        .into_iter()
+        .map(|share| {
+            ASSETS.with(|assets| {
+                let mut assets = assets.borrow_mut();
+                if let Some(mut asset) = assets.get(&share.asset_id) {
+                    change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(share.price), current_time);
+                    assets.insert(share.asset_id, asset);
+                }
+            });
+            store_sale(collected_sale(
+                sale_record(share.asset_id, seller, buyer, share.price, share.royalty, share.platform_fee, current_time),
+                ledger_canister_id,
+            ))
+        })
+        .collect();
+
+    BUNDLE_SALES.with(|bundle_sales| {
+        bundle_sales.borrow_mut().insert(bundle.id, BundleSale {
+            bundle_id: bundle.id,
+            seller,
+            buyer,
+            price: bundle.price,
+            sale_ids: sales.iter().map(|sale| sale.id).collect(),
+            timestamp: current_time,
+        });
+    });
+
+    (bundle, sales)
+}
+
+// Splits the bundle price evenly across the members; the first members take
+// the remainder so that the shares add up to the price
+fn split_bundle_price(price: u64, members: usize) -> Vec<u64> {
+    let members = members as u64;
+    (0..members)
+        .map(|index| price / members + u64::from(index < price % members))
+        .collect()
+}
+
+fn bundle_by_id(bundle_id: u64) -> Option<Bundle> {
+    BUNDLES.with(|bundles| bundles.borrow().get(&bundle_id))
+}
+
+fn active_bundle(bundle_id: u64) -> Result<Bundle, AssetError> {
+    let bundle = bundle_by_id(bundle_id).ok_or(AssetError::BundleNotFound)?;
+    if bundle.status != BundleStatus::Active {
+        return Err(AssetError::InvalidInput("bundle: no longer active".to_string()));
+    }
+    Ok(bundle)
+}
+
+fn save_bundle(bundle: &Bundle) {
+    BUNDLES.with(|bundles| {
+        bundles.borrow_mut().insert(bundle.id, bundle.clone());
+    });
+}
+
+fn ensure_not_bundled(asset_id: u64) -> Result<(), AssetError> {
+    match BUNDLE_MEMBERS.with(|members| members.borrow().get(&asset_id)) {
+        Some(bundle_id) => Err(AssetError::InBundle { bundle_id }),
+        None => Ok(()),
+    }
+}
+
+// Called whenever an asset changes hands or is deleted
+fn invalidate_bundle_of(asset_id: u64, current_time: u64) {
+    let bundle = BUNDLE_MEMBERS
+        .with(|members| members.borrow().get(&asset_id))
+        .and_then(bundle_by_id);
+    if let Some(bundle) = bundle {
+        end_bundle(bundle, BundleStatus::Invalidated, current_time);
+    }
+}
+
+fn end_bundle(mut bundle: Bundle, status: BundleStatus, current_time: u64) -> Bundle {
+    BUNDLE_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        for asset_id in &bundle.asset_ids {
+            members.remove(asset_id);
+        }
+    });
+
+    bundle.status = status;
+    bundle.is_for_sale = false;
+    bundle.updated_at = current_time;
+    save_bundle(&bundle);
+    bundle
+}
+
+#[update]
+fn set_my_profile(
+    display_name: String,
+    bio: String,
+    avatar_url: Option<String>,
+    website: Option<String>,
+) -> Result<Profile, AssetError> {
+    ensure_running("set_my_profile")?;
+    enforce_rate_limit(RateLimitedAction::MetadataUpdate)?;
+    save_profile(caller(), &display_name, &bio, avatar_url, website, time())
+}
+
+#[query]
+fn get_profile(principal: Principal) -> Option<Profile> {
+    profile_of(principal)
+}
+
+// Display names are matched case-insensitively
+#[query]
+fn get_profile_by_name(name: String) -> Option<Profile> {
+    PROFILE_NAMES
+        .with(|names| names.borrow().get(&display_name_key(&name)))
+        .and_then(profile_of)
+}
+
+// Same results as get_assets_filtered, with each owner's display name attached
+#[query]
+fn get_assets_with_creator_info(filter: AssetFilter, sort: SortBy, offset: u64, limit: u64) -> AssetWithCreatorPage {
+    let page = get_assets_filtered(filter, sort, offset, limit);
+    AssetWithCreatorPage {
+        assets: page.assets.into_iter().map(with_creator_info).collect(),
+        total_count: page.total_count,
+    }
+}
+
+fn with_creator_info(asset: Asset) -> AssetWithCreator {
+    AssetWithCreator {
+        owner_display_name: profile_of(asset.owner).map(|profile| profile.display_name),
+        owner_verified: is_verified(asset.owner),
+        asset,
+    }
+}
+
+fn profile_of(principal: Principal) -> Option<Profile> {
+    let mut profile = PROFILES.with(|profiles| profiles.borrow().get(&principal))?;
+    profile.verification = verification_of(principal);
+    profile.follows = Some(follow_counts_of(principal));
+    Some(profile)
+}
+
+fn save_profile(
+    principal: Principal,
+    display_name: &str,
+    bio: &str,
+    avatar_url: Option<String>,
+    website: Option<String>,
+    current_time: u64,
+) -> Result<Profile, AssetError> {
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let display_name = validate_display_name(display_name)?;
+    let bio = validate_bio(bio)?;
+    let avatar_url = validate_profile_url("avatar_url", avatar_url)?;
+    let website = validate_profile_url("website", website)?;
+
+    let name_key = display_name_key(&display_name);
+    let holder = PROFILE_NAMES.with(|names| names.borrow().get(&name_key));
+    if holder.is_some_and(|holder| holder != principal) {
+        return Err(AssetError::DisplayNameTaken);
+    }
+
+    let previous = profile_of(principal);
+    PROFILE_NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        // Renaming frees the old name for others to claim
+        if let Some(previous) = &previous {
+            names.remove(&display_name_key(&previous.display_name));
+        }
+        names.insert(name_key, principal);
+    });
+
+    let profile = Profile {
+        principal,
+        display_name,
+        bio,
+        avatar_url,
+        website,
+        created_at: previous.map_or(current_time, |previous| previous.created_at),
+        updated_at: current_time,
+        verification: verification_of(principal),
+        follows: Some(follow_counts_of(principal)),
+    };
+    PROFILES.with(|profiles| profiles.borrow_mut().insert(principal, profile.clone()));
+    Ok(profile)
+}
+
+fn display_name_key(display_name: &str) -> String {
+    display_name.trim().to_lowercase()
+}
+
+fn validate_display_name(display_name: &str) -> Result<String, AssetError> {
+    let display_name = display_name.trim();
+    if display_name.is_empty() {
+        return Err(AssetError::InvalidInput("display_name: must not be empty".to_string()));
+    }
+    if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "display_name: must be at most {} characters",
+            MAX_DISPLAY_NAME_LENGTH
+        )));
+    }
+    if !display_name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.')) {
+        return Err(AssetError::InvalidInput(
+            "display_name: may only contain letters, digits, spaces, '_', '-' and '.'".to_string(),
+        ));
+    }
+    if RESERVED_DISPLAY_NAMES.contains(&display_name_key(display_name).as_str()) {
+        return Err(AssetError::InvalidInput(format!("display_name: {} is reserved", display_name)));
+    }
+    Ok(display_name.to_string())
+}
+
+fn validate_bio(bio: &str) -> Result<String, AssetError> {
+    if bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(AssetError::InvalidInput(format!("bio: must be at most {} characters", MAX_BIO_LENGTH)));
+    }
+    Ok(bio.trim().to_string())
+}
+
+// Empty strings clear the field
+fn validate_profile_url(field: &str, url: Option<String>) -> Result<Option<String>, AssetError> {
+    let url = url.map(|url| url.trim().to_string()).filter(|url| !url.is_empty());
+    if let Some(url) = &url {
+        if url.len() > MAX_PROFILE_URL_LENGTH {
+            return Err(AssetError::InvalidInput(format!(
+                "{}: must be at most {} bytes",
+                field, MAX_PROFILE_URL_LENGTH
+            )));
+        }
+        if !["https://", "http://", "canister://"].iter().any(|scheme| url.starts_with(scheme)) {
+            return Err(AssetError::InvalidInput(format!(
+                "{}: must be an http(s) or canister:// URL",
+                field
+            )));
+        }
+    }
+    Ok(url)
+}
+
+// Follows
+#[update]
+fn follow_creator(creator: Principal) -> Result<FollowCounts, AssetError> {
+    ensure_running("follow_creator")?;
+    follow(caller(), creator, time())
+}
+
+#[update]
+fn unfollow_creator(creator: Principal) -> Result<FollowCounts, AssetError> {
+    ensure_running("unfollow_creator")?;
+    unfollow(caller(), creator);
+    Ok(follow_counts_of(creator))
+}
+
+#[query]
+fn get_my_following() -> FollowList {
+    let following = following_of(caller());
+    FollowList {
+        count: following.len() as u64,
+        principals: following,
+    }
+}
+
+#[query]
+fn get_followers(creator: Principal) -> FollowList {
+    FollowList {
+        principals: followers_of(creator),
+        count: follower_count_of(creator),
+    }
+}
+
+// Newest first
+#[query]
+fn get_following_feed(offset: u64, limit: u64) -> AssetPage {
+    following_feed(caller(), offset, limit)
+}
+
+fn follow(follower: Principal, creator: Principal, current_time: u64) -> Result<FollowCounts, AssetError> {
+    if follower == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    if creator == follower {
+        return Err(AssetError::InvalidInput("Cannot follow yourself".to_string()));
+    }
+    if creator == Principal::anonymous() {
+        return Err(AssetError::InvalidInput("Cannot follow the anonymous principal".to_string()));
+    }
+
+    let already_following = FOLLOWING.with(|following| {
+        following.borrow_mut().insert((follower, creator), current_time).is_some()
+    });
+    if !already_following {
+        FOLLOWERS.with(|followers| followers.borrow_mut().insert((creator, follower), current_time));
+        FOLLOWER_COUNTS.with(|counts| {
+            let mut counts = counts.borrow_mut();
+            let count = counts.get(&creator).unwrap_or(0);
+            counts.insert(creator, count + 1);
+        });
+    }
+
+    Ok(follow_counts_of(creator))
+}
+
+fn unfollow(follower: Principal, creator: Principal) {
+    let was_following = FOLLOWING.with(|following| following.borrow_mut().remove(&(follower, creator)).is_some());
+    FOLLOWERS.with(|followers| followers.borrow_mut().remove(&(creator, follower)));
+    if was_following {
+        FOLLOWER_COUNTS.with(|counts| {
+            let mut counts = counts.borrow_mut();
+            match counts.get(&creator).unwrap_or(0) {
+                0 | 1 => counts.remove(&creator),
+                count => counts.insert(creator, count - 1),
+            };
+        });
+    }
+}
+
+fn following_of(follower: Principal) -> Vec<Principal> {
+    FOLLOWING.with(|following| {
+        following
+            .borrow()
+            .range((follower, Principal::management_canister())..)
+            .take_while(|((key, _), _)| *key == follower)
+            .map(|((_, creator), _)| creator)
+            .collect()
+    })
+}
+
+fn followers_of(creator: Principal) -> Vec<Principal> {
+    FOLLOWERS.with(|followers| {
+        followers
+            .borrow()
+            .range((creator, Principal::management_canister())..)
+            .take_while(|((key, _), _)| *key == creator)
+            .map(|((_, follower), _)| follower)
+            .collect()
+    })
+}
+
+fn follower_count_of(creator: Principal) -> u64 {
+    FOLLOWER_COUNTS.with(|counts| counts.borrow().get(&creator).unwrap_or(0))
+}
+
+fn follow_counts_of(principal: Principal) -> FollowCounts {
+    FollowCounts {
+        followers: follower_count_of(principal),
+        following: following_of(principal).len() as u64,
+    }
+}
+
+// Public assets published by the creators the principal follows
+fn following_feed(principal: Principal, offset: u64, limit: u64) -> AssetPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let creators: BTreeSet<Principal> = following_of(principal).into_iter().collect();
+    if creators.is_empty() {
+        return AssetPage {
+            assets: Vec::new(),
+            total_count: 0,
+            truncated: false,
+            next_offset: None,
+        };
+    }
+
+    let mut assets: Vec<Asset> = ASSETS.with(|assets| {
+        assets
+            .borrow()
+            .iter()
+            .map(|(_, asset)| asset)
+            .filter(|asset| creators.contains(&asset.creator) && is_listed_publicly(asset))
+            .collect()
+    });
+    assets.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+
+    AssetPage {
+        total_count: assets.len() as u64,
+        assets: assets.into_iter().skip(offset as usize).take(limit as usize).collect(),
+        truncated: false,
+        next_offset: None,
+    }
+}
+
+// Notifications. Appending one never fails the operation that triggered it:
+// notify returns nothing, and a full inbox drops its oldest entries instead
+// of refusing new ones.
+#[query]
+fn get_my_notifications(offset: u64, limit: u64, unread_only: bool) -> NotificationPage {
+    notifications_of(caller(), offset, limit, unread_only)
+}
+
+#[update]
+fn mark_notifications_read(ids: Vec<u64>) -> Result<u64, AssetError> {
+    ensure_running("mark_notifications_read")?;
+    Ok(mark_read(caller(), &ids))
+}
+
+fn notify(
+    recipient: Principal,
+    kind: NotificationKind,
+    asset_id: u64,
+    counterparty: Option<Principal>,
+    amount: Option<u64>,
+    current_time: u64,
+) {
+    if recipient == Principal::anonymous() {
+        return;
+    }
+
+    let notification = Notification {
+        id: get_next_notification_id(),
+        kind,
+        asset_id,
+        counterparty,
+        amount,
+        created_at: current_time,
+        read: false,
+    };
+
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        notifications.insert((recipient, notification.id), notification);
+        trim_inbox(&mut notifications, recipient);
+    });
+}
+
+// Drops the oldest notifications past MAX_NOTIFICATIONS_PER_PRINCIPAL
+fn trim_inbox(notifications: &mut NotificationStore, recipient: Principal) {
+    let keys: Vec<(Principal, u64)> = notifications
+        .range((recipient, 0)..=(recipient, u64::MAX))
+        .map(|(key, _)| key)
+        .collect();
+    let excess = keys.len().saturating_sub(MAX_NOTIFICATIONS_PER_PRINCIPAL);
+    for key in keys.into_iter().take(excess) {
+        notifications.remove(&key);
+    }
+}
+
+fn notify_favoriters(asset_id: u64, new_price: u64, current_time: u64) {
+    for holder in favoriters_of(asset_id) {
+        notify(holder, NotificationKind::PriceDropOnFavorite, asset_id, None, Some(new_price), current_time);
+    }
+}
+
+// Newest first
+fn notifications_of(recipient: Principal, offset: u64, limit: u64, unread_only: bool) -> NotificationPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    NOTIFICATIONS.with(|notifications| {
+        let notifications = notifications.borrow();
+        let mut page = NotificationPage {
+            notifications: Vec::new(),
+            total_count: 0,
+            unread_count: 0,
+        };
+
+        for (_, notification) in notifications.range((recipient, 0)..=(recipient, u64::MAX)).rev() {
+            if !notification.read {
+                page.unread_count += 1;
+            }
+            if unread_only && notification.read {
+                continue;
+            }
+            if page.total_count >= offset && (page.notifications.len() as u64) < limit {
+                page.notifications.push(notification);
+            }
+            page.total_count += 1;
+        }
+
+        page
+    })
+}
+
+// Returns how many notifications changed from unread to read
+fn mark_read(recipient: Principal, ids: &[u64]) -> u64 {
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let mut marked = 0;
+
+        for id in ids {
+            if let Some(mut notification) = notifications.get(&(recipient, *id)) {
+                if !notification.read {
+                    notification.read = true;
+                    notifications.insert((recipient, *id), notification);
+                    marked += 1;
+                }
+            }
+        }
+
+        marked
+    })
+}
+
+#[query]
+fn get_creator_royalties(creator: Principal) -> CreatorRoyalties {
+    let mut royalties = CreatorRoyalties {
+        creator,
+        sale_count: 0,
+        total_accrued: 0,
+        total_paid: 0,
+    };
+
+    SALES.with(|sales| {
+        for (_, sale) in sales.borrow().iter() {
+            if let Some(royalty) = sale.royalty.filter(|royalty| royalty.creator == creator) {
+                royalties.sale_count += 1;
+                royalties.total_accrued = royalties.total_accrued.saturating_add(royalty.amount);
+                if royalty.paid {
+                    royalties.total_paid = royalties.total_paid.saturating_add(royalty.amount);
+                }
+            }
+        }
+    });
+
+    royalties
+}
+
+#[query]
+fn get_my_earnings(window: TimeWindow) -> EarningsSummary {
+    earnings_of(caller(), window, time())
+}
+
+// Newest first
+#[query]
+fn get_my_earnings_history(offset: u64, limit: u64) -> EarningsLegPage {
+    earnings_history(caller(), offset, limit)
+}
+
+fn earnings_of(principal: Principal, window: TimeWindow, current_time: u64) -> EarningsSummary {
+    let since = window.days().map_or(0, |days| window_start_day(days, current_time) * NANOS_PER_DAY);
+    let mut summary = EarningsSummary::default();
+    let mut by_asset: BTreeMap<u64, AssetEarnings> = BTreeMap::new();
+
+    SALES.with(|sales| {
+        // Sale ids follow time, so the scan can stop at the start of the window
+        for (_, sale) in sales.borrow().iter().rev().take_while(|(_, sale)| sale.timestamp >= since) {
+            for leg in earnings_legs(&sale, principal) {
+                let asset = by_asset.entry(sale.asset_id).or_insert_with(|| AssetEarnings {
+                    asset_id: sale.asset_id,
+                    ..Default::default()
+                });
+                match leg.kind {
+                    EarningsLegKind::Sale => {
+                        summary.gross_sales = summary.gross_sales.saturating_add(leg.sale_price);
+                        asset.gross_sales = asset.gross_sales.saturating_add(leg.sale_price);
+                        summary.sale_count += 1;
+                        asset.sale_count += 1;
+                        summary.platform_fees_paid = summary.platform_fees_paid.saturating_add(leg.platform_fee);
+                        asset.platform_fees_paid = asset.platform_fees_paid.saturating_add(leg.platform_fee);
+                    },
+                    EarningsLegKind::Royalty => {
+                        summary.royalty_income = summary.royalty_income.saturating_add(leg.amount);
+                        asset.royalty_income = asset.royalty_income.saturating_add(leg.amount);
+                    },
+                }
+                summary.net_proceeds = summary.net_proceeds.saturating_add(leg.amount);
+                asset.net_proceeds = asset.net_proceeds.saturating_add(leg.amount);
+            }
+        }
+    });
+
+    summary.assets = by_asset.into_values().collect();
+    summary
+}
+
+fn earnings_history(principal: Principal, offset: u64, limit: u64) -> EarningsLegPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let mut legs = Vec::new();
+    let mut total_count = 0;
+
+    SALES.with(|sales| {
+        for (_, sale) in sales.borrow().iter().rev() {
+            for leg in earnings_legs(&sale, principal) {
+                if total_count >= offset && (legs.len() as u64) < limit {
+                    legs.push(leg);
+                }
+                total_count += 1;
+            }
+        }
+    });
+
+    EarningsLegPage { legs, total_count }
+}
+
+// A principal can hold both legs of one sale only if it was never the
+// creator, since royalty_for skips creators selling their own work. Earnings
+// are in e8s, so sales settled in other tokens have no legs.
+fn earnings_legs(sale: &SaleRecord, principal: Principal) -> Vec<EarningsLeg> {
+    if !is_icp_sale(sale) {
+        return Vec::new();
+    }
+    let royalty_amount = sale.royalty.as_ref().map_or(0, |royalty| royalty.amount);
+    let platform_fee = sale.platform_fee.as_ref().map_or(0, |fee| fee.amount);
+    let mut legs = Vec::new();
+
+    if sale.seller == principal {
+        legs.push(EarningsLeg {
+            sale_id: sale.id,
+            asset_id: sale.asset_id,
+            kind: EarningsLegKind::Sale,
+            sale_price: sale.price,
+            // Sales from before platform fees existed did not record the split
+            amount: sale
+                .seller_amount
+                .unwrap_or_else(|| sale.price.saturating_sub(royalty_amount).saturating_sub(platform_fee)),
+            platform_fee,
+            paid: true,
+            timestamp: sale.timestamp,
+        });
+    }
+    if let Some(royalty) = sale.royalty.as_ref().filter(|royalty| royalty.creator == principal) {
+        legs.push(EarningsLeg {
+            sale_id: sale.id,
+            asset_id: sale.asset_id,
+            kind: EarningsLegKind::Royalty,
+            sale_price: sale.price,
+            amount: royalty.amount,
+            platform_fee: 0,
+            paid: royalty.paid,
+            timestamp: sale.timestamp,
+        });
+    }
+    legs
+}
+
+// Sale history
+#[query]
+fn get_asset_sale_history(asset_id: u64, offset: u64, limit: u64) -> SaleRecordPage {
+    paginate_sales(offset, limit, |sale| sale.asset_id == asset_id)
+}
+
+#[query]
+fn get_user_sales(seller: Principal, offset: u64, limit: u64) -> SaleRecordPage {
+    paginate_sales(offset, limit, |sale| sale.seller == seller)
+}
+
+#[query]
+fn get_asset_sale_history_after(
+    asset_id: u64,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<SaleRecordCursorPage, AssetError> {
+    sales_after(cursor, limit, |sale| sale.asset_id == asset_id)
+}
+
+#[query]
+fn get_user_sales_after(seller: Principal, cursor: Option<String>, limit: u64) -> Result<SaleRecordCursorPage, AssetError> {
+    sales_after(cursor, limit, |sale| sale.seller == seller)
+}
+
+// sale_count covers sales in every token; prices and volume are in e8s, so
+// only ICP sales add to them
+#[query]
+fn get_asset_sale_stats(asset_id: u64) -> AssetSaleStats {
+    let mut stats = AssetSaleStats {
+        asset_id,
+        sale_count: 0,
+        last_sold_price: None,
+        last_sold_at: None,
+        total_volume: 0,
+    };
+
+    // Sale ids increase monotonically, so the last match is the latest sale
+    for sale in sale_ids_of(asset_id).into_iter().filter_map(|id| SALES.with(|sales| sales.borrow().get(&id))) {
+        stats.sale_count += 1;
+        stats.last_sold_at = Some(sale.timestamp);
+        if is_icp_sale(&sale) {
+            stats.total_volume = stats.total_volume.saturating_add(sale.price);
+            stats.last_sold_price = Some(sale.price);
+        }
+    }
+
+    stats
+}
+
+#[query]
+fn get_top_sellers(window: TimeWindow, limit: u64) -> Vec<TopSeller> {
+    top_sellers(window, limit, time())
+}
+
+#[query]
+fn get_top_selling_assets(window: TimeWindow, limit: u64) -> Vec<TopSellingAsset> {
+    top_selling_assets(window, limit, time())
+}
+
+// Recent uploads ranked by how much attention they drew early on. Newer
+// assets start ahead and engagement lets slightly older ones overtake them.
+#[query]
+fn get_new_noteworthy(limit: u64) -> Vec<Asset> {
+    new_noteworthy(limit, time())
+}
+
+fn top_sellers(window: TimeWindow, limit: u64, current_time: u64) -> Vec<TopSeller> {
+    let tallies: Vec<(Principal, SaleTally)> = match window.days() {
+        None => SELLER_TALLIES.with(|tallies| tallies.borrow().iter().collect()),
+        Some(days) => {
+            let first_day = window_start_day(days, current_time);
+            let mut summed: BTreeMap<Principal, SaleTally> = BTreeMap::new();
+            SELLER_DAILY_TALLIES.with(|tallies| {
+                for ((_, seller), tally) in tallies.borrow().range((first_day, Principal::management_canister())..) {
+                    summed.entry(seller).or_default().add(&tally);
+                }
+            });
+            summed.into_iter().collect()
+        },
+    };
+
+    rank_tallies(tallies, limit)
+        .into_iter()
+        .map(|(seller, tally)| TopSeller {
+            seller,
+            volume: tally.volume,
+            sale_count: tally.sale_count,
+        })
+        .collect()
+}
+
+fn top_selling_assets(window: TimeWindow, limit: u64, current_time: u64) -> Vec<TopSellingAsset> {
+    let tallies: Vec<(u64, SaleTally)> = match window.days() {
+        None => ASSET_TALLIES.with(|tallies| tallies.borrow().iter().collect()),
+        Some(days) => {
+            let first_day = window_start_day(days, current_time);
+            let mut summed: BTreeMap<u64, SaleTally> = BTreeMap::new();
+            ASSET_DAILY_TALLIES.with(|tallies| {
+                for ((_, asset_id), tally) in tallies.borrow().range((first_day, 0)..) {
+                    summed.entry(asset_id).or_default().add(&tally);
+                }
+            });
+            summed.into_iter().collect()
+        },
+    };
+
+    // Ranked before filtering so hidden or deleted assets do not shorten the list
+    rank_tallies(tallies, u64::MAX)
+        .into_iter()
+        .filter_map(|(asset_id, tally)| {
+            asset_by_id(asset_id)
+                .filter(is_listed_publicly)
+                .map(|asset| TopSellingAsset {
+                    asset,
+                    volume: tally.volume,
+                    sale_count: tally.sale_count,
+                })
+        })
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .collect()
+}
+
+// Highest volume first, then most sales, then the smallest key for stability
+fn rank_tallies<K: Ord>(mut tallies: Vec<(K, SaleTally)>, limit: u64) -> Vec<(K, SaleTally)> {
+    tallies.sort_by(|a, b| {
+        b.1.volume
+            .cmp(&a.1.volume)
+            .then(b.1.sale_count.cmp(&a.1.sale_count))
+            .then(a.0.cmp(&b.0))
+    });
+    tallies.truncate(limit.min(MAX_PAGE_SIZE) as usize);
+    tallies
+}
+
+// The window covers the current day and the days - 1 before it
+fn window_start_day(days: u64, current_time: u64) -> u64 {
+    (current_time / NANOS_PER_DAY + 1).saturating_sub(days)
+}
+
+// Every sale is counted; tallied volumes are in e8s, so sales settled in
+// other tokens add nothing to them
+fn tally_sale(sale: &SaleRecord) {
+    let day = sale.timestamp / NANOS_PER_DAY;
+    let tally = SaleTally {
+        volume: if is_icp_sale(sale) { sale.price } else { 0 },
+        sale_count: 1,
+    };
+
+    SELLER_DAILY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut daily = tallies.get(&(day, sale.seller)).unwrap_or_default();
+        daily.add(&tally);
+        tallies.insert((day, sale.seller), daily);
+    });
+    ASSET_DAILY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut daily = tallies.get(&(day, sale.asset_id)).unwrap_or_default();
+        daily.add(&tally);
+        tallies.insert((day, sale.asset_id), daily);
+    });
+    SELLER_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut total = tallies.get(&sale.seller).unwrap_or_default();
+        total.add(&tally);
+        tallies.insert(sale.seller, total);
+    });
+    MARKETPLACE_SALES.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut total = *cell.get();
+        total.add(&tally);
+        cell.set(total).expect("a sale tally always fits the marketplace sales cell");
+    });
+    ASSET_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut total = tallies.get(&sale.asset_id).unwrap_or_default();
+        total.add(&tally);
+        tallies.insert(sale.asset_id, total);
+    });
+}
+
+fn prune_sale_tallies(current_time: u64) {
+    let first_day = window_start_day(LEADERBOARD_DAYS, current_time);
+
+    SELLER_DAILY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let stale: Vec<(u64, Principal)> = tallies
+            .range(..(first_day, Principal::management_canister()))
+            .map(|(key, _)| key)
+            .collect();
+        for key in stale {
+            tallies.remove(&key);
+        }
+    });
+    ASSET_DAILY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let stale: Vec<(u64, u64)> = tallies.range(..(first_day, 0)).map(|(key, _)| key).collect();
+        for key in stale {
+            tallies.remove(&key);
+        }
+    });
+}
+
+fn rebuild_sale_tallies(current_time: u64) {
+    SELLER_DAILY_TALLIES.with(|tallies| tallies.borrow_mut().clear_new());
+    ASSET_DAILY_TALLIES.with(|tallies| tallies.borrow_mut().clear_new());
+    SELLER_TALLIES.with(|tallies| tallies.borrow_mut().clear_new());
+    ASSET_TALLIES.with(|tallies| tallies.borrow_mut().clear_new());
+    MARKETPLACE_SALES.with(|cell| {
+        cell.borrow_mut()
+            .set(SaleTally::default())
+            .expect("a sale tally always fits the marketplace sales cell");
+    });
+
+    let sales: Vec<SaleRecord> = SALES.with(|sales| sales.borrow().iter().map(|(_, sale)| sale).collect());
+    for sale in &sales {
+        tally_sale(sale);
+    }
+    prune_sale_tallies(current_time);
+}
+
+fn new_noteworthy(limit: u64, current_time: u64) -> Vec<Asset> {
+    let cutoff = current_time.saturating_sub(NOTEWORTHY_WINDOW_NANOS);
+
+    // Asset ids follow upload order, so walking back from the newest stops at
+    // the first asset older than the window
+    let recent: Vec<Asset> = ASSETS.with(|assets| {
+        assets
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, asset)| asset)
+            .take_while(|asset| asset.created_at >= cutoff)
+            .filter(is_listed_publicly)
+            .collect()
+    });
+
+    let mut scored: Vec<(f64, Asset)> = recent
+        .into_iter()
+        .map(|asset| {
+            let age = current_time.saturating_sub(asset.created_at) as f64;
+            let freshness = 1.0 - age / NOTEWORTHY_WINDOW_NANOS as f64;
+            let engagement = ASSET_STATS
+                .with(|stats| stats.borrow().get(&asset.id))
+                .map_or(0.0, |stats| decayed_score(&stats, current_time))
+                + get_favorite_count(asset.id) as f64;
+            (freshness * (1.0 + engagement), asset)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(b.1.id.cmp(&a.1.id)));
+
+    scored
+        .into_iter()
+        .map(|(_, asset)| asset)
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .collect()
+}
+
+fn paginate_sales<F>(offset: u64, limit: u64, filter: F) -> SaleRecordPage
+where
+    F: Fn(&SaleRecord) -> bool,
+{
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    SALES.with(|sales| {
+        let mut page = Vec::new();
+        let mut total_count = 0;
+
+        for (_, sale) in sales.borrow().iter() {
+            if !filter(&sale) {
+                continue;
+            }
+            if total_count >= offset && (page.len() as u64) < limit {
+                page.push(sale);
+            }
+            total_count += 1;
+        }
+
+        SaleRecordPage {
+            sales: page,
+            total_count,
+        }
+    })
+}
+
+fn sales_after<F>(cursor: Option<String>, limit: u64, filter: F) -> Result<SaleRecordCursorPage, AssetError>
+where
+    F: Fn(&SaleRecord) -> bool,
+{
+    let start = cursor_start(cursor, SALE_CURSOR_TAG)?;
+
+    SALES.with(|sales| {
+        let sales = sales.borrow();
+        let matching = sales.range((start, Bound::Unbounded)).filter(|(_, sale)| filter(sale));
+        let (sales, next_cursor) = page_after(matching, limit, SALE_CURSOR_TAG);
+        Ok(SaleRecordCursorPage { sales, next_cursor })
+    })
+}
+
+#[query]
+fn get_asset_provenance(asset_id: u64, offset: u64, limit: u64) -> OwnershipEventPage {
+    paginate_ownership_events(offset, limit, |event| event.asset_id == asset_id)
+}
+
+#[query]
+fn get_user_activity(principal: Principal, offset: u64, limit: u64) -> OwnershipEventPage {
+    paginate_ownership_events(offset, limit, |event| event.to == principal || event.from == Some(principal))
+}
+
+#[query]
+fn get_asset_provenance_after(
+    asset_id: u64,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<OwnershipEventCursorPage, AssetError> {
+    ownership_events_after(cursor, limit, |event| event.asset_id == asset_id)
+}
+
+#[query]
+fn get_user_activity_after(
+    principal: Principal,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<OwnershipEventCursorPage, AssetError> {
+    ownership_events_after(cursor, limit, |event| event.to == principal || event.from == Some(principal))
+}
+
+fn ownership_events_after<F>(cursor: Option<String>, limit: u64, filter: F) -> Result<OwnershipEventCursorPage, AssetError>
+where
+    F: Fn(&OwnershipEvent) -> bool,
+{
+    let start = cursor_start(cursor, EVENT_CURSOR_TAG)?;
+
+    OWNERSHIP_EVENTS.with(|events| {
+        let events = events.borrow();
+        let matching = events.range((start, Bound::Unbounded)).filter(|(_, event)| filter(event));
+        let (events, next_cursor) = page_after(matching, limit, EVENT_CURSOR_TAG);
+        Ok(OwnershipEventCursorPage { events, next_cursor })
+    })
+}
+
+fn record_ownership_event(
+    asset_id: u64,
+    from: Option<Principal>,
+    to: Principal,
+    kind: OwnershipEventKind,
+    price: Option<u64>,
+    current_time: u64,
+) {
+    let event = OwnershipEvent {
+        id: get_next_ownership_event_id(),
+        asset_id,
+        from,
+        to,
+        kind,
+        price,
+        timestamp: current_time,
+        reverted: None,
+    };
+
+    ASSET_OWNERSHIP_EVENT_INDEX.with(|index| index.borrow_mut().insert((asset_id, event.id), ()));
+    OWNERSHIP_EVENTS.with(|events| {
+        events.borrow_mut().insert(event.id, event);
+    });
+}
+
+fn rebuild_asset_ownership_event_index() {
+    let keys: Vec<(u64, u64)> =
+        OWNERSHIP_EVENTS.with(|events| events.borrow().iter().map(|(id, event)| (event.asset_id, id)).collect());
+    ASSET_OWNERSHIP_EVENT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Remediation for wrong transfers, e.g. from a marketplace canister bug.
+// Within the dispute window after a transfer or sale, and only while the
+// asset has not moved again, a controller can hand it back to the previous
+// owner. Payments are not refunded here; they have to be settled separately.
+#[update]
+fn revert_transfer(asset_id: u64, provenance_event_id: u64, reason: String) -> Result<Asset, String> {
+    ensure_running("revert_transfer").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    revert_ownership_event(asset_id, provenance_event_id, &reason, caller(), time())
+}
+
+#[update]
+fn set_dispute_window(window_nanos: u64) -> Result<u64, String> {
+    ensure_running("set_dispute_window").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if window_nanos > MAX_DISPUTE_WINDOW_NANOS {
+        return Err(format!("window_nanos: must be at most {}", MAX_DISPUTE_WINDOW_NANOS));
+    }
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("dispute_window_nanos".to_string(), window_nanos.to_string());
+    });
+    Ok(window_nanos)
+}
+
+#[query]
+fn get_dispute_window() -> u64 {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"dispute_window_nanos".to_string())
+            .and_then(|window| window.parse().ok())
+            .unwrap_or(DEFAULT_DISPUTE_WINDOW_NANOS)
+    })
+}
+
+fn revert_ownership_event(
+    asset_id: u64,
+    event_id: u64,
+    reason: &str,
+    admin: Principal,
+    current_time: u64,
+) -> Result<Asset, String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("Revert reason cannot be empty".to_string());
+    }
+    if reason.chars().count() > MAX_TAKEDOWN_DETAILS_LENGTH {
+        return Err(format!("Revert reason must be at most {} characters", MAX_TAKEDOWN_DETAILS_LENGTH));
+    }
+
+    let mut event = OWNERSHIP_EVENTS
+        .with(|events| events.borrow().get(&event_id))
+        .filter(|event| event.asset_id == asset_id)
+        .ok_or_else(|| "Provenance event not found for this asset".to_string())?;
+    let previous_owner = match (&event.kind, event.from) {
+        (
+            OwnershipEventKind::Transfer
+            | OwnershipEventKind::MarketplaceSale
+            | OwnershipEventKind::Purchase
+            | OwnershipEventKind::AuctionSale
+            | OwnershipEventKind::OfferAccepted
+            | OwnershipEventKind::EscrowSale
+            | OwnershipEventKind::Gift
+            | OwnershipEventKind::Migration,
+            Some(from),
+        ) => from,
+        _ => return Err("Only transfers and sales can be reverted".to_string()),
+    };
+    if event.reverted.is_some() {
+        return Err("Event has already been reverted".to_string());
+    }
+    if current_time > event.timestamp.saturating_add(get_dispute_window()) {
+        return Err("Dispute window for this event has closed".to_string());
+    }
+    let moved_since = ASSET_OWNERSHIP_EVENT_INDEX.with(|index| {
+        index.borrow().range((Bound::Excluded((asset_id, event_id)), Bound::Included((asset_id, u64::MAX)))).next().is_some()
+    });
+
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or_else(|| "Asset not found".to_string())?;
+        if moved_since || asset.owner != event.to {
+            return Err("Asset has changed hands since this event".to_string());
+        }
+        if is_transfer_in_progress(asset_id) {
+            return Err(AssetError::TransferInProgress.to_string());
+        }
+
+        change_owner(&mut asset, previous_owner, OwnershipEventKind::TransferReverted { event_id }, None, current_time);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })?;
+
+    event.reverted = Some(Reversal {
+        reverted_by: admin,
+        reason: reason.to_string(),
+        reverted_at: current_time,
+    });
+    OWNERSHIP_EVENTS.with(|events| events.borrow_mut().insert(event_id, event.clone()));
+    notify(previous_owner, NotificationKind::TransferReverted, asset_id, Some(event.to), None, current_time);
+    notify(event.to, NotificationKind::TransferReverted, asset_id, Some(previous_owner), None, current_time);
+    record_takedown_event(asset_id, TakedownEventKind::TransferReverted { event_id }, admin, reason, current_time);
+    Ok(asset)
+}
+
+// Activity feed for indexers mirroring the marketplace. Events about assets
+// anonymous callers cannot see are skipped, but still advance `next_seq`.
+#[query]
+fn get_events(start_seq: u64, limit: u64) -> ActivityEventPage {
+    activity_page(start_seq, limit)
+}
+
+#[query]
+fn get_latest_seq() -> u64 {
+    latest_activity_seq()
+}
+
+fn activity_page(start_seq: u64, limit: u64) -> ActivityEventPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let oldest_seq = ACTIVITY_EVENTS
+        .with(|events| events.borrow().first_key_value().map(|(seq, _)| seq))
+        .unwrap_or(latest_activity_seq() + 1);
+    let start_seq = start_seq.max(oldest_seq);
+
+    let scanned: Vec<ActivityEvent> = ACTIVITY_EVENTS.with(|events| {
+        events
+            .borrow()
+            .range(start_seq..)
+            .take(limit as usize)
+            .map(|(_, event)| event)
+            .collect()
+    });
+    let next_seq = scanned.last().map_or(start_seq, |event| event.seq + 1);
+    let events = scanned
+        .into_iter()
+        .filter(|event| asset_by_id(event.asset_id).is_none_or(|asset| can_view(&asset, Principal::anonymous())))
+        .collect();
+
+    ActivityEventPage {
+        events,
+        oldest_seq,
+        latest_seq: latest_activity_seq(),
+        next_seq,
+    }
+}
+
+fn latest_activity_seq() -> u64 {
+    ACTIVITY_SEQ_COUNTER.with(|counter| counter.borrow().get(&0).unwrap_or(0))
+}
+
+fn record_activity(asset_id: u64, actor: Option<Principal>, kind: ActivityKind, current_time: u64) {
+    let event = ActivityEvent {
+        seq: get_next_activity_seq(),
+        asset_id,
+        actor,
+        kind,
+        timestamp: current_time,
+    };
+
+    if queue_event_deliveries(&event) {
+        schedule_event_delivery(current_time, current_time);
+    }
+    ACTIVITY_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        events.insert(event.seq, event);
+        while events.len() > MAX_ACTIVITY_EVENTS {
+            events.pop_first();
+        }
+    });
+}
+
+// Emits Listed or Unlisted when an update flipped the asset's listing
+fn record_listing_activity(was_for_sale: bool, asset: &Asset, actor: Option<Principal>, current_time: u64) {
+    match (was_for_sale, asset.is_for_sale) {
+        (false, true) => {
+            record_activity(asset.id, actor, ActivityKind::Listed { price: icp_price(asset) }, current_time)
+        },
+        (true, false) => record_activity(asset.id, actor, ActivityKind::Unlisted, current_time),
+        _ => {},
+    }
+}
+
+// Subscribing a canister again replaces its event kinds and keeps its
+// delivery counts
+#[update]
+fn subscribe_events(callback_canister: Principal, event_kinds: Vec<EventKind>) -> Result<EventSubscription, String> {
+    ensure_running("subscribe_events").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    subscribe(callback_canister, event_kinds, caller(), time())
+}
+
+// Deliveries still queued for the canister are dropped with the subscription
+#[update]
+fn unsubscribe_events(callback_canister: Principal) -> Result<(), String> {
+    ensure_running("unsubscribe_events").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    unsubscribe(callback_canister)
+}
+
+#[query]
+fn list_subscriptions() -> Result<Vec<EventSubscription>, String> {
+    ensure_controller()?;
+    Ok(EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().values().collect()))
+}
+
+fn subscribe(
+    callback_canister: Principal,
+    mut event_kinds: Vec<EventKind>,
+    subscribed_by: Principal,
+    current_time: u64,
+) -> Result<EventSubscription, String> {
+    if callback_canister == Principal::anonymous() || callback_canister == Principal::management_canister() {
+        return Err("callback_canister: must be a canister".to_string());
+    }
+    event_kinds.sort();
+    event_kinds.dedup();
+    if event_kinds.is_empty() {
+        return Err("event_kinds: must name at least one kind".to_string());
+    }
+
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        let subscription = match subscriptions.get(&callback_canister) {
+            Some(existing) => EventSubscription { event_kinds, subscribed_by, subscribed_at: current_time, ..existing },
+            None if subscriptions.len() >= MAX_EVENT_SUBSCRIPTIONS => {
+                return Err(format!("At most {} canisters can subscribe", MAX_EVENT_SUBSCRIPTIONS));
+            },
+            None => EventSubscription {
+                callback_canister,
+                event_kinds,
+                subscribed_by,
+                subscribed_at: current_time,
+                delivered_count: 0,
+                dropped_count: 0,
+                last_failure: None,
+            },
+        };
+        subscriptions.insert(callback_canister, subscription.clone());
+        Ok(subscription)
+    })
+}
+
+fn unsubscribe(callback_canister: Principal) -> Result<(), String> {
+    EVENT_SUBSCRIPTIONS
+        .with(|subscriptions| subscriptions.borrow_mut().remove(&callback_canister))
+        .ok_or_else(|| "Canister is not subscribed".to_string())?;
+    EVENT_OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        let queued: Vec<(u64, Principal)> = outbox.keys().filter(|(_, canister)| *canister == callback_canister).collect();
+        for key in queued {
+            outbox.remove(&key);
+        }
+    });
+    Ok(())
+}
+
+// Queues the event for every canister subscribed to its kind and returns
+// whether anything was queued. Nothing is sent here, so the operation that
+// produced the event never waits on a subscriber or fails because of one.
+fn queue_event_deliveries(event: &ActivityEvent) -> bool {
+    let kind = event.kind.event_kind();
+    let subscribers: Vec<Principal> = EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .values()
+            .filter(|subscription| subscription.event_kinds.contains(&kind))
+            .map(|subscription| subscription.callback_canister)
+            .collect()
+    });
+
+    let mut queued_any = false;
+    for callback_canister in subscribers {
+        let queued = EVENT_OUTBOX.with(|outbox| {
+            let mut outbox = outbox.borrow_mut();
+            if outbox.len() >= MAX_PENDING_EVENT_DELIVERIES {
+                return false;
+            }
+            let delivery = PendingDelivery {
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: event.timestamp,
+            };
+            outbox.insert((event.seq, callback_canister), delivery);
+            true
+        });
+        if queued {
+            queued_any = true;
+        } else {
+            record_dropped_delivery(
+                callback_canister,
+                DeliveryFailure {
+                    event_seq: event.seq,
+                    attempts: 0,
+                    error: "Delivery queue is full".to_string(),
+                    failed_at: event.timestamp,
+                },
+            );
+        }
+    }
+    queued_any
+}
+
+// Sends up to a batch of due deliveries through `send` and returns when the
+// next queued delivery is due. A retried delivery can reach its subscriber
+// after later events; the activity seq gives the order.
+fn deliver_due_events<F>(current_time: u64, mut send: F) -> Option<u64>
+where
+    F: FnMut(Principal, &ActivityEvent) -> Result<(), String>,
+{
+    let due: Vec<((u64, Principal), PendingDelivery)> = EVENT_OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .filter(|(_, delivery)| delivery.next_attempt_at <= current_time)
+            .take(EVENT_DELIVERY_BATCH_SIZE)
+            .collect()
+    });
+
+    for (key, mut delivery) in due {
+        let (event_seq, callback_canister) = key;
+        match send(callback_canister, &delivery.event) {
+            Ok(()) => {
+                EVENT_OUTBOX.with(|outbox| outbox.borrow_mut().remove(&key));
+                update_subscription(callback_canister, |subscription| subscription.delivered_count += 1);
+            },
+            Err(error) => {
+                delivery.attempts += 1;
+                if delivery.attempts >= MAX_EVENT_DELIVERY_ATTEMPTS {
+                    EVENT_OUTBOX.with(|outbox| outbox.borrow_mut().remove(&key));
+                    let failure = DeliveryFailure { event_seq, attempts: delivery.attempts, error, failed_at: current_time };
+                    record_dropped_delivery(callback_canister, failure);
+                } else {
+                    delivery.next_attempt_at = current_time + (EVENT_RETRY_BASE_NANOS << (delivery.attempts - 1));
+                    EVENT_OUTBOX.with(|outbox| outbox.borrow_mut().insert(key, delivery));
+                }
+            },
+        }
+    }
+
+    EVENT_OUTBOX.with(|outbox| outbox.borrow().values().map(|delivery| delivery.next_attempt_at).min())
+}
+
+fn record_dropped_delivery(callback_canister: Principal, failure: DeliveryFailure) {
+    update_subscription(callback_canister, |subscription| {
+        subscription.dropped_count += 1;
+        subscription.last_failure = Some(failure);
+    });
+}
+
+fn update_subscription(callback_canister: Principal, update: impl FnOnce(&mut EventSubscription)) {
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        if let Some(mut subscription) = subscriptions.get(&callback_canister) {
+            update(&mut subscription);
+            subscriptions.insert(callback_canister, subscription);
+        }
+    });
+}
+
+// Sets the delivery timer to fire at `at`, unless it already fires sooner
+fn schedule_event_delivery(at: u64, current_time: u64) {
+    if EVENT_DELIVERY_AT.with(|scheduled| scheduled.borrow().is_some_and(|scheduled| scheduled <= at)) {
+        return;
+    }
+    EVENT_DELIVERY_AT.with(|scheduled| *scheduled.borrow_mut() = Some(at));
+    ic_cdk_timers::set_timer(Duration::from_nanos(at.saturating_sub(current_time)), run_event_delivery);
+}
+
+fn schedule_pending_event_delivery() {
+    if EVENT_OUTBOX.with(|outbox| !outbox.borrow().is_empty()) {
+        schedule_event_delivery(time(), time());
+    }
+}
+
+// One-way calls: a subscriber that traps or rejects is not retried, only a
+// call that could not be sent at all
+fn run_event_delivery() {
+    EVENT_DELIVERY_AT.with(|scheduled| *scheduled.borrow_mut() = None);
+    let current_time = time();
+    let next = deliver_due_events(current_time, |callback_canister, event| {
+        ic_cdk::api::call::notify(callback_canister, EVENT_CALLBACK_METHOD, (event.clone(),))
+            .map_err(|code| format!("Notify call could not be sent ({:?})", code))
+    });
+    if let Some(next) = next {
+        schedule_event_delivery(next.max(current_time), current_time);
+    }
+}
+
+fn paginate_ownership_events<F>(offset: u64, limit: u64, filter: F) -> OwnershipEventPage
+where
+    F: Fn(&OwnershipEvent) -> bool,
+{
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    OWNERSHIP_EVENTS.with(|events| {
+        let mut page = Vec::new();
+        let mut total_count = 0;
+
+        for (_, event) in events.borrow().iter() {
+            if !filter(&event) {
+                continue;
+            }
+            if total_count >= offset && (page.len() as u64) < limit {
+                page.push(event);
+            }
+            total_count += 1;
+        }
+
+        OwnershipEventPage {
+            events: page,
+            total_count,
+        }
+    })
+}
+
+// Rewrites every asset record in the current schema version
+#[update]
+fn migrate_assets() -> Result<u64, String> {
+    ensure_running("migrate_assets").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(rewrite_assets())
+}
+
+fn rewrite_assets() -> u64 {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let records: Vec<(u64, Asset)> = assets.iter().collect();
+        let count = records.len() as u64;
+
+        for (asset_id, asset) in records {
+            assets.insert(asset_id, asset);
+        }
+        count
+    })
+}
+
+// ICRC-7 interface over the asset store. Assets are owned by principals, so
+// only default (empty or all-zero) subaccounts ever hold tokens.
+#[query]
+fn icrc7_name() -> String {
+    ICRC7_NAME.to_string()
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    ICRC7_SYMBOL.to_string()
+}
+
+#[query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(ASSETS.with(|assets| assets.borrow().len()))
+}
+
+#[query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.map_or(Some(0), |prev| token_id_to_asset_id(&prev).map(|id| id.saturating_add(1)));
+    let start = match start {
+        Some(start) => start,
+        None => return Vec::new(),
+    };
+
+    ASSETS.with(|assets| {
+        assets
+            .borrow()
+            .range(start..)
+            .take(icrc7_take(take))
+            .map(|(asset_id, _)| Nat::from(asset_id))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .map(|token_id| {
+            token_id_to_asset_id(token_id)
+                .and_then(asset_by_id)
+                .map(|asset| Account { owner: asset.owner, subaccount: None })
+        })
+        .collect()
+}
+
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .map(|token_id| {
+            token_id_to_asset_id(token_id).and_then(asset_by_id).map(|asset| {
+                // The price is in e8s, so it is left out for assets not sold in ICP
+                let price = icp_price(&asset).map(|price| ("price".to_string(), Value::Nat(Nat::from(price))));
+                vec![
+                    ("name".to_string(), Value::Text(asset.name)),
+                    ("description".to_string(), Value::Text(asset.description)),
+                    ("file_url".to_string(), Value::Text(asset.file_url)),
+                ]
+                .into_iter()
+                .chain(price)
+                .collect()
+            })
+        })
+        .collect()
+}
+
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    if !is_default_subaccount(&account.subaccount) {
+        return Vec::new();
+    }
+    let after = prev.as_ref().and_then(token_id_to_asset_id);
+
+    ASSETS.with(|assets| {
+        assets
+            .borrow()
+            .iter()
+            .filter(|(asset_id, asset)| asset.owner == account.owner && after.is_none_or(|after| *asset_id > after))
+            .take(icrc7_take(take))
+            .map(|(asset_id, _)| Nat::from(asset_id))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .iter()
+        .map(|account| {
+            if !is_default_subaccount(&account.subaccount) {
+                return Nat::from(0u64);
+            }
+            let balance = ASSETS.with(|assets| {
+                assets
+                    .borrow()
+                    .iter()
+                    .filter(|(_, asset)| asset.owner == account.owner)
+                    .count()
+            });
+            Nat::from(balance)
+        })
+        .collect()
+}
+
+#[update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<Result<Nat, TransferError>>> {
+    if let Err(err) = ensure_running("icrc7_transfer") {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: err.to_string(),
+        }))];
+    }
+    let principal = caller();
+
+    if args.len() > ICRC7_MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("At most {} transfers are allowed per call", ICRC7_MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| {
+            if !is_default_subaccount(&arg.from_subaccount) {
+                return Some(Err(TransferError::Unauthorized));
+            }
+            if !is_default_subaccount(&arg.to.subaccount)
+                || arg.to.owner == Principal::anonymous()
+                || arg.to.owner == principal
+            {
+                return Some(Err(TransferError::InvalidRecipient));
+            }
+            let asset_id = match token_id_to_asset_id(&arg.token_id) {
+                Some(asset_id) => asset_id,
+                None => return Some(Err(TransferError::NonExistingTokenId)),
+            };
+
+            let result = match transfer_owned_asset(asset_id, principal, arg.to.owner) {
+                Ok(_) => Ok(Nat::from(get_next_icrc7_tx_id())),
+                Err(AssetError::NotFound) => Err(TransferError::NonExistingTokenId),
+                Err(AssetError::NotOwner) => Err(TransferError::Unauthorized),
+                Err(err) => Err(TransferError::GenericError {
+                    error_code: Nat::from(0u64),
+                    message: err.to_string(),
+                }),
+            };
+            Some(result)
+        })
+        .collect()
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+fn token_id_to_asset_id(token_id: &Nat) -> Option<u64> {
+    u64::try_from(&token_id.0).ok()
+}
+
+fn icrc7_take(take: Option<Nat>) -> usize {
+    take.and_then(|take| u64::try_from(&take.0).ok())
+        .unwrap_or(MAX_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE) as usize
+}
+
+fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    subaccount.as_ref().is_none_or(|subaccount| subaccount.iter().all(|byte| *byte == 0))
+}
+
+// Moderation. Anyone may report an asset; controllers and moderators review
+// reports and can hide or remove assets.
+#[update]
+fn report_asset(asset_id: u64, reason: String) -> Result<AssetReport, AssetError> {
+    ensure_running("report_asset")?;
+    let principal = caller();
+    let current_time = time();
+
+    if principal == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "reason: must be between 1 and {} characters",
+            MAX_REPORT_REASON_LENGTH
+        )));
+    }
+    if asset_by_id(asset_id).is_none() {
+        return Err(AssetError::NotFound);
+    }
+
+    let (already_reported, recent_reports) = REPORTS.with(|reports| {
+        reports.borrow().iter().filter(|(_, report)| report.reporter == principal).fold(
+            (false, Vec::new()),
+            |(already_reported, mut recent_reports), (_, report)| {
+                if current_time - report.created_at < REPORT_WINDOW_NANOS {
+                    recent_reports.push(report.created_at);
+                }
+                (already_reported || report.asset_id == asset_id, recent_reports)
+            },
+        )
+    });
+    if already_reported {
+        return Err(AssetError::AlreadyExists);
+    }
+    if recent_reports.len() >= MAX_REPORTS_PER_DAY {
+        let oldest = recent_reports.iter().min().copied().unwrap_or(current_time);
+        return Err(AssetError::RateLimited {
+            retry_after_seconds: retry_after_seconds(oldest + REPORT_WINDOW_NANOS, current_time),
+        });
+    }
+
+    let report = AssetReport {
+        id: get_next_report_id(),
+        asset_id,
+        reporter: principal,
+        reason,
+        created_at: current_time,
+    };
+    REPORTS.with(|reports| {
+        reports.borrow_mut().insert(report.id, report.clone());
+    });
+
+    Ok(report)
+}
+
+#[query]
+fn get_reported_assets() -> Result<Vec<ReportedAsset>, String> {
+    ensure_moderator()?;
+
+    let mut reported: BTreeMap<u64, Vec<AssetReport>> = BTreeMap::new();
+    REPORTS.with(|reports| {
+        for (_, report) in reports.borrow().iter() {
+            reported.entry(report.asset_id).or_default().push(report);
+        }
+    });
+
+    Ok(reported
+        .into_iter()
+        .map(|(asset_id, reports)| ReportedAsset {
+            asset_id,
+            hidden: asset_by_id(asset_id).and_then(|asset| asset.hidden),
+            reports,
+        })
+        .collect())
+}
+
+#[update]
+fn hide_asset(asset_id: u64, reason: String) -> Result<Asset, String> {
+    ensure_running("hide_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    hide_as_moderator(asset_id, reason.trim(), caller(), time())
+}
+
+fn hide_as_moderator(asset_id: u64, reason: &str, moderator: Principal, current_time: u64) -> Result<Asset, String> {
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress.to_string());
+    }
+
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                let was_for_sale = asset.is_for_sale;
+                unindex_asset(&asset);
+                asset.hidden = Some(Moderation {
+                    reason: reason.to_string(),
+                    hidden_by: moderator,
+                    hidden_at: current_time,
+                });
+                asset.is_for_sale = false;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                record_listing_activity(was_for_sale, &asset, Some(moderator), current_time);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err("Asset not found".to_string()),
+        }
+    })?;
+
+    // Hidden assets cannot be sold, so pending offers and auctions end here
+    invalidate_offers(asset_id, current_time);
+    if is_auction_active(asset_id) {
+        finish_auction(asset_id, AuctionStatus::Cancelled, current_time);
+    }
+    if is_dutch_auction_active(asset_id) {
+        finish_dutch_auction(asset_id, AuctionStatus::Cancelled, None, current_time);
+    }
+
+    Ok(asset)
+}
+
+#[update]
+fn unhide_asset(asset_id: u64) -> Result<Asset, String> {
+    ensure_running("unhide_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    if is_taken_down(asset_id) {
+        return Err("Asset is under a takedown and can only be reinstated through resolve_appeal".to_string());
+    }
+    show_asset(asset_id, time())
+}
+
+fn show_asset(asset_id: u64, current_time: u64) -> Result<Asset, String> {
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+
+        match assets.get(&asset_id) {
+            Some(mut asset) => {
+                unindex_asset(&asset);
+                asset.hidden = None;
+                asset.updated_at = current_time;
+                index_asset(&asset);
+                assets.insert(asset_id, asset.clone());
+                Ok(asset)
+            },
+            None => Err("Asset not found".to_string()),
+        }
+    })
+}
+
+// Freezing keeps the asset visible and listed but refuses every change to it,
+// sale or transfer until a moderator lifts the hold
+#[update]
+fn freeze_asset(asset_id: u64, reason: String) -> Result<Asset, String> {
+    ensure_running("freeze_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    freeze_as_moderator(asset_id, &reason, caller(), time())
+}
+
+#[update]
+fn unfreeze_asset(asset_id: u64) -> Result<Asset, String> {
+    ensure_running("unfreeze_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    lift_freeze(asset_id, caller(), time())
+}
+
+fn freeze_as_moderator(asset_id: u64, reason: &str, moderator: Principal, current_time: u64) -> Result<Asset, String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("Freeze reason cannot be empty".to_string());
+    }
+    if reason.chars().count() > MAX_TAKEDOWN_DETAILS_LENGTH {
+        return Err(format!("Freeze reason must be at most {} characters", MAX_TAKEDOWN_DETAILS_LENGTH));
+    }
+    // A ledger payment already in flight must be allowed to land
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress.to_string());
+    }
+
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or_else(|| "Asset not found".to_string())?;
+        if asset.frozen.is_some() {
+            return Err("Asset is already frozen".to_string());
+        }
+
+        unindex_asset(&asset);
+        asset.frozen = Some(Freeze {
+            reason: reason.to_string(),
+            frozen_by: moderator,
+            frozen_at: current_time,
+        });
+        asset.updated_at = current_time;
+        index_asset(&asset);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })?;
+
+    // Auctions would settle on their own timer, so they end here rather than
+    // at a frozen asset
+    if is_auction_active(asset_id) {
+        finish_auction(asset_id, AuctionStatus::Cancelled, current_time);
+    }
+    if is_dutch_auction_active(asset_id) {
+        finish_dutch_auction(asset_id, AuctionStatus::Cancelled, None, current_time);
+    }
+    record_takedown_event(asset_id, TakedownEventKind::Frozen, moderator, reason, current_time);
+    Ok(asset)
+}
+
+fn lift_freeze(asset_id: u64, moderator: Principal, current_time: u64) -> Result<Asset, String> {
+    let asset = ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let mut asset = assets.get(&asset_id).ok_or_else(|| "Asset not found".to_string())?;
+        if asset.frozen.is_none() {
+            return Err("Asset is not frozen".to_string());
+        }
+
+        unindex_asset(&asset);
+        asset.frozen = None;
+        asset.updated_at = current_time;
+        index_asset(&asset);
+        assets.insert(asset_id, asset.clone());
+        Ok(asset)
+    })?;
+
+    record_takedown_event(asset_id, TakedownEventKind::Unfrozen, moderator, "", current_time);
+    Ok(asset)
+}
+
+// The curated "Featured" carousel. Featuring an asset that is already
+// featured moves it to `position` (the end when None) and replaces its
+// `until`, so moderators can reorder without starting over. Entries lapse on
+// their own once `until` passes or the asset is hidden, made non-public,
+// taken down, deleted or changes hands.
+#[update]
+fn feature_asset(asset_id: u64, position: Option<u32>, until: Option<u64>) -> Result<Vec<FeaturedEntry>, String> {
+    ensure_running("feature_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    feature_as_moderator(asset_id, position, until, caller(), time())
+}
+
+#[update]
+fn unfeature_asset(asset_id: u64) -> Result<Vec<FeaturedEntry>, String> {
+    ensure_running("unfeature_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    unfeature(asset_id, time())
+}
+
+#[query]
+fn get_featured_assets() -> Vec<Asset> {
+    featured_entries(time())
+        .iter()
+        .filter_map(|entry| asset_by_id(entry.asset_id))
+        .collect()
+}
+
+fn feature_as_moderator(
+    asset_id: u64,
+    position: Option<u32>,
+    until: Option<u64>,
+    moderator: Principal,
+    current_time: u64,
+) -> Result<Vec<FeaturedEntry>, String> {
+    let asset = asset_by_id(asset_id).ok_or_else(|| "Asset not found".to_string())?;
+    if !is_featurable(&asset) {
+        return Err("Only public assets can be featured".to_string());
+    }
+    if until.is_some_and(|until| until <= current_time) {
+        return Err("until: must be in the future".to_string());
+    }
+
+    let mut entries = featured_entries(current_time);
+    entries.retain(|entry| entry.asset_id != asset_id);
+    if entries.len() >= MAX_FEATURED_ASSETS {
+        return Err(format!("At most {} assets can be featured", MAX_FEATURED_ASSETS));
+    }
+    let index = position.map_or(entries.len(), |position| (position as usize).min(entries.len()));
+    entries.insert(
+        index,
+        FeaturedEntry {
+            asset_id,
+            owner: asset.owner,
+            until,
+            featured_by: moderator,
+            featured_at: current_time,
+        },
+    );
+    save_featured(&entries);
+    Ok(entries)
+}
+
+fn unfeature(asset_id: u64, current_time: u64) -> Result<Vec<FeaturedEntry>, String> {
+    let mut entries = featured_entries(current_time);
+    let before = entries.len();
+    entries.retain(|entry| entry.asset_id != asset_id);
+    if entries.len() == before {
+        return Err("Asset is not featured".to_string());
+    }
+    save_featured(&entries);
+    Ok(entries)
+}
+
+// Live entries only; lapsed ones are dropped from storage on the next change
+fn featured_entries(current_time: u64) -> Vec<FeaturedEntry> {
+    FEATURED.with(|featured| featured.borrow().get().entries.clone())
+        .into_iter()
+        .filter(|entry| entry.until.is_none_or(|until| current_time < until))
+        .filter(|entry| {
+            asset_by_id(entry.asset_id).is_some_and(|asset| asset.owner == entry.owner && is_featurable(&asset))
+        })
+        .collect()
+}
+
+fn save_featured(entries: &[FeaturedEntry]) {
+    FEATURED.with(|featured| {
+        featured
+            .borrow_mut()
+            .set(FeaturedList { entries: entries.to_vec() })
+            .expect("the featured list fits its memory")
+    });
+}
+
+fn is_featurable(asset: &Asset) -> bool {
+    is_listed_publicly(asset) && !is_taken_down(asset.id)
+}
+
+// Takedowns hide the asset like hide_asset, but also freeze transfers and
+// withhold its files from everyone but moderators until an appeal succeeds
+#[update]
+fn takedown_asset(asset_id: u64, reason_code: TakedownReason, details: String) -> Result<Takedown, String> {
+    ensure_running("takedown_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    issue_takedown(asset_id, reason_code, &details, caller(), time())
+}
+
+#[update]
+fn file_appeal(asset_id: u64, statement: String) -> Result<Takedown, AssetError> {
+    ensure_running("file_appeal")?;
+    appeal_takedown(asset_id, &statement, caller(), time())
+}
+
+#[query]
+fn get_takedown(asset_id: u64) -> Option<Takedown> {
+    let takedown = takedown_of(asset_id)?;
+    let principal = caller();
+    if takedown.owner == principal || is_moderator(principal) {
+        Some(takedown)
+    } else {
+        None
+    }
+}
+
+#[query]
+fn get_pending_appeals() -> Result<Vec<Takedown>, String> {
+    ensure_moderator()?;
+    Ok(pending_appeals())
+}
+
+#[update]
+fn resolve_appeal(asset_id: u64, reinstate: bool, note: String) -> Result<Takedown, String> {
+    ensure_running("resolve_appeal").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    decide_appeal(asset_id, reinstate, &note, caller(), time())
+}
+
+#[query]
+fn get_takedown_events(offset: u64, limit: u64) -> Result<TakedownEventPage, String> {
+    ensure_moderator()?;
+    let limit = limit.min(MAX_PAGE_SIZE);
+    Ok(TAKEDOWN_EVENTS.with(|events| {
+        let events = events.borrow();
+        TakedownEventPage {
+            events: events
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|(_, event)| event)
+                .collect(),
+            total_count: events.len(),
+        }
+    }))
+}
+
+fn issue_takedown(
+    asset_id: u64,
+    reason_code: TakedownReason,
+    details: &str,
+    moderator: Principal,
+    current_time: u64,
+) -> Result<Takedown, String> {
+    let details = details.trim();
+    if details.is_empty() {
+        return Err("Takedown details cannot be empty".to_string());
+    }
+    if details.chars().count() > MAX_TAKEDOWN_DETAILS_LENGTH {
+        return Err(format!("Takedown details must be at most {} characters", MAX_TAKEDOWN_DETAILS_LENGTH));
+    }
+    if is_taken_down(asset_id) {
+        return Err("Asset is already taken down".to_string());
+    }
+
+    let asset = hide_as_moderator(asset_id, &format!("{:?}: {}", reason_code, details), moderator, current_time)?;
+    // A frozen asset cannot be handed over through anything already in flight
+    PENDING_TRANSFERS.with(|transfers| {
+        transfers.borrow_mut().remove(&asset_id);
+    });
+    remove_gift(asset_id);
+
+    let takedown = Takedown {
+        asset_id,
+        owner: asset.owner,
+        reason_code,
+        details: details.to_string(),
+        taken_down_by: moderator,
+        taken_down_at: current_time,
+        appeal: None,
+        resolution: None,
+    };
+    TAKEDOWNS.with(|takedowns| takedowns.borrow_mut().insert(asset_id, takedown.clone()));
+    record_takedown_event(asset_id, TakedownEventKind::TakenDown { reason_code }, moderator, details, current_time);
+    notify(asset.owner, NotificationKind::AssetTakenDown, asset_id, None, None, current_time);
+    Ok(takedown)
+}
+
+// Each takedown can be appealed once, by whoever owned the asset when it was
+// taken down
+fn appeal_takedown(asset_id: u64, statement: &str, principal: Principal, current_time: u64) -> Result<Takedown, AssetError> {
+    let mut takedown = takedown_of(asset_id)
+        .filter(Takedown::is_active)
+        .ok_or(AssetError::NotFound)?;
+    if takedown.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if takedown.appeal.is_some() {
+        return Err(AssetError::AlreadyExists);
+    }
+    let statement = statement.trim();
+    if statement.is_empty() {
+        return Err(AssetError::InvalidInput("statement: cannot be empty".to_string()));
+    }
+    if statement.chars().count() > MAX_APPEAL_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "statement: must be at most {} characters",
+            MAX_APPEAL_LENGTH
+        )));
+    }
+
+    takedown.appeal = Some(Appeal {
+        statement: statement.to_string(),
+        filed_at: current_time,
+    });
+    TAKEDOWNS.with(|takedowns| takedowns.borrow_mut().insert(asset_id, takedown.clone()));
+    record_takedown_event(asset_id, TakedownEventKind::AppealFiled, principal, statement, current_time);
+    Ok(takedown)
+}
+
+fn decide_appeal(
+    asset_id: u64,
+    reinstate: bool,
+    note: &str,
+    moderator: Principal,
+    current_time: u64,
+) -> Result<Takedown, String> {
+    let mut takedown = takedown_of(asset_id).ok_or_else(|| "Asset has no takedown".to_string())?;
+    if takedown.appeal.is_none() {
+        return Err("No appeal has been filed for this takedown".to_string());
+    }
+    if takedown.resolution.is_some() {
+        return Err("This appeal has already been resolved".to_string());
+    }
+    let note = note.trim();
+    if note.chars().count() > MAX_TAKEDOWN_DETAILS_LENGTH {
+        return Err(format!("Resolution note must be at most {} characters", MAX_TAKEDOWN_DETAILS_LENGTH));
+    }
+
+    if reinstate {
+        show_asset(asset_id, current_time)?;
+    }
+    takedown.resolution = Some(AppealResolution {
+        reinstated: reinstate,
+        note: note.to_string(),
+        resolved_by: moderator,
+        resolved_at: current_time,
+    });
+    TAKEDOWNS.with(|takedowns| takedowns.borrow_mut().insert(asset_id, takedown.clone()));
+
+    let (kind, notification) = if reinstate {
+        (TakedownEventKind::Reinstated, NotificationKind::AssetReinstated)
+    } else {
+        (TakedownEventKind::Upheld, NotificationKind::AppealRejected)
+    };
+    record_takedown_event(asset_id, kind, moderator, note, current_time);
+    notify(takedown.owner, notification, asset_id, None, None, current_time);
+    Ok(takedown)
+}
+
+fn pending_appeals() -> Vec<Takedown> {
+    TAKEDOWNS.with(|takedowns| {
+        takedowns
+            .borrow()
+            .iter()
+            .map(|(_, takedown)| takedown)
+            .filter(|takedown| takedown.appeal.is_some() && takedown.resolution.is_none())
+            .collect()
+    })
+}
+
+fn takedown_of(asset_id: u64) -> Option<Takedown> {
+    TAKEDOWNS.with(|takedowns| takedowns.borrow().get(&asset_id))
+}
+
+fn is_taken_down(asset_id: u64) -> bool {
+    takedown_of(asset_id).is_some_and(|takedown| takedown.is_active())
+}
+
+fn record_takedown_event(asset_id: u64, kind: TakedownEventKind, actor: Principal, note: &str, current_time: u64) {
+    let event = TakedownEvent {
+        id: get_next_takedown_event_id(),
+        asset_id,
+        kind,
+        actor,
+        note: note.to_string(),
+        timestamp: current_time,
+    };
+    TAKEDOWN_EVENTS.with(|events| events.borrow_mut().insert(event.id, event));
+}
+
+#[update]
+fn admin_remove_asset(asset_id: u64) -> Result<(), String> {
+    ensure_running("admin_remove_asset").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+
+    if PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(&asset_id)) {
+        return Err(AssetError::TransferInProgress.to_string());
+    }
+
+    let asset = ASSETS.with(|assets| assets.borrow_mut().remove(&asset_id))
+        .ok_or_else(|| "Asset not found".to_string())?;
+    if is_auction_active(asset_id) {
+        finish_auction(asset_id, AuctionStatus::Cancelled, time());
+    }
+    purge_asset_data(&asset);
+    record_activity(asset_id, Some(caller()), ActivityKind::Deleted, time());
+    close_marketplace_listings(asset_id);
+
+    Ok(())
+}
+
+// Tells every authorized marketplace that the asset is gone so it closes its
+// listings for it. Not awaited: a marketplace that misses the call still
+// cannot sell the asset, since the transfer it asks for fails with NotFound.
+fn close_marketplace_listings(asset_id: u64) {
+    let marketplaces: Vec<Principal> = AUTHORIZED_MARKETPLACES.with(|marketplaces| marketplaces.borrow().keys().collect());
+    for marketplace in marketplaces {
+        ic_cdk::spawn(async move {
+            let _: Result<(Result<u64, String>,), _> = call(marketplace, "close_listings_for_asset", (asset_id,)).await;
+        });
+    }
+}
+
+#[update]
+fn add_moderator(moderator: Principal) -> Result<(), String> {
+    ensure_running("add_moderator").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    MODERATORS.with(|moderators| {
+        moderators.borrow_mut().insert(moderator, time());
+    });
+    Ok(())
+}
+
+#[update]
+fn remove_moderator(moderator: Principal) -> Result<(), String> {
+    ensure_running("remove_moderator").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    match MODERATORS.with(|moderators| moderators.borrow_mut().remove(&moderator)) {
+        Some(_) => Ok(()),
+        None => Err("Principal is not a moderator".to_string()),
+    }
+}
+
+#[query]
+fn list_moderators() -> Vec<Principal> {
+    MODERATORS.with(|moderators| moderators.borrow().keys().collect())
+}
+
+// Marks a creator as verified so buyers can tell them apart from impersonators
+#[update]
+fn verify_creator(principal: Principal) -> Result<Verification, String> {
+    ensure_running("verify_creator").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    grant_verification(principal, caller(), time())
+}
+
+#[update]
+fn revoke_verification(principal: Principal) -> Result<(), String> {
+    ensure_running("revoke_verification").map_err(|err| err.to_string())?;
+    ensure_moderator()?;
+    withdraw_verification(principal, caller(), time())
+}
+
+#[query]
+fn get_verified_creators(offset: u64, limit: u64) -> VerificationPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    VERIFIED_CREATORS.with(|verified| {
+        let verified = verified.borrow();
+        VerificationPage {
+            creators: verified
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|(_, verification)| verification)
+                .collect(),
+            total_count: verified.len(),
+        }
+    })
+}
+
+#[query]
+fn get_verification_events(offset: u64, limit: u64) -> VerificationEventPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    VERIFICATION_EVENTS.with(|events| {
+        let events = events.borrow();
+        VerificationEventPage {
+            events: events
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|(_, event)| event)
+                .collect(),
+            total_count: events.len(),
+        }
+    })
+}
+
+fn grant_verification(principal: Principal, moderator: Principal, current_time: u64) -> Result<Verification, String> {
+    if principal == Principal::anonymous() {
+        return Err("The anonymous principal cannot be verified".to_string());
+    }
+    if let Some(verification) = verification_of(principal) {
+        return Ok(verification);
+    }
+
+    let verification = Verification {
+        principal,
+        verified_by: moderator,
+        verified_at: current_time,
+    };
+    VERIFIED_CREATORS.with(|verified| verified.borrow_mut().insert(principal, verification.clone()));
+    record_verification_event(principal, VerificationEventKind::Granted, moderator, current_time);
+    Ok(verification)
+}
+
+fn withdraw_verification(principal: Principal, moderator: Principal, current_time: u64) -> Result<(), String> {
+    match VERIFIED_CREATORS.with(|verified| verified.borrow_mut().remove(&principal)) {
+        Some(_) => {
+            record_verification_event(principal, VerificationEventKind::Revoked, moderator, current_time);
+            Ok(())
+        },
+        None => Err("Principal is not verified".to_string()),
+    }
+}
+
+fn record_verification_event(principal: Principal, kind: VerificationEventKind, moderator: Principal, current_time: u64) {
+    let event = VerificationEvent {
+        id: get_next_verification_event_id(),
+        principal,
+        kind,
+        moderator,
+        timestamp: current_time,
+    };
+    VERIFICATION_EVENTS.with(|events| events.borrow_mut().insert(event.id, event));
+}
+
+fn verification_of(principal: Principal) -> Option<Verification> {
+    VERIFIED_CREATORS.with(|verified| verified.borrow().get(&principal))
+}
+
+fn is_verified(principal: Principal) -> bool {
+    VERIFIED_CREATORS.with(|verified| verified.borrow().contains_key(&principal))
+}
+
+fn ensure_moderator() -> Result<(), String> {
+    if !is_moderator(caller()) {
+        return Err("Only canister controllers and moderators can perform this action".to_string());
+    }
+    Ok(())
+}
+
+fn is_moderator(principal: Principal) -> bool {
+    MODERATORS.with(|moderators| moderators.borrow().contains_key(&principal))
+        || ic_cdk::api::is_controller(&principal)
+}
+
+// Whether an asset may appear in listings, search and other browsing results
+fn is_listed_publicly(asset: &Asset) -> bool {
+    asset.hidden.is_none() && asset.archived_at.is_none() && asset.visibility == Visibility::Public
+}
+
+// Listings scoped to one owner show everything to that owner
+fn is_visible_to(asset: &Asset, principal: Principal) -> bool {
+    asset.owner == principal || is_listed_publicly(asset)
+}
+
+// Direct lookups by id: unlisted assets resolve for anyone, private and
+// archived ones only for their owner
+fn can_view(asset: &Asset, principal: Principal) -> bool {
+    asset.owner == principal || (asset.visibility != Visibility::Private && asset.archived_at.is_none())
+}
+
+// A file is withheld only when every asset using it denies the caller: the
+// model file, earlier versions and attachments need download access, previews
+// only need the asset to be visible. Files no asset refers to yet (e.g.
+// mid-upload) stay readable.
+fn can_read_file(file_hash: &str, principal: Principal) -> bool {
+    let asset_ids = referencing_assets(file_hash);
+    if asset_ids.is_empty() {
+        return true;
+    }
+
+    asset_ids.into_iter().filter_map(asset_by_id).any(|asset| {
+        let downloadable = asset.file_hash == file_hash
+            || earlier_versions_of(&asset).iter().any(|version| version.file_hash == file_hash)
+            || attachments_of(asset.id).iter().any(|attachment| attachment.file_hash == file_hash);
+        if downloadable {
+            can_download(&asset, principal)
+        } else {
+            preview_file_hash(&asset.preview_image_url).as_deref() == Some(file_hash) && can_view(&asset, principal)
+        }
+    })
+}
+
+// Free assets, priced at zero in ICP, are open to anyone who can see them;
+// paid ones only to principals holding usage rights. Taken-down assets are withheld from
+// everyone but moderators.
+fn can_download(asset: &Asset, principal: Principal) -> bool {
+    if is_taken_down(asset.id) {
+        return is_moderator(principal);
+    }
+    if !can_view(asset, principal) {
+        return false;
+    }
+    icp_price(asset) == Some(0)
+        || holds_lasting_rights(asset, principal)
+        || active_rental_of(asset.id, principal).is_some_and(|rental| time() < rental.expires_at)
+}
+
+fn holds_usage_rights(asset: &Asset, principal: Principal, current_time: u64) -> bool {
+    holds_lasting_rights(asset, principal)
+        || active_rental_of(asset.id, principal).is_some_and(|rental| current_time < rental.expires_at)
+}
+
+// The owner, past buyers and principals the owner granted access to
+fn holds_lasting_rights(asset: &Asset, principal: Principal) -> bool {
+    asset.owner == principal
+        || has_bought(asset.id, principal)
+        || FILE_ACCESS_GRANTS.with(|grants| grants.borrow().contains_key(&(asset.id, principal)))
+}
+
+// Ownership checks for other canisters, e.g. games gating content. They are
+// answered from the owner index and return no asset data, so they are cheap
+// to call from composite queries.
+#[query(composite = true)]
+fn owns_asset(principal: Principal, asset_id: u64) -> bool {
+    is_owner_of(principal, asset_id)
+}
+
+#[query(composite = true)]
+fn owns_any_of(principal: Principal, asset_ids: Vec<u64>) -> Result<Vec<bool>, AssetError> {
+    if asset_ids.len() > MAX_IDS_PER_LOOKUP {
+        return Err(AssetError::InvalidInput(format!(
+            "asset_ids: at most {} ids can be checked per call",
+            MAX_IDS_PER_LOOKUP
+        )));
+    }
+    Ok(asset_ids.into_iter().map(|asset_id| is_owner_of(principal, asset_id)).collect())
+}
+
+#[query(composite = true)]
+fn get_owned_asset_ids(principal: Principal, offset: u64, limit: u64) -> Vec<u64> {
+    owned_asset_id_page(principal, offset, limit)
+}
+
+// Unlike ownership, rights also come from purchases, rentals and access
+// grants. Taken-down assets grant none.
+#[query(composite = true)]
+fn has_usage_rights(principal: Principal, asset_id: u64) -> bool {
+    asset_by_id(asset_id)
+        .is_some_and(|asset| !is_taken_down(asset_id) && holds_usage_rights(&asset, principal, time()))
+}
+
+fn is_owner_of(principal: Principal, asset_id: u64) -> bool {
+    OWNER_INDEX.with(|index| index.borrow().contains_key(&(principal, asset_id)))
+}
+
+fn owned_asset_id_page(principal: Principal, offset: u64, limit: u64) -> Vec<u64> {
+    let limit = limit.min(MAX_IDS_PER_LOOKUP as u64) as usize;
+    OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((principal, 0)..=(principal, u64::MAX))
+            .skip(offset as usize)
+            .take(limit)
+            .map(|((_, asset_id), _)| asset_id)
+            .collect()
+    })
+}
+
+#[query]
+fn has_download_access(asset_id: u64, principal: Principal) -> bool {
+    asset_by_id(asset_id).is_some_and(|asset| can_download(&asset, principal))
+}
+
+#[update]
+fn grant_file_access(asset_id: u64, principal: Principal) -> Result<(), AssetError> {
+    ensure_running("grant_file_access")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+    if principal == Principal::anonymous() {
+        return Err(AssetError::InvalidInput("principal: cannot grant access to the anonymous principal".to_string()));
+    }
+
+    FILE_ACCESS_GRANTS.with(|grants| {
+        grants.borrow_mut().insert((asset_id, principal), time());
+    });
+    Ok(())
+}
+
+#[update]
+fn revoke_file_access(asset_id: u64, principal: Principal) -> Result<(), AssetError> {
+    ensure_running("revoke_file_access")?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+
+    FILE_ACCESS_GRANTS.with(|grants| {
+        grants.borrow_mut().remove(&(asset_id, principal));
+    });
+    Ok(())
+}
+
+fn remove_file_access_grants(asset_id: u64) {
+    FILE_ACCESS_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        let keys: Vec<(u64, Principal)> = grants
+            .range((asset_id, Principal::management_canister())..)
+            .take_while(|((granted_id, _), _)| *granted_id == asset_id)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in keys {
+            grants.remove(&key);
+        }
+    });
+}
+
+// Rentals grant download access for a number of days without transferring
+// the asset. Renting is paid like purchase_asset, so the renter must first
+// approve this canister for the rental price plus three ledger fees: one for
+// the pull and one each for paying out the owner and the platform fee.
+#[update]
+fn create_rental_listing(asset_id: u64, price_per_day: u64, max_days: u32) -> Result<RentalListing, AssetError> {
+    ensure_running("create_rental_listing")?;
+    list_for_rent(asset_id, caller(), price_per_day, max_days, time())
+}
+
+// Rentals already paid for keep running until they expire
+#[update]
+fn withdraw_rental_listing(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("withdraw_rental_listing")?;
+    let listing = rental_listing_of(asset_id).ok_or(AssetError::NotForRent)?;
+    if listing.owner != caller() {
+        return Err(AssetError::NotOwner);
+    }
+    RENTAL_LISTINGS.with(|listings| listings.borrow_mut().remove(&asset_id));
+    Ok(())
+}
+
+#[update]
+async fn rent_asset(asset_id: u64, days: u32) -> Result<Rental, AssetError> {
+    ensure_running("rent_asset")?;
+    let renter = caller();
+
+    if renter == Principal::anonymous() {
+        return Err(AssetError::AnonymousCaller);
+    }
+
+    let ledger_canister_id = get_ledger_canister_principal()
+        .map_err(AssetError::PaymentFailed)?;
+
+    // Terms are fixed before the payment so a listing change cannot affect it
+    let (listing, price) = prepare_rental(asset_id, renter, days)?;
+    let platform_fee = platform_fee_for(price);
+
+    let legs = payout_leg_count(&None, &platform_fee);
+    collect_payment(ledger_canister_id, renter, price, legs, asset_id)
+        .await
+        .map_err(AssetError::PaymentFailed)?;
+
+    let current_time = time();
+    let rental = open_rental(&listing, renter, days, price, platform_fee, current_time);
+
+    // As with sales, a leg the ledger refuses is retried rather than undoing the rental
+    pay_out(queue_rental_payouts(&rental, ledger_canister_id, current_time)).await;
+    Ok(rental_by_id(rental.id).unwrap_or(rental))
+}
+
+#[query]
+fn get_rental_listing(asset_id: u64) -> Option<RentalListing> {
+    rental_listing_of(asset_id)
+}
+
+// The caller's rentals that have not expired yet
+#[query]
+fn get_my_rentals() -> Vec<Rental> {
+    let principal = caller();
+    let current_time = time();
+    active_rentals(|_, renter| renter == principal, current_time)
+}
+
+#[query]
+fn get_asset_rentals(asset_id: u64) -> Vec<Rental> {
+    let current_time = time();
+    active_rentals(|rented_id, _| rented_id == asset_id, current_time)
+}
+
+fn list_for_rent(
+    asset_id: u64,
+    principal: Principal,
+    price_per_day: u64,
+    max_days: u32,
+    current_time: u64,
+) -> Result<RentalListing, AssetError> {
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.owner != principal {
+        return Err(AssetError::NotOwner);
+    }
+    if asset.hidden.is_some() {
+        return Err(AssetError::AssetHidden);
+    }
+    if asset.archived_at.is_some() {
+        return Err(AssetError::AssetArchived);
+    }
+    validate_listing_price(price_per_day)?;
+    if max_days == 0 || max_days > MAX_RENTAL_DAYS {
+        return Err(AssetError::InvalidInput(format!("max_days: must be between 1 and {}", MAX_RENTAL_DAYS)));
+    }
+
+    let listing = RentalListing {
+        asset_id,
+        owner: principal,
+        price_per_day,
+        max_days,
+        created_at: current_time,
+    };
+    RENTAL_LISTINGS.with(|listings| listings.borrow_mut().insert(asset_id, listing.clone()));
+    Ok(listing)
+}
+
+fn prepare_rental(asset_id: u64, renter: Principal, days: u32) -> Result<(RentalListing, u64), AssetError> {
+    let listing = rental_listing_of(asset_id).ok_or(AssetError::NotForRent)?;
+    let asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if asset.hidden.is_some() {
+        return Err(AssetError::AssetHidden);
+    }
+    if asset.archived_at.is_some() {
+        return Err(AssetError::AssetArchived);
+    }
+    if asset.owner == renter {
+        return Err(AssetError::InvalidInput("Cannot rent your own asset".to_string()));
+    }
+    if days == 0 || days > listing.max_days {
+        return Err(AssetError::InvalidInput(format!("days: must be between 1 and {}", listing.max_days)));
+    }
+
+    let price = listing
+        .price_per_day
+        .checked_mul(days as u64)
+        .filter(|price| *price <= get_max_price())
+        .ok_or(AssetError::InvalidPrice { max: get_max_price() })?;
+    Ok((listing, price))
+}
+
+// Renting again before a rental ends extends it rather than overlapping it
+fn open_rental(
+    listing: &RentalListing,
+    renter: Principal,
+    days: u32,
+    price: u64,
+    platform_fee: Option<PlatformFee>,
+    current_time: u64,
+) -> Rental {
+    let starts_at = active_rental_of(listing.asset_id, renter)
+        .map_or(current_time, |rental| rental.expires_at.max(current_time));
+    let rental = Rental {
+        id: get_next_rental_id(),
+        asset_id: listing.asset_id,
+        owner: listing.owner,
+        renter,
+        days,
+        price,
+        platform_fee,
+        starts_at,
+        expires_at: starts_at.saturating_add(days as u64 * NANOS_PER_DAY),
+        owner_paid: Some(false),
+    };
+
+    RENTALS.with(|rentals| rentals.borrow_mut().insert(rental.id, rental.clone()));
+    ACTIVE_RENTALS.with(|active| active.borrow_mut().insert((rental.asset_id, renter), rental.id));
+    rental
+}
+
+// Queues the owner's share and the platform fee of a rental whose payment
+// collect_payment holds
+fn queue_rental_payouts(rental: &Rental, ledger_canister_id: Principal, current_time: u64) -> Vec<u64> {
+    let fee = rental.platform_fee.as_ref().filter(|fee| !fee.paid);
+    let mut legs = Vec::new();
+    if rental.owner_paid == Some(false) {
+        let owner_amount = rental.price - rental.platform_fee.as_ref().map_or(0, |fee| fee.amount);
+        legs.push((PayoutLeg::RentalOwner { rental_id: rental.id }, rental.owner, owner_amount));
+    }
+    if let Some(fee) = fee {
+        legs.push((PayoutLeg::RentalFee { rental_id: rental.id }, fee.recipient, fee.amount));
+    }
+
+    legs.into_iter()
+        .map(|(leg, to, amount)| {
+            queue_payout(PendingPayout {
+                id: get_next_payout_id(),
+                leg,
+                ledger: ledger_canister_id,
+                source: PayoutSource::Settlement,
+                to,
+                amount,
+                asset_id: rental.asset_id,
+                attempts: 0,
+                last_error: None,
+                created_at: current_time,
+            })
+        })
+        .collect()
+}
+
+fn mark_rental_owner_paid(rental_id: u64) {
+    RENTALS.with(|rentals| {
+        let mut rentals = rentals.borrow_mut();
+        if let Some(mut rental) = rentals.get(&rental_id) {
+            rental.owner_paid = Some(true);
+            rentals.insert(rental_id, rental);
+        }
+    });
+}
+
+fn mark_rental_fee_paid(rental_id: u64) {
+    RENTALS.with(|rentals| {
+        let mut rentals = rentals.borrow_mut();
+        if let Some(mut rental) = rentals.get(&rental_id) {
+            if let Some(fee) = rental.platform_fee.as_mut() {
+                fee.paid = true;
+            }
+            rentals.insert(rental_id, rental);
+        }
+    });
+}
+
+fn rental_listing_of(asset_id: u64) -> Option<RentalListing> {
+    RENTAL_LISTINGS.with(|listings| listings.borrow().get(&asset_id))
+}
+
+fn rental_by_id(rental_id: u64) -> Option<Rental> {
+    RENTALS.with(|rentals| rentals.borrow().get(&rental_id))
+}
+
+// May have expired; callers compare expires_at against the current time
+fn active_rental_of(asset_id: u64, renter: Principal) -> Option<Rental> {
+    ACTIVE_RENTALS
+        .with(|active| active.borrow().get(&(asset_id, renter)))
+        .and_then(rental_by_id)
+}
+
+fn active_rentals<F>(filter: F, current_time: u64) -> Vec<Rental>
+where
+    F: Fn(u64, Principal) -> bool,
+{
+    let rental_ids: Vec<u64> = ACTIVE_RENTALS.with(|active| {
+        active
+            .borrow()
+            .iter()
+            .filter(|((asset_id, renter), _)| filter(*asset_id, *renter))
+            .map(|(_, rental_id)| rental_id)
+            .collect()
+    });
+    rental_ids
+        .into_iter()
+        .filter_map(rental_by_id)
+        .filter(|rental| current_time < rental.expires_at)
+        .collect()
+}
+
+fn schedule_rental_cleanup() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), || remove_expired_rentals(time()));
+}
+
+// Expired rentals already grant nothing; this only keeps the index small
+fn remove_expired_rentals(current_time: u64) {
+    let expired: Vec<(u64, Principal)> = ACTIVE_RENTALS.with(|active| {
+        active
+            .borrow()
+            .iter()
+            .filter(|(_, rental_id)| rental_by_id(*rental_id).is_none_or(|rental| current_time >= rental.expires_at))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    ACTIVE_RENTALS.with(|active| {
+        let mut active = active.borrow_mut();
+        for key in expired {
+            active.remove(&key);
+        }
+    });
+}
+
+fn remove_rentals_of(asset_id: u64) {
+    RENTAL_LISTINGS.with(|listings| listings.borrow_mut().remove(&asset_id));
+    ACTIVE_RENTALS.with(|active| {
+        let mut active = active.borrow_mut();
+        let keys: Vec<(u64, Principal)> = active
+            .range((asset_id, Principal::management_canister())..)
+            .take_while(|((rented_id, _), _)| *rented_id == asset_id)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in keys {
+            active.remove(&key);
+        }
+    });
+}
+
+fn remove_reports(asset_id: u64) {
+    REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        let report_ids: Vec<u64> = reports
+            .iter()
+            .filter(|(_, report)| report.asset_id == asset_id)
+            .map(|(report_id, _)| report_id)
+            .collect();
+
+        for report_id in report_ids {
+            reports.remove(&report_id);
+        }
+    });
+}
+
+// Secondary indexes. Every code path that creates, removes or changes the
+// indexed fields of an asset unindexes the old record and indexes the new one.
+fn index_asset(asset: &Asset) {
+    certify_asset(asset);
+    OWNER_INDEX.with(|index| {
+        index.borrow_mut().insert((asset.owner, asset.id), ());
+    });
+    CATEGORY_INDEX.with(|index| {
+        index.borrow_mut().insert((asset.category_id, asset.id), ());
+    });
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in tag_keys(asset) {
+            index.insert(key, ());
+        }
+    });
+    TEXT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in token_keys(asset) {
+            index.insert(key, ());
+        }
+    });
+    if asset.is_for_sale {
+        FOR_SALE_INDEX.with(|index| {
+            index.borrow_mut().insert(asset.id, ());
+        });
+        if is_listed_publicly(asset) {
+            PUBLIC_FOR_SALE_INDEX.with(|index| {
+                index.borrow_mut().insert(asset.id, ());
+            });
+        }
+    }
+    let counts = StatCounts::of(asset);
+    update_stats(asset, |stats| stats.add(&counts));
+}
+
+fn unindex_asset(asset: &Asset) {
+    uncertify_asset(asset);
+    OWNER_INDEX.with(|index| {
+        index.borrow_mut().remove(&(asset.owner, asset.id));
+    });
+    CATEGORY_INDEX.with(|index| {
+        index.borrow_mut().remove(&(asset.category_id, asset.id));
+    });
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in tag_keys(asset) {
+            index.remove(&key);
+        }
+    });
+    TEXT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in token_keys(asset) {
+            index.remove(&key);
+        }
+    });
+    FOR_SALE_INDEX.with(|index| {
+        index.borrow_mut().remove(&asset.id);
+    });
+    PUBLIC_FOR_SALE_INDEX.with(|index| {
+        index.borrow_mut().remove(&asset.id);
+    });
+    let counts = StatCounts::of(asset);
+    update_stats(asset, |stats| stats.subtract(&counts));
+}
+
+// Applies the same change to the asset's owner, its category and the
+// marketplace totals. Entries that no longer count any asset are dropped so
+// the owner map only holds current owners.
+fn update_stats(asset: &Asset, change: impl Fn(&mut StatCounts)) {
+    OWNER_STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let mut counts = stats.get(&asset.owner).unwrap_or_default();
+        change(&mut counts);
+        if counts.asset_count == 0 {
+            stats.remove(&asset.owner);
+        } else {
+            stats.insert(asset.owner, counts);
+        }
+    });
+    CATEGORY_STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let mut counts = stats.get(&asset.category_id).unwrap_or_default();
+        change(&mut counts);
+        if counts.asset_count == 0 {
+            stats.remove(&asset.category_id);
+        } else {
+            stats.insert(asset.category_id, counts);
+        }
+    });
+    MARKETPLACE_COUNTS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut counts = *cell.get();
+        change(&mut counts);
+        cell.set(counts).expect("stat counts always fit the marketplace counts cell");
+    });
+}
+
+fn reset_stats() {
+    OWNER_STATS.with(|stats| stats.borrow_mut().clear_new());
+    CATEGORY_STATS.with(|stats| stats.borrow_mut().clear_new());
+    MARKETPLACE_COUNTS.with(|cell| {
+        cell.borrow_mut()
+            .set(StatCounts::default())
+            .expect("stat counts always fit the marketplace counts cell");
+    });
+}
+
+// Recounts every counter from the asset records and the per-seller sale
+// tallies, discarding whatever drift the running totals picked up
+fn rebuild_stats() -> MarketplaceStats {
+    reset_stats();
+    ASSETS.with(|assets| {
+        for (_, asset) in assets.borrow().iter() {
+            let counts = StatCounts::of(&asset);
+            update_stats(&asset, |stats| stats.add(&counts));
+        }
+    });
+    let sales = SELLER_TALLIES.with(|tallies| {
+        tallies.borrow().iter().fold(SaleTally::default(), |mut total, (_, tally)| {
+            total.add(&tally);
+            total
+        })
+    });
+    MARKETPLACE_SALES.with(|cell| {
+        cell.borrow_mut().set(sales).expect("a sale tally always fits the marketplace sales cell");
+    });
+    marketplace_stats()
+}
+
+fn marketplace_stats() -> MarketplaceStats {
+    let counts = MARKETPLACE_COUNTS.with(|cell| *cell.borrow().get());
+    let sales = MARKETPLACE_SALES.with(|cell| *cell.borrow().get());
+    MarketplaceStats {
+        asset_count: counts.asset_count,
+        for_sale_count: counts.for_sale_count,
+        favorite_count: counts.favorite_count,
+        owner_count: OWNER_STATS.with(|stats| stats.borrow().len()),
+        category_count: CATEGORIES.with(|categories| categories.borrow().len()),
+        sale_count: sales.sale_count,
+        sales_volume: sales.volume,
+    }
+}
+
+fn normalize_category(category: &str) -> String {
+    category.trim().to_lowercase()
+}
+
+fn tag_keys(asset: &Asset) -> Vec<TagKey> {
+    asset
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| TagKey { tag, asset_id: asset.id })
+        .collect()
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+fn owned_asset_ids(owner: Principal) -> Vec<u64> {
+    OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((owner, 0)..=(owner, u64::MAX))
+            .map(|((_, asset_id), _)| asset_id)
+            .collect()
+    })
+}
+
+// Rebuilds the owner, category, for-sale, tag and text indexes from the asset records
+#[update]
+fn rebuild_owner_index() -> Result<u64, String> {
+    ensure_running("rebuild_owner_index").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(rebuild_indexes())
+}
+
+// Recounts the category, owner and marketplace counters from the asset
+// records, for when they have drifted from what is stored
+#[update]
+fn recompute_stats() -> Result<MarketplaceStats, String> {
+    ensure_running("recompute_stats").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(rebuild_stats())
+}
+
+fn rebuild_indexes() -> u64 {
+    reset_stats();
+    OWNER_INDEX.with(|index| index.borrow_mut().clear_new());
+    CATEGORY_INDEX.with(|index| index.borrow_mut().clear_new());
+    TAG_INDEX.with(|index| index.borrow_mut().clear_new());
+    TEXT_INDEX.with(|index| index.borrow_mut().clear_new());
+    FOR_SALE_INDEX.with(|index| index.borrow_mut().clear_new());
+    PUBLIC_FOR_SALE_INDEX.with(|index| index.borrow_mut().clear_new());
+
+    with_deferred_certification(|| {
+        ASSETS.with(|assets| {
+            let assets = assets.borrow();
+            for (_, asset) in assets.iter() {
+                index_asset(&asset);
+                if let Some(AssetSource::Imported { external_id }) = &asset.source {
+                    EXTERNAL_ID_INDEX.with(|index| index.borrow_mut().insert(external_id.clone(), asset.id));
+                }
+            }
+            assets.len()
+        })
+    })
+}
+
+// Storage maintenance
+#[update]
+fn prune_orphaned_files() -> Result<PruneReport, String> {
+    ensure_running("prune_orphaned_files").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    // Recount from the asset records rather than trusting the stored counts
+    rebuild_file_refs();
+    Ok(prune_orphans(time()))
+}
+
+// Removes stored files no asset refers to. Files uploaded within the grace
+// period are kept: they are usually uploaded ahead of the asset that will
+// refer to them.
+fn prune_orphans(current_time: u64) -> PruneReport {
+    let orphans: Vec<String> = stored_file_hashes()
+        .into_iter()
+        .filter(|file_hash| !FILE_REFS.with(|refs| refs.borrow().contains_key(file_hash)))
+        .filter(|file_hash| {
+            FILE_INFO
+                .with(|file_info| file_info.borrow().get(file_hash))
+                .and_then(|info| info.uploaded_at)
+                .is_none_or(|uploaded_at| current_time.saturating_sub(uploaded_at) >= ORPHAN_GRACE_NANOS)
+        })
+        .collect();
+
+    let mut report = PruneReport {
+        files_removed: 0,
+        bytes_reclaimed: 0,
+    };
+    for file_hash in orphans {
+        report.bytes_reclaimed += remove_file(&file_hash);
+        report.files_removed += 1;
+    }
+    report
+}
+
+// Sweeps abandoned upload sessions and expired offers, transfers and gifts,
+// and settles auctions whose own settlement timer was lost
+#[update]
+fn run_maintenance_now() -> Result<MaintenanceReport, String> {
+    ensure_running("run_maintenance_now").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(run_maintenance())
+}
+
+fn schedule_maintenance() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(10 * 60), || {
+        run_maintenance();
+    });
+}
+
+fn run_maintenance() -> MaintenanceReport {
+    let (mut report, ended_auctions) = sweep_expired_state(time());
+    report.files_split = split_whole_files();
+    report.images_hashed = MAINTENANCE_CURSORS.with(|cursors| backfill_image_digests(&mut cursors.borrow_mut().galleries));
+    for asset_id in ended_auctions {
+        ic_cdk::spawn(settle_auction(asset_id));
+    }
+    if PENDING_PAYOUTS.with(|payouts| !payouts.borrow().is_empty()) {
+        ic_cdk::spawn(async {
+            retry_payout_batch().await;
+        });
+    }
+    report
+}
+
+// One bounded pass of every sweep. Returns the ended auctions for the caller
+// to settle, since settlement needs to await the ledger.
+fn sweep_expired_state(current_time: u64) -> (MaintenanceReport, Vec<u64>) {
+    MAINTENANCE_CURSORS.with(|cursors| {
+        let cursors = &mut *cursors.borrow_mut();
+        let mut report = MaintenanceReport::default();
+
+        let sessions = UPLOAD_SESSIONS.with(|sessions| next_maintenance_batch(&sessions.borrow(), &mut cursors.upload_sessions));
+        for (session_id, session) in sessions {
+            if current_time.saturating_sub(session.updated_at) >= UPLOAD_SESSION_IDLE_TTL_NANOS {
+                remove_upload_session(session_id);
+                report.upload_sessions_removed += 1;
+            }
+        }
+
+        let offers = OFFERS.with(|offers| next_maintenance_batch(&offers.borrow(), &mut cursors.offers));
+        for (offer_id, mut offer) in offers {
+            if offer.status == OfferStatus::Active
+                && offer.expires_at.is_some_and(|expires_at| current_time >= expires_at)
+            {
+                offer.status = OfferStatus::Expired;
+                offer.updated_at = current_time;
+                OFFERS.with(|offers| offers.borrow_mut().insert(offer_id, offer));
+                report.offers_expired += 1;
+            }
+        }
+
+        let transfers = PENDING_TRANSFERS
+            .with(|transfers| next_maintenance_batch(&transfers.borrow(), &mut cursors.pending_transfers));
+        for (asset_id, transfer) in transfers {
+            if transfer.is_expired(current_time) {
+                remove_expired_transfer(asset_id, current_time);
+                report.transfers_removed += 1;
+            }
+        }
+
+        let gifts = GIFTS.with(|gifts| next_maintenance_batch(&gifts.borrow(), &mut cursors.gifts));
+        for (asset_id, gift) in gifts {
+            if gift.is_expired(current_time) {
+                remove_expired_gift(asset_id, current_time);
+                report.gifts_removed += 1;
+            }
+        }
+
+        let auctions = AUCTIONS.with(|auctions| next_maintenance_batch(&auctions.borrow(), &mut cursors.auctions));
+        let ended: Vec<u64> = auctions
+            .into_iter()
+            .filter(|(asset_id, auction)| {
+                auction.status == AuctionStatus::Active
+                    && current_time >= auction.end_time
+                    && !PENDING_PURCHASES.with(|pending| pending.borrow().contains_key(asset_id))
+            })
+            .map(|(asset_id, _)| asset_id)
+            .collect();
+        report.auctions_settled = ended.len() as u64;
+
+        (report, ended)
+    })
+}
+
+// The next MAINTENANCE_BATCH_SIZE entries after `cursor`. The cursor wraps
+// back to the start once a sweep reaches the end of the map.
+fn next_maintenance_batch<V: Storable>(map: &StableBTreeMap<u64, V, Memory>, cursor: &mut Option<u64>) -> Vec<(u64, V)> {
+    let start = match *cursor {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(u64, V)> = map.range((start, Bound::Unbounded)).take(MAINTENANCE_BATCH_SIZE).collect();
+    *cursor = if batch.len() < MAINTENANCE_BATCH_SIZE { None } else { batch.last().map(|(key, _)| *key) };
+    batch
+}
+
+#[query]
+fn get_storage_stats() -> StorageStats {
+    let mut stats = StorageStats {
+        total_file_bytes: 0,
+        file_count: 0,
+        orphan_count: 0,
+    };
+
+    for file_hash in stored_file_hashes() {
+        stats.file_count += 1;
+        stats.total_file_bytes += file_size_of(&file_hash);
+        if !FILE_REFS.with(|refs| refs.borrow().contains_key(&file_hash)) {
+            stats.orphan_count += 1;
+        }
+    }
+
+    stats
+}
+
+// Compresses files stored before compression existed. Each call handles one
+// bounded batch; call again until the report says done.
+#[update]
+fn recompress_existing_files() -> Result<RecompressReport, String> {
+    ensure_running("recompress_existing_files").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    Ok(recompress_file_batch())
+}
+
+fn recompress_file_batch() -> RecompressReport {
+    let start = RECOMPRESS_CURSOR.with(|cursor| cursor.borrow().clone());
+    let batch: Vec<String> = stored_file_hashes()
+        .into_iter()
+        .filter(|file_hash| start.as_ref().is_none_or(|after| file_hash > after))
+        .take(RECOMPRESS_BATCH_SIZE)
+        .collect();
+
+    let mut report = RecompressReport::default();
+    let mut compressed_input = 0;
+    for file_hash in &batch {
+        report.files_examined += 1;
+        if let Some(saved) = recompress_file(file_hash) {
+            report.files_compressed += 1;
+            report.bytes_saved += saved;
+            compressed_input += file_size_of(file_hash);
+        }
+        if compressed_input >= RECOMPRESS_BATCH_BYTES {
+            break;
+        }
+    }
+
+    let examined = report.files_examined as usize;
+    report.done = examined == batch.len() && batch.len() < RECOMPRESS_BATCH_SIZE;
+    RECOMPRESS_CURSOR.with(|cursor| {
+        *cursor.borrow_mut() = if report.done { None } else { batch.get(examined - 1).cloned() };
+    });
+    report
+}
+
+// Compresses one stored file in place if its type compresses and it is not
+// compressed yet, returning the bytes saved
+fn recompress_file(file_hash: &String) -> Option<u64> {
+    let mut info = FILE_INFO.with(|file_info| file_info.borrow().get(file_hash))?;
+    if info.encoding.is_some() || !is_compressible(&info.content_type) || info.total_size > MAX_COMPRESSED_FILE_SIZE {
+        return None;
+    }
+    let file_data = read_stored_file(file_hash)?;
+    // Files still being restored are left until all their bytes are in
+    if file_data.len() as u64 != info.total_size {
+        return None;
+    }
+
+    let (stored, encoding) = encode_for_storage(&info.content_type, file_data);
+    encoding?;
+    let saved = info.total_size - stored.len() as u64;
+    info.encoding = encoding;
+    write_stored_file(file_hash, &stored);
+    FILE_INFO.with(|file_info| file_info.borrow_mut().insert(file_hash.clone(), info));
+    Some(saved)
+}
+
+// Backup and restore. An export is the asset records, the asset id counter
+// and the stored files; the secondary indexes, file reference counts and
+// storage usage are derived from those and rebuilt by finish_restore.
+#[query]
+fn export_assets(offset: u64, limit: u64) -> Result<AssetExport, String> {
+    ensure_controller()?;
+    Ok(export_asset_page(offset, limit))
+}
+
+#[query]
+fn export_files_manifest(offset: u64, limit: u64) -> Result<FileManifestPage, String> {
+    ensure_controller()?;
+    Ok(export_file_manifest_page(offset, limit))
+}
+
+#[query]
+fn export_file_chunk(file_hash: String, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+    ensure_controller()?;
+    read_file_chunk(&file_hash, offset, length).ok_or_else(|| format!("File {} not found", file_hash))
+}
+
+// Exports take every asset, so the records before the page are skipped by
+// key without being decoded
+fn export_asset_page(offset: u64, limit: u64) -> AssetExport {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let asset_id_counter = last_asset_id();
+    ASSETS.with(|assets| {
+        let assets = assets.borrow();
+        let page = match assets.keys().nth(offset as usize) {
+            Some(first_id) => assets.values_range(first_id..).take(limit as usize).collect(),
+            None => Vec::new(),
+        };
+        AssetExport {
+            assets: page,
+            asset_id_counter,
+            total_count: assets.len(),
+        }
+    })
+}
+
+fn export_file_manifest_page(offset: u64, limit: u64) -> FileManifestPage {
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let file_hashes = stored_file_hashes();
+
+    FileManifestPage {
+        total_count: file_hashes.len() as u64,
+        files: file_hashes
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .filter_map(|file_hash| file_info_of(&file_hash))
+            .collect(),
+    }
+}
+
+#[update]
+fn import_assets(assets: Vec<Asset>, asset_id_counter: u64, force: bool) -> Result<u64, String> {
+    ensure_running("import_assets").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    begin_restore(force)?;
+    Ok(import_asset_batch(assets, asset_id_counter))
+}
+
+// Files are restored by sending their chunks in order from offset 0; the
+// bytes are checked against the file hash once the last chunk arrives
+#[update]
+fn import_file_chunk(info: FileInfo, offset: u64, bytes: Vec<u8>, force: bool) -> Result<u64, String> {
+    ensure_running("import_file_chunk").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    begin_restore(force)?;
+    import_file_bytes(info, offset, bytes)
+}
+
+#[update]
+fn finish_restore() -> Result<RestoreReport, String> {
+    ensure_running("finish_restore").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    complete_restore()
+}
+
+// A restore only starts on a canister without assets or files unless forced.
+// Once started, later batches are accepted until finish_restore.
+fn begin_restore(force: bool) -> Result<(), String> {
+    let in_progress = CONFIG.with(|config| config.borrow().contains_key(&"restore_in_progress".to_string()));
+    let empty = ASSETS.with(|assets| assets.borrow().is_empty()) && !has_stored_files();
+    if !force && !in_progress && !empty {
+        return Err("The canister already holds assets or files; pass force to restore over them".to_string());
+    }
+
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("restore_in_progress".to_string(), "true".to_string());
+    });
+    Ok(())
+}
+
+fn import_asset_batch(assets: Vec<Asset>, asset_id_counter: u64) -> u64 {
+    let mut imported = 0;
+    let mut highest_id = asset_id_counter;
+
+    for asset in assets {
+        if let Some(previous) = asset_by_id(asset.id) {
+            unindex_asset(&previous);
+        }
+        highest_id = highest_id.max(asset.id);
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(asset.id, asset));
+        imported += 1;
+    }
+
+    set_last_asset_id(last_asset_id().max(highest_id));
+    imported
+}
+
+fn import_file_bytes(mut info: FileInfo, offset: u64, bytes: Vec<u8>) -> Result<u64, String> {
+    // Exports carry the uncompressed bytes; they are compressed again once
+    // the file is complete
+    info.encoding = None;
+    let file_hash = info.file_hash.clone();
+    if offset == 0 {
+        remove_stored_file(&file_hash);
+    }
+    let stored_len = stored_file_len(&file_hash).unwrap_or(0);
+    if stored_len != offset {
+        return Err(format!(
+            "{}: expected the chunk at offset {}, got offset {}",
+            file_hash, stored_len, offset
+        ));
+    }
+
+    let received = offset + bytes.len() as u64;
+    if received > info.total_size {
+        return Err(format!("{}: more than the declared {} bytes", file_hash, info.total_size));
+    }
+    append_stored_bytes(&file_hash, stored_len, &bytes);
+    if received == info.total_size {
+        let file_data = read_stored_file(&file_hash).unwrap_or_default();
+        if let Err(err) = verify_declared_hash(&file_hash, &sha256_hex(&file_data)) {
+            remove_stored_file(&file_hash);
+            FILE_INFO.with(|file_info| file_info.borrow_mut().remove(&file_hash));
+            return Err(err.to_string());
+        }
+        certify_file(&file_hash, received);
+        let (stored, encoding) = encode_for_storage(&info.content_type, file_data);
+        if encoding.is_some() {
+            write_stored_file(&file_hash, &stored);
+            info.encoding = encoding;
+        }
+    }
+    FILE_INFO.with(|file_info| file_info.borrow_mut().insert(file_hash, info));
+    Ok(received)
+}
+
+fn complete_restore() -> Result<RestoreReport, String> {
+    let file_hashes = stored_file_hashes();
+    let incomplete: Vec<String> = file_hashes
+        .iter()
+        .filter(|file_hash| {
+            stored_encoding_of(file_hash).is_none() && Some(file_size_of(file_hash)) != stored_file_len(file_hash)
+        })
+        .cloned()
+        .collect();
+    if !incomplete.is_empty() {
+        return Err(format!("Files still missing chunks: {}", incomplete.join(", ")));
+    }
+
+    let assets = rebuild_indexes();
+    rebuild_file_refs();
+    rebuild_storage_usage();
+    CONFIG.with(|config| config.borrow_mut().remove(&"restore_in_progress".to_string()));
+
+    Ok(RestoreReport {
+        assets,
+        files: file_hashes.len() as u64,
+    })
+}
+
+// Migration from an external catalog. Each record becomes an asset that keeps
+// its original timestamps and points at its external file; the results are
+// returned in manifest order. An external id maps to its asset for good, so
+// re-running a manifest returns the same ids and never recreates an asset
+// that was since deleted.
+#[update]
+fn import_external_assets(manifest: Vec<ExternalAssetRecord>) -> Result<Vec<Result<u64, String>>, String> {
+    ensure_running("import_external_assets").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    if manifest.len() > MAX_UPLOAD_BATCH_SIZE {
+        return Err(format!("At most {} records can be imported per call", MAX_UPLOAD_BATCH_SIZE));
+    }
+
+    let current_time = time();
+    Ok(manifest
+        .into_iter()
+        .map(|record| import_external_asset(record, current_time).map_err(|err| err.to_string()))
+        .collect())
+}
+
+// Stores the file of an imported asset, which is then served from here
+#[update]
+fn attach_imported_file(asset_id: u64, file_data: Vec<u8>) -> Result<Asset, String> {
+    ensure_running("attach_imported_file").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    attach_file_to_import(asset_id, file_data, time()).map_err(|err| err.to_string())
+}
+
+fn import_external_asset(record: ExternalAssetRecord, current_time: u64) -> Result<u64, AssetError> {
+    let external_id = record.external_id.trim().to_string();
+    if external_id.is_empty() || external_id.len() > MAX_EXTERNAL_ID_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "external_id: must be 1 to {} characters",
+            MAX_EXTERNAL_ID_LENGTH
+        )));
+    }
+    if let Some(asset_id) = EXTERNAL_ID_INDEX.with(|index| index.borrow().get(&external_id)) {
+        return Ok(asset_id);
+    }
+
+    if record.owner == Principal::anonymous() || record.owner == Principal::management_canister() {
+        return Err(AssetError::InvalidRecipient);
+    }
+    let external_url = record.external_url.trim().to_string();
+    if !external_url.starts_with("https://") || external_url.len() > MAX_EXTERNAL_URL_LENGTH {
+        return Err(AssetError::InvalidInput(format!(
+            "external_url: must be an https:// url of at most {} characters",
+            MAX_EXTERNAL_URL_LENGTH
+        )));
+    }
+    let path = external_url.split(['?', '#']).next().unwrap_or_default();
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    let file_type = validate_file_type(extension)?;
+    if record.original_created_at > current_time {
+        return Err(AssetError::InvalidInput("original_created_at: must not be in the future".to_string()));
+    }
+    let category = find_category_by_name(&record.category)
+        .filter(|category| !category.archived)
+        .or_else(|| category_by_id(UNCATEGORIZED_CATEGORY_ID))
+        .expect("the Uncategorized category is seeded");
+
+    let mut asset = Asset {
+        id: 0,
+        name: validate_name(&record.name)?,
+        description: validate_description(&record.description)?,
+        owner: record.owner,
+        file_hash: String::new(),
+        file_url: external_url,
+        file_type,
+        file_size: 0,
+        prices: icp_prices(validate_price(record.price)?),
+        is_for_sale: false,
+        created_at: record.original_created_at,
+        updated_at: record.original_created_at,
+        category_id: category.id,
+        category: category.name,
+        tags: validate_tags(&record.tags)?,
+        preview_image_url: None,
+        creator: record.owner,
+        royalty_bps: 0,
+        hidden: None,
+        visibility: Visibility::Public,
+        metadata: Vec::new(),
+        archived_at: None,
+        license: License::PersonalUse,
+        slug: None,
+        reservation: None,
+        source: Some(AssetSource::Imported { external_id: external_id.clone() }),
+        frozen: None,
+    };
+
+    // Imports share the normal id counter, which traps rather than hand out
+    // an id that is taken. Checking first fails only this record.
+    let next_id = last_asset_id().saturating_add(1);
+    if asset_by_id(next_id).is_some() {
+        return Err(AssetError::InvalidInput(format!(
+            "asset id {} is already in use; the id counter is behind the stored assets",
+            next_id
+        )));
+    }
+    asset.id = get_next_asset_id();
+    assign_slug(&mut asset, current_time);
+
+    index_asset(&asset);
+    record_ownership_event(asset.id, None, asset.owner, OwnershipEventKind::Upload, None, asset.created_at);
+    record_price_point(asset.id, record.price, asset.owner, asset.created_at);
+    EXTERNAL_ID_INDEX.with(|index| index.borrow_mut().insert(external_id, asset.id));
+    ASSETS.with(|assets| assets.borrow_mut().insert(asset.id, asset.clone()));
+    Ok(asset.id)
+}
+
+fn attach_file_to_import(asset_id: u64, file_data: Vec<u8>, current_time: u64) -> Result<Asset, AssetError> {
+    let mut asset = asset_by_id(asset_id).ok_or(AssetError::NotFound)?;
+    if !matches!(asset.source, Some(AssetSource::Imported { .. })) {
+        return Err(AssetError::InvalidInput("asset_id: not an imported asset".to_string()));
+    }
+    if !asset.file_hash.is_empty() {
+        return Err(AssetError::AlreadyExists);
+    }
+    check_not_frozen(&asset)?;
+    if file_data.is_empty() {
+        return Err(AssetError::InvalidInput("file_data: must not be empty".to_string()));
+    }
+    ensure_file_size(&asset.file_type, file_data.len() as u64)?;
+    validate_file_format(&asset.file_type, &file_data)?;
+
+    let file_hash = sha256_hex(&file_data);
+    let file_size = file_data.len() as u64;
+    // The owner is charged as if they had uploaded the file themselves
+    store_or_reuse_file(file_hash.clone(), &asset.file_type, file_data, asset.owner, current_time)?;
+    add_file_ref(&file_hash, asset.id);
+
+    unindex_asset(&asset);
+    asset.file_url = format!("canister://{}", file_hash);
+    asset.file_hash = file_hash;
+    asset.file_size = file_size;
+    index_asset(&asset);
+    ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset.clone()));
+    Ok(asset)
+}
+
+fn rebuild_storage_usage() {
+    let charges: Vec<(Principal, u64)> = FILE_INFO.with(|file_info| {
+        file_info
+            .borrow()
+            .iter()
+            .filter_map(|(_, info)| info.uploaded_by.map(|uploader| (uploader, info.total_size)))
+            .collect()
+    });
+
+    let dedup_charges: Vec<(Principal, u64)> = DEDUP_CHARGES.with(|charges| {
+        charges
+            .borrow()
+            .iter()
+            .filter_map(|(key, bytes)| {
+                let (_, principal) = key.rsplit_once(':')?;
+                Some((Principal::from_text(principal).ok()?, bytes))
+            })
+            .collect()
+    });
+
+    STORAGE_USAGE.with(|usage| usage.borrow_mut().clear_new());
+    for (uploader, bytes) in charges.into_iter().chain(dedup_charges) {
+        charge_storage(uploader, bytes);
+    }
+}
+
+// Per-principal rate limits on update calls. Controllers are exempt.
+fn enforce_rate_limit(action: RateLimitedAction) -> Result<(), AssetError> {
+    let principal = caller();
+    if ic_cdk::api::is_controller(&principal) {
+        return Ok(());
+    }
+    check_rate_limit(principal, action, time())
+}
+
+fn check_rate_limit(principal: Principal, action: RateLimitedAction, now: u64) -> Result<(), AssetError> {
+    let limits = get_rate_limits();
+    let budget = match action {
+        RateLimitedAction::Upload => limits.uploads_per_minute,
+        RateLimitedAction::MetadataUpdate => limits.metadata_updates_per_minute,
+        RateLimitedAction::Reservation => limits.reservations_per_minute.unwrap_or(DEFAULT_RESERVATIONS_PER_MINUTE),
+    } as usize;
+
+    RATE_LIMITER.with(|limiter| {
+        let mut limiter = limiter.borrow_mut();
+        let in_window = |called_at: &u64| now.saturating_sub(*called_at) < RATE_LIMIT_WINDOW_NANOS;
+
+        // Drop principals that have gone quiet so the log cannot grow forever
+        if now.saturating_sub(limiter.last_swept_at) >= RATE_LIMIT_WINDOW_NANOS {
+            limiter.calls.retain(|_, calls| calls.back().is_some_and(in_window));
+            limiter.last_swept_at = now;
+        }
+
+        let calls = limiter.calls.entry((principal, action)).or_default();
+        while calls.front().is_some_and(|first| !in_window(first)) {
+            calls.pop_front();
+        }
+        if calls.len() >= budget {
+            let oldest = calls.front().copied().unwrap_or(now);
+            return Err(AssetError::RateLimited {
+                retry_after_seconds: retry_after_seconds(oldest + RATE_LIMIT_WINDOW_NANOS, now),
+            });
+        }
+        calls.push_back(now);
+        Ok(())
+    })
+}
+
+// Whole seconds until `available_at`, rounded up so that retrying after the
+// hint always succeeds
+fn retry_after_seconds(available_at: u64, now: u64) -> u64 {
+    available_at.saturating_sub(now).div_ceil(1_000_000_000)
+}
+
+#[update]
+fn set_rate_limits(limits: RateLimits) -> Result<RateLimits, String> {
+    ensure_running("set_rate_limits").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if limits.uploads_per_minute == 0
+        || limits.metadata_updates_per_minute == 0
+        || limits.reservations_per_minute == Some(0)
+    {
+        return Err("limits: must allow at least one call per minute".to_string());
+    }
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        config.insert("uploads_per_minute".to_string(), limits.uploads_per_minute.to_string());
+        config.insert(
+            "metadata_updates_per_minute".to_string(),
+            limits.metadata_updates_per_minute.to_string(),
+        );
+        if let Some(reservations_per_minute) = limits.reservations_per_minute {
+            config.insert("reservations_per_minute".to_string(), reservations_per_minute.to_string());
+        }
+    });
+    Ok(get_rate_limits())
+}
+
+#[query]
+fn get_rate_limits() -> RateLimits {
+    CONFIG.with(|config| {
+        let config = config.borrow();
+        RateLimits {
+            uploads_per_minute: config
+                .get(&"uploads_per_minute".to_string())
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(DEFAULT_UPLOADS_PER_MINUTE),
+            metadata_updates_per_minute: config
+                .get(&"metadata_updates_per_minute".to_string())
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(DEFAULT_METADATA_UPDATES_PER_MINUTE),
+            reservations_per_minute: Some(
+                config
+                    .get(&"reservations_per_minute".to_string())
+                    .and_then(|limit| limit.parse().ok())
+                    .unwrap_or(DEFAULT_RESERVATIONS_PER_MINUTE),
+            ),
+        }
+    })
+}
+
+#[update]
+fn set_upload_limits(limits: UploadLimits) -> Result<UploadLimits, String> {
+    ensure_running("set_upload_limits").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if limits.max_file_size == 0 || limits.max_preview_image_size == 0 {
+        return Err("limits: file sizes must be greater than zero".to_string());
+    }
+    if limits.file_type_limits.len() > MAX_FILE_TYPE_LIMITS {
+        return Err(format!(
+            "file_type_limits: at most {} file types can have their own limit",
+            MAX_FILE_TYPE_LIMITS
+        ));
+    }
+    let mut file_type_limits: Vec<FileTypeLimit> = Vec::new();
+    for limit in limits.file_type_limits {
+        let file_type = normalize_file_type(&limit.file_type);
+        if file_type.is_empty() || file_type.contains([',', ':']) {
+            return Err(format!("file_type_limits: invalid file type '{}'", limit.file_type));
+        }
+        if limit.max_size == 0 {
+            return Err(format!("file_type_limits: the limit for {} must be greater than zero", file_type));
+        }
+        if file_type_limits.iter().any(|existing| existing.file_type == file_type) {
+            return Err(format!("file_type_limits: {} is listed more than once", file_type));
+        }
+        file_type_limits.push(FileTypeLimit { file_type, max_size: limit.max_size });
+    }
+
+    let limits = UploadLimits { file_type_limits, ..limits };
+    let encoded_type_limits: Vec<String> = limits
+        .file_type_limits
+        .iter()
+        .map(|limit| format!("{}:{}", limit.file_type, limit.max_size))
+        .collect();
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        config.insert("max_file_size".to_string(), limits.max_file_size.to_string());
+        config.insert("file_type_size_limits".to_string(), encoded_type_limits.join(","));
+        config.insert("max_preview_image_size".to_string(), limits.max_preview_image_size.to_string());
+        config.insert("max_attachments".to_string(), limits.max_attachments.to_string());
+    });
+    Ok(limits)
+}
+
+#[query]
+fn get_upload_limits() -> UploadLimits {
+    CONFIG.with(|config| {
+        let config = config.borrow();
+        UploadLimits {
+            max_file_size: config
+                .get(&"max_file_size".to_string())
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            file_type_limits: config
+                .get(&"file_type_size_limits".to_string())
+                .map(|limits| {
+                    limits
+                        .split(',')
+                        .filter_map(|limit| {
+                            let (file_type, max_size) = limit.split_once(':')?;
+                            Some(FileTypeLimit {
+                                file_type: file_type.to_string(),
+                                max_size: max_size.parse().ok()?,
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_preview_image_size: config
+                .get(&"max_preview_image_size".to_string())
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(DEFAULT_MAX_PREVIEW_IMAGE_SIZE),
+            max_attachments: config
+                .get(&"max_attachments".to_string())
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ASSET_ATTACHMENTS),
+        }
+    })
+}
+
+// A file type's own limit takes precedence over the global one
+fn file_size_limit(limits: &UploadLimits, file_type: &str) -> u64 {
+    let file_type = normalize_file_type(file_type);
+    limits
+        .file_type_limits
+        .iter()
+        .find(|limit| limit.file_type == file_type)
+        .map_or(limits.max_file_size, |limit| limit.max_size)
+}
+
+fn ensure_file_size(file_type: &str, size: u64) -> Result<(), AssetError> {
+    let max = file_size_limit(&get_upload_limits(), file_type);
+    if size > max {
+        return Err(AssetError::FileTooLarge { max });
+    }
+    Ok(())
+}
+
+#[query]
+fn get_canister_metrics() -> Result<CanisterMetrics, String> {
+    ensure_moderator()?;
+
+    let storage = get_storage_stats();
+    // Owner index keys are ordered by owner, so each owner's keys are adjacent
+    let owner_count = OWNER_INDEX.with(|index| {
+        let mut owners = 0;
+        let mut previous = None;
+        for (owner, _) in index.borrow().keys() {
+            if previous != Some(owner) {
+                owners += 1;
+                previous = Some(owner);
+            }
+        }
+        owners
+    });
+    // Regions that were never written to have no pages allocated
+    let memory_regions = MEMORY_MANAGER.with(|manager| {
+        let manager = manager.borrow();
+        (0..u8::MAX)
+            .map(|memory_id| MemoryRegionUsage {
+                memory_id,
+                bytes: manager.get(MemoryId::new(memory_id)).size() * WASM_PAGE_SIZE,
+            })
+            .filter(|region| region.bytes > 0)
+            .collect()
+    });
+
+    Ok(CanisterMetrics {
+        asset_count: ASSETS.with(|assets| assets.borrow().len()),
+        assets_for_sale: FOR_SALE_INDEX.with(|index| index.borrow().len()),
+        owner_count,
+        file_count: storage.file_count,
+        total_file_bytes: storage.total_file_bytes,
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        memory_regions,
+        cycles_balance: ic_cdk::api::canister_balance128(),
+        last_upgrade_at: CONFIG.with(|config| {
+            config
+                .borrow()
+                .get(&"last_upgrade_at".to_string())
+                .and_then(|last_upgrade_at| last_upgrade_at.parse().ok())
+        }),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    })
+}
+
+// Cheap enough for monitoring to poll
+#[query]
+fn health() -> Health {
+    let status = if ic_cdk::api::canister_balance128() < LOW_CYCLES_THRESHOLD {
+        HealthStatus::LowCycles
+    } else {
+        HealthStatus::Healthy
+    };
+
+    Health {
+        status,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+// File type allowlist, managed by the canister controllers
+#[update]
+fn set_allowed_file_types(file_types: Vec<String>) -> Result<Vec<String>, String> {
+    ensure_running("set_allowed_file_types").map_err(|err| err.to_string())?;
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err("Only canister controllers can change the allowed file types".to_string());
+    }
+
+    let file_types: Vec<String> = file_types
+        .iter()
+        .map(|file_type| file_type.trim().to_lowercase())
+        .filter(|file_type| !file_type.is_empty())
+        .collect();
+
+    if file_types.is_empty() {
+        return Err("At least one file type must be allowed".to_string());
+    }
+
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("allowed_file_types".to_string(), file_types.join(","));
+    });
+
+    Ok(file_types)
+}
+
+#[query]
+fn get_allowed_file_types() -> Vec<String> {
+    CONFIG.with(|config| {
+        match config.borrow().get(&"allowed_file_types".to_string()) {
+            Some(file_types) => file_types.split(',').map(|file_type| file_type.to_string()).collect(),
+            None => DEFAULT_ALLOWED_FILE_TYPES.iter().map(|file_type| file_type.to_string()).collect(),
+        }
+    })
+}
+
+// Whether files of a type we cannot sniff are accepted; on by default
+#[update]
+fn set_file_validation_permissive(permissive: bool) -> Result<bool, String> {
+    ensure_running("set_file_validation_permissive").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("file_validation_permissive".to_string(), permissive.to_string());
+    });
+    Ok(permissive)
+}
+
+#[query]
+fn is_file_validation_permissive() -> bool {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"file_validation_permissive".to_string())
+            .is_none_or(|permissive| permissive == "true")
+    })
+}
+
+// How long buyers have to request a refund on an escrowed purchase
+#[update]
+fn set_escrow_window(seconds: u64) -> Result<u64, String> {
+    ensure_running("set_escrow_window").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if !(MIN_ESCROW_WINDOW_SECONDS..=MAX_ESCROW_WINDOW_SECONDS).contains(&seconds) {
+        return Err(format!(
+            "seconds: must be between {} and {}",
+            MIN_ESCROW_WINDOW_SECONDS, MAX_ESCROW_WINDOW_SECONDS
+        ));
+    }
+
+    CONFIG.with(|config| {
+        config.borrow_mut().insert("escrow_window_seconds".to_string(), seconds.to_string());
+    });
+    Ok(seconds)
+}
+
+#[query]
+fn get_escrow_window() -> u64 {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get(&"escrow_window_seconds".to_string())
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(DEFAULT_ESCROW_WINDOW_SECONDS)
+    })
+}
+
+// Marketplace authorization, managed by the canister controllers
+#[update]
+fn add_authorized_marketplace(marketplace: Principal) -> Result<(), String> {
+    ensure_running("add_authorized_marketplace").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+    authorize_marketplace(marketplace, time());
+    Ok(())
+}
+
+#[update]
+fn remove_authorized_marketplace(marketplace: Principal) -> Result<(), String> {
+    ensure_running("remove_authorized_marketplace").map_err(|err| err.to_string())?;
+    ensure_controller()?;
+
+    if !deauthorize_marketplace(&marketplace) {
+        return Err("Marketplace is not authorized".to_string());
+    }
+    Ok(())
+}
+
+#[query]
+fn list_authorized_marketplaces() -> Vec<Principal> {
+    AUTHORIZED_MARKETPLACES.with(|marketplaces| {
+        marketplaces
+            .borrow()
+            .iter()
+            .map(|(marketplace, _)| marketplace)
+            .collect()
+    })
+}
+
+// Emergency pause. Every update method checks ensure_running first; queries
+// are never paused, and controllers bypass the pause so they can run fixes.
+#[update]
+fn set_paused(global: bool, reason: Option<String>) -> Result<PauseStatus, String> {
+    ensure_controller()?;
+    let pause = if global { Some(new_pause(reason, time())?) } else { None };
+    set_pause(GLOBAL_PAUSE_KEY, pause);
+    Ok(pause_status())
+}
+
+#[update]
+fn pause_method(name: String, reason: Option<String>) -> Result<PauseStatus, String> {
+    ensure_controller()?;
+    if !INGRESS_UPDATE_METHODS.iter().any(|(method, _, _)| *method == name) {
+        return Err(format!("{} is not an update method", name));
+    }
+    set_pause(&name, Some(new_pause(reason, time())?));
+    Ok(pause_status())
+}
+
+#[update]
+fn resume_method(name: String) -> Result<PauseStatus, String> {
+    ensure_controller()?;
+    set_pause(&name, None);
+    Ok(pause_status())
+}
+
+#[query]
+fn get_pause_status() -> PauseStatus {
+    pause_status()
+}
+
+fn ensure_running(method: &str) -> Result<(), AssetError> {
+    if ic_cdk::api::is_controller(&caller()) {
+        return Ok(());
+    }
+    check_pause(method)
+}
+
+fn check_pause(method: &str) -> Result<(), AssetError> {
+    let pause = PAUSES.with(|pauses| {
+        let pauses = pauses.borrow();
+        pauses.get(&GLOBAL_PAUSE_KEY.to_string()).or_else(|| pauses.get(&method.to_string()))
+    });
+    match pause {
+        Some(pause) => Err(AssetError::MethodPaused { reason: pause.reason }),
+        None => Ok(()),
+    }
+}
+
+fn new_pause(reason: Option<String>, current_time: u64) -> Result<Pause, String> {
+    let reason = reason.map(|reason| reason.trim().to_string()).filter(|reason| !reason.is_empty());
+    if reason.as_ref().is_some_and(|reason| reason.chars().count() > MAX_PAUSE_REASON_LENGTH) {
+        return Err(format!("reason: must be at most {} characters", MAX_PAUSE_REASON_LENGTH));
+    }
+    Ok(Pause {
+        reason,
+        paused_at: current_time,
+    })
+}
+
+fn set_pause(key: &str, pause: Option<Pause>) {
+    PAUSES.with(|pauses| {
+        let mut pauses = pauses.borrow_mut();
+        match pause {
+            Some(pause) => pauses.insert(key.to_string(), pause),
+            None => pauses.remove(&key.to_string()),
+        };
+    });
+}
+
+fn pause_status() -> PauseStatus {
+    PAUSES.with(|pauses| {
+        let pauses = pauses.borrow();
+        PauseStatus {
+            global: pauses.get(&GLOBAL_PAUSE_KEY.to_string()),
+            methods: pauses.iter().filter(|(key, _)| key != GLOBAL_PAUSE_KEY).collect(),
+        }
+    })
+}
+
+fn ensure_controller() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return Err("Only canister controllers can perform this action".to_string());
+    }
+    Ok(())
+}
+
+fn authorize_marketplace(marketplace: Principal, current_time: u64) {
+    AUTHORIZED_MARKETPLACES.with(|marketplaces| {
+        marketplaces.borrow_mut().insert(marketplace, current_time);
+    });
+}
+
+fn deauthorize_marketplace(marketplace: &Principal) -> bool {
+    AUTHORIZED_MARKETPLACES.with(|marketplaces| {
+        marketplaces.borrow_mut().remove(marketplace).is_some()
+    })
+}
+
+fn is_authorized_marketplace(marketplace: &Principal) -> bool {
+    AUTHORIZED_MARKETPLACES.with(|marketplaces| {
+        marketplaces.borrow().contains_key(marketplace)
+    })
+}
+
+// HTTP gateway: GET /file/<file_hash>, GET /asset/<id>/preview,
+// GET /asset/<id>/image/<image_id>, GET /asset/<id>.json, GET /assets.json and
+// GET /user/<principal>/assets.json. /assets.json is the compact index unless
+// offset or limit is given, which pages through full asset documents instead.
+// Responses whose body matches the certified hash for their path carry an
+// IC-Certificate header.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method.to_uppercase() != "GET" {
+        return http_error(405, "Method not allowed");
+    }
+
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let paged = query_param(query, "offset").is_some() || query_param(query, "limit").is_some();
+
+    let gzip_accepted = accepts_gzip(&request.headers);
+    let mut response = match segments.as_slice() {
+        ["file", file_hash] => serve_file(file_hash, "public, max-age=31536000, immutable", gzip_accepted),
+        ["asset", asset_id, "preview"] => serve_asset_preview(asset_id, gzip_accepted),
+        ["asset", asset_id, "image", image_id] => serve_asset_image(asset_id, image_id),
+        ["asset", document] if document.ends_with(".json") => {
+            serve_asset_document(document.trim_end_matches(".json"), &request.headers, caller())
+        },
+        ["assets.json"] if paged => serve_asset_documents(query, None, caller()),
+        ["assets.json"] => serve_asset_index(gzip_accepted),
+        ["user", owner, "assets.json"] => match Principal::from_text(owner) {
+            Ok(owner) => serve_asset_documents(query, Some(owner), caller()),
+            Err(_) => http_error(404, "User not found"),
+        },
+        _ => return http_error(404, "Not found"),
+    };
+    add_certificate_header(path, &segments, &mut response);
+    response
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let (body, next_token) = if can_read_file(&token.file_hash, caller()) {
+        file_chunk_for_http(&token.file_hash, token.chunk_index, token.content_encoding)
+    } else {
+        (Vec::new(), None)
+    };
+
+    StreamingCallbackHttpResponse {
+        body,
+        token: next_token,
+    }
+}
+
+fn serve_asset_preview(asset_id: &str, gzip_accepted: bool) -> HttpResponse {
+    let asset = match asset_id.parse::<u64>().ok().and_then(asset_by_id) {
+        Some(asset) if can_view(&asset, caller()) => asset,
+        _ => return http_error(404, "Asset not found"),
+    };
+
+    // Gallery images take precedence over preview_image_url
+    if let Some(image_id) = gallery_of(asset.id).image_ids.first() {
+        return serve_image(*image_id, "public, max-age=300");
+    }
+
+    match asset.preview_image_url {
+        // Previews can be replaced, so they are only cached briefly
+        Some(url) => match url.strip_prefix("canister://") {
+            Some(file_hash) => serve_file(file_hash, "public, max-age=300", gzip_accepted),
+            None => HttpResponse {
+                status_code: 302,
+                headers: vec![("Location".to_string(), url)],
+                body: Vec::new(),
+                streaming_strategy: None,
+            },
+        },
+        None => http_error(404, "Asset has no preview image"),
+    }
+}
+
+fn serve_asset_image(asset_id: &str, image_id: &str) -> HttpResponse {
+    let asset = match asset_id.parse::<u64>().ok().and_then(asset_by_id) {
+        Some(asset) if can_view(&asset, caller()) => asset,
+        _ => return http_error(404, "Asset not found"),
+    };
+
+    match image_id.parse::<u64>() {
+        // Image ids are never reused, so an image's bytes never change
+        Ok(image_id) if gallery_of(asset.id).image_ids.contains(&image_id) => {
+            serve_image(image_id, "public, max-age=31536000, immutable")
+        },
+        _ => http_error(404, "Image not found"),
+    }
+}
+
+fn serve_image(image_id: u64, cache_control: &str) -> HttpResponse {
+    match ASSET_IMAGES.with(|images| images.borrow().get(&image_id)) {
+        Some(image) => HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), image.content_type),
+                ("Content-Length".to_string(), image.bytes.len().to_string()),
+                ("Cache-Control".to_string(), cache_control.to_string()),
+            ],
+            body: image.bytes,
+            streaming_strategy: None,
+        },
+        None => http_error(404, "Image not found"),
+    }
+}
+
+fn serve_file(file_hash: &str, cache_control: &str, gzip_accepted: bool) -> HttpResponse {
+    // Gateway requests are anonymous, so private and paid files are reported
+    // as missing rather than revealing they exist
+    if !can_read_file(file_hash, caller()) {
+        return http_error(404, "File not found");
+    }
+
+    if !is_file_stored(file_hash) {
+        return http_error(404, "File not found");
+    }
+    let info = FILE_INFO.with(|file_info| file_info.borrow().get(&file_hash.to_string()));
+    let stored_encoding = info.as_ref().and_then(|info| info.encoding);
+
+    // Compressed files go out as stored to clients that accept the encoding
+    let content_encoding = stored_encoding.filter(|_| gzip_accepted);
+    let content_length = served_len(file_hash, content_encoding.is_some()).unwrap_or(0);
+    let (body, next_token) = file_chunk_for_http(file_hash, 0, content_encoding);
+
+    let content_type = match info {
+        Some(info) if info.content_type != content_type_for("") => info.content_type,
+        _ => sniff_content_type(&body).unwrap_or("application/octet-stream").to_string(),
+    };
+
+    let mut headers = vec![
+        ("Content-Type".to_string(), content_type),
+        ("Content-Length".to_string(), content_length.to_string()),
+        ("Cache-Control".to_string(), cache_control.to_string()),
+    ];
+    match content_encoding {
+        Some(_) => {
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+            headers.push(("ETag".to_string(), format!("\"{}.gz\"", file_hash)));
+        },
+        None => headers.push(("ETag".to_string(), format!("\"{}\"", file_hash))),
+    }
+    if stored_encoding.is_some() {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
+
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+        streaming_strategy: next_token.map(|token| StreamingStrategy::Callback {
+            callback: StreamingCallback::new(ic_cdk::id(), "http_request_streaming_callback".to_string()),
+            token,
+        }),
+    }
+}
+
+// Serves a file in response-sized chunks, returning the requested chunk and
+// the token for the next one if the file continues past it
+fn file_chunk_for_http(
+    file_hash: &str,
+    chunk_index: u64,
+    content_encoding: Option<ContentEncoding>,
+) -> (Vec<u8>, Option<StreamingCallbackToken>) {
+    let as_stored = content_encoding.is_some();
+    let start = chunk_index.saturating_mul(MAX_FILE_CHUNK_SIZE);
+    let body = served_range(file_hash, start, MAX_FILE_CHUNK_SIZE, as_stored).unwrap_or_default();
+
+    let next_token = if start + (body.len() as u64) < served_len(file_hash, as_stored).unwrap_or(0) {
+        Some(StreamingCallbackToken {
+            file_hash: file_hash.to_string(),
+            chunk_index: chunk_index + 1,
+            content_encoding,
+        })
+    } else {
+        None
+    };
+
+    (body, next_token)
+}
+
+fn http_error(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
+
+fn serve_asset_index(gzip_accepted: bool) -> HttpResponse {
+    let body = CERTIFIED_HTTP.with(|certified| {
+        let certified = certified.borrow();
+        if gzip_accepted { certified.index_body_gzip.clone() } else { certified.index_body.clone() }
+    });
+    let mut headers = vec![
+        ("Content-Type".to_string(), "application/json".to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+        ("Cache-Control".to_string(), "public, max-age=60".to_string()),
+        ("Vary".to_string(), "Accept-Encoding".to_string()),
+    ];
+    if gzip_accepted {
+        headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+    }
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+        streaming_strategy: None,
+    }
+}
+
+// Plugins revalidate with If-None-Match; the tag changes whenever the asset does
+fn serve_asset_document(asset_id: &str, headers: &[(String, String)], principal: Principal) -> HttpResponse {
+    let asset = match asset_id.parse::<u64>().ok().and_then(asset_by_id) {
+        Some(asset) if is_document_visible(&asset, principal) => asset,
+        _ => return http_error(404, "Asset not found"),
+    };
+
+    let etag = asset_document_etag(&asset);
+    let mut response_headers = vec![
+        ("Content-Type".to_string(), "application/json".to_string()),
+        ("Cache-Control".to_string(), "public, max-age=60".to_string()),
+        ("ETag".to_string(), etag.clone()),
+    ];
+    let revalidated = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|tag| tag.trim() == etag || tag.trim() == "*");
+    if revalidated {
+        return HttpResponse {
+            status_code: 304,
+            headers: response_headers,
+            body: Vec::new(),
+            streaming_strategy: None,
+        };
+    }
+
+    let body = serde_json::to_vec(&asset_document(&asset)).unwrap();
+    response_headers.push(("Content-Length".to_string(), body.len().to_string()));
+    HttpResponse {
+        status_code: 200,
+        headers: response_headers,
+        body,
+        streaming_strategy: None,
+    }
+}
+
+// Publicly listed assets, or one owner's visible assets, in id order
+fn serve_asset_documents(query: &str, owner: Option<Principal>, principal: Principal) -> HttpResponse {
+    let parse = |name: &str, default: u64| query_param(query, name).map_or(Ok(default), |value| value.parse::<u64>());
+    let (offset, limit) = match (parse("offset", 0), parse("limit", MAX_PAGE_SIZE)) {
+        (Ok(offset), Ok(limit)) => (offset, limit.min(MAX_PAGE_SIZE)),
+        _ => return http_error(400, "offset and limit must be non-negative integers"),
+    };
+
+    let page = match owner {
+        Some(owner) => {
+            let visible: Vec<Asset> = owned_asset_ids(owner)
+                .into_iter()
+                .filter_map(asset_by_id)
+                .filter(|asset| is_visible_to(asset, principal))
+                .collect();
+            AssetPage {
+                total_count: visible.len() as u64,
+                assets: visible.into_iter().skip(offset as usize).take(limit as usize).collect(),
+                truncated: false,
+                next_offset: None,
+            }
+        },
+        None => paginate_assets(offset, limit, is_listed_publicly),
+    };
+
+    let body = serde_json::to_vec(&AssetDocumentPage {
+        assets: page.assets.iter().map(asset_document).collect(),
+        total_count: page.total_count,
+        offset,
+        limit,
+    })
+    .unwrap();
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), "public, max-age=60".to_string()),
+        ],
+        body,
+        streaming_strategy: None,
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Direct lookups follow get_asset, except that moderator-hidden assets are
+// withheld from everyone but their owner
+fn is_document_visible(asset: &Asset, principal: Principal) -> bool {
+    can_view(asset, principal) && (asset.hidden.is_none() || asset.owner == principal)
+}
+
+fn asset_document_etag(asset: &Asset) -> String {
+    format!("\"{}-{}\"", asset.id, asset.updated_at)
+}
+
+fn asset_document(asset: &Asset) -> AssetDocument<'_> {
+    let has_preview = asset.preview_image_url.is_some() || !gallery_of(asset.id).image_ids.is_empty();
+    let (license, license_url) = match &asset.license {
+        License::PersonalUse => ("PersonalUse", None),
+        License::CommercialUse => ("CommercialUse", None),
+        License::CcBy => ("CCBY", None),
+        License::Cc0 => ("CC0", None),
+        License::Custom { url } => ("Custom", Some(url.as_str())),
+    };
+    let metadata = asset
+        .metadata
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                MetadataValue::Text(text) => serde_json::Value::from(text.as_str()),
+                MetadataValue::Nat(number) => serde_json::Value::from(*number),
+                MetadataValue::Bool(flag) => serde_json::Value::from(*flag),
+            };
+            (key.clone(), value)
+        })
+        .collect();
+
+    AssetDocument {
+        id: asset.id,
+        name: &asset.name,
+        description: &asset.description,
+        slug: asset.slug.as_deref(),
+        owner: asset.owner.to_text(),
+        creator: asset.creator.to_text(),
+        category_id: asset.category_id,
+        category: &asset.category,
+        tags: &asset.tags,
+        file_type: &asset.file_type,
+        file_size: asset.file_size,
+        // Imported assets point at their external copy until a file is attached
+        file: if asset.file_hash.is_empty() { asset.file_url.clone() } else { format!("/file/{}", asset.file_hash) },
+        preview: has_preview.then(|| format!("/asset/{}/preview", asset.id)),
+        price_e8s: icp_price(asset),
+        for_sale: asset.is_for_sale,
+        royalty_bps: asset.royalty_bps,
+        license,
+        license_url,
+        metadata,
+        created_at: asset.created_at,
+        updated_at: asset.updated_at,
+    }
+}
+
+// Whether an Accept-Encoding header allows gzip, directly or through "*",
+// without ruling it out with q=0
+fn accepts_gzip(headers: &[(String, String)]) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|coding| {
+            let mut parameters = coding.split(';');
+            let name = parameters.next().unwrap_or_default().trim();
+            let refused = parameters.any(|parameter| {
+                parameter.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+// Certifies a response only if its body is exactly what was certified for the
+// path. Streamed bodies cannot be certified as a whole and say so explicitly.
+fn add_certificate_header(path: &str, segments: &[&str], response: &mut HttpResponse) {
+    if response.streaming_strategy.is_some() {
+        response.headers.push(("X-Uncertified-Reason".to_string(), "streamed".to_string()));
+        return;
+    }
+    // Gateways verify a gzip response against its decoded body
+    let gzipped = response.headers.iter().any(|(name, value)| name == "Content-Encoding" && value == "gzip");
+    let body_hash: Option<Sha256Digest> = if gzipped {
+        gzip_source_hash(segments)
+    } else {
+        Some(Sha256::digest(&response.body).into())
+    };
+    if body_hash.is_none() || certified_body_hash(path) != body_hash {
+        return;
+    }
+    if let Some(certificate) = ic_cdk::api::data_certificate() {
+        response.headers.push(("IC-Certificate".to_string(), certificate_header(&certificate, path)));
+    }
+}
+
+// What a gzip response decodes to, known from where its body came from
+// rather than by decoding it: the content address of a stored file, or the
+// hash kept with the compressed asset index
+fn gzip_source_hash(segments: &[&str]) -> Option<Sha256Digest> {
+    match segments {
+        ["file", file_hash] => digest_from_hex(file_hash),
+        ["asset", asset_id, "preview"] => {
+            let asset = asset_id.parse::<u64>().ok().and_then(asset_by_id)?;
+            digest_from_hex(&preview_file_hash(&asset.preview_image_url)?)
+        },
+        ["assets.json"] => CERTIFIED_HTTP.with(|certified| Some(certified.borrow().index_body_hash)),
+        _ => None,
+    }
+}
+
+// The certificate and the certified tree pruned down to `path`, as the HTTP
+// gateway expects them: CBOR behind the self-describing tag, in base64
+fn certificate_header(certificate: &[u8], path: &str) -> String {
+    let tree = CERTIFIED_HTTP.with(|certified| {
+        let certified = certified.borrow();
+        let witness = labeled(b"http_assets", certified.tree.witness(path.as_bytes()));
+        let mut serializer = serde_cbor::Serializer::new(Vec::new());
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    });
+    format!("certificate=:{}:, tree=:{}:", BASE64.encode(certificate), BASE64.encode(tree))
+}
+
+// Certified HTTP paths. Every write that changes what a path serves updates
+// its entry here, which refreshes the canister's certified data.
+fn certified_body_hash(path: &str) -> Option<Sha256Digest> {
+    CERTIFIED_HTTP.with(|certified| certified.borrow().tree.get(path.as_bytes()).copied())
+}
+
+fn certify_path(path: String, body_hash: Sha256Digest) {
+    let changed = CERTIFIED_HTTP.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        let changed = certified.tree.get(path.as_bytes()) != Some(&body_hash);
+        if changed {
+            certified.tree.insert(path, body_hash);
+        }
+        changed
+    });
+    if changed {
+        refresh_certified_data();
+    }
+}
+
+fn uncertify_path(path: &str) {
+    let changed = CERTIFIED_HTTP.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        let changed = certified.tree.get(path.as_bytes()).is_some();
+        if changed {
+            certified.tree.delete(path.as_bytes());
+        }
+        changed
+    });
+    if changed {
+        refresh_certified_data();
+    }
+}
+
+// Files are content addressed, so the hash in the path is the body hash.
+// Files too large for one response are streamed and left uncertified.
+fn certify_file(file_hash: &str, size: u64) {
+    if size > MAX_FILE_CHUNK_SIZE {
+        return;
+    }
+    if let Some(body_hash) = digest_from_hex(file_hash) {
+        certify_path(format!("/file/{}", file_hash), body_hash);
+    }
+}
+
+fn digest_from_hex(hex: &str) -> Option<Sha256Digest> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (index, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn image_digest(image_id: u64) -> Option<Sha256Digest> {
+    ASSET_IMAGE_DIGESTS.with(|digests| digests.borrow().get(&image_id))
+}
+
+// What /asset/<id>/preview serves: the first gallery image, the stored
+// preview file or an empty redirect body. A gallery image whose digest has
+// not been backfilled yet leaves the preview uncertified.
+fn preview_body_hash(asset: &Asset) -> Option<Sha256Digest> {
+    if let Some(image_id) = gallery_of(asset.id).image_ids.first() {
+        return image_digest(*image_id);
+    }
+    match asset.preview_image_url.as_deref()?.strip_prefix("canister://") {
+        Some(file_hash) => certified_body_hash(&format!("/file/{}", file_hash)),
+        None => Some(Sha256::digest(b"").into()),
+    }
+}
+
+fn certify_asset(asset: &Asset) {
+    let preview_path = format!("/asset/{}/preview", asset.id);
+    match preview_body_hash(asset) {
+        Some(body_hash) => certify_path(preview_path, body_hash),
+        None => uncertify_path(&preview_path),
+    }
+
+    // Gateway requests are anonymous, so only documents anyone may read are certified
+    let document_path = format!("/asset/{}.json", asset.id);
+    if is_document_visible(asset, Principal::anonymous()) {
+        certify_path(document_path, Sha256::digest(serde_json::to_vec(&asset_document(asset)).unwrap()).into());
+    } else {
+        uncertify_path(&document_path);
+    }
+
+    let entry = is_listed_publicly(asset).then(|| {
+        let has_preview = asset.preview_image_url.is_some() || !gallery_of(asset.id).image_ids.is_empty();
+        serde_json::to_string(&AssetIndexEntry {
+            id: asset.id,
+            name: &asset.name,
+            slug: asset.slug.as_deref(),
+            category: &asset.category,
+            file_type: &asset.file_type,
+            file: format!("/file/{}", asset.file_hash),
+            preview: has_preview.then(|| format!("/asset/{}/preview", asset.id)),
+        })
+        .unwrap()
+    });
+    set_asset_index_entry(asset.id, entry);
+}
+
+fn uncertify_asset(asset: &Asset) {
+    uncertify_path(&format!("/asset/{}/preview", asset.id));
+    uncertify_path(&format!("/asset/{}.json", asset.id));
+    set_asset_index_entry(asset.id, None);
+}
+
+fn set_asset_index_entry(asset_id: u64, entry: Option<String>) {
+    let changed = CERTIFIED_HTTP.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        let changed = match entry {
+            Some(entry) => certified.index_entries.insert(asset_id, entry.clone()) != Some(entry),
+            None => certified.index_entries.remove(&asset_id).is_some(),
+        };
+        certified.index_dirty |= changed;
+        changed
+    });
+    if changed {
+        refresh_certified_data();
+    }
+}
+
+// Recertifies the gallery images and the preview after a gallery change
+fn certify_gallery(asset_id: u64) {
+    let image_ids = gallery_of(asset_id).image_ids;
+    let previous = CERTIFIED_HTTP.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        let previous = certified.gallery_images.remove(&asset_id).unwrap_or_default();
+        if !image_ids.is_empty() {
+            certified.gallery_images.insert(asset_id, image_ids.clone());
+        }
+        previous
+    });
+
+    with_deferred_certification(|| {
+        for image_id in previous.into_iter().filter(|image_id| !image_ids.contains(image_id)) {
+            uncertify_path(&format!("/asset/{}/image/{}", asset_id, image_id));
+        }
+        // Images still waiting for a backfilled digest stay uncertified
+        for image_id in image_ids {
+            let path = format!("/asset/{}/image/{}", asset_id, image_id);
+            match image_digest(image_id) {
+                Some(body_hash) => certify_path(path, body_hash),
+                None => uncertify_path(&path),
+            }
+        }
+        if let Some(asset) = asset_by_id(asset_id) {
+            certify_asset(&asset);
+        }
+    });
+}
+
+// Hashes gallery images stored before digests were recorded at upload and
+// certifies their galleries, stopping after the gallery that takes the batch
+// past IMAGE_DIGEST_BATCH_BYTES. Returns the number of images hashed.
+fn backfill_image_digests(cursor: &mut Option<u64>) -> u64 {
+    let galleries = ASSET_GALLERIES.with(|galleries| next_maintenance_batch(&galleries.borrow(), cursor));
+    let mut hashed = 0;
+    let mut bytes = 0;
+    for (asset_id, gallery) in galleries {
+        if bytes >= IMAGE_DIGEST_BATCH_BYTES {
+            break;
+        }
+        let mut backfilled = false;
+        for image_id in gallery.image_ids {
+            if image_digest(image_id).is_some() {
+                continue;
+            }
+            if let Some(image) = ASSET_IMAGES.with(|images| images.borrow().get(&image_id)) {
+                ASSET_IMAGE_DIGESTS
+                    .with(|digests| digests.borrow_mut().insert(image_id, Sha256::digest(&image.bytes).into()));
+                bytes += image.bytes.len() as u64;
+                hashed += 1;
+                backfilled = true;
+            }
+        }
+        if backfilled {
+            certify_gallery(asset_id);
+        }
+        // The next pass resumes after the last gallery this one finished
+        *cursor = Some(asset_id);
+    }
+    hashed
+}
+
+// Runs `f` with certified data refreshed once at the end rather than after
+// every path it certifies
+fn with_deferred_certification<T>(f: impl FnOnce() -> T) -> T {
+    let already_deferred = CERTIFIED_HTTP.with(|certified| std::mem::replace(&mut certified.borrow_mut().deferred, true));
+    let result = f();
+    if !already_deferred {
+        CERTIFIED_HTTP.with(|certified| certified.borrow_mut().deferred = false);
+        refresh_certified_data();
+    }
+    result
+}
+
+// Recertifies everything served over HTTP from the stable stores. Gallery
+// images are certified from their stored digests, never rehashed.
+fn rebuild_certification() {
+    CERTIFIED_HTTP.with(|certified| *certified.borrow_mut() = CertifiedHttp::new());
+    with_deferred_certification(|| {
+        for file_hash in stored_file_hashes() {
+            let size = file_size_of(&file_hash);
+            certify_file(&file_hash, size);
+        }
+        let asset_ids: Vec<u64> = ASSETS.with(|assets| assets.borrow().keys().collect());
+        for asset_id in asset_ids {
+            certify_gallery(asset_id);
+        }
+    });
+}
+
+// Rebuilds the asset index if it changed, then certifies the new root:
+// "http_assets" labeling the tree of path body hashes
+fn refresh_certified_data() {
+    let root = CERTIFIED_HTTP.with(|certified| {
+        let mut certified = certified.borrow_mut();
+        if certified.deferred {
+            return None;
+        }
+
+        if certified.index_dirty {
+            let entries: Vec<&str> =
+                certified.index_entries.values().rev().take(MAX_ASSET_INDEX_ENTRIES).map(String::as_str).collect();
+            let body = format!("{{\"assets\":[{}]}}", entries.join(",")).into_bytes();
+            let body_hash: Sha256Digest = Sha256::digest(&body).into();
+            certified.index_body_gzip = gzip(&body);
+            certified.index_body_hash = body_hash;
+            certified.index_body = body;
+            certified.index_dirty = false;
+            certified.tree.insert("/assets.json".to_string(), body_hash);
+        }
+        Some(labeled_hash(b"http_assets", &certified.tree.root_hash()))
+    });
+    if let Some(root) = root {
+        publish_certified_data(&root);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn publish_certified_data(root: &Sha256Digest) {
+    ic_cdk::api::set_certified_data(root);
+}
+
+// Certified data only exists inside a canister
+#[cfg(not(target_arch = "wasm32"))]
+fn publish_certified_data(_root: &Sha256Digest) {}
+
+// Compression. Text model formats are stored as gzip members, encoded and
+// decoded by flate2 on its pure Rust miniz_oxide backend.
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    // No file name or timestamp, so equal input always gives equal output
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes).and_then(|_| encoder.finish()).expect("writing to a Vec cannot fail")
+}
+
+// Stops at `max_len` decoded bytes, so a corrupt member cannot expand
+// without bound
+fn gunzip(member: &[u8], max_len: u64) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    GzDecoder::new(member)
+        .take(max_len + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|err| format!("Invalid gzip member: {}", err))?;
+    if bytes.len() as u64 > max_len {
+        return Err(format!("Gzip member decodes to more than {} bytes", max_len));
+    }
+    Ok(bytes)
+}
+
+// API v2. These return typed errors, summaries and cursor pages where the
+// original methods return strings, whole assets and offsets. The original
+// methods keep their original response types for deployed clients (see
+// AssetV1Response) and share the internals below; each v2 method is paused
+// together with its v1 counterpart.
+#[query]
+fn get_api_version() -> ApiVersion {
+    ApiVersion {
+        current: API_VERSION,
+        supported: (1..=API_VERSION).collect(),
+    }
+}
+
+#[query(composite = true)]
+fn get_asset_v2(asset_id: u64) -> Result<Asset, AssetError> {
+    visible_asset(asset_id, caller())
+}
+
+#[query]
+fn list_assets_v2(cursor: Option<String>, limit: u64) -> Result<AssetSummaryCursorPage, AssetError> {
+    Ok(assets_after(cursor, limit)?.into())
+}
+
+#[query]
+fn get_user_assets_v2(owner: Principal, cursor: Option<String>, limit: u64) -> Result<AssetSummaryCursorPage, AssetError> {
+    Ok(user_assets_after(owner, caller(), cursor, limit)?.into())
+}
+
+#[query]
+fn get_assets_for_sale_v2(cursor: Option<String>, limit: u64) -> Result<AssetSummaryCursorPage, AssetError> {
+    Ok(assets_for_sale_after(cursor, limit, time())?.into())
+}
+
+#[update]
+fn upload_asset_with_file_v2(asset_input: AssetInput, file_data: Vec<u8>) -> Result<AssetUpload, AssetError> {
+    ensure_running("upload_asset_with_file")?;
+    create_asset_with_file(asset_input, file_data)
+}
+
+#[update]
+fn delete_asset_v2(asset_id: u64) -> Result<(), AssetError> {
+    ensure_running("delete_asset")?;
+    delete_owned_asset(asset_id, caller())?;
+    close_marketplace_listings(asset_id);
+    Ok(())
+}
+
+// The v1 methods answered with these messages before their errors were typed
+fn upload_error_v1(err: AssetError) -> String {
+    match err {
+        AssetError::AnonymousCaller => "Anonymous users cannot upload assets".to_string(),
+        err => err.to_string(),
+    }
+}
+
+fn delete_error_v1(err: AssetError) -> String {
+    match err {
+        AssetError::NotOwner => "Only the owner can delete the asset".to_string(),
+        AssetError::NotArchived => "Archive the asset before deleting it".to_string(),
+        err => err.to_string(),
+    }
+}
+
+// Endpoints for the instruction-count benchmarks in backend/benchmarks. They
+// only exist in builds with the bench feature, which are never deployed.
+#[cfg(feature = "bench")]
+#[update]
+fn bench_seed_assets(first_id: u64, count: u64) {
+    let owner = caller();
+    let now = time();
+    for asset_id in first_id..first_id + count {
+        let asset = Asset {
+            id: asset_id,
+            name: format!("Benchmark asset {}", asset_id),
+            description: "A seeded asset with a description of typical length for a listing page".to_string(),
+            owner,
+            file_hash: format!("{:064x}", asset_id),
+            file_url: String::new(),
+            file_type: "glb".to_string(),
+            file_size: 1_000_000,
+            prices: icp_prices(100_000_000 + asset_id),
+            is_for_sale: true,
+            created_at: now,
+            updated_at: now,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            category: UNCATEGORIZED_CATEGORY_NAME.to_string(),
+            tags: vec!["benchmark".to_string(), "seeded".to_string()],
+            preview_image_url: None,
+            creator: owner,
+            royalty_bps: 500,
+            hidden: None,
+            visibility: Visibility::Public,
+            metadata: vec![("polygons".to_string(), MetadataValue::Nat(12_000))],
+            archived_at: None,
+            license: License::CommercialUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
+        };
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+    }
+}
+
+// Instructions one call of a listing query takes, counted inside the canister
+#[cfg(feature = "bench")]
+#[query]
+fn bench_listing_instructions(query: String, offset: u64, limit: u64) -> u64 {
+    let any_asset = AssetFilter {
+        category_id: None,
+        owner: None,
+        for_sale_only: Some(true),
+        min_price: None,
+        max_price: None,
+        file_type: None,
+        tag: None,
+        created_after: None,
+        created_before: None,
+        metadata: None,
+        verified_only: None,
+        license: None,
+        ledger: None,
+    };
+    match query.as_str() {
+        "get_assets_for_sale" => drop(get_assets_for_sale(offset, limit)),
+        "get_assets_for_sale_summary" => drop(get_assets_for_sale_summary(offset, limit)),
+        "get_assets_summary_paginated" => drop(get_assets_summary_paginated(offset, limit)),
+        "get_assets_filtered" => drop(get_assets_filtered(any_asset, SortBy::PriceDesc, offset, limit)),
+        other => ic_cdk::trap(&format!("No benchmark for {}", other)),
+    }
+    ic_cdk::api::performance_counter(0)
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn insert_listed_asset(asset_id: u64, owner: Principal) {
+        let asset = Asset {
+            id: asset_id,
+            name: "Test asset".to_string(),
+            description: String::new(),
+            owner,
+            file_hash: format!("hash-{}", asset_id),
+            file_url: String::new(),
+            file_type: "glb".to_string(),
+            file_size: 1,
+            prices: icp_prices(100),
+            is_for_sale: true,
+            created_at: 0,
+            updated_at: 0,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            category: "props".to_string(),
+            tags: Vec::new(),
+            preview_image_url: None,
+            creator: owner,
+            royalty_bps: 0,
+            hidden: None,
+            visibility: Visibility::Public,
+            metadata: Vec::new(),
+            archived_at: None,
+            license: License::PersonalUse,
+            slug: None,
+            reservation: None,
+            source: None,
+            frozen: None,
+        };
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+    }
+
+    #[test]
+    fn unauthorized_marketplace_cannot_transfer() {
+        let (marketplace, seller, buyer) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, seller);
+
+        let result = transfer_for_marketplace(marketplace, 1, seller, buyer, 100, 10);
+
+        assert!(matches!(result, Err(AssetError::Unauthorized)));
+        let asset = ASSETS.with(|assets| assets.borrow().get(&1)).unwrap();
+        assert_eq!(asset.owner, seller);
+        assert!(asset.is_for_sale);
+    }
+
+    #[test]
+    fn controller_manages_the_allowlist() {
+        let (first, second) = (principal(1), principal(2));
+
+        authorize_marketplace(first, 1);
+        authorize_marketplace(second, 2);
+        assert_eq!(list_authorized_marketplaces(), vec![first, second]);
+
+        assert!(deauthorize_marketplace(&first));
+        assert!(!deauthorize_marketplace(&first));
+        assert_eq!(list_authorized_marketplaces(), vec![second]);
+        assert!(!is_authorized_marketplace(&first));
+    }
+
+    #[test]
+    fn authorized_marketplace_can_transfer() {
+        let (marketplace, seller, buyer) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, seller);
+        authorize_marketplace(marketplace, 1);
+
+        let asset = transfer_for_marketplace(marketplace, 1, seller, buyer, 100, 10).unwrap();
+
+        assert_eq!(asset.owner, buyer);
+        assert!(!asset.is_for_sale);
+        assert_eq!(asset.updated_at, 10);
+
+        let history = get_asset_sale_history(1, 0, 10);
+        assert_eq!(history.total_count, 1);
+        assert_eq!(history.sales[0].price, 100);
+        assert_eq!(history.sales[0].buyer, buyer);
+    }
+
+    #[test]
+    fn owner_index_follows_ownership_changes() {
+        let (marketplace, seller, buyer) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        authorize_marketplace(marketplace, 1);
+
+        transfer_for_marketplace(marketplace, 1, seller, buyer, 100, 10).unwrap();
+
+        assert_eq!(owned_asset_ids(seller), vec![2]);
+        assert_eq!(owned_asset_ids(buyer), vec![1]);
+        // The transfer delists the asset
+        let listed: Vec<u64> = FOR_SALE_INDEX.with(|index| index.borrow().keys().collect());
+        assert_eq!(listed, vec![2]);
+
+        // Rebuilding from the asset records yields the same index
+        assert_eq!(rebuild_indexes(), 2);
+        assert_eq!(owned_asset_ids(seller), vec![2]);
+        assert_eq!(owned_asset_ids(buyer), vec![1]);
+    }
+
+    #[test]
+    fn legacy_assets_backfill_creator_from_owner() {
+        let owner = principal(7);
+        let legacy = AssetV1 {
+            id: 1,
+            name: "Legacy".to_string(),
+            description: String::new(),
+            owner,
+            file_hash: "hash".to_string(),
+            file_url: String::new(),
+            file_type: "glb".to_string(),
+            file_size: 1,
+            price: 5,
+            is_for_sale: false,
+            created_at: 0,
+            updated_at: 0,
+            category: String::new(),
+            tags: Vec::new(),
+            preview_image_url: None,
+        };
+
+        let asset = Asset::from_bytes(Cow::Owned(candid::encode_one(&legacy).unwrap()));
+
+        assert_eq!(asset.creator, owner);
+        assert_eq!(asset.royalty_bps, 0);
+
+        let mut tagged = vec![ASSET_SCHEMA_TAG, 1];
+        tagged.extend(candid::encode_one(&legacy).unwrap());
+        let asset = Asset::from_bytes(Cow::Owned(tagged));
+
+        assert_eq!(asset.id, 1);
+        assert_eq!(asset.creator, owner);
+    }
+
+    #[test]
+    fn assets_round_trip_in_the_current_schema() {
+        let owner = principal(7);
+        insert_listed_asset(1, owner);
+        let asset = asset_by_id(1).unwrap();
+
+        let bytes = asset.to_bytes();
+        assert_eq!(&bytes[..2], &[ASSET_SCHEMA_TAG, ASSET_SCHEMA_VERSION]);
+        let decoded = Asset::from_bytes(bytes);
+        assert_eq!(decoded.id, 1);
+        assert_eq!(decoded.owner, owner);
+
+        // Records written before schema tags existed, which still had a
+        // single price like the v1 response
+        let untagged = Asset::from_bytes(Cow::Owned(candid::encode_one(AssetV1Response::from(asset)).unwrap()));
+        assert_eq!((untagged.creator, icp_price(&untagged)), (owner, Some(100)));
+
+        assert_eq!(rewrite_assets(), 1);
+    }
+
+    #[test]
+    fn free_text_categories_migrate_to_the_registry() {
+        let owner = principal(1);
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        ASSETS.with(|assets| {
+            let mut assets = assets.borrow_mut();
+            let mut asset = assets.get(&1).unwrap();
+            asset.category = " furniture".to_string();
+            assets.insert(1, asset);
+        });
+
+        assert!(seed_categories(0));
+        assert!(!seed_categories(0));
+        assert_eq!(migrate_asset_categories(), 2);
+        rebuild_indexes();
+
+        let furniture = find_category_by_name("Furniture").unwrap();
+        assert_eq!(asset_by_id(1).unwrap().category_id, furniture.id);
+        assert_eq!(asset_by_id(2).unwrap().category, UNCATEGORIZED_CATEGORY_NAME);
+        assert_eq!(get_assets_by_category(furniture.id, 0, 10).total_count, 1);
+        assert_eq!(get_assets_by_category(UNCATEGORIZED_CATEGORY_ID, 0, 10).total_count, 1);
+
+        // Renames reach the assets; names stay unique ignoring case
+        rename_category_to(furniture.id, "Furnishings").unwrap();
+        assert_eq!(asset_by_id(1).unwrap().category, "Furnishings");
+        assert!(add_category("FURNISHINGS", None, 0).is_err());
+
+        let chairs = add_category("Chairs", Some(furniture.id), 0).unwrap();
+        let tree = get_categories();
+        let node = tree.iter().find(|node| node.category.id == furniture.id).unwrap();
+        assert_eq!(node.children[0].category.id, chairs.id);
+        assert!(!tree.iter().any(|node| node.category.id == chairs.id));
+    }
+
+    fn unlisted_asset(asset_id: u64, owner: Principal) {
+        insert_listed_asset(asset_id, owner);
+        list_asset(asset_id, owner, false, 0).unwrap();
+    }
+
+    #[test]
+    fn bundles_move_every_member_together() {
+        let (seller, buyer) = (principal(1), principal(2));
+        for asset_id in 1..=3 {
+            unlisted_asset(asset_id, seller);
+        }
+        insert_listed_asset(4, seller);
+
+        let result = create_bundle_for(seller, "Set", "", vec![1, 4], 100, 0);
+        assert!(matches!(result, Err(AssetError::ListedForSale)));
+        let result = create_bundle_for(buyer, "Set", "", vec![1, 2], 100, 0);
+        assert!(matches!(result, Err(AssetError::NotOwner)));
+
+        let bundle = create_bundle_for(seller, "Set", "", vec![1, 2, 3], 100, 0).unwrap();
+        assert_eq!(list_asset(1, seller, true, 1).err(), Some(AssetError::InBundle { bundle_id: bundle.id }));
+        set_bundle_for_sale(bundle.id, seller, true, 1).unwrap();
+        assert_eq!(get_bundles_for_sale(0, 10).total_count, 1);
+
+        let (bundle, shares) = prepare_bundle_sale(bundle.id, buyer, 10).unwrap();
+        assert_eq!(shares.iter().map(|share| share.price).collect::<Vec<_>>(), vec![34, 33, 33]);
+        let (bundle, sales) = complete_bundle_sale(bundle, buyer, shares, principal(90), 2);
+
+        assert_eq!(bundle.status, BundleStatus::Sold);
+        assert_eq!(sales.len(), 3);
+        assert!((1..=3).all(|asset_id| asset_by_id(asset_id).unwrap().owner == buyer));
+        assert_eq!(get_bundle_sale(bundle.id).unwrap().sale_ids, sales.iter().map(|sale| sale.id).collect::<Vec<_>>());
+        assert!(ensure_not_bundled(1).is_ok());
+    }
+
+    #[test]
+    fn moving_a_member_invalidates_its_bundle() {
+        let (owner, friend) = (principal(1), principal(2));
+        unlisted_asset(1, owner);
+        unlisted_asset(2, owner);
+        let bundle = create_bundle_for(owner, "Set", "", vec![1, 2], 100, 0).unwrap();
+        set_bundle_for_sale(bundle.id, owner, true, 1).unwrap();
+
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, friend, OwnershipEventKind::Transfer, None, 2);
+        ASSETS.with(|assets| assets.borrow_mut().insert(1, asset));
+
+        let bundle = get_bundle(bundle.id).unwrap();
+        assert_eq!(bundle.status, BundleStatus::Invalidated);
+        assert!(!bundle.is_for_sale);
+        assert!(ensure_not_bundled(2).is_ok());
+        assert!(matches!(prepare_bundle_sale(bundle.id, friend, 10), Err(AssetError::NotForSale)));
+    }
+
+    fn glb_bytes(version: u32, declared_length: u32, payload: usize) -> Vec<u8> {
+        let mut bytes = b"glTF".to_vec();
+        bytes.extend(version.to_le_bytes());
+        bytes.extend(declared_length.to_le_bytes());
+        bytes.resize(payload, 0);
+        bytes
+    }
+
+    #[test]
+    fn model_files_must_match_their_declared_format() {
+        assert!(validate_file_format("glb", &glb_bytes(2, 20, 20)).is_ok());
+        assert!(validate_file_format("model/gltf-binary", &glb_bytes(2, 20, 20)).is_ok());
+        assert!(validate_file_format("glb", &glb_bytes(1, 20, 20)).is_err());
+        assert!(validate_file_format("glb", &glb_bytes(2, 40, 20)).is_err());
+        assert!(validate_file_format("vrm", b"PK\x03\x04 zipped").is_err());
+
+        assert!(validate_file_format("gltf", br#"{"asset": {"version": "2.0"}}"#).is_ok());
+        assert!(validate_file_format("gltf", br#"{"scenes": []}"#).is_err());
+        assert!(validate_file_format("gltf", b"not json").is_err());
+
+        // Types that cannot be sniffed depend on the permissive flag
+        assert!(validate_file_format("fbx", b"anything").is_ok());
+        CONFIG.with(|config| {
+            config.borrow_mut().insert("file_validation_permissive".to_string(), "false".to_string());
+        });
+        assert!(matches!(
+            validate_file_format("fbx", b"anything"),
+            Err(AssetError::InvalidFileFormat { .. })
+        ));
+        assert!(validate_file_format("", b"anything").is_ok());
+    }
+
+    #[test]
+    fn transfers_to_unusable_recipients_are_rejected() {
+        let owner = principal(2);
+        insert_listed_asset(1, owner);
+
+        for recipient in [Principal::anonymous(), Principal::management_canister(), owner] {
+            let result = transfer_owned_asset(1, owner, recipient);
+            assert!(matches!(result, Err(AssetError::InvalidRecipient)));
+        }
+        assert_eq!(asset_by_id(1).unwrap().owner, owner);
+    }
+
+    #[test]
+    fn listings_need_a_price_within_bounds() {
+        let owner = principal(2);
+        insert_listed_asset(1, owner);
+
+        // A listed asset cannot be repriced to zero or past the maximum
+        let result = set_asset_price(1, owner, 0, 10);
+        assert_eq!(result.err(), Some(AssetError::InvalidPrice { max: MAX_PRICE_E8S }));
+        let result = set_asset_price(1, owner, MAX_PRICE_E8S + 1, 10);
+        assert_eq!(result.err(), Some(AssetError::InvalidPrice { max: MAX_PRICE_E8S }));
+        assert_eq!(icp_price(&asset_by_id(1).unwrap()), Some(100));
+
+        // Free assets can exist, but cannot be listed
+        list_asset(1, owner, false, 10).unwrap();
+        assert_eq!(icp_price(&set_asset_price(1, owner, 0, 11).unwrap()), Some(0));
+        let result = list_asset(1, owner, true, 12);
+        assert_eq!(result.err(), Some(AssetError::InvalidPrice { max: MAX_PRICE_E8S }));
+        assert!(!asset_by_id(1).unwrap().is_for_sale);
+
+        // Prices past the maximum are rejected even when unlisted
+        let result = set_asset_price(1, owner, MAX_PRICE_E8S + 1, 13);
+        assert!(matches!(result, Err(AssetError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn rate_limiter_uses_a_sliding_window() {
+        let (uploader, other) = (principal(1), principal(2));
+        let second = 1_000_000_000;
+
+        for call in 0..DEFAULT_UPLOADS_PER_MINUTE as u64 {
+            check_rate_limit(uploader, RateLimitedAction::Upload, call * second).unwrap();
+        }
+        let result = check_rate_limit(uploader, RateLimitedAction::Upload, 30 * second);
+        assert_eq!(result.err(), Some(AssetError::RateLimited { retry_after_seconds: 30 }));
+
+        // Budgets are tracked per principal and per action
+        check_rate_limit(other, RateLimitedAction::Upload, 30 * second).unwrap();
+        check_rate_limit(uploader, RateLimitedAction::MetadataUpdate, 30 * second).unwrap();
+
+        // The first call leaves the window after a minute
+        check_rate_limit(uploader, RateLimitedAction::Upload, 60 * second + 1).unwrap();
+
+        // Quiet principals are swept from the log
+        check_rate_limit(uploader, RateLimitedAction::MetadataUpdate, 200 * second).unwrap();
+        let tracked = RATE_LIMITER.with(|limiter| limiter.borrow().calls.len());
+        assert_eq!(tracked, 1);
+    }
+
+    // Methods and whether they are queries, as exported in the candid interface
+    fn exported_methods() -> Vec<(String, bool)> {
+        let candid = __export_service();
+        let service = &candid[candid.find("service").unwrap()..];
+        let body = &service[service.find('{').unwrap() + 1..service.rfind('}').unwrap()];
+
+        let mut methods = Vec::new();
+        let (mut depth, mut entry) = (0, String::new());
+        for ch in body.chars() {
+            match ch {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ';' if depth == 0 => {
+                    let method = std::mem::take(&mut entry).split_whitespace().collect::<Vec<_>>().join(" ");
+                    let name = method.split(" : ").next().unwrap().to_string();
+                    methods.push((name, method.ends_with("query")));
+                    continue;
+                },
+                _ => {},
+            }
+            entry.push(ch);
+        }
+        methods
+    }
+
+    #[test]
+    fn inspect_message_lists_every_exported_method() {
+        let methods = exported_methods();
+        for (method, is_query) in &methods {
+            let listed = if *is_query {
+                INGRESS_QUERY_METHODS.contains(&method.as_str())
+            } else {
+                INGRESS_UPDATE_METHODS.iter().any(|(name, _, _)| name == method)
+            };
+            assert!(listed, "{} is missing from the inspect_message tables", method);
+        }
+
+        let listed = INGRESS_QUERY_METHODS.len() + INGRESS_UPDATE_METHODS.len();
+        assert_eq!(listed, methods.len(), "inspect_message lists methods that are not exported");
+    }
+
+    #[test]
+    fn inspect_message_drops_invalid_ingress() {
+        let user = principal(1);
+        let anonymous = Principal::anonymous();
+
+        assert!(screen_ingress("upload_asset", user, 1_000).is_ok());
+        assert!(screen_ingress("upload_asset", anonymous, 1_000).is_err());
+        assert!(screen_ingress("transfer_asset_ownership", anonymous, 100).is_err());
+        assert!(screen_ingress("upload_asset", user, METADATA_ARG_BYTES + 1).is_err());
+        assert!(screen_ingress("not_a_method", user, 0).is_err());
+
+        // Views may be recorded anonymously, and queries are never screened
+        assert!(screen_ingress("record_view", anonymous, 100).is_ok());
+        assert!(screen_ingress("get_asset", anonymous, 10_000_000).is_ok());
+    }
+
+    fn retag_asset(asset_id: u64, tags: &[&str]) {
+        let previous = ASSETS.with(|assets| assets.borrow().get(&asset_id)).unwrap();
+        let mut asset = previous.clone();
+        asset.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        unindex_asset(&previous);
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+    }
+
+    #[test]
+    fn tag_index_follows_tag_edits() {
+        let owner = principal(1);
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        retag_asset(1, &[" VR ", "Chair"]);
+        retag_asset(2, &["vr"]);
+
+        let tags: Vec<(String, u64)> = get_all_tags(0, 10).tags.into_iter().map(|t| (t.tag, t.count)).collect();
+        assert_eq!(tags, vec![("chair".to_string(), 1), ("vr".to_string(), 2)]);
+        assert_eq!(get_assets_by_tag("VR".to_string(), 0, 10).total_count, 2);
+
+        retag_asset(1, &["seat"]);
+        assert_eq!(get_assets_by_tag("vr".to_string(), 0, 10).total_count, 1);
+        assert_eq!(get_assets_by_tag("chair".to_string(), 0, 10).total_count, 0);
+
+        let suggestions: Vec<String> = suggest_tags("S".to_string(), 5).into_iter().map(|t| t.tag).collect();
+        assert_eq!(suggestions, vec!["seat".to_string()]);
+    }
+
+    fn metadata_filter(metadata: Vec<(&str, MetadataValue)>) -> AssetFilter {
+        AssetFilter {
+            category_id: None,
+            owner: None,
+            for_sale_only: None,
+            min_price: None,
+            max_price: None,
+            file_type: None,
+            tag: None,
+            created_after: None,
+            created_before: None,
+            metadata: Some(metadata.into_iter().map(|(key, value)| (key.to_string(), value)).collect()),
+            verified_only: None,
+            license: None,
+            ledger: None,
+        }
+    }
+
+    #[test]
+    fn metadata_fields_are_capped_and_filterable() {
+        let (owner, other) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+
+        let text = |value: &str| MetadataValue::Text(value.to_string());
+        assert!(matches!(set_metadata_field(1, other, "rig", text("humanoid"), 1), Err(AssetError::NotOwner)));
+        set_metadata_field(1, owner, " rig ", text("generic"), 1).unwrap();
+        set_metadata_field(1, owner, "rig", text("humanoid"), 2).unwrap();
+        let asset = set_metadata_field(1, owner, "polygons", MetadataValue::Nat(12_000), 3).unwrap();
+        assert_eq!(asset.metadata.len(), 2);
+        assert_eq!(metadata_field(&asset, "rig"), Some(&text("humanoid")));
+
+        let oversized = text(&"x".repeat(MAX_METADATA_VALUE_BYTES + 1));
+        assert!(matches!(set_metadata_field(1, owner, "notes", oversized, 4), Err(AssetError::InvalidInput(_))));
+        for field in asset.metadata.len()..MAX_METADATA_FIELDS {
+            set_metadata_field(1, owner, &format!("field-{}", field), MetadataValue::Bool(true), 4).unwrap();
+        }
+        assert!(matches!(
+            set_metadata_field(1, owner, "one-too-many", MetadataValue::Bool(true), 5),
+            Err(AssetError::InvalidInput(_))
+        ));
+
+        let asset = ASSETS.with(|assets| assets.borrow().get(&1)).unwrap();
+        assert!(metadata_filter(vec![("rig", text("humanoid")), ("polygons", MetadataValue::Nat(12_000))]).matches(&asset));
+        assert!(!metadata_filter(vec![("polygons", MetadataValue::Nat(12_001))]).matches(&asset));
+        assert!(!metadata_filter(vec![("polygons", text("12000"))]).matches(&asset));
+
+        let asset = remove_metadata_field(1, owner, "rig", 6).unwrap();
+        assert!(!metadata_filter(vec![("rig", text("humanoid"))]).matches(&asset));
+        assert!(matches!(remove_metadata_field(1, owner, "rig", 7), Err(AssetError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn display_names_are_unique_and_released_on_rename() {
+        let (alice, bob) = (principal(1), principal(2));
+
+        save_profile(alice, "Alice", "", None, None, 1).unwrap();
+        assert!(matches!(save_profile(bob, " alice ", "", None, None, 2), Err(AssetError::DisplayNameTaken)));
+        assert!(matches!(save_profile(bob, "Admin", "", None, None, 2), Err(AssetError::InvalidInput(_))));
+        assert!(matches!(
+            save_profile(bob, "Bob", "", Some("javascript:alert(1)".to_string()), None, 2),
+            Err(AssetError::InvalidInput(_))
+        ));
+
+        let renamed = save_profile(alice, "Alicia", "3D artist", None, Some("https://example.com".to_string()), 3).unwrap();
+        assert_eq!(renamed.created_at, 1);
+        assert!(get_profile_by_name("alice".to_string()).is_none());
+        assert_eq!(get_profile_by_name("ALICIA".to_string()).unwrap().principal, alice);
+
+        save_profile(bob, "Alice", "", None, None, 4).unwrap();
+        insert_listed_asset(1, bob);
+        let listed = with_creator_info(asset_by_id(1).unwrap());
+        assert_eq!(listed.owner_display_name.as_deref(), Some("Alice"));
+    }
+
+    #[test]
+    fn verification_is_logged_and_filterable() {
+        let (moderator, creator, other) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, creator);
+        insert_listed_asset(2, other);
+        save_profile(creator, "Creator", "", None, None, 1).unwrap();
+
+        grant_verification(creator, moderator, 5).unwrap();
+        assert_eq!(get_profile(creator).unwrap().verification.unwrap().verified_by, moderator);
+        assert_eq!(get_verified_creators(0, 10).total_count, 1);
+        assert!(with_creator_info(asset_by_id(1).unwrap()).owner_verified);
+
+        let mut filter = metadata_filter(Vec::new());
+        filter.metadata = None;
+        filter.verified_only = Some(true);
+        assert!(filter.matches(&asset_by_id(1).unwrap()));
+        assert!(!filter.matches(&asset_by_id(2).unwrap()));
+
+        withdraw_verification(creator, moderator, 6).unwrap();
+        assert!(withdraw_verification(creator, moderator, 7).is_err());
+        assert!(get_profile(creator).unwrap().verification.is_none());
+        assert!(!filter.matches(&asset_by_id(1).unwrap()));
+
+        let kinds: Vec<VerificationEventKind> = get_verification_events(0, 10).events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![VerificationEventKind::Granted, VerificationEventKind::Revoked]);
+    }
+
+    #[test]
+    fn escrow_reserves_the_asset_until_settled() {
+        let (seller, buyer) = (principal(1), principal(2));
+        insert_listed_asset(1, seller);
+
+        let escrow = prepare_escrow(1, buyer, 10).unwrap();
+        assert_eq!(escrow.expires_at, 10 + DEFAULT_ESCROW_WINDOW_SECONDS * 1_000_000_000);
+        open_escrow(&escrow);
+        assert!(matches!(prepare_escrow(1, principal(3), 11), Err(AssetError::TransferInProgress)));
+        assert!(matches!(list_asset(1, seller, false, 11), Err(AssetError::TransferInProgress)));
+
+        let refunded = complete_refund(escrow, 12);
+        assert_eq!(refunded.status, EscrowStatus::Refunded);
+        assert!(get_asset_escrow(1).is_none());
+        assert!(asset_by_id(1).unwrap().is_for_sale);
+
+        let escrow = prepare_escrow(1, buyer, 13).unwrap();
+        open_escrow(&escrow);
+        let (asset, sale) = complete_escrow_sale(escrow.clone(), EscrowStatus::AutoConfirmed, 14).unwrap();
+        assert_eq!(asset.owner, buyer);
+        assert!(!asset.is_for_sale);
+        assert_eq!(sale.price, 100);
+        let settled = get_escrow(escrow.id).unwrap();
+        assert_eq!((settled.status, settled.sale_id), (EscrowStatus::AutoConfirmed, Some(sale.id)));
+        assert!(!is_transfer_in_progress(1));
+
+        let kinds: Vec<OwnershipEventKind> = get_asset_provenance(1, 0, 10).events.into_iter().map(|event| event.kind).collect();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                OwnershipEventKind::EscrowReserved,
+                OwnershipEventKind::EscrowRefunded,
+                OwnershipEventKind::EscrowReserved,
+                OwnershipEventKind::EscrowSale,
+            ]
+        ));
+    }
+
+    #[test]
+    fn gifts_lock_the_asset_until_claimed() {
+        let (sender, friend) = (principal(1), principal(2));
+        insert_listed_asset(1, sender);
+
+        assert!(matches!(open_gift(1, friend, "", None, "code", 1), Err(AssetError::NotOwner)));
+        let gift = open_gift(1, sender, " Happy birthday ", None, "C0DE", 1).unwrap();
+        assert_eq!(gift.message, "Happy birthday");
+        assert!(matches!(list_asset(1, sender, true, 2), Err(AssetError::TransferInProgress)));
+        assert!(GIFT_CODES.with(|codes| codes.borrow().keys().all(|hash| hash != "C0DE" && hash != "code")));
+
+        assert!(matches!(redeem_gift("wrong", friend, 3), Err(AssetError::GiftNotFound)));
+        assert!(matches!(redeem_gift("c0de", sender, 3), Err(AssetError::InvalidRecipient)));
+        let asset = redeem_gift("c0de", friend, 3).unwrap();
+        assert_eq!(asset.owner, friend);
+        assert!(matches!(redeem_gift("c0de", principal(3), 4), Err(AssetError::GiftNotFound)));
+        let events = get_asset_provenance(1, 0, 10).events;
+        assert!(matches!(events.last().map(|event| &event.kind), Some(OwnershipEventKind::Gift)));
+
+        // An expired gift no longer locks the asset or accepts its code
+        open_gift(1, friend, "", Some(10), "later", 5).unwrap();
+        assert!(matches!(redeem_gift("later", sender, 10), Err(AssetError::GiftNotFound)));
+        assert!(gift_of(1).is_none());
+        assert!(list_asset(1, friend, false, 11).is_ok());
+
+        // Removing a gift drops only its own code
+        insert_listed_asset(2, sender);
+        open_gift(1, friend, "", None, "first", 12).unwrap();
+        open_gift(2, sender, "", None, "second", 12).unwrap();
+        GIFT_CODE_HASHES.with(|hashes| hashes.borrow_mut().clear_new());
+        rebuild_gift_code_hashes();
+        remove_gift(1);
+        assert!(GIFT_CODE_HASHES.with(|hashes| hashes.borrow().get(&1)).is_none());
+        assert!(matches!(redeem_gift("first", sender, 13), Err(AssetError::GiftNotFound)));
+        assert_eq!(redeem_gift("second", friend, 13).unwrap().owner, friend);
+        assert!(GIFT_CODES.with(|codes| codes.borrow().is_empty()));
+    }
+
+    #[test]
+    fn rentals_grant_access_until_they_expire() {
+        let (owner, renter) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+
+        assert!(matches!(list_for_rent(1, renter, 10, 7, 0), Err(AssetError::NotOwner)));
+        let listing = list_for_rent(1, owner, 10, 7, 0).unwrap();
+        assert!(matches!(prepare_rental(1, owner, 1), Err(AssetError::InvalidInput(_))));
+        assert!(matches!(prepare_rental(1, renter, 8), Err(AssetError::InvalidInput(_))));
+        let (_, price) = prepare_rental(1, renter, 3).unwrap();
+        assert_eq!(price, 30);
+
+        let first = open_rental(&listing, renter, 3, price, None, 100);
+        assert_eq!(first.expires_at, 100 + 3 * NANOS_PER_DAY);
+        // Renting again extends the running rental
+        let second = open_rental(&listing, renter, 1, 10, None, 200);
+        assert_eq!(second.starts_at, first.expires_at);
+        assert_eq!(active_rentals(|_, principal| principal == renter, 200).len(), 1);
+
+        // The owner may withdraw the listing without cutting the rental short
+        RENTAL_LISTINGS.with(|listings| listings.borrow_mut().remove(&1));
+        assert!(matches!(prepare_rental(1, renter, 1), Err(AssetError::NotForRent)));
+        assert_eq!(active_rentals(|asset_id, _| asset_id == 1, second.expires_at - 1).len(), 1);
+
+        remove_expired_rentals(second.expires_at);
+        assert!(active_rental_of(1, renter).is_none());
+        assert_eq!(rental_by_id(first.id).unwrap().renter, renter);
+    }
+
+    #[test]
+    fn asset_lookups_line_up_with_ids() {
+        let (owner, stranger) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        let mut asset = asset_by_id(2).unwrap();
+        unindex_asset(&asset);
+        asset.visibility = Visibility::Private;
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, asset));
+
+        let found: Vec<Option<u64>> = lookup_assets(&[2, 1, 9], stranger)
+            .unwrap()
+            .into_iter()
+            .map(|asset| asset.map(|asset| AssetSummary::from(asset).id))
+            .collect();
+        assert_eq!(found, vec![None, Some(1), None]);
+        let found: Vec<Option<u64>> = lookup_summaries(&[2, 1, 9], stranger)
+            .unwrap()
+            .into_iter()
+            .map(|summary| summary.map(|summary| summary.id))
+            .collect();
+        assert_eq!(found, vec![None, Some(1), None]);
+
+        let page = get_assets_summary_paginated(0, 10);
+        assert_eq!(page.total_count, 1);
+        assert_eq!((page.assets[0].id, page.assets[0].is_for_sale), (1, true));
+        assert_eq!(page.assets[0].file_type, "glb");
+        assert_eq!(page.assets[0].prices, icp_prices(100));
+        assert_eq!(assets_for_sale_summary(0, 10, 0).assets.len(), 1);
+        assert_eq!(user_assets_summary(owner, stranger).len(), 1);
+        assert_eq!(user_assets_summary(owner, owner).len(), 2);
+        assert!(lookup_assets(&[2], owner).unwrap()[0].is_some());
+        assert!(lookup_summaries(&[2], owner).unwrap()[0].is_some());
+        assert!(lookup_assets(&vec![1; MAX_IDS_PER_LOOKUP + 1], owner).is_err());
+    }
+
+    #[test]
+    fn summaries_decode_older_schema_records() {
+        let owner = principal(1);
+        insert_listed_asset(1, owner);
+        let asset = asset_by_id(1).unwrap();
+
+        // Older layouts go through the full upgrade path
+        let mut legacy = vec![ASSET_SCHEMA_TAG, 1];
+        legacy.extend(candid::encode_one(AssetV1Response::from(asset.clone())).unwrap());
+        let summary = StoredAssetSummary::from_bytes(Cow::Owned(legacy));
+        assert_eq!((summary.id, summary.name.as_str(), summary.owner), (1, "Test asset", owner));
+        assert!(summary.is_listed_publicly());
+
+        let summary = StoredAssetSummary::from_bytes(asset.to_bytes());
+        assert_eq!((summary.id, summary.prices), (1, icp_prices(100)));
+    }
+
+    #[test]
+    fn filtered_sorts_page_through_every_match_in_order() {
+        let owner = principal(1);
+        for (asset_id, name, price, created_at) in
+            [(1, "delta", 300, 5), (2, "Alpha", 100, 9), (3, "charlie", 300, 1), (4, "bravo", 200, 9), (5, "echo", 50, 3)]
+        {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            asset.name = name.to_string();
+            asset.prices = icp_prices(price);
+            asset.created_at = created_at;
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+        let any_asset = || AssetFilter { metadata: None, ..metadata_filter(Vec::new()) };
+
+        let pages = |sort: SortBy| -> (Vec<u64>, u64) {
+            let mut asset_ids = Vec::new();
+            let mut total_count = 0;
+            for offset in [0, 2, 4, 6] {
+                let page = get_assets_filtered(any_asset(), sort, offset, 2);
+                total_count = page.total_count;
+                asset_ids.extend(page.assets.iter().map(|asset| asset.id));
+            }
+            (asset_ids, total_count)
+        };
+        assert_eq!(pages(SortBy::PriceAsc), (vec![5, 2, 4, 1, 3], 5));
+        assert_eq!(pages(SortBy::PriceDesc), (vec![1, 3, 4, 2, 5], 5));
+        assert_eq!(pages(SortBy::Newest), (vec![4, 2, 1, 5, 3], 5));
+        assert_eq!(pages(SortBy::NameAsc), (vec![2, 4, 3, 1, 5], 5));
+        assert_eq!(pages(SortBy::Oldest), (vec![1, 2, 3, 4, 5], 5));
+        assert_eq!(first_matches_by(&any_asset(), 0, |asset| asset.id), (Vec::new(), 5));
+    }
+
+    #[test]
+    fn paused_methods_reject_with_the_reason() {
+        assert!(check_pause("purchase_asset").is_ok());
+
+        set_pause("purchase_asset", Some(new_pause(Some(" exploit ".to_string()), 1).unwrap()));
+        let err = check_pause("purchase_asset").err().unwrap();
+        assert!(matches!(&err, AssetError::MethodPaused { reason: Some(reason) } if reason == "exploit"));
+        assert!(check_pause("upload_asset").is_ok());
+
+        set_pause(GLOBAL_PAUSE_KEY, Some(new_pause(None, 2).unwrap()));
+        assert!(matches!(check_pause("upload_asset"), Err(AssetError::MethodPaused { reason: None })));
+        let status = pause_status();
+        assert!(status.global.is_some());
+        assert_eq!(status.methods.len(), 1);
+
+        set_pause(GLOBAL_PAUSE_KEY, None);
+        set_pause("purchase_asset", None);
+        assert!(check_pause("purchase_asset").is_ok());
+        assert!(new_pause(Some("x".repeat(MAX_PAUSE_REASON_LENGTH + 1)), 3).is_err());
+    }
+
+    #[test]
+    fn price_changes_are_kept_and_summarized() {
+        let seller = principal(1);
+        insert_listed_asset(1, seller);
+        record_price_point(1, 100, seller, 0);
+
+        set_asset_price(1, seller, 100, 1).unwrap();
+        assert!(set_prices_bulk(vec![(1, 70)], seller, 2)[0].is_ok());
+        set_asset_price(1, seller, 130, 3).unwrap();
+        let prices: Vec<(u64, u64)> = price_history_of(1).iter().map(|point| (point.price, point.timestamp)).collect();
+        assert_eq!(prices, vec![(100, 0), (70, 2), (130, 3)]);
+        assert_eq!(
+            price_summary_of(1),
+            Some(PriceSummary { lowest: 70, highest: 130, previous: Some(70) })
+        );
+
+        for price in 0..MAX_PRICE_HISTORY as u64 {
+            set_asset_price(1, seller, 1_000 + price, 4 + price).unwrap();
+        }
+        let history = price_history_of(1);
+        assert_eq!(history.len(), MAX_PRICE_HISTORY);
+        assert_eq!(history[0].price, 1_000);
+        assert_eq!(price_summary_of(2), None);
+    }
+
+    #[test]
+    fn notifications_follow_asset_events() {
+        let (seller, buyer, fan) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        add_favorite(fan, &asset_by_id(1).unwrap(), 0);
+        add_favorite(buyer, &asset_by_id(2).unwrap(), 0);
+        ASSET_FAVORITE_INDEX.with(|index| index.borrow_mut().clear_new());
+        rebuild_asset_favorite_index();
+        assert_eq!(favoriters_of(1), vec![fan]);
+
+        set_asset_price(1, seller, 150, 1).unwrap();
+        set_asset_price(1, seller, 90, 2).unwrap();
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(90), 3);
+        change_owner(&mut asset, fan, OwnershipEventKind::Gift, None, 4);
+
+        let kinds = |principal: Principal| -> Vec<NotificationKind> {
+            notifications_of(principal, 0, 10, false)
+                .notifications
+                .iter()
+                .map(|notification| notification.kind)
+                .collect()
+        };
+        assert_eq!(kinds(seller), vec![NotificationKind::AssetSold]);
+        assert!(kinds(buyer).is_empty());
+        assert_eq!(
+            kinds(fan),
+            vec![NotificationKind::AssetTransferredToYou, NotificationKind::PriceDropOnFavorite]
+        );
+
+        let inbox = notifications_of(fan, 0, 10, false);
+        assert_eq!(inbox.notifications[1].amount, Some(90));
+        assert_eq!(mark_read(fan, &[inbox.notifications[1].id, 999]), 1);
+        assert_eq!(mark_read(seller, &[inbox.notifications[0].id]), 0);
+        let unread = notifications_of(fan, 0, 10, true);
+        assert_eq!((unread.total_count, unread.unread_count), (1, 1));
+
+        for time in 0..MAX_NOTIFICATIONS_PER_PRINCIPAL as u64 {
+            notify(seller, NotificationKind::OfferReceived, 1, Some(buyer), Some(time), 10 + time);
+        }
+        let inbox = notifications_of(seller, 0, 1, false);
+        assert_eq!(inbox.total_count, MAX_NOTIFICATIONS_PER_PRINCIPAL as u64);
+        let oldest = notifications_of(seller, MAX_NOTIFICATIONS_PER_PRINCIPAL as u64 - 1, 1, false);
+        assert_eq!(oldest.notifications[0].amount, Some(0));
+
+        remove_favorites(1);
+        assert!(favoriters_of(1).is_empty());
+        assert_eq!(FAVORITES.with(|favorites| favorites.borrow().keys().collect::<Vec<_>>()), vec![(buyer, 2)]);
+    }
+
+    #[test]
+    fn follows_are_indexed_both_ways() {
+        let (fan, creator, other) = (principal(1), principal(2), principal(3));
+        assert!(follow(fan, fan, 1).is_err());
+        assert_eq!(follow(fan, creator, 1).unwrap(), FollowCounts { followers: 1, following: 0 });
+        follow(fan, creator, 2).unwrap();
+        follow(other, creator, 2).unwrap();
+        assert_eq!(follower_count_of(creator), 2);
+        assert_eq!(follow_counts_of(fan), FollowCounts { followers: 0, following: 1 });
+
+        insert_listed_asset(1, creator);
+        insert_listed_asset(2, other);
+        insert_listed_asset(3, creator);
+        ASSETS.with(|assets| {
+            let mut asset = assets.borrow().get(&3).unwrap();
+            asset.created_at = 10;
+            assets.borrow_mut().insert(3, asset);
+        });
+        let feed: Vec<u64> = following_feed(fan, 0, 10).assets.iter().map(|asset| asset.id).collect();
+        assert_eq!(feed, vec![3, 1]);
+
+        unfollow(fan, creator);
+        unfollow(fan, creator);
+        assert_eq!(followers_of(creator), vec![other]);
+        assert!(following_of(fan).is_empty());
+        assert_eq!(follower_count_of(creator), 1);
+        assert_eq!(following_feed(fan, 0, 10).total_count, 0);
+    }
+
+    #[test]
+    fn bulk_updates_fail_per_item() {
+        let (seller, other) = (principal(1), principal(2));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, other);
+        insert_listed_asset(3, seller);
+
+        let results = set_prices_bulk(vec![(1, 80), (2, 80), (3, 0), (9, 80)], seller, 5);
+        assert_eq!(icp_price(results[0].as_ref().unwrap()), Some(80));
+        assert_eq!(results[0].as_ref().unwrap().updated_at, 5);
+        assert!(matches!(results[1], Err(AssetError::NotOwner)));
+        assert!(results[2].is_err());
+        assert!(matches!(results[3], Err(AssetError::NotFound)));
+        assert_eq!(icp_price(&asset_by_id(3).unwrap()), Some(100));
+
+        let results = list_assets_bulk(vec![1, 2, 3], seller, false, 6);
+        assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+        let for_sale: Vec<u64> = FOR_SALE_INDEX.with(|index| index.borrow().keys().collect());
+        assert_eq!(for_sale, vec![2]);
+
+        let results = set_prices_bulk(vec![(1, 80); MAX_BULK_UPDATE_SIZE + 1], seller, 7);
+        assert!(results.iter().all(|result| result.is_err()));
+        assert_eq!(asset_by_id(1).unwrap().updated_at, 6);
+    }
+
+    #[test]
+    fn archived_assets_leave_listings_until_restored() {
+        let (owner, stranger) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+
+        let asset = archive_owned_asset(1, owner, 5).unwrap();
+        assert_eq!((asset.archived_at, asset.is_for_sale), (Some(5), false));
+        assert!(matches!(archive_owned_asset(1, owner, 6), Err(AssetError::AssetArchived)));
+        assert!(FOR_SALE_INDEX.with(|index| !index.borrow().contains_key(&1)));
+        assert_eq!(paginate_assets(0, 10, is_listed_publicly).total_count, 0);
+        assert!(can_view(&asset, owner) && is_visible_to(&asset, owner));
+        assert!(!can_view(&asset, stranger) && !is_visible_to(&asset, stranger));
+
+        assert!(matches!(list_asset(1, owner, true, 7), Err(AssetError::AssetArchived)));
+        assert!(matches!(transfer_owned_asset(1, owner, stranger), Err(AssetError::AssetArchived)));
+        assert!(list_asset(1, owner, false, 7).is_ok());
+
+        let restored = unarchive_owned_asset(1, owner, 8).unwrap();
+        assert!(can_view(&restored, stranger) && !restored.is_for_sale);
+        assert!(unarchive_owned_asset(1, owner, 8).is_err());
+        assert!(list_asset(1, owner, true, 9).unwrap().is_for_sale);
+    }
+
+    #[test]
+    fn backups_round_trip_through_export_and_import() {
+        let owner = principal(1);
+        let file_data = b"glTF backup contents".to_vec();
+        let file_hash = sha256_hex(&file_data);
+        store_file(file_hash.clone(), "glb", file_data.clone(), owner, 0);
+        for asset_id in 1..=3 {
+            insert_listed_asset(asset_id, owner);
+        }
+        set_last_asset_id(5);
+        rebuild_file_refs();
+
+        let mut exported = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = export_asset_page(offset, 2);
+            assert_eq!((page.total_count, page.asset_id_counter), (3, 5));
+            if page.assets.is_empty() {
+                break;
+            }
+            offset += page.assets.len() as u64;
+            exported.push(page.assets);
+        }
+        let manifest = export_file_manifest_page(0, 10);
+        assert_eq!(manifest.total_count, 1);
+        let chunks: Vec<Vec<u8>> = (0..file_data.len() as u64)
+            .step_by(8)
+            .map(|offset| read_file_chunk(&file_hash, offset, 8).unwrap())
+            .collect();
+        let encoded = |assets: &[Asset]| -> Vec<Vec<u8>> {
+            assets.iter().map(|asset| candid::encode_one(asset).unwrap()).collect()
+        };
+        let before = encoded(&export_asset_page(0, 10).assets);
+        assert!(begin_restore(false).is_err());
+
+        ASSETS.with(|assets| assets.borrow_mut().clear_new());
+        set_last_asset_id(0);
+        FILES.with(|files| files.borrow_mut().clear_new());
+        FILE_PIECES.with(|pieces| pieces.borrow_mut().clear_new());
+        FILE_INFO.with(|file_info| file_info.borrow_mut().clear_new());
+        FILE_REFS.with(|refs| refs.borrow_mut().clear_new());
+        FILE_REF_ASSETS.with(|refs| refs.borrow_mut().clear_new());
+        STORAGE_USAGE.with(|usage| usage.borrow_mut().clear_new());
+        rebuild_indexes();
+
+        for assets in exported {
+            begin_restore(false).unwrap();
+            import_asset_batch(assets, 5);
+        }
+        let info = manifest.files[0].clone();
+        let mut received = 0;
+        for chunk in chunks {
+            assert!(import_file_bytes(info.clone(), received + 1, chunk.clone()).is_err());
+            if received > 0 {
+                assert!(complete_restore().is_err());
+            }
+            received = import_file_bytes(info.clone(), received, chunk).unwrap();
+        }
+        assert_eq!(complete_restore().unwrap().assets, 3);
+
+        assert_eq!(encoded(&export_asset_page(0, 10).assets), before);
+        assert_eq!(get_next_asset_id(), 6);
+        assert_eq!(owned_asset_ids(owner), vec![1, 2, 3]);
+        assert_eq!(read_file_chunk(&file_hash, 0, 1024), Some(file_data.clone()));
+        assert_eq!(storage_usage_of(owner).used, file_data.len() as u64);
+        assert!(begin_restore(false).is_err());
+
+        let corrupt = FileInfo { file_hash: sha256_hex(b"other"), ..info };
+        assert!(import_file_bytes(corrupt.clone(), 0, file_data).is_err());
+        assert!(file_info_of(&corrupt.file_hash).is_none());
+    }
+
+    #[test]
+    fn undecodable_assets_report_their_id() {
+        let mut bytes = vec![ASSET_SCHEMA_TAG, 2];
+        bytes.extend(candid::encode_one(StoredAssetId { id: 42 }).unwrap());
+
+        let err = decode_asset(&bytes).err().unwrap();
+        assert!(err.starts_with("Failed to decode stored asset 42"));
+
+        let err = decode_asset(&[ASSET_SCHEMA_TAG, 9]).err().unwrap();
+        assert_eq!(err, "Unsupported asset schema version 9");
+    }
+
+    #[test]
+    fn oversized_listings_resume_from_the_cursor() {
+        let owner = principal(1);
+        for asset_id in 1..=20 {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            asset.description = "x".repeat(100_000);
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+
+        let first = get_all_assets(None);
+        assert!(first.truncated);
+        assert!(first.assets.len() < 20);
+        let next_cursor = first.next_cursor.unwrap();
+        assert_eq!(next_cursor, first.assets.len() as u64 + 1);
+
+        let rest = listed_assets_matching(Some(next_cursor), RESPONSE_SIZE_BUDGET, |_| true);
+        assert!(!rest.truncated);
+        assert_eq!(rest.next_cursor, None);
+        assert_eq!(first.assets.len() + rest.assets.len(), 20);
+
+        let page = paginate_asset_ids(1..=20, 2, MAX_PAGE_SIZE);
+        assert_eq!(page.total_count, 20);
+        assert!(page.truncated);
+        assert_eq!(page.next_offset, Some(2 + page.assets.len() as u64));
+        assert_eq!(page.assets[0].id, 3);
+
+        let owned = user_assets(owner, owner, Some(15), RESPONSE_SIZE_BUDGET);
+        assert_eq!(owned.assets.iter().map(|asset| asset.id).collect::<Vec<_>>(), (15..=20).collect::<Vec<_>>());
+        assert!(!owned.truncated);
+    }
+
+    #[test]
+    fn cursors_resume_after_the_last_key() {
+        let owner = principal(1);
+        for asset_id in [1, 2, 3, 5] {
+            insert_listed_asset(asset_id, owner);
+        }
+
+        let first = assets_after(None, 2).unwrap();
+        assert_eq!(first.assets.iter().map(|asset| asset.id).collect::<Vec<_>>(), vec![1, 2]);
+        let cursor = first.next_cursor.unwrap();
+
+        // Inserting before the cursor neither shifts nor repeats later pages
+        insert_listed_asset(0, owner);
+        insert_listed_asset(4, owner);
+        let rest = assets_after(Some(cursor.clone()), 10).unwrap();
+        assert_eq!(rest.assets.iter().map(|asset| asset.id).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(rest.next_cursor, None);
+
+        let owned = user_assets_after(owner, owner, Some(cursor.clone()), 2).unwrap();
+        assert_eq!(owned.assets.iter().map(|asset| asset.id).collect::<Vec<_>>(), vec![3, 4]);
+        assert!(owned.next_cursor.is_some());
+
+        for sale in 0..3 {
+            record_sale(1, owner, principal(2), 100 + sale, None, None, sale);
+        }
+        let sales = sales_after(None, 2, |sale| sale.asset_id == 1).unwrap();
+        assert_eq!(sales.sales.len(), 2);
+        let sales = sales_after(sales.next_cursor, 2, |sale| sale.asset_id == 1).unwrap();
+        assert_eq!(sales.sales.iter().map(|sale| sale.price).collect::<Vec<_>>(), vec![102]);
+
+        assert!(matches!(sales_after(Some(cursor), 2, |_| true), Err(AssetError::InvalidCursor)));
+        assert!(matches!(assets_after(Some("zz".repeat(9)), 2), Err(AssetError::InvalidCursor)));
+        assert!(matches!(assets_after(Some("61".to_string()), 2), Err(AssetError::InvalidCursor)));
+        assert!(matches!(ownership_events_after(Some("é".repeat(9)), 2, |_| true), Err(AssetError::InvalidCursor)));
+    }
+
+    #[test]
+    fn clones_share_files_and_start_unlisted() {
+        let owner = principal(1);
+        insert_listed_asset(1, owner);
+        set_last_asset_id(1);
+        let refs = |file_hash: &str| FILE_REFS.with(|refs| refs.borrow().get(&file_hash.to_string()));
+        let before = refs("hash-1").unwrap_or(0);
+
+        let overrides = UpdateAssetRequest {
+            name: Some("Variant".to_string()),
+            description: None,
+            category_id: None,
+            tags: Some(vec!["red".to_string()]),
+            preview_image_url: None,
+        };
+        let clone = clone_owned_asset(1, overrides, owner, 50).unwrap();
+        let source = asset_by_id(1).unwrap();
+        assert_ne!(clone.id, 1);
+        assert_eq!(clone.name, "Variant");
+        assert_eq!(clone.tags, vec!["red".to_string()]);
+        assert_eq!((clone.file_hash.as_str(), clone.category.as_str()), (source.file_hash.as_str(), source.category.as_str()));
+        assert_eq!((clone.created_at, clone.visibility, clone.is_for_sale), (50, Visibility::Unlisted, false));
+        assert_eq!(refs("hash-1"), Some(before + 1));
+        assert!(!is_listed_publicly(&clone));
+
+        let events = get_asset_provenance(clone.id, 0, 10).events;
+        assert!(matches!(
+            events.as_slice(),
+            [OwnershipEvent { kind: OwnershipEventKind::DerivedFrom { source_asset_id: 1 }, from: None, .. }]
+        ));
+
+        let no_overrides = || UpdateAssetRequest {
+            name: None,
+            description: None,
+            category_id: None,
+            tags: None,
+            preview_image_url: None,
+        };
+        assert!(matches!(clone_owned_asset(1, no_overrides(), principal(2), 60), Err(AssetError::NotOwner)));
+        let invalid = UpdateAssetRequest { name: Some(String::new()), ..no_overrides() };
+        let next_id = last_asset_id();
+        assert!(clone_owned_asset(1, invalid, owner, 60).is_err());
+        assert_eq!(last_asset_id(), next_id);
+    }
+
+    #[test]
+    fn attachments_follow_the_parent_asset() {
+        let owner = principal(1);
+        let stranger = principal(2);
+        insert_listed_asset(1, owner);
+
+        let texture = b"texture pack".to_vec();
+        let texture_hash = sha256_hex(&texture);
+        let attachment = attach_file(1, owner, " Textures ", texture_hash.clone(), texture.clone(), "png", 5).unwrap();
+        assert_eq!((attachment.label.as_str(), attachment.file_size), ("Textures", texture.len() as u64));
+        assert_eq!(storage_usage_of(owner).used, texture.len() as u64);
+        assert!(matches!(
+            attach_file(1, owner, "Textures", texture_hash.clone(), texture.clone(), "png", 6),
+            Err(AssetError::AlreadyExists)
+        ));
+        assert!(matches!(
+            attach_file(1, stranger, "Mine", texture_hash.clone(), texture.clone(), "png", 6),
+            Err(AssetError::NotOwner)
+        ));
+        assert!(attach_file(1, owner, "Script", texture_hash.clone(), texture.clone(), "exe", 6).is_err());
+
+        // The asset is paid for, so only the owner may fetch its attachments
+        assert!(can_read_file(&texture_hash, owner));
+        assert!(!can_read_file(&texture_hash, stranger));
+
+        // A second attachment of the same bytes shares the stored file
+        attach_file(1, owner, "Textures copy", texture_hash.clone(), texture.clone(), "png", 7).unwrap();
+        assert_eq!(storage_usage_of(owner).used, texture.len() as u64);
+        let remaining = detach_file(1, owner, "Textures").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(is_file_stored(&texture_hash));
+
+        remove_asset_attachments(1);
+        assert!(attachments_of(1).is_empty());
+        assert!(!is_file_stored(&texture_hash));
+        assert_eq!(storage_usage_of(owner).used, 0);
+    }
+
+    #[test]
+    fn dutch_auctions_decline_then_hold_at_the_floor() {
+        let seller = principal(1);
+        let buyer = principal(2);
+        insert_listed_asset(1, seller);
+
+        assert!(open_dutch_auction(1, seller, 100, 100, 10, 0).is_err());
+        assert!(open_dutch_auction(1, buyer, 1_000, 400, 100, 0).is_err());
+        let auction = open_dutch_auction(1, seller, 1_000, 400, 100, 50).unwrap();
+        assert!(!asset_by_id(1).unwrap().is_for_sale);
+        assert!(is_transfer_in_progress(1));
+        assert_eq!(auction.price_at(50), 1_000);
+        assert_eq!(auction.price_at(100), 700);
+        assert_eq!(auction.price_at(149), 406);
+        assert_eq!(auction.price_at(150), 400);
+        assert_eq!(auction.price_at(10_000), 400);
+
+        assert!(reserve_dutch_purchase(1, seller, 60).is_err());
+        let (paid_to, price, _, _) = reserve_dutch_purchase(1, buyer, 75).unwrap();
+        assert_eq!((paid_to, price), (seller, 850));
+        assert!(matches!(reserve_dutch_purchase(1, principal(3), 80), Err(AssetError::TransferInProgress)));
+        assert!(matches!(close_dutch_auction(1, seller, 80), Err(AssetError::TransferInProgress)));
+
+        // The payment failed, so the auction stays open until the seller cancels
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+        assert!(matches!(close_dutch_auction(1, buyer, 90), Err(AssetError::NotOwner)));
+        let cancelled = close_dutch_auction(1, seller, 90).unwrap();
+        assert!(cancelled.status == AuctionStatus::Cancelled);
+        assert_eq!(cancelled.ended_at, Some(90));
+        assert!(matches!(reserve_dutch_purchase(1, buyer, 95), Err(AssetError::AuctionNotActive)));
+        assert!(!is_transfer_in_progress(1));
+    }
+
+    #[test]
+    fn deposits_are_credited_once() {
+        let buyer = principal(1);
+        assert_eq!(deposit_subaccount(buyer).len(), 32);
+        assert_ne!(deposit_subaccount(buyer), deposit_subaccount(principal(2)));
+
+        assert_eq!(credit_deposit(buyer, 500, 1).balance, 500);
+        // Seeing the same ledger balance again credits nothing
+        assert_eq!(credit_deposit(buyer, 500, 2).balance, 500);
+        assert_eq!(credit_deposit(buyer, 800, 3).balance, 800);
+
+        lock_deposit(buyer).unwrap();
+        assert!(matches!(lock_deposit(buyer), Err(AssetError::TransferInProgress)));
+        let deposit = debit_deposit(buyer, 130, 110, DepositEntryKind::Purchase { asset_id: 4 }, None, 4);
+        assert_eq!((deposit.balance, deposit.ledger_balance), (670, 690));
+        record_deposit_payout(buyer, 20);
+        unlock_deposit(buyer);
+
+        // The subaccount now holds exactly the unspent balance, so nothing new is credited
+        let deposit = credit_deposit(buyer, 670, 5);
+        assert_eq!((deposit.balance, deposit.ledger_balance, deposit.in_flight), (670, 670, false));
+
+        let kinds: Vec<(DepositEntryKind, u64, u64)> = DEPOSIT_LOG.with(|log| {
+            log.borrow()
+                .range((buyer, 0)..=(buyer, u64::MAX))
+                .map(|(_, entry)| (entry.kind, entry.credit, entry.debit))
+                .collect()
+        });
+        assert_eq!(
+            kinds,
+            vec![
+                (DepositEntryKind::Deposit, 500, 0),
+                (DepositEntryKind::Deposit, 300, 0),
+                (DepositEntryKind::Purchase { asset_id: 4 }, 0, 130),
+            ]
+        );
+    }
+
+    #[test]
+    fn takedowns_freeze_the_asset_until_reinstated() {
+        let owner = principal(1);
+        let moderator = principal(2);
+        let stranger = principal(3);
+        insert_listed_asset(1, owner);
+        MODERATORS.with(|moderators| moderators.borrow_mut().insert(moderator, 0));
+
+        let takedown = issue_takedown(1, TakedownReason::Copyright, "  DMCA notice  ", moderator, 10).unwrap();
+        assert_eq!(takedown.details, "DMCA notice");
+        assert!(issue_takedown(1, TakedownReason::Other, "again", moderator, 11).is_err());
+        let asset = asset_by_id(1).unwrap();
+        assert!(asset.hidden.is_some() && !asset.is_for_sale);
+        assert!(can_download(&asset, moderator));
+        assert!(matches!(transfer_owned_asset(1, owner, stranger), Err(AssetError::TakenDown)));
+
+        assert!(decide_appeal(1, true, "", moderator, 12).is_err());
+        assert!(matches!(appeal_takedown(1, "mine", stranger, 13), Err(AssetError::NotOwner)));
+        appeal_takedown(1, "I hold the licence", owner, 14).unwrap();
+        assert!(matches!(appeal_takedown(1, "again", owner, 15), Err(AssetError::AlreadyExists)));
+        assert_eq!(pending_appeals().len(), 1);
+
+        let takedown = decide_appeal(1, true, "licence confirmed", moderator, 16).unwrap();
+        assert!(takedown.resolution.is_some_and(|resolution| resolution.reinstated));
+        assert!(pending_appeals().is_empty());
+        assert!(!is_taken_down(1) && asset_by_id(1).unwrap().hidden.is_none());
+
+        let kinds: Vec<TakedownEventKind> = TAKEDOWN_EVENTS.with(|events| events.borrow().iter().map(|(_, event)| event.kind).collect());
+        assert_eq!(
+            kinds,
+            vec![
+                TakedownEventKind::TakenDown { reason_code: TakedownReason::Copyright },
+                TakedownEventKind::AppealFiled,
+                TakedownEventKind::Reinstated,
+            ]
+        );
+    }
+
+    #[test]
+    fn leaderboards_roll_over_daily_tallies() {
+        let (alice, bob, buyer) = (principal(1), principal(2), principal(3));
+        let now = 40 * NANOS_PER_DAY;
+        insert_listed_asset(1, alice);
+        insert_listed_asset(2, bob);
+
+        record_sale(2, bob, buyer, 1_000, None, None, now - 35 * NANOS_PER_DAY);
+        record_sale(1, alice, buyer, 100, None, None, now - 20 * NANOS_PER_DAY);
+        record_sale(2, bob, buyer, 300, None, None, now - 10 * NANOS_PER_DAY);
+        record_sale(1, alice, buyer, 150, None, None, now - NANOS_PER_DAY);
+        record_sale(1, alice, buyer, 100, None, None, now);
+
+        let ranking = |window| -> Vec<(Principal, u64, u64)> {
+            top_sellers(window, 10, now).into_iter().map(|top| (top.seller, top.volume, top.sale_count)).collect()
+        };
+        assert_eq!(ranking(TimeWindow::Last7Days), vec![(alice, 250, 2)]);
+        assert_eq!(ranking(TimeWindow::Last30Days), vec![(alice, 350, 3), (bob, 300, 1)]);
+        assert_eq!(ranking(TimeWindow::AllTime), vec![(bob, 1_300, 2), (alice, 350, 3)]);
+
+        // Rebuilding from the sale log lands on the same tallies
+        rebuild_sale_tallies(now);
+        assert_eq!(ranking(TimeWindow::Last30Days), vec![(alice, 350, 3), (bob, 300, 1)]);
+        let assets: Vec<u64> = top_selling_assets(TimeWindow::Last7Days, 10, now).iter().map(|top| top.asset.id).collect();
+        assert_eq!(assets, vec![1]);
+
+        // Only uploads inside the window qualify, and engagement outranks freshness
+        insert_listed_asset(3, alice);
+        for (asset_id, created_at) in [(1, now - 20 * NANOS_PER_DAY), (2, now - 2 * NANOS_PER_DAY), (3, now)] {
+            let mut asset = asset_by_id(asset_id).unwrap();
+            asset.created_at = created_at;
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+        FAVORITE_COUNTS.with(|counts| counts.borrow_mut().insert(2, 5));
+        let noteworthy: Vec<u64> = new_noteworthy(10, now).iter().map(|asset| asset.id).collect();
+        assert_eq!(noteworthy, vec![2, 3]);
+    }
+
+    #[test]
+    fn coupons_discount_within_scope_and_uses() {
+        let (seller, buyer) = (principal(1), principal(2));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        let code_hash = coupon_code_hash("discord20");
+
+        open_coupon(seller, CouponScope::Asset(1), &code_hash.to_uppercase(), 20, 1, Some(100), 10).unwrap();
+        assert!(matches!(open_coupon(seller, CouponScope::Asset(1), &code_hash, 10, 1, None, 10), Err(AssetError::AlreadyExists)));
+        assert!(matches!(open_coupon(buyer, CouponScope::Asset(1), &code_hash, 10, 1, None, 10), Err(AssetError::NotOwner)));
+        assert!(matches!(open_coupon(seller, CouponScope::AllMyAssets, "not-a-hash", 10, 1, None, 10), Err(AssetError::InvalidInput(_))));
+
+        assert!(matches!(reserve_purchase(2, buyer, Some("discord20"), 20), Err(AssetError::CouponNotApplicable)));
+        assert!(matches!(reserve_purchase(1, buyer, Some("other"), 20), Err(AssetError::CouponNotFound)));
+
+        let reserved = reserve_purchase(1, buyer, Some(" Discord20 "), 20).unwrap();
+        assert_eq!(reserved.price, 80);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+        // A failed payment gives the use back
+        release_coupon(reserved.coupon.as_deref());
+        reserve_purchase(1, buyer, Some("discord20"), 30).unwrap();
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+        assert!(matches!(reserve_purchase(1, buyer, Some("discord20"), 40), Err(AssetError::CouponUsedUp)));
+        assert_eq!(reserve_purchase(1, buyer, None, 40).unwrap().price, 100);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+
+        open_coupon(seller, CouponScope::AllMyAssets, &coupon_code_hash("everything"), 50, 5, Some(100), 50).unwrap();
+        assert_eq!(reserve_purchase(2, buyer, Some("everything"), 60).unwrap().price, 50);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&2));
+        assert!(matches!(reserve_purchase(2, buyer, Some("everything"), 100), Err(AssetError::CouponExpired)));
+        assert_eq!(coupons_of(seller).len(), 2);
+        assert!(coupons_of(buyer).is_empty());
+    }
+
+    #[test]
+    fn ranked_search_orders_by_match_then_popularity() {
+        let owner = principal(1);
+        let fixtures = [
+            (1, "Chair Collection", "", ""),
+            (2, "Oak chair", "", ""),
+            (3, "Table", "chair", ""),
+            (4, "Lamp", "", "Goes with any chair"),
+            (5, "Chair", "", ""),
+            (6, "Chair Deluxe", "", ""),
+            (7, "Sofa", "", ""),
+        ];
+        for (asset_id, name, tag, description) in fixtures {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            unindex_asset(&asset);
+            asset.name = name.to_string();
+            asset.description = description.to_string();
+            asset.tags = if tag.is_empty() { Vec::new() } else { vec![tag.to_string()] };
+            index_asset(&asset);
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+        FAVORITE_COUNTS.with(|counts| counts.borrow_mut().insert(6, 3));
+
+        let page = ranked_search(" CHAIR ", 0, 10, false);
+        let ids: Vec<u64> = page.results.iter().map(|result| result.asset.id).collect();
+        assert_eq!(ids, vec![5, 6, 1, 2, 3, 4]);
+        assert_eq!(page.total_count, 6);
+        assert!(page.results.iter().all(|result| result.score.is_none()));
+
+        let page = ranked_search("chair", 1, 2, true);
+        let scores: Vec<(u64, SearchScore)> = page.results.iter().map(|result| (result.asset.id, result.score.unwrap())).collect();
+        assert_eq!(
+            scores,
+            vec![
+                (6, SearchScore { match_kind: MatchKind::NamePrefix, popularity: 3 }),
+                (1, SearchScore { match_kind: MatchKind::NamePrefix, popularity: 0 }),
+            ]
+        );
+        assert!(ranked_search("  ", 0, 10, false).results.is_empty());
+    }
+
+    #[test]
+    fn text_search_intersects_token_postings() {
+        let owner = principal(1);
+        let fixtures = [
+            (1, "Oak Dining Chair", "", ""),
+            (2, "Office chair", "Ergonomic, adjustable", ""),
+            (3, "Chairman statue", "", ""),
+            (4, "Table", "", "oak-wood"),
+        ];
+        for (asset_id, name, description, tag) in fixtures {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            unindex_asset(&asset);
+            asset.name = name.to_string();
+            asset.description = description.to_string();
+            asset.tags = if tag.is_empty() { Vec::new() } else { vec![tag.to_string()] };
+            index_asset(&asset);
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+
+        let ids = |query: &str| text_search_ids(query).map(|ids| ids.into_iter().collect::<Vec<u64>>());
+        assert_eq!(ids("CHAIR"), Some(vec![1, 2, 3]));
+        assert_eq!(ids("oak ch"), Some(vec![1]));
+        assert_eq!(ids("office, ergo"), Some(vec![2]));
+        assert_eq!(ids("a"), Some(Vec::new()));
+        assert_eq!(ids("  "), None);
+
+        let resumed: Vec<u64> = listed_text_matches("chair", Some(2), usize::MAX).assets.iter().map(|asset| asset.id).collect();
+        assert_eq!(resumed, vec![2, 3]);
+
+        unindex_asset(&asset_by_id(3).unwrap());
+        assert_eq!(ids("chair"), Some(vec![1, 2]));
+        assert_eq!(rebuild_text_index(), 4);
+        assert_eq!(ids("chair"), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn similar_assets_weigh_tags_category_and_price() {
+        let (alice, bob) = (principal(1), principal(2));
+        let fixtures = [
+            (1, alice, vec!["Wood", "Chair"], UNCATEGORIZED_CATEGORY_ID, 100),
+            (2, bob, vec!["wood", "chair"], UNCATEGORIZED_CATEGORY_ID, 100),
+            (3, alice, vec!["wood"], UNCATEGORIZED_CATEGORY_ID, 1_000),
+            (4, bob, vec![], UNCATEGORIZED_CATEGORY_ID, 150),
+            (5, bob, vec!["chair"], UNCATEGORIZED_CATEGORY_ID, 100),
+            (6, bob, vec!["wood"], 99, 90),
+        ];
+        for (asset_id, owner, tags, category_id, price) in fixtures {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            unindex_asset(&asset);
+            asset.tags = tags.into_iter().map(|tag| tag.to_string()).collect();
+            asset.category_id = category_id;
+            set_icp_price(&mut asset, price);
+            if asset_id == 5 {
+                asset.hidden = Some(Moderation { reason: "spam".to_string(), hidden_by: alice, hidden_at: 0 });
+            }
+            index_asset(&asset);
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+        }
+
+        let ids = |asset_id: u64, exclude_same_owner: bool| -> Vec<u64> {
+            similar_assets(&asset_by_id(asset_id).unwrap(), 10, exclude_same_owner).iter().map(|asset| asset.id).collect()
+        };
+        assert_eq!(ids(1, false), vec![2, 3, 6, 4]);
+        assert_eq!(ids(1, true), vec![2, 6, 4]);
+        // Without tags only the category and price band count
+        assert_eq!(ids(4, false), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn earnings_come_from_recorded_splits() {
+        let (seller, creator, buyer) = (principal(1), principal(2), principal(3));
+        let now = 40 * NANOS_PER_DAY;
+        let royalty = Royalty { creator, royalty_bps: 500, amount: 50, paid: true };
+        let fee = |amount| PlatformFee { recipient: principal(9), fee_bps: 250, amount, paid: true };
+
+        record_sale(1, seller, buyer, 1_000, Some(royalty), Some(fee(25)), now - 20 * NANOS_PER_DAY);
+        record_sale(2, creator, buyer, 400, None, Some(fee(10)), now - NANOS_PER_DAY);
+        // Recorded before platform fees existed, so without a seller amount
+        let legacy = SaleRecord {
+            id: get_next_sale_id(),
+            asset_id: 3,
+            seller,
+            buyer,
+            price: 200,
+            timestamp: now,
+            royalty: None,
+            platform_fee: None,
+            seller_amount: None,
+            license: None,
+            seller_paid: None,
+            ledger: None,
+        };
+        SALES.with(|sales| sales.borrow_mut().insert(legacy.id, legacy));
+
+        let summary = earnings_of(seller, TimeWindow::AllTime, now);
+        assert_eq!(
+            (summary.gross_sales, summary.royalty_income, summary.platform_fees_paid, summary.net_proceeds, summary.sale_count),
+            (1_200, 0, 25, 1_125, 2)
+        );
+        let net: Vec<(u64, u64)> = summary.assets.iter().map(|asset| (asset.asset_id, asset.net_proceeds)).collect();
+        assert_eq!(net, vec![(1, 925), (3, 200)]);
+        assert_eq!(earnings_of(seller, TimeWindow::Last7Days, now).net_proceeds, 200);
+
+        let summary = earnings_of(creator, TimeWindow::AllTime, now);
+        assert_eq!(
+            (summary.gross_sales, summary.royalty_income, summary.platform_fees_paid, summary.net_proceeds, summary.sale_count),
+            (400, 50, 10, 440, 1)
+        );
+
+        let history = earnings_history(creator, 0, 10);
+        assert_eq!(history.total_count, 2);
+        let legs: Vec<(EarningsLegKind, u64)> = history.legs.iter().map(|leg| (leg.kind, leg.amount)).collect();
+        assert_eq!(legs, vec![(EarningsLegKind::Sale, 390), (EarningsLegKind::Royalty, 50)]);
+        assert_eq!(earnings_history(creator, 1, 1).legs[0].kind, EarningsLegKind::Royalty);
+    }
+
+    #[test]
+    fn upload_limits_apply_per_file_type_overrides() {
+        assert_eq!(get_upload_limits().max_file_size, DEFAULT_MAX_FILE_SIZE);
+        assert!(ensure_file_size("glb", DEFAULT_MAX_FILE_SIZE).is_ok());
+
+        CONFIG.with(|config| {
+            let mut config = config.borrow_mut();
+            config.insert("max_file_size".to_string(), "1000".to_string());
+            config.insert("file_type_size_limits".to_string(), "glb:5000,fbx:200".to_string());
+        });
+        let limits = get_upload_limits();
+        assert_eq!(limits.file_type_limits.len(), 2);
+        assert_eq!(limits.max_preview_image_size, DEFAULT_MAX_PREVIEW_IMAGE_SIZE);
+
+        // MIME types resolve to the same override as the extension
+        assert!(ensure_file_size("model/gltf-binary", 5000).is_ok());
+        assert!(matches!(ensure_file_size("glb", 5001), Err(AssetError::FileTooLarge { max: 5000 })));
+        assert!(matches!(ensure_file_size("fbx", 201), Err(AssetError::FileTooLarge { max: 200 })));
+        assert!(matches!(ensure_file_size("gltf", 1001), Err(AssetError::FileTooLarge { max: 1000 })));
+        assert!(ensure_file_size("", 1000).is_ok());
+    }
+
+    #[test]
+    fn asset_id_counter_migrates_from_the_legacy_map() {
+        LEGACY_ASSET_ID_COUNTER.with(|counter| counter.borrow_mut().insert(0, 41));
+        assert_eq!(get_next_asset_id(), 42);
+        assert!(LEGACY_ASSET_ID_COUNTER.with(|counter| counter.borrow().is_empty()));
+        assert_eq!(get_next_asset_id(), 43);
+
+        // A stale legacy value never moves the counter backwards
+        LEGACY_ASSET_ID_COUNTER.with(|counter| counter.borrow_mut().insert(0, 7));
+        assert_eq!(last_asset_id(), 43);
+    }
+
+    #[test]
+    fn reused_uploads_share_bytes_and_charge_a_fraction() {
+        let (original, reuser) = (principal(1), principal(2));
+        let file_data = b"glTF shared base mesh".to_vec();
+        let file_hash = sha256_hex(&file_data);
+        let used = |principal| storage_usage_of(principal).used;
+
+        assert!(!file_exists(file_hash.clone()));
+        assert_eq!(store_or_reuse_file(file_hash.clone(), "glb", file_data.clone(), original, 0), Ok(false));
+        assert!(file_exists(file_hash.clone()));
+        assert_eq!(used(original), file_data.len() as u64);
+
+        // Free by default, and free again for the original uploader
+        assert_eq!(store_or_reuse_file(file_hash.clone(), "glb", file_data.clone(), reuser, 0), Ok(true));
+        assert_eq!(used(reuser), 0);
+        CONFIG.with(|config| config.borrow_mut().insert("dedup_charge_percent".to_string(), "50".to_string()));
+        assert_eq!(store_or_reuse_file(file_hash.clone(), "glb", file_data.clone(), original, 0), Ok(true));
+        assert_eq!(used(original), file_data.len() as u64);
+
+        // Charged once however often the same bytes are reused
+        let half = file_data.len() as u64 / 2;
+        assert_eq!(store_or_reuse_file(file_hash.clone(), "glb", file_data.clone(), reuser, 0), Ok(true));
+        assert_eq!(store_or_reuse_file(file_hash.clone(), "glb", file_data.clone(), reuser, 0), Ok(true));
+        assert_eq!(used(reuser), half);
+        rebuild_storage_usage();
+        assert_eq!((used(original), used(reuser)), (file_data.len() as u64, half));
+
+        remove_file(&file_hash);
+        assert_eq!((used(original), used(reuser)), (0, 0));
+        assert!(DEDUP_CHARGES.with(|charges| charges.borrow().is_empty()));
+    }
+
+    #[test]
+    fn activity_feed_pages_by_sequence_and_hides_private_assets() {
+        let (owner, buyer) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        let mut private = asset_by_id(2).unwrap();
+        private.visibility = Visibility::Private;
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, private));
+
+        let mut asset = asset_by_id(1).unwrap();
+        record_price_change(&asset, Some(150), owner, 10);
+        set_icp_price(&mut asset, 150);
+        record_price_change(&asset, Some(150), owner, 11);
+        record_price_change(&asset, Some(100), owner, 11);
+        record_activity(2, Some(owner), ActivityKind::Unlisted, 12);
+        let mut transferred = asset.clone();
+        change_owner(&mut transferred, buyer, OwnershipEventKind::Transfer, None, 13);
+        record_activity(3, Some(owner), ActivityKind::Deleted, 14);
+        assert_eq!(get_latest_seq(), 6);
+
+        let page = activity_page(0, 3);
+        assert_eq!((page.oldest_seq, page.latest_seq, page.next_seq), (1, 6, 4));
+        // The private asset's event is skipped but still consumed
+        assert_eq!(page.events.iter().map(|event| event.seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(matches!(
+            page.events[0].kind,
+            ActivityKind::PriceChanged { old_price: Some(100), new_price: Some(150) }
+        ));
+
+        let page = activity_page(page.next_seq, 10);
+        assert_eq!(page.next_seq, 7);
+        assert!(matches!(
+            page.events.iter().map(|event| &event.kind).collect::<Vec<_>>()[..],
+            [ActivityKind::Transferred { .. }, ActivityKind::Unlisted, ActivityKind::Deleted]
+        ));
+        assert_eq!(page.events[1].actor, None);
+        assert_eq!(activity_page(7, 10).events.len(), 0);
+
+        // Reads before the retained range start at the oldest event
+        ACTIVITY_EVENTS.with(|events| events.borrow_mut().pop_first());
+        let page = activity_page(0, 1);
+        assert_eq!((page.oldest_seq, page.events[0].seq), (2, 2));
+    }
+
+    // Responses recorded from the v1 methods before the v2 surface existed.
+    // Deployed clients decode these, so the adapters must reproduce them
+    // byte for byte.
+    #[test]
+    fn v1_adapters_match_recorded_responses() {
+        let assert_fixture = |encoded: Vec<u8>, fixture: &str| assert_eq!(to_hex(&encoded), fixture.trim());
+        let (owner, other) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        let mut archived = asset_by_id(2).unwrap();
+        archived.archived_at = Some(5);
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, archived));
+
+        assert_fixture(
+            candid::encode_one(visible_asset(1, other).ok().map(AssetV1Response::from)).unwrap(),
+            include_str!("../fixtures/v1/get_asset.hex"),
+        );
+        assert_fixture(
+            candid::encode_one(visible_asset(9, other).ok().map(AssetV1Response::from)).unwrap(),
+            include_str!("../fixtures/v1/get_asset_missing.hex"),
+        );
+        assert!(matches!(visible_asset(9, other), Err(AssetError::NotFound)));
+
+        assert_fixture(
+            candid::encode_one(delete_owned_asset(9, owner).map_err(delete_error_v1)).unwrap(),
+            include_str!("../fixtures/v1/delete_asset_not_found.hex"),
+        );
+        assert_fixture(
+            candid::encode_one(delete_owned_asset(2, other).map_err(delete_error_v1)).unwrap(),
+            include_str!("../fixtures/v1/delete_asset_not_owner.hex"),
+        );
+        assert_fixture(
+            candid::encode_one(delete_owned_asset(1, owner).map_err(delete_error_v1)).unwrap(),
+            include_str!("../fixtures/v1/delete_asset_not_archived.hex"),
+        );
+        assert_eq!(delete_owned_asset(1, owner), Err(AssetError::NotArchived));
+
+        let anonymous: Result<AssetUploadV1Response, String> = Err(upload_error_v1(AssetError::AnonymousCaller));
+        assert_fixture(
+            candid::encode_one(anonymous).unwrap(),
+            include_str!("../fixtures/v1/upload_asset_with_file_anonymous.hex"),
+        );
+    }
+
+    #[test]
+    fn licenses_only_broaden_once_an_asset_has_sold() {
+        let (owner, buyer) = (principal(1), principal(2));
+        insert_listed_asset(1, owner);
+        let custom = || License::Custom { url: "https://example.com/license".to_string() };
+
+        assert!(License::PersonalUse.is_broadened_by(&License::CcBy));
+        assert!(custom().is_broadened_by(&License::Cc0));
+        assert!(!custom().is_broadened_by(&License::CommercialUse));
+        assert!(!License::CommercialUse.is_broadened_by(&custom()));
+        assert!(validate_license(License::Custom { url: "http://example.com".to_string() }).is_err());
+
+        // Anything goes before the first sale
+        assert_eq!(change_license(1, owner, License::CcBy, 1).unwrap().license, License::CcBy);
+        assert_eq!(change_license(1, owner, License::PersonalUse, 2).unwrap().license, License::PersonalUse);
+        assert!(matches!(change_license(1, buyer, License::Cc0, 3), Err(AssetError::NotOwner)));
+
+        let sale = record_sale(1, owner, buyer, 100, None, None, 4);
+        assert_eq!(sale.license, Some(License::PersonalUse));
+        assert_eq!(change_license(1, owner, License::CommercialUse, 5).unwrap().license, License::CommercialUse);
+        assert!(matches!(change_license(1, owner, License::PersonalUse, 6), Err(AssetError::InvalidInput(_))));
+        assert!(matches!(change_license(1, owner, custom(), 6), Err(AssetError::InvalidInput(_))));
+        assert_eq!(get_asset_sale_history(1, 0, 10).sales[0].license, Some(License::PersonalUse));
+
+        let mut filter = metadata_filter(Vec::new());
+        filter.license = Some(License::CommercialUse);
+        assert!(filter.matches(&asset_by_id(1).unwrap()));
+        filter.license = Some(custom());
+        assert!(!filter.matches(&asset_by_id(1).unwrap()));
+        assert_eq!(AssetSummary::from(asset_by_id(1).unwrap()).license, License::CommercialUse);
+    }
+
+    #[test]
+    fn slugs_dedupe_and_redirect_after_rename() {
+        assert_eq!(slugify("  Crystal Sword!! v2 "), "crystal-sword-v2");
+        assert_eq!(slugify("Café Ünder Ørb"), "cafe-under-orb");
+        assert_eq!(slugify("剑 ⚔"), "");
+        assert_eq!(slugify(&"a".repeat(100)).len(), MAX_SLUG_LENGTH);
+
+        let owner = principal(1);
+        let mut assigned = Vec::new();
+        for (asset_id, name) in [(1, "Crystal Sword"), (2, "crystal  sword"), (3, "剑"), (4, "1984")] {
+            insert_listed_asset(asset_id, owner);
+            let mut asset = asset_by_id(asset_id).unwrap();
+            asset.name = name.to_string();
+            assign_slug(&mut asset, 10);
+            ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset.clone()));
+            assigned.push(asset.slug.unwrap());
+        }
+        assert_eq!(assigned, ["crystal-sword", "crystal-sword-2", "asset-3", "asset-1984"]);
+        assert_eq!(resolve_slug("crystal-sword-2", 10), Some(2));
+
+        // A rename with the same base keeps the deduped slug
+        let mut asset = asset_by_id(2).unwrap();
+        asset.name = "Crystal sword".to_string();
+        assign_slug(&mut asset, 20);
+        assert_eq!(asset.slug.as_deref(), Some("crystal-sword-2"));
+
+        // A real rename leaves the old slug redirecting for the grace period
+        asset.name = "Ice Blade".to_string();
+        assign_slug(&mut asset, 20);
+        assert_eq!(asset.slug.as_deref(), Some("ice-blade"));
+        assert_eq!(resolve_slug("ice-blade", 20), Some(2));
+        assert_eq!(resolve_slug("crystal-sword-2", 20), Some(2));
+        let expiry = 20 + SLUG_REDIRECT_GRACE_NANOS;
+        assert_eq!(resolve_slug("crystal-sword-2", expiry), None);
+
+        // Expired redirects can be taken by other assets
+        let mut other = asset_by_id(3).unwrap();
+        other.name = "Crystal Sword".to_string();
+        assign_slug(&mut other, 20);
+        assert_eq!(other.slug.as_deref(), Some("crystal-sword-3"));
+        let mut late = asset_by_id(4).unwrap();
+        late.name = "Crystal Sword".to_string();
+        assign_slug(&mut late, expiry);
+        assert_eq!(late.slug.as_deref(), Some("crystal-sword-2"));
+
+        // Removing an asset frees its current slug
+        let first = asset_by_id(1).unwrap();
+        release_slug(&first);
+        assert_eq!(resolve_slug("crystal-sword", 20), None);
+    }
+
+    #[test]
+    fn maintenance_sweeps_expired_state_in_bounded_batches() {
+        let (owner, bidder) = (principal(1), principal(2));
+        let day = UPLOAD_SESSION_IDLE_TTL_NANOS;
+        let session_count = MAINTENANCE_BATCH_SIZE as u64 + 5;
+        UPLOAD_SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            for session_id in 1..=session_count {
+                let session = UploadSession {
+                    id: session_id,
+                    owner,
+                    file_hash: format!("hash-{}", session_id),
+                    file_type: "glb".to_string(),
+                    total_size: 10,
+                    received_size: 0,
+                    chunk_count: 0,
+                    created_at: 0,
+                    // The last session is still in use
+                    updated_at: if session_id == session_count { 3 * day } else { 0 },
+                };
+                sessions.insert(session_id, session);
+            }
+        });
+        UPLOAD_CHUNKS.with(|chunks| chunks.borrow_mut().insert((1, 0), vec![1, 2, 3]));
+
+        for (offer_id, expires_at) in [(1, Some(day)), (2, None)] {
+            let offer = Offer {
+                id: offer_id,
+                asset_id: 1,
+                bidder,
+                amount: 10,
+                expires_at,
+                created_at: 0,
+                updated_at: 0,
+                status: OfferStatus::Active,
+            };
+            OFFERS.with(|offers| offers.borrow_mut().insert(offer_id, offer));
+        }
+        let transfer = PendingTransfer { asset_id: 1, from: owner, to: bidder, created_at: 0, expires_at: Some(day) };
+        PENDING_TRANSFERS.with(|transfers| transfers.borrow_mut().insert(1, transfer));
+        let gift = Gift { asset_id: 2, sender: owner, message: String::new(), created_at: 0, expires_at: Some(3 * day) };
+        GIFTS.with(|gifts| gifts.borrow_mut().insert(2, gift));
+        let auction = Auction {
+            asset_id: 3,
+            seller: owner,
+            starting_price: 10,
+            reserve_price: None,
+            end_time: day,
+            high_bid: None,
+            bid_count: 0,
+            status: AuctionStatus::Active,
+            created_at: 0,
+            settled_at: None,
+        };
+        AUCTIONS.with(|auctions| auctions.borrow_mut().insert(3, auction));
+
+        let (report, ended) = sweep_expired_state(2 * day);
+        assert_eq!(
+            report,
+            MaintenanceReport {
+                upload_sessions_removed: MAINTENANCE_BATCH_SIZE as u64,
+                offers_expired: 1,
+                transfers_removed: 1,
+                gifts_removed: 0,
+                auctions_settled: 1,
+                files_split: 0,
+                images_hashed: 0,
+            }
+        );
+        assert_eq!(ended, vec![3]);
+        assert!(UPLOAD_CHUNKS.with(|chunks| chunks.borrow().is_empty()));
+        assert!(OFFERS.with(|offers| offers.borrow().get(&1).unwrap().status == OfferStatus::Expired));
+        assert!(OFFERS.with(|offers| offers.borrow().get(&2).unwrap().status == OfferStatus::Active));
+        assert!(pending_transfer_of(1).is_none());
+        assert!(gift_of(2).is_some());
+
+        // The next run picks up where the last one stopped
+        let (report, _) = sweep_expired_state(3 * day);
+        assert_eq!(report.upload_sessions_removed, 4);
+        assert_eq!(report.gifts_removed, 1);
+        assert_eq!(UPLOAD_SESSIONS.with(|sessions| sessions.borrow().len()), 1);
+        assert!(gift_of(2).is_none());
+    }
+
+    #[test]
+    fn ownership_checks_use_the_owner_and_purchase_indexes() {
+        let (owner, buyer, stranger) = (principal(1), principal(2), principal(3));
+        for asset_id in 1..=3 {
+            insert_listed_asset(asset_id, owner);
+        }
+        insert_listed_asset(4, buyer);
+
+        assert!(owns_asset(owner, 2));
+        assert!(!owns_asset(buyer, 2));
+        assert_eq!(owns_any_of(buyer, vec![1, 4, 9]).unwrap(), vec![false, true, false]);
+        assert!(owns_any_of(owner, vec![1; MAX_IDS_PER_LOOKUP + 1]).is_err());
+        assert_eq!(owned_asset_id_page(owner, 0, 10), vec![1, 2, 3]);
+        assert_eq!(owned_asset_id_page(owner, 1, 1), vec![2]);
+        assert!(owned_asset_id_page(stranger, 0, 10).is_empty());
+
+        // A past purchase keeps usage rights after the asset is resold
+        let asset = asset_by_id(1).unwrap();
+        assert!(!holds_usage_rights(&asset, buyer, 10));
+        record_sale(1, owner, buyer, 100, None, None, 10);
+        assert!(holds_usage_rights(&asset, buyer, 10));
+        assert!(!holds_usage_rights(&asset, stranger, 10));
+
+        PURCHASE_INDEX.with(|index| index.borrow_mut().clear_new());
+        rebuild_purchase_index();
+        assert!(has_bought(1, buyer));
+    }
+
+    #[test]
+    fn http_responses_are_certified_against_the_current_root() {
+        let owner = principal(1);
+        let preview = b"preview bytes".to_vec();
+        let preview_hash = sha256_hex(&preview);
+        store_file(preview_hash.clone(), "png", preview.clone(), owner, 0);
+        insert_listed_asset(7, owner);
+        let mut asset = asset_by_id(7).unwrap();
+        unindex_asset(&asset);
+        asset.preview_image_url = Some(format!("canister://{}", preview_hash));
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(7, asset.clone()));
+
+        let expected: Sha256Digest = Sha256::digest(&preview).into();
+        assert_eq!(certified_body_hash(&format!("/file/{}", preview_hash)), Some(expected));
+        assert_eq!(certified_body_hash("/asset/7/preview"), Some(expected));
+
+        CERTIFIED_HTTP.with(|certified| {
+            let certified = certified.borrow();
+            let root = labeled_hash(b"http_assets", &certified.tree.root_hash());
+            for path in [format!("/file/{}", preview_hash), "/asset/7/preview".to_string(), "/assets.json".to_string()] {
+                let witness = labeled(b"http_assets", certified.tree.witness(path.as_bytes()));
+                assert_eq!(witness.reconstruct(), root, "{}", path);
+            }
+        });
+        let header = certificate_header(b"certificate", "/asset/7/preview");
+        let tree = header.split("tree=:").nth(1).unwrap().trim_end_matches(':');
+        assert!(header.starts_with("certificate=:Y2VydGlmaWNhdGU=:, "));
+        assert!(BASE64.decode(tree).unwrap().starts_with(&[0xd9, 0xd9, 0xf7]));
+
+        let index = serve_asset_index(false);
+        assert_eq!(certified_body_hash("/assets.json"), Some(Sha256::digest(&index.body).into()));
+
+        // Gzip responses are checked against hashes known without decoding them
+        assert_eq!(gunzip(&serve_asset_index(true).body, MAX_COMPRESSED_FILE_SIZE).unwrap(), index.body);
+        assert_eq!(gzip_source_hash(&["assets.json"]), certified_body_hash("/assets.json"));
+        assert_eq!(gzip_source_hash(&["file", &preview_hash]), Some(expected));
+        assert_eq!(gzip_source_hash(&["asset", "7", "preview"]), Some(expected));
+        assert_eq!(gzip_source_hash(&["asset", "7.json"]), None);
+        let json: serde_json::Value = serde_json::from_slice(&index.body).unwrap();
+        assert_eq!(json["assets"][0]["id"], 7);
+        assert_eq!(json["assets"][0]["preview"], "/asset/7/preview");
+
+        // Unlisting the asset drops it from the index; deleting the file
+        // uncertifies both paths that served it
+        unindex_asset(&asset);
+        asset.visibility = Visibility::Unlisted;
+        index_asset(&asset);
+        let json: serde_json::Value = serde_json::from_slice(&serve_asset_index(false).body).unwrap();
+        assert!(json["assets"].as_array().unwrap().is_empty());
+        remove_file(&preview_hash);
+        rebuild_certification();
+        assert_eq!(certified_body_hash(&format!("/file/{}", preview_hash)), None);
+        assert_eq!(certified_body_hash("/asset/7/preview"), None);
+    }
+
+    #[test]
+    fn gzip_round_trips_within_the_output_bound() {
+        let gltf = br#"{"accessor":0,"componentType":5126,"type":"VEC3"},"#.repeat(3_000);
+        for input in [Vec::new(), b"a".to_vec(), vec![0u8; 100_000], gltf.clone()] {
+            let member = gzip(&input);
+            assert_eq!(gunzip(&member, input.len() as u64).unwrap(), input);
+        }
+        assert!(gzip(&gltf).len() < gltf.len() / 20);
+        assert_eq!(gzip(&gltf), gzip(&gltf));
+
+        // A member that decodes past the bound is refused, not inflated whole
+        let bomb = gzip(&vec![0u8; 1_000_000]);
+        assert!(gunzip(&bomb, 1_000).is_err());
+
+        let mut corrupt = gzip(&gltf);
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert!(gunzip(&corrupt, MAX_COMPRESSED_FILE_SIZE).is_err());
+        assert!(gunzip(&corrupt[..20], MAX_COMPRESSED_FILE_SIZE).is_err());
+    }
+
+    #[test]
+    fn text_files_are_stored_compressed() {
+        FILES.with(|files| files.borrow_mut().clear_new());
+        FILE_PIECES.with(|pieces| pieces.borrow_mut().clear_new());
+        FILE_INFO.with(|file_info| file_info.borrow_mut().clear_new());
+        let owner = Principal::from_slice(&[7; 29]);
+
+        let gltf = br#"{"asset":{"version":"2.0"},"nodes":[{"mesh":0}]}"#.repeat(500);
+        let gltf_hash = sha256_hex(&gltf);
+        store_file(gltf_hash.clone(), "gltf", gltf.clone(), owner, 0);
+        let info = file_info_of(&gltf_hash).unwrap();
+        assert_eq!(info.encoding, Some(ContentEncoding::Gzip));
+        assert_eq!(info.total_size, gltf.len() as u64);
+        assert!(read_stored_file(&gltf_hash).unwrap().len() < gltf.len() / 10);
+        assert_eq!(read_file_chunk(&gltf_hash, 10, 20), Some(gltf[10..30].to_vec()));
+        assert!(verify_file_integrity(gltf_hash.clone()).unwrap().matches);
+
+        // Binary formats are stored as they are
+        let glb = b"glTF".repeat(500);
+        let glb_hash = sha256_hex(&glb);
+        store_file(glb_hash.clone(), "glb", glb.clone(), owner, 0);
+        assert_eq!(file_info_of(&glb_hash).unwrap().encoding, None);
+        assert_eq!(read_stored_file(&glb_hash), Some(glb));
+
+        // Files stored before compression are compressed by the migration
+        let obj = b"v 0.0 1.0 0.0\n".repeat(400);
+        let obj_hash = sha256_hex(&obj);
+        FILES.with(|files| files.borrow_mut().insert(obj_hash.clone(), obj.clone()));
+        let legacy = FileInfo { file_hash: obj_hash.clone(), total_size: obj.len() as u64, encoding: None, ..info };
+        FILE_INFO.with(|file_info| file_info.borrow_mut().insert(obj_hash.clone(), FileInfo { content_type: "model/obj".to_string(), ..legacy }));
+
+        let report = recompress_file_batch();
+        assert_eq!((report.files_examined, report.files_compressed, report.done), (3, 1, true));
+        assert!(report.bytes_saved > obj.len() as u64 / 2);
+        assert_eq!(file_info_of(&obj_hash).unwrap().encoding, Some(ContentEncoding::Gzip));
+        assert_eq!(read_file_chunk(&obj_hash, 0, MAX_FILE_CHUNK_SIZE), Some(obj));
+        assert_eq!(recompress_file_batch().files_compressed, 0);
+    }
+
+    #[test]
+    fn accept_encoding_selects_gzip() {
+        let header = |value: &str| vec![("Accept-Encoding".to_string(), value.to_string())];
+        assert!(accepts_gzip(&header("gzip, deflate, br")));
+        assert!(accepts_gzip(&header("br;q=1.0, gzip;q=0.8")));
+        assert!(accepts_gzip(&header("*")));
+        assert!(!accepts_gzip(&header("gzip;q=0, br")));
+        assert!(!accepts_gzip(&header("identity")));
+        assert!(!accepts_gzip(&[]));
+    }
+
+    #[test]
+    fn reservations_hold_an_asset_for_their_holder() {
+        let (marketplace, seller, buyer, rival) = (principal(1), principal(2), principal(3), principal(4));
+        insert_listed_asset(1, seller);
+        authorize_marketplace(marketplace, 1);
+
+        assert!(matches!(place_reservation(1, seller, 10), Err(AssetError::InvalidInput(_))));
+        let (reservation, placed) = place_reservation(1, buyer, 10).unwrap();
+        assert!(placed);
+        assert_eq!(reservation.expires_at, 10 + RESERVATION_DURATION_NANOS);
+        assert_eq!(asset_by_id(1).unwrap().reservation, Some(reservation.clone()));
+        assert_eq!(asset_reservation(1, rival, 20), Some(reservation.clone()));
+
+        // Reserving again neither extends the hold nor lets anyone else in
+        assert_eq!(place_reservation(1, buyer, 20).unwrap(), (reservation.clone(), false));
+        let held = Some(AssetError::AssetReserved { expires_at: reservation.expires_at });
+        assert_eq!(place_reservation(1, rival, 20).err(), held);
+        assert!(matches!(reserve_purchase(1, rival, None, 20), Err(AssetError::AssetReserved { .. })));
+        assert_eq!(prepare_escrow(1, rival, 20).err(), held);
+        assert_eq!(transfer_for_marketplace(marketplace, 1, seller, rival, 100, 20).err(), held);
+        assert_eq!(clear_reservation(1, rival, 20), Err(AssetError::Unauthorized));
+
+        // Once lapsed the hold is ignored, and taking a new one replaces it
+        let later = reservation.expires_at;
+        assert_eq!(asset_reservation(1, rival, later), None);
+        assert!(prepare_escrow(1, rival, later).is_ok());
+        assert_eq!(place_reservation(1, rival, later).unwrap().0.holder, rival);
+        expire_reservation(1, later + 1);
+        assert_eq!(asset_by_id(1).unwrap().reservation.map(|held| held.holder), Some(rival));
+        assert_eq!(clear_reservation(1, rival, later + 1), Ok(()));
+        assert_eq!(clear_reservation(1, rival, later + 1), Err(AssetError::NoReservation));
+
+        // A sale to the holder completes and leaves no hold behind
+        let after_cooldown = later + RESERVATION_COOLDOWN_NANOS;
+        place_reservation(1, buyer, after_cooldown).unwrap();
+        let asset = transfer_for_marketplace(marketplace, 1, seller, buyer, 100, after_cooldown + 1).unwrap();
+        assert_eq!(asset.owner, buyer);
+        assert_eq!(asset_by_id(1).unwrap().reservation, None);
+    }
+
+    #[test]
+    fn external_catalogs_import_once_and_take_files_later() {
+        seed_categories(0);
+        let owner = principal(5);
+        let record = |external_id: &str, url: &str| ExternalAssetRecord {
+            external_id: external_id.to_string(),
+            name: "Old Car".to_string(),
+            description: "From the web store".to_string(),
+            category: " vehicles ".to_string(),
+            tags: vec!["car".to_string()],
+            price: 500,
+            original_created_at: 1_000,
+            owner,
+            external_url: url.to_string(),
+        };
+
+        let asset_id = import_external_asset(record("shop-1", "https://cdn.example.com/car.glb?v=2"), 5_000).unwrap();
+        let asset = asset_by_id(asset_id).unwrap();
+        assert_eq!(asset.source, Some(AssetSource::Imported { external_id: "shop-1".to_string() }));
+        assert_eq!((asset.created_at, asset.updated_at), (1_000, 1_000));
+        assert_eq!((asset.category.as_str(), asset.file_type.as_str()), ("Vehicles", "glb"));
+        assert_eq!(asset.file_url, "https://cdn.example.com/car.glb?v=2");
+        assert!(file_refs_of(&asset).is_empty());
+
+        // Re-running the manifest returns the same asset without a new id
+        assert_eq!(import_external_asset(record(" shop-1 ", "https://cdn.example.com/car.glb"), 6_000), Ok(asset_id));
+        assert_eq!(last_asset_id(), asset_id);
+        assert!(import_external_asset(record("shop-2", "http://cdn.example.com/car.glb"), 5_000).is_err());
+        assert!(import_external_asset(record("shop-2", "https://cdn.example.com/car"), 5_000).is_err());
+
+        // An id already taken behind the counter fails the record, not the batch
+        insert_listed_asset(asset_id + 1, owner);
+        let err = import_external_asset(record("shop-2", "https://cdn.example.com/bus.glb"), 5_000).unwrap_err();
+        assert!(err.to_string().contains("already in use"));
+        set_last_asset_id(asset_id + 1);
+        assert_eq!(import_external_asset(record("shop-2", "https://cdn.example.com/bus.glb"), 5_000), Ok(asset_id + 2));
+
+        let mut glb = b"glTF".to_vec();
+        glb.extend(2u32.to_le_bytes());
+        glb.extend(12u32.to_le_bytes());
+        assert!(matches!(attach_file_to_import(asset_id, b"not a model".to_vec(), 0), Err(AssetError::InvalidFileFormat { .. })));
+        assert!(attach_file_to_import(asset_id + 1, glb.clone(), 0).is_err());
+        let asset = attach_file_to_import(asset_id, glb.clone(), 0).unwrap();
+        assert_eq!(asset.file_url, format!("canister://{}", sha256_hex(&glb)));
+        assert_eq!((asset.file_size, asset.created_at), (12, 1_000));
+        assert_eq!(read_file_chunk(&asset.file_hash, 0, 100), Some(glb.clone()));
+        assert_eq!(attach_file_to_import(asset_id, glb, 0).err(), Some(AssetError::AlreadyExists));
+    }
+
+    #[test]
+    fn stats_counters_follow_mutations_and_recompute_repairs_drift() {
+        seed_categories(0);
+        let (seller, buyer, fan) = (principal(1), principal(2), principal(3));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        add_favorite(fan, &asset_by_id(1).unwrap(), 10);
+        add_favorite(fan, &asset_by_id(1).unwrap(), 11);
+
+        // Delisting and handing one asset over moves its counts between owners
+        let mut asset = asset_by_id(2).unwrap();
+        unindex_asset(&asset);
+        asset.is_for_sale = false;
+        asset.owner = buyer;
+        index_asset(&asset);
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, asset));
+        record_sale(2, seller, buyer, 100, None, None, 20);
+
+        assert_eq!(
+            owner_stats(seller),
+            OwnerStats { asset_count: 1, for_sale_count: 1, favorite_count: 1, sale_count: 1, sales_volume: 100 }
+        );
+        assert_eq!(owner_stats(buyer).asset_count, 1);
+        assert_eq!(owner_stats(fan), OwnerStats { asset_count: 0, for_sale_count: 0, favorite_count: 0, sale_count: 0, sales_volume: 0 });
+        let uncategorized = category_counts()
+            .into_iter()
+            .find(|(name, _, _)| name == UNCATEGORIZED_CATEGORY_NAME)
+            .unwrap();
+        assert_eq!((uncategorized.1, uncategorized.2), (2, 1));
+
+        let expected = MarketplaceStats {
+            asset_count: 2,
+            for_sale_count: 1,
+            favorite_count: 1,
+            owner_count: 2,
+            category_count: CATEGORIES.with(|categories| categories.borrow().len()),
+            sale_count: 1,
+            sales_volume: 100,
+        };
+        assert_eq!(marketplace_stats(), expected);
+
+        remove_favorite(fan, 1);
+        assert_eq!(owner_stats(seller).favorite_count, 0);
+        add_favorite(fan, &asset_by_id(1).unwrap(), 30);
+
+        // Counters knocked out of step are recounted from the records
+        OWNER_STATS.with(|stats| stats.borrow_mut().insert(fan, StatCounts { asset_count: 7, ..Default::default() }));
+        MARKETPLACE_SALES.with(|cell| cell.borrow_mut().set(SaleTally::default()).unwrap());
+        assert_eq!(rebuild_stats(), expected);
+        assert_eq!(owner_stats(fan).asset_count, 0);
+    }
+
+    #[test]
+    fn frozen_assets_stay_visible_but_refuse_changes() {
+        let (marketplace, seller, buyer, moderator) = (principal(1), principal(2), principal(3), principal(4));
+        insert_listed_asset(1, seller);
+        authorize_marketplace(marketplace, 1);
+
+        assert!(freeze_as_moderator(1, "  ", moderator, 10).is_err());
+        let asset = freeze_as_moderator(1, " counterfeit claim ", moderator, 10).unwrap();
+        assert_eq!(asset.frozen.as_ref().map(|freeze| freeze.reason.as_str()), Some("counterfeit claim"));
+        assert!(freeze_as_moderator(1, "again", moderator, 11).is_err());
+
+        // Still listed and readable, but nothing may touch it
+        assert!(asset_by_id(1).unwrap().is_for_sale && asset_by_id(1).unwrap().hidden.is_none());
+        let frozen = Some(AssetError::AssetFrozen);
+        assert_eq!(set_asset_price(1, seller, 50, 12).err(), frozen);
+        assert_eq!(list_asset(1, seller, false, 12).err(), frozen);
+        assert_eq!(archive_owned_asset(1, seller, 12).err(), frozen);
+        assert_eq!(transfer_owned_asset(1, seller, buyer).err(), frozen);
+        assert_eq!(transfer_for_marketplace(marketplace, 1, seller, buyer, 100, 12).err(), frozen);
+        assert_eq!(reserve_purchase(1, buyer, None, 12).err(), frozen);
+        assert_eq!(place_reservation(1, buyer, 12).err(), frozen);
+
+        assert!(lift_freeze(1, moderator, 20).unwrap().frozen.is_none());
+        assert!(lift_freeze(1, moderator, 21).is_err());
+        assert_eq!(icp_price(&set_asset_price(1, seller, 50, 22).unwrap()), Some(50));
+
+        let kinds: Vec<TakedownEventKind> = TAKEDOWN_EVENTS.with(|events| events.borrow().iter().map(|(_, event)| event.kind).collect());
+        assert_eq!(kinds, vec![TakedownEventKind::Frozen, TakedownEventKind::Unfrozen]);
+    }
+
+    #[test]
+    fn direct_uploads_reuse_matching_bytes_and_never_overwrite() {
+        seed_categories(0);
+        let (original, reuser) = (principal(1), principal(2));
+        let glb = |payload: &[u8]| {
+            let mut bytes = b"glTF".to_vec();
+            bytes.extend(2u32.to_le_bytes());
+            bytes.extend((12 + payload.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(payload);
+            bytes
+        };
+        let input = |file_hash: &str, file_size: usize| AssetInput {
+            name: "Chair".to_string(),
+            description: String::new(),
+            file_hash: file_hash.to_string(),
+            file_url: String::new(),
+            file_type: "glb".to_string(),
+            file_size: file_size as u64,
+            price: 0,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            tags: Vec::new(),
+            preview_image_url: None,
+            royalty_bps: None,
+            license: License::PersonalUse,
+        };
+
+        let chair = glb(b"chair");
+        let chair_hash = sha256_hex(&chair);
+        let upload = upload_file_as(chair_hash.clone(), chair.clone(), Some("glb".to_string()), original, 0).unwrap();
+        assert!(!upload.deduplicated);
+        assert!(matches!(
+            upload_file_as(sha256_hex(b"other"), chair.clone(), None, original, 0),
+            Err(AssetError::InvalidInput(_))
+        ));
+
+        // The same bytes under the same hash are shared
+        let upload = create_asset_with_file_as(input(&chair_hash, chair.len()), chair.clone(), reuser, 10).unwrap();
+        assert!(upload.deduplicated);
+        assert_eq!(upload.asset.file_hash, chair_hash);
+        assert_eq!(read_file_bytes(&chair_hash), Some(chair.clone()));
+
+        // A stored copy that differs from its key is kept and the upload refused
+        let table = glb(b"table");
+        let table_hash = sha256_hex(&table);
+        FILES.with(|files| files.borrow_mut().insert(table_hash.clone(), chair.clone()));
+        let last_id = last_asset_id();
+        assert!(matches!(
+            create_asset_with_file_as(input(&table_hash, table.len()), table.clone(), reuser, 20),
+            Err(AssetError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            upload_file_as(table_hash.clone(), table, Some("glb".to_string()), reuser, 0),
+            Err(AssetError::InvalidInput(_))
+        ));
+        assert_eq!(read_file_bytes(&table_hash), Some(chair));
+        assert_eq!(last_asset_id(), last_id);
+    }
+
+    #[test]
+    fn asset_documents_are_served_as_json_with_visibility_rules() {
+        let (owner, anonymous) = (principal(1), Principal::anonymous());
+        insert_listed_asset(1, owner);
+        insert_listed_asset(2, owner);
+        let mut private = asset_by_id(2).unwrap();
+        unindex_asset(&private);
+        private.visibility = Visibility::Private;
+        index_asset(&private);
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, private));
+
+        let response = serve_asset_document("1", &[], anonymous);
+        assert_eq!(response.status_code, 200);
+        assert!(response.headers.contains(&("Content-Type".to_string(), "application/json".to_string())));
+        assert_eq!(certified_body_hash("/asset/1.json"), Some(Sha256::digest(&response.body).into()));
+        let json: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(json["owner"], owner.to_text());
+        assert_eq!((json["price_e8s"].as_u64(), json["license"].as_str()), (Some(100), Some("PersonalUse")));
+        assert_eq!(json["file"], "/file/hash-1");
+
+        // A matching ETag revalidates without a body; any change moves the tag
+        let etag = asset_document_etag(&asset_by_id(1).unwrap());
+        let revalidate = [("If-None-Match".to_string(), etag.clone())];
+        assert_eq!(serve_asset_document("1", &revalidate, anonymous).status_code, 304);
+        set_asset_price(1, owner, 250, 99).unwrap();
+        assert_ne!(asset_document_etag(&asset_by_id(1).unwrap()), etag);
+        let response = serve_asset_document("1", &revalidate, anonymous);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(certified_body_hash("/asset/1.json"), Some(Sha256::digest(&response.body).into()));
+
+        // Private and missing assets are not found, except the owner's own
+        assert_eq!(serve_asset_document("2", &[], anonymous).status_code, 404);
+        assert_eq!(serve_asset_document("2", &[], owner).status_code, 200);
+        assert_eq!(serve_asset_document("9", &[], anonymous).status_code, 404);
+        assert_eq!(certified_body_hash("/asset/2.json"), None);
+
+        let page: serde_json::Value =
+            serde_json::from_slice(&serve_asset_documents("offset=0&limit=5", None, anonymous).body).unwrap();
+        assert_eq!((page["total_count"].as_u64(), page["assets"][0]["id"].as_u64()), (Some(1), Some(1)));
+        let mine: serde_json::Value =
+            serde_json::from_slice(&serve_asset_documents("", Some(owner), owner).body).unwrap();
+        assert_eq!(mine["assets"].as_array().unwrap().len(), 2);
+        assert_eq!(serve_asset_documents("limit=-1", None, anonymous).status_code, 400);
+    }
+
+    #[test]
+    fn price_alerts_fire_once_when_the_price_drops_to_target() {
+        let (seller, watcher, other, buyer) = (principal(1), principal(2), principal(3), principal(4));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        let mut private = asset_by_id(2).unwrap();
+        private.visibility = Visibility::Private;
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, private));
+
+        assert!(matches!(place_price_alert(2, 50, watcher, 0), Err(AssetError::NotFound)));
+        assert!(matches!(place_price_alert(1, 50, seller, 0), Err(AssetError::InvalidInput(_))));
+        assert!(matches!(place_price_alert(1, 100, watcher, 0), Err(AssetError::InvalidInput(_))));
+        assert!(matches!(place_price_alert(1, 50, Principal::anonymous(), 0), Err(AssetError::AnonymousCaller)));
+        place_price_alert(1, 50, watcher, 1).unwrap();
+        place_price_alert(1, 80, other, 1).unwrap();
+        assert_eq!(price_alerts_of(watcher).len(), 1);
+
+        set_asset_price(1, seller, 70, 2).unwrap();
+        let kinds = |principal: Principal| -> Vec<NotificationKind> {
+            notifications_of(principal, 0, 10, false)
+                .notifications
+                .iter()
+                .map(|notification| notification.kind)
+                .collect()
+        };
+        assert_eq!(kinds(other), vec![NotificationKind::PriceAlertTriggered]);
+        assert!(kinds(watcher).is_empty());
+        assert!(price_alerts_of(other).is_empty());
+
+        set_asset_price(1, seller, 40, 3).unwrap();
+        set_asset_price(1, seller, 30, 4).unwrap();
+        assert_eq!(kinds(watcher), vec![NotificationKind::PriceAlertTriggered]);
+        assert_eq!(notifications_of(watcher, 0, 1, false).notifications[0].amount, Some(40));
+
+        place_price_alert(1, 10, watcher, 5).unwrap();
+        insert_listed_asset(3, seller);
+        place_price_alert(3, 10, watcher, 5).unwrap();
+        ASSET_PRICE_ALERT_INDEX.with(|index| index.borrow_mut().clear_new());
+        rebuild_asset_price_alert_index();
+        assert_eq!(watchers_of(1), vec![watcher]);
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(30), 6);
+        // Only the alerts on the asset that changed hands go
+        assert_eq!(price_alerts_of(watcher).iter().map(|alert| alert.asset_id).collect::<Vec<_>>(), vec![3]);
+        assert!(watchers_of(1).is_empty());
+        assert_eq!(watchers_of(3), vec![watcher]);
+    }
+
+    #[test]
+    fn account_migration_moves_the_library_in_batches() {
+        let (old, new, other, buyer) = (principal(1), principal(2), principal(3), principal(4));
+        let count = MIGRATION_BATCH_SIZE as u64 + 2;
+        for asset_id in 1..=count {
+            insert_listed_asset(asset_id, old);
+        }
+        insert_listed_asset(100, other);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().insert(3, buyer));
+        freeze_as_moderator(4, "compliance", other, 0).unwrap();
+        PENDING_TRANSFERS.with(|transfers| {
+            transfers.borrow_mut().insert(
+                5,
+                PendingTransfer { asset_id: 5, from: old, to: other, created_at: 0, expires_at: None },
+            )
+        });
+        save_profile(old, "Alice", "", None, None, 0).unwrap();
+        add_favorite(old, &asset_by_id(100).unwrap(), 7);
+        notify(old, NotificationKind::OfferReceived, 1, Some(buyer), Some(10), 0);
+
+        assert!(matches!(migrate_account(old, old, None, false, 1), Err(AssetError::InvalidRecipient)));
+        let preview = migrate_account(old, new, None, true, 1).unwrap();
+        assert_eq!(preview.moved.len(), MIGRATION_BATCH_SIZE - 2);
+        assert_eq!(preview.skipped, vec![(3, AssetError::TransferInProgress), (4, AssetError::AssetFrozen)]);
+        assert_eq!((preview.remaining, preview.next_cursor, preview.done), (2, Some(MIGRATION_BATCH_SIZE as u64), false));
+        assert_eq!(asset_by_id(1).unwrap().owner, old);
+        let preview = migrate_account(old, new, preview.next_cursor, true, 1).unwrap();
+        assert!(preview.done && preview.profile_moved);
+        assert_eq!((preview.favorites_moved, preview.notifications_moved), (1, 1));
+
+        let first = migrate_account(old, new, None, false, 2).unwrap();
+        assert!(!first.done);
+        let last = migrate_account(old, new, first.next_cursor, false, 3).unwrap();
+        assert!(last.done && last.profile_moved);
+        assert_eq!(last.moved, vec![count - 1, count]);
+
+        assert_eq!(asset_by_id(1).unwrap().owner, new);
+        assert!(!asset_by_id(1).unwrap().is_for_sale);
+        assert_eq!(asset_by_id(3).unwrap().owner, old);
+        assert!(pending_transfer_of(5).is_none());
+        assert!(matches!(
+            get_asset_provenance(1, 0, 10).events.last().map(|event| &event.kind),
+            Some(OwnershipEventKind::Migration)
+        ));
+        assert_eq!(get_profile_by_name("alice".to_string()).map(|profile| profile.principal), Some(new));
+        assert!(profile_of(old).is_none());
+        assert_eq!(FAVORITES.with(|favorites| favorites.borrow().get(&(new, 100))), Some(7));
+        assert_eq!(notifications_of(new, 0, 10, false).total_count, 1);
+        assert_eq!(notifications_of(old, 0, 10, false).total_count, 0);
+        assert_eq!(owner_stats(new).asset_count, count - 2);
+    }
+
+    #[test]
+    fn featured_assets_keep_curated_order_and_lapse() {
+        let (seller, moderator, buyer) = (principal(1), principal(2), principal(3));
+        for asset_id in 1..=MAX_FEATURED_ASSETS as u64 + 3 {
+            insert_listed_asset(asset_id, seller);
+        }
+        let featured_ids = |current_time: u64| -> Vec<u64> {
+            featured_entries(current_time).iter().map(|entry| entry.asset_id).collect()
+        };
+
+        assert!(feature_as_moderator(999, None, None, moderator, 0).is_err());
+        assert!(feature_as_moderator(1, None, Some(5), moderator, 5).is_err());
+        feature_as_moderator(1, None, None, moderator, 0).unwrap();
+        feature_as_moderator(2, None, Some(100), moderator, 0).unwrap();
+        feature_as_moderator(3, Some(0), None, moderator, 0).unwrap();
+        assert_eq!(featured_ids(0), vec![3, 1, 2]);
+        // Featuring again moves the entry instead of adding a second one
+        feature_as_moderator(2, Some(0), Some(100), moderator, 1).unwrap();
+        assert_eq!(featured_ids(1), vec![2, 3, 1]);
+        assert_eq!(featured_ids(100), vec![3, 1]);
+
+        let mut private = asset_by_id(3).unwrap();
+        private.visibility = Visibility::Private;
+        ASSETS.with(|assets| assets.borrow_mut().insert(3, private));
+        assert!(feature_as_moderator(3, None, None, moderator, 1).is_err());
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(100), 2);
+        ASSETS.with(|assets| assets.borrow_mut().insert(1, asset));
+        assert_eq!(featured_ids(2), vec![2]);
+
+        assert!(unfeature(1, 2).is_err());
+        assert!(unfeature(2, 2).unwrap().is_empty());
+        for asset_id in 4..4 + MAX_FEATURED_ASSETS as u64 {
+            feature_as_moderator(asset_id, None, None, moderator, 3).unwrap();
+        }
+        let last = 3 + MAX_FEATURED_ASSETS as u64;
+        assert!(feature_as_moderator(2, None, None, moderator, 3).is_err());
+        feature_as_moderator(last, Some(0), None, moderator, 4).unwrap();
+        assert_eq!(featured_ids(4)[0], last);
+    }
+
+    #[test]
+    fn reverting_a_transfer_restores_the_previous_owner() {
+        let (seller, buyer, other, admin) = (principal(1), principal(2), principal(3), principal(4));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        record_ownership_event(1, None, seller, OwnershipEventKind::Upload, None, 0);
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(100), 10);
+        ASSETS.with(|assets| assets.borrow_mut().insert(1, asset));
+        let mut asset = asset_by_id(2).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Purchase, Some(100), 10);
+        change_owner(&mut asset, other, OwnershipEventKind::Transfer, None, 11);
+        ASSETS.with(|assets| assets.borrow_mut().insert(2, asset));
+        let event_ids = |asset_id: u64| -> Vec<u64> {
+            get_asset_provenance(asset_id, 0, 10).events.iter().map(|event| event.id).collect()
+        };
+        let (upload, sale) = (event_ids(1)[0], event_ids(1)[1]);
+
+        let err = |result: Result<Asset, String>| result.err().unwrap();
+        assert_eq!(err(revert_ownership_event(1, sale, " ", admin, 20)), "Revert reason cannot be empty");
+        assert_eq!(err(revert_ownership_event(2, sale, "bug", admin, 20)), "Provenance event not found for this asset");
+        assert_eq!(err(revert_ownership_event(1, upload, "bug", admin, 20)), "Only transfers and sales can be reverted");
+        assert_eq!(
+            err(revert_ownership_event(1, sale, "bug", admin, 11 + DEFAULT_DISPUTE_WINDOW_NANOS)),
+            "Dispute window for this event has closed"
+        );
+        assert_eq!(
+            err(revert_ownership_event(2, event_ids(2)[0], "bug", admin, 20)),
+            "Asset has changed hands since this event"
+        );
+
+        let asset = revert_ownership_event(1, sale, "marketplace bug", admin, 20).unwrap();
+        assert_eq!(asset.owner, seller);
+        assert_eq!(asset_by_id(1).unwrap().owner, seller);
+        let events = get_asset_provenance(1, 0, 10).events;
+        assert_eq!(events[1].reverted.as_ref().map(|reversal| reversal.reverted_by), Some(admin));
+        assert!(matches!(
+            events.last().map(|event| &event.kind),
+            Some(OwnershipEventKind::TransferReverted { event_id }) if *event_id == sale
+        ));
+        for party in [seller, buyer] {
+            let inbox = notifications_of(party, 0, 1, false);
+            assert_eq!(inbox.notifications[0].kind, NotificationKind::TransferReverted);
+        }
+        let audit = TAKEDOWN_EVENTS.with(|events| events.borrow().iter().last().map(|(_, event)| event)).unwrap();
+        assert_eq!(
+            (audit.kind, audit.actor, audit.note.as_str()),
+            (TakedownEventKind::TransferReverted { event_id: sale }, admin, "marketplace bug")
+        );
+        assert_eq!(err(revert_ownership_event(1, sale, "again", admin, 21)), "Event has already been reverted");
+
+        // Later events of other assets do not count as the asset moving
+        let mut asset = asset_by_id(1).unwrap();
+        change_owner(&mut asset, buyer, OwnershipEventKind::Transfer, None, 22);
+        ASSETS.with(|assets| assets.borrow_mut().insert(1, asset));
+        let transfer = *event_ids(1).last().unwrap();
+        record_ownership_event(2, Some(other), other, OwnershipEventKind::Upload, None, 23);
+        ASSET_OWNERSHIP_EVENT_INDEX.with(|index| index.borrow_mut().clear_new());
+        rebuild_asset_ownership_event_index();
+        assert_eq!(revert_ownership_event(1, transfer, "bug", admin, 24).unwrap().owner, seller);
+    }
+
+    #[test]
+    fn for_sale_pages_count_only_live_public_listings() {
+        let seller = principal(1);
+        for asset_id in 1..=30 {
+            insert_listed_asset(asset_id, seller);
+        }
+        // Still for sale, but no longer listed publicly
+        let mut unlisted = asset_by_id(4).unwrap();
+        unindex_asset(&unlisted);
+        unlisted.visibility = Visibility::Unlisted;
+        index_asset(&unlisted);
+        ASSETS.with(|assets| assets.borrow_mut().insert(4, unlisted));
+        assert!(FOR_SALE_INDEX.with(|index| index.borrow().contains_key(&4)));
+        assert!(PUBLIC_FOR_SALE_INDEX.with(|index| !index.borrow().contains_key(&4)));
+        LISTING_WINDOWS.with(|windows| {
+            windows.borrow_mut().insert(
+                7,
+                ListingWindow { asset_id: 7, starts_at: Some(100), expires_at: None, expired_at: None },
+            )
+        });
+
+        let ids = |page: &AssetPage| page.assets.iter().map(|asset| asset.id).collect::<Vec<u64>>();
+        let first = assets_for_sale(0, 5, 10);
+        assert_eq!((ids(&first), first.total_count), (vec![1, 2, 3, 5, 6], 28));
+        let second = assets_for_sale(5, 5, 10);
+        assert_eq!(ids(&second), vec![8, 9, 10, 11, 12]);
+        assert_eq!(assets_for_sale(0, 5, 100).total_count, 29);
+        assert!(assets_for_sale(40, 5, 10).assets.is_empty());
+        rebuild_indexes();
+        assert_eq!(assets_for_sale(0, 5, 10).total_count, 28);
+
+        let export = export_asset_page(28, 5);
+        let exported: Vec<u64> = export.assets.iter().map(|asset| asset.id).collect();
+        assert_eq!((exported, export.total_count), (vec![29, 30], 30));
+        assert!(export_asset_page(30, 5).assets.is_empty());
+    }
+
+    #[test]
+    fn prices_in_other_tokens_follow_the_ledger_allowlist() {
+        let (seller, buyer) = (principal(1), principal(2));
+        let (icp, ckbtc, unlisted) = (principal(90), principal(91), principal(92));
+        CONFIG.with(|config| config.borrow_mut().insert("ledger_canister_id".to_string(), icp.to_text()));
+        assert!(allow_ledger(icp, "ICP", 1).is_err());
+        assert!(allow_ledger(ckbtc, " ", 1).is_err());
+        allow_ledger(ckbtc, "ckBTC", 1).unwrap();
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+
+        let option = |ledger, amount| PriceOption { ledger, amount };
+        assert!(matches!(
+            set_asset_prices_as(1, seller, vec![option(unlisted, 5)], 2),
+            Err(AssetError::UnsupportedLedger)
+        ));
+        assert!(matches!(
+            set_asset_prices_as(1, seller, vec![option(ckbtc, 5), option(ckbtc, 6)], 2),
+            Err(AssetError::InvalidInput(_))
+        ));
+        assert!(matches!(set_asset_prices_as(1, buyer, vec![option(ckbtc, 5)], 2), Err(AssetError::NotOwner)));
+
+        let asset = set_asset_prices_as(1, seller, vec![option(icp, 120), option(ckbtc, 7)], 3).unwrap();
+        assert_eq!(asset.prices, vec![option(icp, 120), option(ckbtc, 7)]);
+        let asset = asset_by_id(1).unwrap();
+        assert_eq!((price_in(&asset, icp), price_in(&asset, ckbtc), price_in(&asset, unlisted)), (Some(120), Some(7), None));
+        assert_eq!(price_in(&asset_by_id(2).unwrap(), ckbtc), None);
+
+        // Only assets priced in the token match, compared and sorted by that price
+        let mut filter = metadata_filter(Vec::new());
+        filter.metadata = None;
+        filter.ledger = Some(ckbtc);
+        filter.max_price = Some(10);
+        assert!(filter.matches(&asset) && !filter.matches(&asset_by_id(2).unwrap()));
+        let page = get_assets_filtered(filter, SortBy::PriceAsc, 0, 10);
+        assert_eq!(page.assets.iter().map(|asset| asset.id).collect::<Vec<u64>>(), vec![1]);
+
+        assert!(matches!(
+            reserve_purchase_in(2, buyer, Some(ckbtc), None, 4),
+            Err(AssetError::NotPricedInLedger)
+        ));
+        assert!(matches!(
+            reserve_purchase_in(1, buyer, Some(ckbtc), Some("code"), 4),
+            Err(AssetError::CouponNotApplicable)
+        ));
+        assert_eq!(reserve_purchase_in(1, buyer, Some(ckbtc), None, 4).unwrap().price, 7);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+
+        // Sales in other tokens keep their ledger and are counted, but stay
+        // out of e8s volumes
+        change_license(1, seller, License::CommercialUse, 5).unwrap();
+        let mut sale = sale_record(1, seller, buyer, 7, None, None, 5);
+        assert_eq!(sale.ledger, Some(icp));
+        sale.ledger = Some(ckbtc);
+        let sale = store_sale(sale);
+        assert_eq!(SALES.with(|sales| sales.borrow().get(&sale.id)).unwrap().ledger, Some(ckbtc));
+        let stats = get_asset_sale_stats(1);
+        assert_eq!((stats.sale_count, stats.total_volume, stats.last_sold_price), (1, 0, None));
+        assert_eq!(top_sellers(TimeWindow::AllTime, 10, 5).iter().map(|top| (top.volume, top.sale_count)).collect::<Vec<_>>(), vec![(0, 1)]);
+        // A sale in any token locks the license against narrowing
+        assert!(matches!(
+            change_license(1, seller, License::PersonalUse, 5),
+            Err(AssetError::InvalidInput(_))
+        ));
+        record_sale(1, seller, buyer, 120, None, None, 6);
+        let stats = get_asset_sale_stats(1);
+        assert_eq!((stats.sale_count, stats.total_volume, stats.last_sold_price), (2, 120, Some(120)));
+
+        ASSET_SALE_INDEX.with(|index| index.borrow_mut().clear_new());
+        assert!(!has_sales(1));
+        rebuild_asset_sale_index();
+        assert!(has_sales(1));
+
+        // Removing the ledger stops purchases in it without dropping the price
+        SUPPORTED_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&ckbtc));
+        assert_eq!(price_in(&asset_by_id(1).unwrap(), ckbtc), None);
+        assert_eq!(asset_by_id(1).unwrap().prices.len(), 2);
+    }
+
+    #[test]
+    fn event_deliveries_retry_with_backoff_then_drop() {
+        let (admin, companion, other) = (principal(1), principal(90), principal(91));
+        assert!(subscribe(companion, Vec::new(), admin, 1).is_err());
+        assert!(subscribe(Principal::anonymous(), vec![EventKind::Sold], admin, 1).is_err());
+        let subscription = subscribe(companion, vec![EventKind::Sold, EventKind::Listed, EventKind::Sold], admin, 1).unwrap();
+        assert_eq!(subscription.event_kinds, vec![EventKind::Listed, EventKind::Sold]);
+        subscribe(other, vec![EventKind::Deleted], admin, 1).unwrap();
+
+        let event = |seq, kind| ActivityEvent { seq, asset_id: 1, actor: None, kind, timestamp: 10 };
+        let sold = event(1, ActivityKind::Sold { seller: admin, buyer: other, price: 100 });
+        assert!(queue_event_deliveries(&sold));
+        assert!(!queue_event_deliveries(&event(2, ActivityKind::Unlisted)));
+
+        // A failed send is retried later, twice as late each time
+        let mut sent = Vec::new();
+        let next = deliver_due_events(10, |canister, event| {
+            sent.push((canister, event.seq));
+            Err("queue full".to_string())
+        });
+        assert_eq!((sent, next), (vec![(companion, 1)], Some(10 + EVENT_RETRY_BASE_NANOS)));
+        assert_eq!(deliver_due_events(11, |_, _| Ok(())), Some(10 + EVENT_RETRY_BASE_NANOS));
+        let mut at = 10 + EVENT_RETRY_BASE_NANOS;
+        for attempt in 2..MAX_EVENT_DELIVERY_ATTEMPTS {
+            let next = deliver_due_events(at, |_, _| Err("queue full".to_string())).unwrap();
+            assert_eq!(next, at + (EVENT_RETRY_BASE_NANOS << (attempt - 1)));
+            at = next;
+        }
+        assert_eq!(deliver_due_events(at, |_, _| Err("queue full".to_string())), None);
+        let dropped = EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&companion)).unwrap();
+        assert_eq!(dropped.dropped_count, 1);
+        assert_eq!(
+            dropped.last_failure,
+            Some(DeliveryFailure {
+                event_seq: 1,
+                attempts: MAX_EVENT_DELIVERY_ATTEMPTS,
+                error: "queue full".to_string(),
+                failed_at: at,
+            })
+        );
+
+        queue_event_deliveries(&event(3, ActivityKind::Listed { price: Some(5) }));
+        queue_event_deliveries(&event(4, ActivityKind::Deleted));
+        let next = deliver_due_events(20, |canister, _| match canister == other {
+            true => Err("busy".to_string()),
+            false => Ok(()),
+        });
+        assert_eq!(next, Some(20 + EVENT_RETRY_BASE_NANOS));
+        let delivered = EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&companion)).unwrap();
+        assert_eq!(delivered.delivered_count, 1);
+
+        // Re-subscribing keeps the counts; unsubscribing drops queued deliveries
+        assert_eq!(subscribe(companion, vec![EventKind::Deleted], admin, 30).unwrap().delivered_count, 1);
+        unsubscribe(other).unwrap();
+        assert!(EVENT_OUTBOX.with(|outbox| outbox.borrow().is_empty()));
+        assert!(unsubscribe(other).is_err());
+    }
+
+    #[test]
+    fn collected_sales_queue_every_leg_until_paid() {
+        let (seller, buyer, creator, ledger) = (principal(1), principal(2), principal(3), principal(90));
+        insert_listed_asset(1, seller);
+        let royalty = Royalty { creator, royalty_bps: 1_000, amount: 100, paid: false };
+        let fee = PlatformFee { recipient: principal(9), fee_bps: 250, amount: 25, paid: false };
+        assert_eq!(payout_leg_count(&Some(royalty.clone()), &Some(fee.clone())), 3);
+        assert_eq!(payout_leg_count(&None, &None), 1);
+
+        let sale = store_sale(collected_sale(sale_record(1, seller, buyer, 1_000, Some(royalty), Some(fee), 5), ledger));
+        let payout_ids = queue_sale_payouts(&sale, ledger, PayoutSource::Settlement, 5);
+        let payouts: Vec<PendingPayout> =
+            PENDING_PAYOUTS.with(|payouts| payout_ids.iter().map(|id| payouts.borrow().get(id).unwrap()).collect());
+        let legs: Vec<(Principal, u64)> = payouts.iter().map(|payout| (payout.to, payout.amount)).collect();
+        assert_eq!(legs, vec![(seller, 875), (creator, 100), (principal(9), 25)]);
+        assert!(payouts.iter().all(|payout| payout.source == PayoutSource::Settlement && payout.ledger == ledger));
+
+        // A refused payout stays queued for the next retry
+        assert!(!record_payout_result(payout_ids[0], Err("TemporarilyUnavailable".to_string())));
+        let retried = PENDING_PAYOUTS.with(|payouts| payouts.borrow().get(&payout_ids[0])).unwrap();
+        assert_eq!((retried.attempts, retried.last_error.as_deref()), (1, Some("TemporarilyUnavailable")));
+        let stored = |id| SALES.with(|sales| sales.borrow().get(&id)).unwrap();
+        assert_eq!(stored(sale.id).seller_paid, Some(false));
+
+        for payout_id in &payout_ids {
+            assert!(record_payout_result(*payout_id, Ok(())));
+        }
+        let paid = stored(sale.id);
+        assert_eq!(paid.seller_paid, Some(true));
+        assert!(paid.royalty.unwrap().paid && paid.platform_fee.unwrap().paid);
+        assert!(PENDING_PAYOUTS.with(|payouts| payouts.borrow().is_empty()));
+        assert!(queue_sale_payouts(&stored(sale.id), ledger, PayoutSource::Settlement, 6).is_empty());
+    }
+
+    #[test]
+    fn rental_fees_are_paid_out_of_the_collected_payment() {
+        let (owner, renter, recipient, ledger) = (principal(1), principal(2), principal(9), principal(90));
+        insert_listed_asset(1, owner);
+        let listing = list_for_rent(1, owner, 10, 7, 0).unwrap();
+        let fee = PlatformFee { recipient, fee_bps: 1_000, amount: 3, paid: false };
+        assert_eq!(payout_leg_count(&None, &Some(fee.clone())), 2);
+
+        let rental = open_rental(&listing, renter, 3, 30, Some(fee), 100);
+        assert_eq!(rental.owner_paid, Some(false));
+        let payout_ids = queue_rental_payouts(&rental, ledger, 100);
+        let legs: Vec<(PayoutLeg, Principal, u64)> = PENDING_PAYOUTS.with(|payouts| {
+            let payouts = payouts.borrow();
+            payout_ids.iter().map(|id| payouts.get(id).unwrap()).map(|payout| (payout.leg, payout.to, payout.amount)).collect()
+        });
+        assert_eq!(
+            legs,
+            vec![
+                (PayoutLeg::RentalOwner { rental_id: rental.id }, owner, 27),
+                (PayoutLeg::RentalFee { rental_id: rental.id }, recipient, 3),
+            ]
+        );
+
+        assert!(!record_payout_result(payout_ids[1], Err("TemporarilyUnavailable".to_string())));
+        assert!(!rental_by_id(rental.id).unwrap().platform_fee.unwrap().paid);
+        for payout_id in &payout_ids {
+            assert!(record_payout_result(*payout_id, Ok(())));
+        }
+        let paid = rental_by_id(rental.id).unwrap();
+        assert_eq!(paid.owner_paid, Some(true));
+        assert!(paid.platform_fee.unwrap().paid);
+        assert_eq!(payout_subaccount(PayoutSource::Escrow { escrow_id: 4 }), escrow_subaccount(4));
+    }
+
+    #[test]
+    fn deposit_purchase_legs_stay_reserved_until_paid_out() {
+        let (seller, buyer, creator, ledger) = (principal(1), principal(2), principal(3), principal(90));
+        insert_listed_asset(1, seller);
+        save_deposit(buyer, DepositAccount { balance: 0, ledger_balance: 150, in_flight: false });
+        let royalty = Royalty { creator, royalty_bps: 1_000, amount: 10, paid: false };
+        let fee = PlatformFee { recipient: principal(9), fee_bps: 250, amount: 2, paid: false };
+        let sale = record_sale(1, seller, buyer, 100, Some(royalty), Some(fee), 5);
+
+        let source = PayoutSource::Deposit { buyer, ledger_fee: 1 };
+        let payout_ids = queue_sale_payouts(&sale, ledger, source, 5);
+        let legs: Vec<(Principal, u64)> = PENDING_PAYOUTS.with(|payouts| {
+            let payouts = payouts.borrow();
+            payout_ids.iter().map(|id| payouts.get(id).unwrap()).map(|payout| (payout.to, payout.amount)).collect()
+        });
+        assert_eq!(legs, vec![(creator, 10), (principal(9), 2)]);
+        assert_eq!(payout_subaccount(source), deposit_subaccount(buyer));
+
+        // A failed leg stays queued against the subaccount instead of being dropped
+        assert!(!record_payout_result(payout_ids[0], Err("InsufficientFunds".to_string())));
+        assert_eq!(deposit_of(buyer).ledger_balance, 150);
+        for payout_id in &payout_ids {
+            assert!(record_payout_result(*payout_id, Ok(())));
+        }
+        assert_eq!(deposit_of(buyer).ledger_balance, 150 - 11 - 3);
+        assert!(PENDING_PAYOUTS.with(|payouts| payouts.borrow().is_empty()));
+    }
+
+    #[test]
+    fn unfunded_offers_cannot_be_accepted() {
+        let (seller, bidder, ledger) = (principal(1), principal(2), principal(90));
+        insert_listed_asset(1, seller);
+        let offer = Offer {
+            id: 1,
+            asset_id: 1,
+            bidder,
+            amount: 80,
+            expires_at: None,
+            created_at: 0,
+            updated_at: 0,
+            status: OfferStatus::Active,
+        };
+        OFFERS.with(|offers| offers.borrow_mut().insert(offer.id, offer.clone()));
+        let competing = Offer { id: 2, amount: 70, ..offer.clone() };
+        OFFERS.with(|offers| offers.borrow_mut().insert(competing.id, competing));
+        insert_listed_asset(2, seller);
+        let elsewhere = Offer { id: 3, asset_id: 2, ..offer.clone() };
+        OFFERS.with(|offers| offers.borrow_mut().insert(elsewhere.id, elsewhere));
+        rebuild_asset_offer_index();
+        assert_eq!(offer_ids_of(1), vec![1, 2]);
+
+        assert!(matches!(reserve_offer_sale(1, bidder, 5), Err(AssetError::NotOwner)));
+        let (reserved, terms) = reserve_offer_sale(1, seller, 5).unwrap();
+        assert!(is_transfer_in_progress(1));
+        assert!(matches!(reserve_offer_sale(1, seller, 5), Err(AssetError::TransferInProgress)));
+
+        let refused = finish_offer_sale(reserved, terms, Err("InsufficientAllowance".to_string()), ledger, 6);
+        assert!(matches!(refused, Err(AssetError::PaymentFailed(_))));
+        assert_eq!(asset_by_id(1).unwrap().owner, seller);
+        assert!(OFFERS.with(|offers| offers.borrow().get(&1)).unwrap().status == OfferStatus::Active);
+        assert!(!is_transfer_in_progress(1) && !has_bought(1, bidder));
+        assert!(SALES.with(|sales| sales.borrow().is_empty()));
+
+        // Once the payment is held the bidder gets the asset and the seller's share is queued
+        let (reserved, terms) = reserve_offer_sale(1, seller, 7).unwrap();
+        let sale = finish_offer_sale(reserved, terms, Ok(()), ledger, 8).unwrap();
+        assert_eq!((sale.price, sale.seller_paid, sale.ledger), (80, Some(false), Some(ledger)));
+        assert_eq!(asset_by_id(1).unwrap().owner, bidder);
+        assert!(OFFERS.with(|offers| offers.borrow().get(&1)).unwrap().status == OfferStatus::Accepted);
+        // The competing offer on the asset is invalidated, the one on another asset is not
+        assert!(OFFERS.with(|offers| offers.borrow().get(&2)).unwrap().status == OfferStatus::Invalidated);
+        assert!(OFFERS.with(|offers| offers.borrow().get(&3)).unwrap().status == OfferStatus::Active);
+        assert_eq!(queue_sale_payouts(&sale, ledger, PayoutSource::Settlement, 8).len(), 1);
+    }
+
+    #[test]
+    fn stored_files_are_only_referenced_by_their_uploaders() {
+        seed_categories(0);
+        let (seller, copier) = (principal(1), principal(2));
+        let glb = |payload: &[u8]| {
+            let mut bytes = b"glTF".to_vec();
+            bytes.extend(2u32.to_le_bytes());
+            bytes.extend((12 + payload.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(payload);
+            bytes
+        };
+        let input = |file_hash: &str, preview_image_url: Option<String>| AssetInput {
+            name: "Statue".to_string(),
+            description: String::new(),
+            file_hash: file_hash.to_string(),
+            file_url: String::new(),
+            file_type: "glb".to_string(),
+            file_size: 1,
+            price: 0,
+            category_id: UNCATEGORIZED_CATEGORY_ID,
+            tags: Vec::new(),
+            preview_image_url,
+            royalty_bps: None,
+            license: License::PersonalUse,
+        };
+
+        let model = glb(b"statue");
+        let model_hash = sha256_hex(&model);
+        upload_file_as(model_hash.clone(), model.clone(), Some("glb".to_string()), seller, 0).unwrap();
+        let paid = create_asset(AssetInput { price: 500, ..input(&model_hash, None) }, seller, 1).unwrap();
+        assert_eq!(referencing_assets(&model_hash), vec![paid.id]);
+
+        // A free asset of the copier's own cannot open up the paid model
+        assert!(matches!(create_asset(input(&model_hash, None), copier, 2), Err(AssetError::InvalidInput(_))));
+        let preview = Some(format!("canister://{}", model_hash));
+        assert!(matches!(create_asset(input("elsewhere", preview), copier, 2), Err(AssetError::InvalidInput(_))));
+        assert!(!can_read_file(&model_hash, copier));
+
+        // Supplying the bytes proves the copier already has them
+        upload_file_as(model_hash.clone(), model, Some("glb".to_string()), copier, 0).unwrap();
+        let copy = create_asset(input(&model_hash, None), copier, 3).unwrap();
+        assert_eq!(referencing_assets(&model_hash), vec![paid.id, copy.id]);
+        assert!(can_read_file(&model_hash, principal(3)));
+    }
+
+    #[test]
+    fn files_are_stored_and_read_in_pieces() {
+        let owner = principal(1);
+        let file_data: Vec<u8> = (0..2 * FILE_PIECE_SIZE + 10).map(|byte| (byte % 251) as u8).collect();
+        let file_hash = sha256_hex(&file_data);
+        store_file(file_hash.clone(), "glb", file_data.clone(), owner, 0);
+        assert_eq!(FILE_PIECES.with(|pieces| pieces.borrow().len()), 3);
+        assert_eq!(stored_file_len(&file_hash), Some(file_data.len() as u64));
+
+        // A range across a piece boundary reads just the two pieces around it
+        let offset = FILE_PIECE_SIZE - 5;
+        assert_eq!(read_file_chunk(&file_hash, offset, 20), Some(file_data[offset as usize..offset as usize + 20].to_vec()));
+        assert_eq!(read_file_chunk(&file_hash, file_data.len() as u64, 20), Some(Vec::new()));
+        let (body, next) = file_chunk_for_http(&file_hash, 2, None);
+        assert_eq!(body, file_data[2 * MAX_FILE_CHUNK_SIZE as usize..].to_vec());
+        assert!(next.is_none());
+        assert_eq!(file_chunk_for_http(&file_hash, 1, None).1.map(|token| token.chunk_index), Some(2));
+
+        // Restores append piece by piece
+        let info = file_info_of(&file_hash).unwrap();
+        remove_stored_file(&file_hash);
+        let mut received = 0;
+        for chunk in file_data.chunks(1_000_000) {
+            received = import_file_bytes(info.clone(), received, chunk.to_vec()).unwrap();
+        }
+        assert_eq!(read_stored_file(&file_hash), Some(file_data.clone()));
+
+        // Files stored whole stay readable until maintenance splits them
+        let whole = b"stored before pieces".to_vec();
+        let whole_hash = sha256_hex(&whole);
+        FILES.with(|files| files.borrow_mut().insert(whole_hash.clone(), whole.clone()));
+        assert_eq!(read_file_chunk(&whole_hash, 7, 6), Some(b"before".to_vec()));
+        assert_eq!(stored_file_hashes(), {
+            let mut hashes = vec![file_hash, whole_hash.clone()];
+            hashes.sort();
+            hashes
+        });
+        assert_eq!(split_whole_files(), 1);
+        assert!(FILES.with(|files| files.borrow().is_empty()));
+        assert_eq!(read_stored_file(&whole_hash), Some(whole));
+    }
+
+    #[test]
+    fn assets_priced_without_icp_cannot_be_bought_in_icp() {
+        let (seller, buyer) = (principal(1), principal(2));
+        let (icp, ckbtc) = (principal(90), principal(91));
+        CONFIG.with(|config| config.borrow_mut().insert("ledger_canister_id".to_string(), icp.to_text()));
+        allow_ledger(ckbtc, "ckBTC", 1).unwrap();
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+
+        let option = |ledger, amount| PriceOption { ledger, amount };
+        assert!(matches!(set_asset_prices_as(1, seller, Vec::new(), 2), Err(AssetError::InvalidInput(_))));
+        let asset = set_asset_prices_as(1, seller, vec![option(ckbtc, 7)], 2).unwrap();
+        assert_eq!((price_in(&asset, icp), price_in(&asset, ckbtc)), (None, Some(7)));
+        assert_eq!(asset.prices, vec![option(ckbtc, 7)]);
+
+        // No e8s price is published anywhere and the asset is not free
+        let v1 = AssetV1Response::from(asset.clone());
+        assert_eq!((v1.price, v1.is_for_sale), (0, false));
+        assert_eq!(asset_document(&asset).price_e8s, None);
+        let metadata = icrc7_token_metadata(vec![Nat::from(1u64)]).remove(0).unwrap();
+        assert!(metadata.iter().all(|(key, _)| key != "price"));
+        assert!(!can_download(&asset, principal(3)));
+        // It stays listable, being buyable in ckBTC
+        list_asset(1, seller, false, 2).unwrap();
+        list_asset(1, seller, true, 2).unwrap();
+        assert!(matches!(reserve_purchase(1, buyer, None, 3), Err(AssetError::NotPricedInLedger)));
+        assert!(matches!(prepare_escrow(1, buyer, 3), Err(AssetError::NotPricedInLedger)));
+        assert_eq!(reserve_purchase_in(1, buyer, Some(ckbtc), None, 3).unwrap().price, 7);
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+
+        // ICP price bounds and sorts leave it out; unbounded filters still list it
+        let mut filter = metadata_filter(Vec::new());
+        filter.metadata = None;
+        assert!(filter.matches(&asset));
+        filter.max_price = Some(1_000);
+        assert!(!filter.matches(&asset) && filter.matches(&asset_by_id(2).unwrap()));
+        filter.max_price = None;
+        let page = get_assets_filtered(filter, SortBy::PriceAsc, 0, 10);
+        assert_eq!(page.assets.iter().map(|asset| asset.id).collect::<Vec<u64>>(), vec![2, 1]);
+
+        // Setting an ICP price makes it buyable in ICP again
+        let asset = set_asset_price(1, seller, 90, 4).unwrap();
+        assert_eq!(asset.prices, vec![option(icp, 90), option(ckbtc, 7)]);
+        let asset = set_asset_prices_as(1, seller, vec![option(icp, 80), option(ckbtc, 7)], 5).unwrap();
+        assert_eq!((icp_price(&asset), reserve_purchase(1, buyer, None, 6).unwrap().price), (Some(80), 80));
+        PENDING_PURCHASES.with(|pending| pending.borrow_mut().remove(&1));
+
+        // A new ICP ledger takes over the ICP prices
+        let new_icp = principal(92);
+        apply_init_args(Some(InitArgs { ledger_canister_id: Some(new_icp), default_storage_quota: None, max_price_e8s: None }));
+        assert_eq!(asset_by_id(1).unwrap().prices, vec![option(new_icp, 80), option(ckbtc, 7)]);
+    }
+
+    #[test]
+    fn version_6_records_decode_their_icp_price_as_a_price_option() {
+        let (owner, icp, ckbtc) = (principal(1), principal(90), principal(91));
+        CONFIG.with(|config| config.borrow_mut().insert("ledger_canister_id".to_string(), icp.to_text()));
+        insert_listed_asset(1, owner);
+        let asset = asset_by_id(1).unwrap();
+        let stored = |price: u64, prices: Option<Vec<PriceOption>>, sold_in_icp: Option<bool>| {
+            let legacy = AssetV6 {
+                id: asset.id,
+                name: asset.name.clone(),
+                description: asset.description.clone(),
+                owner,
+                file_hash: asset.file_hash.clone(),
+                file_url: asset.file_url.clone(),
+                file_type: asset.file_type.clone(),
+                file_size: asset.file_size,
+                price,
+                is_for_sale: true,
+                created_at: 0,
+                updated_at: 0,
+                category_id: asset.category_id,
+                category: asset.category.clone(),
+                tags: Vec::new(),
+                preview_image_url: None,
+                creator: owner,
+                royalty_bps: 0,
+                hidden: None,
+                visibility: Visibility::Public,
+                metadata: Vec::new(),
+                archived_at: None,
+                license: License::CommercialUse,
+                slug: None,
+                reservation: None,
+                source: None,
+                frozen: None,
+                prices,
+                sold_in_icp,
+            };
+            let mut bytes = vec![ASSET_SCHEMA_TAG, 6];
+            bytes.extend(candid::encode_one(legacy).unwrap());
+            decode_asset(&bytes).unwrap()
+        };
+        let option = |ledger, amount| PriceOption { ledger, amount };
+
+        assert_eq!(stored(100, None, None).prices, vec![option(icp, 100)]);
+        assert_eq!(stored(100, Some(vec![option(ckbtc, 7)]), None).prices, vec![option(icp, 100), option(ckbtc, 7)]);
+        assert_eq!(stored(100, Some(vec![option(ckbtc, 7)]), Some(false)).prices, vec![option(ckbtc, 7)]);
+    }
+
+    #[test]
+    fn reservations_are_capped_per_holder_and_cool_down() {
+        let (marketplace, seller, buyer) = (principal(1), principal(2), principal(3));
+        authorize_marketplace(marketplace, 1);
+        for asset_id in 1..=5 {
+            insert_listed_asset(asset_id, seller);
+        }
+
+        for asset_id in 1..=3 {
+            place_reservation(asset_id, buyer, 10).unwrap();
+        }
+        assert_eq!(place_reservation(4, buyer, 10).err(), Some(AssetError::TooManyReservations));
+        // Marketplaces hold for many buyers and are not capped
+        for asset_id in 4..=5 {
+            place_reservation(asset_id, marketplace, 10).unwrap();
+        }
+        clear_reservation(4, marketplace, 11).unwrap();
+
+        // Releasing frees a slot, but the same asset stays off limits until
+        // the cooldown has passed
+        clear_reservation(1, buyer, 20).unwrap();
+        let cooling = place_reservation(1, buyer, 30).err();
+        let retry_after_seconds = retry_after_seconds(20 + RESERVATION_COOLDOWN_NANOS, 30);
+        assert_eq!(cooling, Some(AssetError::RateLimited { retry_after_seconds }));
+        place_reservation(4, buyer, 30).unwrap();
+
+        // Lapsed holds stop counting and are forgotten after their cooldown
+        let lapsed = 10 + RESERVATION_DURATION_NANOS;
+        assert_eq!(place_reservation(5, buyer, lapsed).unwrap().0.holder, buyer);
+        let forgotten = lapsed + RESERVATION_COOLDOWN_NANOS;
+        assert_eq!(place_reservation(2, buyer, forgotten).unwrap().0.holder, buyer);
+        let held: Vec<u64> = RESERVATION_HOLDS.with(|holds| holds.borrow().keys().map(|(_, asset_id)| asset_id).collect());
+        assert_eq!(held, vec![2, 4, 5]);
+
+        // Buying the held asset ends the hold at once
+        transfer_for_marketplace(marketplace, 2, seller, buyer, 100, forgotten + 1).unwrap();
+        assert_eq!(RESERVATION_HOLDS.with(|holds| holds.borrow().get(&(buyer, 2))), Some(forgotten + 1));
+
+        let rates = (0..5).map(|n| check_rate_limit(buyer, RateLimitedAction::Reservation, n)).collect::<Vec<_>>();
+        assert!(rates.iter().all(Result::is_ok));
+        assert!(matches!(
+            check_rate_limit(buyer, RateLimitedAction::Reservation, 5),
+            Err(AssetError::RateLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn pruning_spares_recently_uploaded_orphans() {
+        let owner = principal(1);
+        let (old, recent, legacy) = (b"old orphan".to_vec(), b"recent orphan".to_vec(), b"legacy orphan".to_vec());
+        store_file(sha256_hex(&old), "glb", old.clone(), owner, 0);
+        store_file(sha256_hex(&recent), "glb", recent.clone(), owner, ORPHAN_GRACE_NANOS);
+        write_stored_file(&sha256_hex(&legacy), &legacy);
+
+        let report = prune_orphans(ORPHAN_GRACE_NANOS + 1);
+        assert_eq!(report.files_removed, 2);
+        assert!(is_file_stored(&sha256_hex(&recent)));
+        assert!(!is_file_stored(&sha256_hex(&old)) && !is_file_stored(&sha256_hex(&legacy)));
+
+        // Uploading the same bytes again restarts the grace period
+        let later = 2 * ORPHAN_GRACE_NANOS;
+        assert_eq!(store_or_reuse_file(sha256_hex(&recent), "glb", recent.clone(), principal(2), later), Ok(true));
+        assert_eq!(prune_orphans(later + 1).files_removed, 0);
+        assert_eq!(prune_orphans(later + ORPHAN_GRACE_NANOS).files_removed, 1);
+    }
+
+    #[test]
+    fn owner_edits_recertify_the_asset_document() {
+        let (owner, anonymous) = (principal(1), Principal::anonymous());
+        insert_listed_asset(1, owner);
+        let certified_matches_served = || {
+            let response = serve_asset_document("1", &[], anonymous);
+            certified_body_hash("/asset/1.json") == Some(Sha256::digest(&response.body).into())
+        };
+
+        set_metadata_field(1, owner, "polygons", MetadataValue::Nat(12_000), 2).unwrap();
+        assert!(certified_matches_served());
+        remove_metadata_field(1, owner, "polygons", 3).unwrap();
+        assert!(certified_matches_served());
+        change_license(1, owner, License::CommercialUse, 4).unwrap();
+        assert!(certified_matches_served());
+
+        // Archived documents are owner-only, so nothing stays certified
+        archive_owned_asset(1, owner, 5).unwrap();
+        assert_eq!(certified_body_hash("/asset/1.json"), None);
+        assert!(!FOR_SALE_INDEX.with(|index| index.borrow().contains_key(&1)));
+        unarchive_owned_asset(1, owner, 6).unwrap();
+        assert!(certified_matches_served());
+        assert_eq!(asset_by_id(1).unwrap().updated_at, 6);
+    }
+
+    #[test]
+    fn gallery_images_are_certified_from_stored_digests() {
+        let owner = principal(1);
+        insert_listed_asset(1, owner);
+        let image = |bytes: &[u8]| PreviewImage {
+            content_type: "image/png".to_string(),
+            bytes: bytes.to_vec(),
+            uploaded_by: owner,
+            uploaded_at: 0,
+        };
+        let first: Sha256Digest = Sha256::digest(b"first").into();
+        let legacy: Sha256Digest = Sha256::digest(b"legacy").into();
+        ASSET_IMAGES.with(|images| {
+            images.borrow_mut().insert(1, image(b"first"));
+            images.borrow_mut().insert(2, image(b"legacy"));
+        });
+        ASSET_IMAGE_DIGESTS.with(|digests| digests.borrow_mut().insert(1, first));
+        save_gallery(1, AssetGallery { image_ids: vec![1, 2] });
+
+        // Only the recorded digest is certified; the legacy image waits for
+        // the backfill
+        rebuild_certification();
+        assert_eq!(certified_body_hash("/asset/1/image/1"), Some(first));
+        assert_eq!(certified_body_hash("/asset/1/image/2"), None);
+        assert_eq!(certified_body_hash("/asset/1/preview"), Some(first));
+
+        let mut cursor = None;
+        assert_eq!(backfill_image_digests(&mut cursor), 1);
+        assert_eq!(certified_body_hash("/asset/1/image/2"), Some(legacy));
+        assert_eq!(backfill_image_digests(&mut cursor), 0);
+
+        // Dropping an image from the gallery uncertifies it
+        save_gallery(1, AssetGallery { image_ids: vec![2] });
+        certify_gallery(1);
+        assert_eq!(certified_body_hash("/asset/1/image/1"), None);
+        assert_eq!(certified_body_hash("/asset/1/preview"), Some(legacy));
+    }
+
+    #[test]
+    fn assets_leave_only_their_owners_collections() {
+        let (seller, curator) = (principal(1), principal(2));
+        insert_listed_asset(1, seller);
+        insert_listed_asset(2, seller);
+        for (collection_id, owner) in [(1, seller), (2, curator)] {
+            let collection = Collection {
+                id: collection_id,
+                owner,
+                name: format!("Collection {}", collection_id),
+                description: String::new(),
+                cover_image: None,
+                created_at: 0,
+                updated_at: 0,
+            };
+            COLLECTIONS.with(|collections| collections.borrow_mut().insert(collection_id, collection));
+            add_collection_item(collection_id, 1, 0);
+            add_collection_item(collection_id, 2, 0);
+        }
+        ASSET_COLLECTION_INDEX.with(|index| index.borrow_mut().clear_new());
+        rebuild_asset_collection_index();
+
+        remove_from_collections(1, Some(seller));
+        assert_eq!((collection_asset_ids(1), collection_asset_ids(2)), (vec![2], vec![1, 2]));
+        remove_from_collections(1, None);
+        assert_eq!((collection_asset_ids(1), collection_asset_ids(2)), (vec![2], vec![2]));
+        assert!(ASSET_COLLECTION_INDEX.with(|index| index.borrow().keys_range((1, 0)..=(1, u64::MAX)).next().is_none()));
+        assert!(!remove_collection_item(2, 1));
+        assert!(remove_collection_item(2, 2));
+    }
+}
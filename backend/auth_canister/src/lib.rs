@@ -21,7 +21,7 @@ pub struct UserProfile {
 }
 
 impl Storable for UserProfile {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -0,0 +1,72 @@
+// Instruction counts of the asset canister's listing queries over 10k
+// assets, checked against a budget. Needs the PocketIC server (POCKET_IC_BIN,
+// or downloaded on first run) and the canister built with the bench feature:
+//
+//   cargo build -p asset_canister --target wasm32-unknown-unknown --release --features bench
+//   cargo bench -p benchmarks
+//
+// ASSET_CANISTER_WASM overrides where the wasm is read from.
+
+use candid::{decode_one, encode_args, Principal};
+use pocket_ic::PocketIc;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const ASSET_COUNT: u64 = 10_000;
+const SEED_BATCH_SIZE: u64 = 500;
+const PAGE_SIZE: u64 = 50;
+// A fifth of the 5B instructions a query may use
+const INSTRUCTION_BUDGET: u64 = 1_000_000_000;
+
+const QUERIES: [&str; 4] = [
+    "get_assets_for_sale",
+    "get_assets_for_sale_summary",
+    "get_assets_summary_paginated",
+    "get_assets_filtered",
+];
+
+fn asset_canister_wasm() -> Vec<u8> {
+    let path = std::env::var_os("ASSET_CANISTER_WASM").map(PathBuf::from).unwrap_or_else(|| {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../target/wasm32-unknown-unknown/release/asset_canister.wasm")
+    });
+    std::fs::read(&path).unwrap_or_else(|err| {
+        panic!("Cannot read {}: {}. Build the canister with --features bench first.", path.display(), err)
+    })
+}
+
+fn main() -> ExitCode {
+    let pic = PocketIc::new();
+    let seller = Principal::self_authenticating(b"benchmark seller");
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 100_000_000_000_000);
+    pic.install_canister(canister_id, asset_canister_wasm(), encode_args(()).unwrap(), None);
+
+    for first_id in (1..=ASSET_COUNT).step_by(SEED_BATCH_SIZE as usize) {
+        let count = SEED_BATCH_SIZE.min(ASSET_COUNT + 1 - first_id);
+        pic.update_call(canister_id, seller, "bench_seed_assets", encode_args((first_id, count)).unwrap())
+            .expect("seeding failed");
+    }
+
+    let mut over_budget = false;
+    println!("{:<32} {:>8} {:>16}", "query", "offset", "instructions");
+    for query in QUERIES {
+        // The first page and the last one, which has to skip every other match
+        for offset in [0, ASSET_COUNT - PAGE_SIZE] {
+            let payload = encode_args((query, offset, PAGE_SIZE)).unwrap();
+            let response = pic
+                .query_call(canister_id, Principal::anonymous(), "bench_listing_instructions", payload)
+                .expect("benchmark query failed");
+            let instructions: u64 = decode_one(&response).unwrap();
+            let verdict = if instructions > INSTRUCTION_BUDGET { "over budget" } else { "" };
+            println!("{:<32} {:>8} {:>16} {}", query, offset, instructions, verdict);
+            over_budget |= instructions > INSTRUCTION_BUDGET;
+        }
+    }
+
+    if over_budget {
+        eprintln!("Some queries took more than {} instructions", INSTRUCTION_BUDGET);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -14,6 +14,15 @@ type ListingIdCounter = StableBTreeMap<u8, u64, Memory>;
 type TransactionIdCounter = StableBTreeMap<u8, u64, Memory>;
 type ConfigStore = StableBTreeMap<String, String, Memory>;
 
+// The most ids the asset canister accepts in one lookup
+const ASSET_LOOKUP_BATCH_SIZE: usize = 200;
+
+// Batches never exceed the lookup limit, so the error needs no decoding
+type AssetSummaryResult = Result<Vec<Option<AssetSummary>>, candid::Reserved>;
+// Neither the transferred asset nor the reason for a refusal is used, so
+// neither has to track the asset canister's types
+type AssetTransferResult = Result<candid::Reserved, candid::Reserved>;
+
 #[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
 pub struct Listing {
     pub id: u64,
@@ -30,7 +39,7 @@ pub struct Listing {
 }
 
 impl Storable for Listing {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -54,7 +63,7 @@ pub struct Transaction {
 }
 
 impl Storable for Transaction {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).unwrap())
     }
 
@@ -83,6 +92,33 @@ pub struct ListingInput {
     pub tags: Vec<String>,
 }
 
+// Mirrors the asset canister's PriceOption
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct PriceOption {
+    pub ledger: Principal,
+    pub amount: u64,
+}
+
+// Mirrors the asset canister's AssetSummary
+#[derive(CandidType, Serialize, SerdeDeserialize, Clone)]
+pub struct AssetSummary {
+    pub id: u64,
+    pub name: String,
+    pub prices: Vec<PriceOption>,
+    pub is_for_sale: bool,
+    pub owner: Principal,
+    pub category: String,
+    pub preview_image_url: Option<String>,
+    pub file_type: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, SerdeDeserialize)]
+pub struct ListingWithAsset {
+    pub listing: Listing,
+    pub asset: Option<AssetSummary>, // None if the asset is gone or not public
+}
+
 #[derive(CandidType, Serialize, SerdeDeserialize)]
 pub struct MarketplaceStats {
     pub total_listings: u64,
@@ -198,6 +234,38 @@ fn get_marketplace_listings() -> Vec<Listing> {
     })
 }
 
+// Active listings together with their asset details, so the frontend needs a
+// single call. The asset canister must be on the same subnet.
+#[query(composite = true)]
+async fn get_marketplace_listings_with_assets() -> Result<Vec<ListingWithAsset>, String> {
+    let asset_canister_principal = get_asset_canister_principal()?;
+    let listings = get_marketplace_listings();
+
+    let mut merged = Vec::with_capacity(listings.len());
+    for batch in listings.chunks(ASSET_LOOKUP_BATCH_SIZE) {
+        let asset_ids: Vec<u64> = batch.iter().map(|listing| listing.asset_id).collect();
+        let lookup_result: Result<(AssetSummaryResult,), _> = call(
+            asset_canister_principal,
+            "get_assets_summary",
+            (asset_ids,),
+        ).await;
+
+        let summaries = match lookup_result {
+            Ok((Ok(summaries),)) => summaries,
+            Ok((Err(_),)) => return Err("Asset canister rejected the lookup".to_string()),
+            Err((code, message)) => return Err(format!("Asset canister call rejected ({:?}): {}", code, message)),
+        };
+        merged.extend(
+            batch
+                .iter()
+                .cloned()
+                .zip(summaries)
+                .map(|(listing, asset)| ListingWithAsset { listing, asset }),
+        );
+    }
+    Ok(merged)
+}
+
 #[query]
 fn get_user_listings(seller: Principal) -> Vec<Listing> {
     LISTINGS.with(|listings| {
@@ -266,35 +334,15 @@ async fn buy_asset(listing_id: u64) -> Result<Transaction, String> {
         transactions.insert(transaction_id, transaction.clone());
     });
 
-    // Define a struct to match the Asset return type from the asset canister
-    #[derive(CandidType, Serialize, SerdeDeserialize)]
-    struct AssetResult {
-        id: u64,
-        name: String,
-        description: String,
-        owner: Principal,
-        file_hash: String,
-        file_url: String,
-        file_type: String,
-        file_size: u64,
-        price: u64,
-        is_for_sale: bool,
-        created_at: u64,
-        updated_at: u64,
-        category: String,
-        tags: Vec<String>,
-        preview_image_url: Option<String>,
-    }
-
     // Now attempt to transfer ownership via inter-canister call
-    let transfer_result: Result<(Result<AssetResult, String>,), _> = call(
+    let transfer_result: Result<(AssetTransferResult,), _> = call(
         asset_canister_principal,
         "marketplace_transfer_asset", 
-        (listing.asset_id, listing.seller, buyer),
+        (listing.asset_id, listing.seller, buyer, listing.price),
     ).await;
 
     match transfer_result {
-        Ok((Ok(_asset),)) => {
+        Ok((Ok(_),)) => {
             // Transfer successful, update transaction status
             transaction.status = TransactionStatus::Completed;
             TRANSACTIONS.with(|transactions| {
@@ -303,7 +351,7 @@ async fn buy_asset(listing_id: u64) -> Result<Transaction, String> {
             });
             Ok(transaction)
         },
-        Ok((Err(transfer_err),)) => {
+        Ok((Err(_),)) => {
             // Transfer failed, mark transaction as failed and reactivate listing
             transaction.status = TransactionStatus::Failed;
             TRANSACTIONS.with(|transactions| {
@@ -320,7 +368,7 @@ async fn buy_asset(listing_id: u64) -> Result<Transaction, String> {
                 }
             });
 
-            Err(format!("Failed to transfer asset ownership: {}", transfer_err))
+            Err("Asset canister rejected the transfer".to_string())
         },
         Err(call_err) => {
             // Inter-canister call failed
@@ -394,6 +442,31 @@ fn cancel_listing(listing_id: u64) -> Result<Listing, String> {
     })
 }
 
+// Called by the asset canister once an asset has been deleted. Returns how
+// many active listings were closed.
+#[update]
+fn close_listings_for_asset(asset_id: u64) -> Result<u64, String> {
+    if caller() != get_asset_canister_principal()? {
+        return Err("Only the asset canister can close listings for an asset".to_string());
+    }
+
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        let open: Vec<Listing> = listings
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| listing.asset_id == asset_id && listing.is_active)
+            .collect();
+        let closed = open.len() as u64;
+        for mut listing in open {
+            listing.is_active = false;
+            listing.updated_at = time();
+            listings.insert(listing.id, listing);
+        }
+        Ok(closed)
+    })
+}
+
 #[query]
 fn get_user_transactions(user: Principal) -> Vec<Transaction> {
     TRANSACTIONS.with(|transactions| {
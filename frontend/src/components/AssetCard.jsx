@@ -1,6 +1,7 @@
 import React from 'react'
 import { Link } from 'react-router-dom'
-import { formatICP, formatDate, truncateText } from '../utils/helpers'
+import canisterService from '../services/canisterService'
+import { formatAssetPrice, formatDate, truncateText } from '../utils/helpers'
 import { useCanisterPreviewImage } from '../hooks/useCanisterFile'
 import { Eye, ShoppingCart, Tag, Calendar } from 'lucide-react'
 
@@ -50,7 +51,7 @@ const AssetCard = ({ asset, showBuyButton = false, showViewButton = true, onBuy
         {/* Price Badge */}
         {asset.is_for_sale && (
           <div className="absolute top-3 right-3 bg-white bg-opacity-90 backdrop-blur-sm rounded-full px-2 py-1 text-sm font-semibold text-primary-600">
-            {formatICP(asset.price)} ICP
+            {formatAssetPrice(canisterService.icpPrice(asset))}
           </div>
         )}
 
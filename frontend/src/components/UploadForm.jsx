@@ -1,14 +1,15 @@
 import React, { useState } from 'react'
 import { Upload, X, FileText, Image } from 'lucide-react'
-import { isValidVRFile, isValidImageFile, formatFileSize, ASSET_CATEGORIES } from '../utils/helpers'
+import { isValidVRFile, isValidImageFile, formatFileSize, flattenCategories } from '../utils/helpers'
 
-const UploadForm = ({ onSubmit, loading = false }) => {
+const UploadForm = ({ onSubmit, categories = [], loading = false }) => {
   const [formData, setFormData] = useState({
     name: '',
     description: '',
     category: '',
     tags: '',
-    price: ''
+    price: '',
+    license: 'PersonalUse'
   })
   const [vrFile, setVrFile] = useState(null)
   const [previewImage, setPreviewImage] = useState(null)
@@ -196,9 +197,9 @@ const UploadForm = ({ onSubmit, loading = false }) => {
                 className={`input-field ${errors.category ? 'border-red-500' : ''}`}
               >
                 <option value="">Select a category</option>
-                {ASSET_CATEGORIES.map(category => (
-                  <option key={category} value={category}>
-                    {category}
+                {flattenCategories(categories).map(category => (
+                  <option key={category.id} value={category.id}>
+                    {'\u00a0\u00a0'.repeat(category.depth)}{category.name}
                   </option>
                 ))}
               </select>
@@ -224,6 +225,25 @@ const UploadForm = ({ onSubmit, loading = false }) => {
             </div>
           </div>
 
+          <div>
+            <label htmlFor="license" className="block text-sm font-medium text-gray-700 mb-1">
+              License
+            </label>
+            <select
+              id="license"
+              name="license"
+              value={formData.license}
+              onChange={handleInputChange}
+              className="input-field"
+            >
+              <option value="PersonalUse">Personal use</option>
+              <option value="CommercialUse">Commercial use</option>
+              <option value="CCBY">CC BY</option>
+              <option value="CC0">CC0</option>
+            </select>
+            <p className="text-gray-500 text-sm mt-1">Once the asset has sold, the license can only be broadened.</p>
+          </div>
+
           <div>
             <label htmlFor="tags" className="block text-sm font-medium text-gray-700 mb-1">
               Tags (comma-separated)
@@ -2,7 +2,7 @@ import React, { useEffect, useState } from 'react'
 import { Link } from 'react-router-dom'
 import { useAuth } from '../services/AuthContext'
 import canisterService from '../services/canisterService'
-import { formatICP, formatDate } from '../utils/helpers'
+import { formatAssetPrice, formatICP, formatDate } from '../utils/helpers'
 import { 
   Upload, 
   ShoppingBag, 
@@ -131,7 +131,7 @@ const Dashboard = () => {
     )
   }
 
-  const totalAssetValue = userAssets.reduce((total, asset) => total + Number(asset.price), 0)
+  const totalAssetValue = userAssets.reduce((total, asset) => total + Number(canisterService.icpPrice(asset) ?? 0), 0)
   const completedTransactions = userTransactions.filter(tx => 
     tx.status && 'Completed' in tx.status
   ).length
@@ -293,7 +293,7 @@ const Dashboard = () => {
                       {asset.name}
                     </p>
                     <p className="text-sm text-gray-500">
-                      {formatICP(asset.price)} ICP
+                      {formatAssetPrice(canisterService.icpPrice(asset))}
                     </p>
                   </div>
                   <Link
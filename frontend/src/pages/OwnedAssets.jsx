@@ -126,11 +126,11 @@ const OwnedAssets = () => {
 
   const openListingModal = (asset) => {
     setSelectedAsset(asset)
-    setListingPrice(formatICP(asset.price))
+    setListingPrice(formatICP(canisterService.icpPrice(asset) ?? 0))
     setShowListingModal(true)
   }
 
-  const totalValue = assets.reduce((total, asset) => total + Number(asset.price), 0)
+  const totalValue = assets.reduce((total, asset) => total + Number(canisterService.icpPrice(asset) ?? 0), 0)
   const assetsForSale = assets.filter(asset => asset.is_for_sale).length
   const completedSales = transactions.filter(tx => 
     tx.seller.toString() === principal.toString() && 
@@ -1,9 +1,9 @@
-import React, { useState } from 'react'
+import React, { useEffect, useState } from 'react'
 import { useNavigate } from 'react-router-dom'
 import { useAuth } from '../services/AuthContext'
 import canisterService from '../services/canisterService'
 import UploadForm from '../components/UploadForm'
-import { generateFileHash, icpToE8s, formatICP, fileToBytes, testFileConversion, getVRFileMimeType } from '../utils/helpers'
+import { generateFileHash, icpToE8s, formatICP, fileToBytes, testFileConversion } from '../utils/helpers'
 import { CheckCircle, AlertCircle } from 'lucide-react'
 
 const UploadAsset = () => {
@@ -13,6 +13,19 @@ const UploadAsset = () => {
   const [uploadStep, setUploadStep] = useState('form') // 'form', 'uploading', 'success', 'error'
   const [uploadedAsset, setUploadedAsset] = useState(null)
   const [error, setError] = useState('')
+  const [categories, setCategories] = useState([])
+
+  useEffect(() => {
+    const loadCategories = async () => {
+      try {
+        await canisterService.initializeAgent(identity)
+        setCategories(await canisterService.getCategories())
+      } catch (error) {
+        console.error('Failed to load categories:', error)
+      }
+    }
+    loadCategories()
+  }, [identity])
 
   const handleUpload = async (formData) => {
     try {
@@ -61,11 +74,16 @@ const UploadAsset = () => {
           // Upload preview image (only if less than 1MB)
           if (previewBytes.length < 1024 * 1024) {
             console.log("Reached the uploading section")
-            const previewResult = await canisterService.uploadFile(previewHash, previewBytes)
-            console.log("Just after previewResult")
-            if ('Ok' in previewResult) {
-              console.log("inside ok previewresult")
+            // Skip sending bytes the canister already has
+            if (await canisterService.fileExists(previewHash)) {
               previewImageUrl = `canister://${previewHash}`
+            } else {
+              const previewResult = await canisterService.uploadFile(previewHash, previewBytes)
+              console.log("Just after previewResult")
+              if ('Ok' in previewResult) {
+                console.log("inside ok previewresult")
+                previewImageUrl = `canister://${previewHash}`
+              }
             }
           } else {
             // Fallback to object URL for large preview images
@@ -78,18 +96,19 @@ const UploadAsset = () => {
       }
 
       // Step 4: Create asset record and upload file to canister
-      const correctMimeType = getVRFileMimeType(formData.vrFile.name, formData.vrFile.type)
+      const fileExtension = formData.vrFile.name.toLowerCase().split('.').pop()
       const assetInput = {
         name: formData.name,
         description: formData.description,
         file_hash: fileHash,
         file_url: `canister://${fileHash}`, // This will be the canister storage URL
-        file_type: correctMimeType, // ✅ FIXED: Use proper MIME type instead of just extension
+        file_type: fileExtension, // Must be one of the canister's allowed file types (glb, gltf, fbx, vrm)
         file_size: BigInt(formData.vrFile.size),
         price: BigInt(icpToE8s(formData.price)),
-        category: formData.category,
+        category_id: BigInt(formData.category),
         tags: formData.tags,
         preview_image_url: previewImageUrl ? [previewImageUrl] : [],
+        license: { [formData.license || 'PersonalUse']: null },
       }
       console.log("📦 Asset Input:", assetInput)
       console.log("📊 File bytes length:", fileBytes.length)
@@ -99,7 +118,7 @@ const UploadAsset = () => {
       console.log("📥 Upload result:", result)
 
       if ('Ok' in result) {
-        setUploadedAsset(result.Ok)
+        setUploadedAsset(result.Ok.asset)
         setUploadStep('success')
         console.log("Ok in result");
         // Auto-navigate to assets page after 3 seconds
@@ -293,7 +312,7 @@ const UploadAsset = () => {
         </p>
       </div>
 
-      <UploadForm onSubmit={handleUpload} loading={uploading} />
+      <UploadForm onSubmit={handleUpload} categories={categories} loading={uploading} />
 
       {/* Upload Guidelines */}
       <div className="mt-8 grid grid-cols-1 md:grid-cols-2 gap-6">
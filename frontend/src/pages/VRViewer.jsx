@@ -3,7 +3,7 @@ import { useParams, useNavigate, Link } from 'react-router-dom'
 import { useAuth } from '../services/AuthContext'
 import canisterService from '../services/canisterService'
 import VRViewer from '../components/VRViewer'
-import { formatICP, formatDate, formatFileSize } from '../utils/helpers'
+import { formatAssetPrice, formatICP, formatDate, formatFileSize } from '../utils/helpers'
 import { 
   ArrowLeft, 
   Share2, 
@@ -85,6 +85,11 @@ const VRViewerPage = () => {
     }
   }
 
+  // Reservations stay on the record after they lapse, so check the expiry
+  const isReserved = asset?.reservation?.length > 0 &&
+    BigInt(asset.reservation[0].expires_at) > BigInt(Date.now()) * 1_000_000n
+  const freeze = asset?.frozen?.[0]
+
   const handleShare = async () => {
     const url = window.location.href
     try {
@@ -293,7 +298,7 @@ const VRViewerPage = () => {
             ) : isOwner ? (
               <div className="text-center">
                 <div className="text-2xl font-bold text-gray-900 mb-1">
-                  {formatICP(asset.price)} ICP
+                  {formatAssetPrice(canisterService.icpPrice(asset))}
                 </div>
                 <p className="text-gray-600 mb-4">Your asset</p>
                 <Link
@@ -306,7 +311,7 @@ const VRViewerPage = () => {
             ) : (
               <div className="text-center">
                 <div className="text-2xl font-bold text-gray-900 mb-1">
-                  {formatICP(asset.price)} ICP
+                  {formatAssetPrice(canisterService.icpPrice(asset))}
                 </div>
                 <p className="text-gray-600">Not for sale</p>
               </div>
@@ -346,9 +351,9 @@ const VRViewerPage = () => {
               <div className="flex justify-between">
                 <span className="text-gray-600">Status</span>
                 <span className={`font-medium ${
-                  asset.is_for_sale ? 'text-green-600' : 'text-gray-600'
-                }`}>
-                  {asset.is_for_sale ? 'For Sale' : 'Not Listed'}
+                  freeze ? 'text-red-600' : isReserved ? 'text-amber-600' : asset.is_for_sale ? 'text-green-600' : 'text-gray-600'
+                }`} title={freeze?.reason}>
+                  {freeze ? 'Frozen' : isReserved ? "In someone's cart" : asset.is_for_sale ? 'For Sale' : 'Not Listed'}
                 </span>
               </div>
             </div>
@@ -37,6 +37,14 @@ const authIdlFactory = ({ IDL }) => {
 }
 
 const assetIdlFactory = ({ IDL }) => {
+  const License = IDL.Variant({
+    'PersonalUse': IDL.Null,
+    'CommercialUse': IDL.Null,
+    'CCBY': IDL.Null,
+    'CC0': IDL.Null,
+    'Custom': IDL.Record({ 'url': IDL.Text }),
+  })
+
   const AssetInput = IDL.Record({
     'name': IDL.Text,
     'description': IDL.Text,
@@ -45,12 +53,125 @@ const assetIdlFactory = ({ IDL }) => {
     'file_type': IDL.Text,
     'file_size': IDL.Nat64,
     'price': IDL.Nat64,
-    'category': IDL.Text,
+    'category_id': IDL.Nat64,
     'tags': IDL.Vec(IDL.Text),
     'preview_image_url': IDL.Opt(IDL.Text),
+    'license': License,
+  })
+
+  const AssetError = IDL.Variant({
+    'NotFound': IDL.Null,
+    'NotOwner': IDL.Null,
+    'NotForSale': IDL.Null,
+    'AnonymousCaller': IDL.Null,
+    'InvalidInput': IDL.Text,
+    'FileTooLarge': IDL.Record({ 'max': IDL.Nat64 }),
+    'AlreadyExists': IDL.Null,
+    'Unauthorized': IDL.Null,
+    'ListedForSale': IDL.Null,
+    'TransferInProgress': IDL.Null,
+    'PaymentFailed': IDL.Text,
+    'ApprovalExpired': IDL.Null,
+    'QuotaExceeded': IDL.Record({ 'remaining': IDL.Nat64 }),
+    'OfferNotFound': IDL.Null,
+    'OfferNotActive': IDL.Null,
+    'OfferExpired': IDL.Null,
+    'AuctionNotFound': IDL.Null,
+    'AuctionNotActive': IDL.Null,
+    'AuctionEnded': IDL.Null,
+    'BidTooLow': IDL.Record({ 'min_bid': IDL.Nat64 }),
+    'RateLimited': IDL.Record({ 'retry_after_seconds': IDL.Nat64 }),
+    'AssetHidden': IDL.Null,
+    'CollectionNotFound': IDL.Null,
+    'NoPendingTransfer': IDL.Null,
+    'InvalidRecipient': IDL.Null,
+    'InvalidPrice': IDL.Record({ 'max': IDL.Nat64 }),
+    'BundleNotFound': IDL.Null,
+    'InBundle': IDL.Record({ 'bundle_id': IDL.Nat64 }),
+    'InvalidFileFormat': IDL.Record({ 'expected': IDL.Text }),
+    'DisplayNameTaken': IDL.Null,
+    'EscrowNotFound': IDL.Null,
+    'RefundWindowClosed': IDL.Null,
+    'GiftNotFound': IDL.Null,
+    'NotForRent': IDL.Null,
+    'AssetArchived': IDL.Null,
+    'MethodPaused': IDL.Record({ 'reason': IDL.Opt(IDL.Text) }),
+    'InvalidCursor': IDL.Null,
+    'InsufficientBalance': IDL.Record({ 'available': IDL.Nat64, 'required': IDL.Nat64 }),
+    'TakenDown': IDL.Null,
+    'CouponNotFound': IDL.Null,
+    'CouponExpired': IDL.Null,
+    'CouponUsedUp': IDL.Null,
+    'CouponNotApplicable': IDL.Null,
+    'NotArchived': IDL.Null,
+    'AssetReserved': IDL.Record({ 'expires_at': IDL.Nat64 }),
+    'NoReservation': IDL.Null,
+    'AssetFrozen': IDL.Null,
+    'UnsupportedLedger': IDL.Null,
+    'NotPricedInLedger': IDL.Null,
+    'TooManyReservations': IDL.Null,
+  })
+
+  const Reservation = IDL.Record({
+    'holder': IDL.Principal,
+    'reserved_at': IDL.Nat64,
+    'expires_at': IDL.Nat64,
+  })
+
+  const Freeze = IDL.Record({
+    'reason': IDL.Text,
+    'frozen_by': IDL.Principal,
+    'frozen_at': IDL.Nat64,
+  })
+
+  const PriceOption = IDL.Record({
+    'ledger': IDL.Principal,
+    'amount': IDL.Nat64,
+  })
+
+  const SupportedLedger = IDL.Record({
+    'ledger': IDL.Principal,
+    'symbol': IDL.Text,
+    'added_at': IDL.Nat64,
+  })
+
+  const AssetSource = IDL.Variant({
+    'Imported': IDL.Record({ 'external_id': IDL.Text }),
   })
 
   const Asset = IDL.Record({
+    'id': IDL.Nat64,
+    'name': IDL.Text,
+    'description': IDL.Text,
+    'owner': IDL.Principal,
+    'file_hash': IDL.Text,
+    'file_url': IDL.Text,
+    'file_type': IDL.Text,
+    'file_size': IDL.Nat64,
+    'prices': IDL.Vec(PriceOption),
+    'is_for_sale': IDL.Bool,
+    'created_at': IDL.Nat64,
+    'updated_at': IDL.Nat64,
+    'category_id': IDL.Nat64,
+    'category': IDL.Text,
+    'tags': IDL.Vec(IDL.Text),
+    'preview_image_url': IDL.Opt(IDL.Text),
+    'license': License,
+    'slug': IDL.Opt(IDL.Text),
+    'reservation': IDL.Opt(Reservation),
+    'source': IDL.Opt(AssetSource),
+    'frozen': IDL.Opt(Freeze),
+  })
+
+  const AssetPage = IDL.Record({
+    'assets': IDL.Vec(Asset),
+    'total_count': IDL.Nat64,
+    'truncated': IDL.Bool,
+    'next_offset': IDL.Opt(IDL.Nat64),
+  })
+
+  // v1 methods keep the Asset shape from before API v2
+  const AssetV1Response = IDL.Record({
     'id': IDL.Nat64,
     'name': IDL.Text,
     'description': IDL.Text,
@@ -63,26 +184,288 @@ const assetIdlFactory = ({ IDL }) => {
     'is_for_sale': IDL.Bool,
     'created_at': IDL.Nat64,
     'updated_at': IDL.Nat64,
+    'category_id': IDL.Nat64,
     'category': IDL.Text,
     'tags': IDL.Vec(IDL.Text),
     'preview_image_url': IDL.Opt(IDL.Text),
   })
 
+  const AssetCursorPageV1Response = IDL.Record({
+    'assets': IDL.Vec(AssetV1Response),
+    'next_cursor': IDL.Opt(IDL.Text),
+  })
+
+  const AssetChunk = IDL.Record({
+    'assets': IDL.Vec(Asset),
+    'truncated': IDL.Bool,
+    'next_cursor': IDL.Opt(IDL.Nat64),
+  })
+
+  const FileUpload = IDL.Record({
+    'file_hash': IDL.Text,
+    'deduplicated': IDL.Bool,
+  })
+
+  const AssetUploadV1Response = IDL.Record({
+    'asset': AssetV1Response,
+    'deduplicated': IDL.Bool,
+  })
+
+  const AssetSummary = IDL.Record({
+    'id': IDL.Nat64,
+    'name': IDL.Text,
+    'prices': IDL.Vec(PriceOption),
+    'is_for_sale': IDL.Bool,
+    'owner': IDL.Principal,
+    'category': IDL.Text,
+    'preview_image_url': IDL.Opt(IDL.Text),
+    'file_type': IDL.Text,
+    'created_at': IDL.Nat64,
+    'license': License,
+  })
+
+  const TimeWindow = IDL.Variant({
+    'Last7Days': IDL.Null,
+    'Last30Days': IDL.Null,
+    'AllTime': IDL.Null,
+  })
+
+  const EarningsTotals = {
+    'gross_sales': IDL.Nat64,
+    'royalty_income': IDL.Nat64,
+    'platform_fees_paid': IDL.Nat64,
+    'net_proceeds': IDL.Nat64,
+    'sale_count': IDL.Nat64,
+  }
+
+  const EarningsSummary = IDL.Record({
+    ...EarningsTotals,
+    'assets': IDL.Vec(IDL.Record({ 'asset_id': IDL.Nat64, ...EarningsTotals })),
+  })
+
+  const EarningsLegPage = IDL.Record({
+    'legs': IDL.Vec(IDL.Record({
+      'sale_id': IDL.Nat64,
+      'asset_id': IDL.Nat64,
+      'kind': IDL.Variant({ 'Sale': IDL.Null, 'Royalty': IDL.Null }),
+      'sale_price': IDL.Nat64,
+      'amount': IDL.Nat64,
+      'platform_fee': IDL.Nat64,
+      'paid': IDL.Bool,
+      'timestamp': IDL.Nat64,
+    })),
+    'total_count': IDL.Nat64,
+  })
+
+  const MatchKind = IDL.Variant({
+    'Description': IDL.Null,
+    'Tag': IDL.Null,
+    'NameSubstring': IDL.Null,
+    'NamePrefix': IDL.Null,
+    'ExactName': IDL.Null,
+  })
+
+  const RankedSearchPage = IDL.Record({
+    'results': IDL.Vec(IDL.Record({
+      'asset': Asset,
+      'score': IDL.Opt(IDL.Record({ 'match_kind': MatchKind, 'popularity': IDL.Nat64 })),
+    })),
+    'total_count': IDL.Nat64,
+    'truncated': IDL.Bool,
+    'next_offset': IDL.Opt(IDL.Nat64),
+  })
+
+  const Category = IDL.Record({
+    'id': IDL.Nat64,
+    'name': IDL.Text,
+    'parent': IDL.Opt(IDL.Nat64),
+    'archived': IDL.Bool,
+    'created_at': IDL.Nat64,
+  })
+
+  const CategoryNode = IDL.Rec()
+  CategoryNode.fill(IDL.Record({
+    'category': Category,
+    'children': IDL.Vec(CategoryNode),
+  }))
+
+  const OwnerStats = IDL.Record({
+    'asset_count': IDL.Nat64,
+    'for_sale_count': IDL.Nat64,
+    'favorite_count': IDL.Nat64,
+    'sale_count': IDL.Nat64,
+    'sales_volume': IDL.Nat64,
+  })
+
+  const MarketplaceStats = IDL.Record({
+    'asset_count': IDL.Nat64,
+    'for_sale_count': IDL.Nat64,
+    'favorite_count': IDL.Nat64,
+    'owner_count': IDL.Nat64,
+    'category_count': IDL.Nat64,
+    'sale_count': IDL.Nat64,
+    'sales_volume': IDL.Nat64,
+  })
+
+  const TagCount = IDL.Record({
+    'tag': IDL.Text,
+    'count': IDL.Nat64,
+  })
+
+  const TagPage = IDL.Record({
+    'tags': IDL.Vec(TagCount),
+    'total_count': IDL.Nat64,
+  })
+
+  const Verification = IDL.Record({
+    'principal': IDL.Principal,
+    'verified_by': IDL.Principal,
+    'verified_at': IDL.Nat64,
+  })
+
+  const FollowCounts = IDL.Record({
+    'followers': IDL.Nat64,
+    'following': IDL.Nat64,
+  })
+
+  const FollowList = IDL.Record({
+    'principals': IDL.Vec(IDL.Principal),
+    'count': IDL.Nat64,
+  })
+
+  const Pause = IDL.Record({
+    'reason': IDL.Opt(IDL.Text),
+    'paused_at': IDL.Nat64,
+  })
+
+  const PauseStatus = IDL.Record({
+    'global': IDL.Opt(Pause),
+    'methods': IDL.Vec(IDL.Tuple(IDL.Text, Pause)),
+  })
+
+  const NotificationKind = IDL.Variant({
+    'OfferReceived': IDL.Null,
+    'OfferAccepted': IDL.Null,
+    'AssetSold': IDL.Null,
+    'AssetTransferredToYou': IDL.Null,
+    'PriceDropOnFavorite': IDL.Null,
+    'AssetTakenDown': IDL.Null,
+    'AssetReinstated': IDL.Null,
+    'AppealRejected': IDL.Null,
+    'PriceAlertTriggered': IDL.Null,
+    'TransferReverted': IDL.Null,
+  })
+
+  const Notification = IDL.Record({
+    'id': IDL.Nat64,
+    'kind': NotificationKind,
+    'asset_id': IDL.Nat64,
+    'counterparty': IDL.Opt(IDL.Principal),
+    'amount': IDL.Opt(IDL.Nat64),
+    'created_at': IDL.Nat64,
+    'read': IDL.Bool,
+  })
+
+  const NotificationPage = IDL.Record({
+    'notifications': IDL.Vec(Notification),
+    'total_count': IDL.Nat64,
+    'unread_count': IDL.Nat64,
+  })
+
+  const FeaturedEntry = IDL.Record({
+    'asset_id': IDL.Nat64,
+    'owner': IDL.Principal,
+    'until': IDL.Opt(IDL.Nat64),
+    'featured_by': IDL.Principal,
+    'featured_at': IDL.Nat64,
+  })
+
+  const AccountMigration = IDL.Record({
+    'to': IDL.Principal,
+    'dry_run': IDL.Bool,
+    'moved': IDL.Vec(IDL.Nat64),
+    'skipped': IDL.Vec(IDL.Tuple(IDL.Nat64, AssetError)),
+    'remaining': IDL.Nat64,
+    'next_cursor': IDL.Opt(IDL.Nat64),
+    'profile_moved': IDL.Bool,
+    'favorites_moved': IDL.Nat64,
+    'notifications_moved': IDL.Nat64,
+    'done': IDL.Bool,
+  })
+
+  const PriceAlert = IDL.Record({
+    'asset_id': IDL.Nat64,
+    'target_price': IDL.Nat64,
+    'created_at': IDL.Nat64,
+  })
+
+  const Profile = IDL.Record({
+    'principal': IDL.Principal,
+    'display_name': IDL.Text,
+    'bio': IDL.Text,
+    'avatar_url': IDL.Opt(IDL.Text),
+    'website': IDL.Opt(IDL.Text),
+    'created_at': IDL.Nat64,
+    'updated_at': IDL.Nat64,
+    'verification': IDL.Opt(Verification),
+    'follows': IDL.Opt(FollowCounts),
+  })
+
   return IDL.Service({
-    'upload_asset': IDL.Func([AssetInput], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'upload_file': IDL.Func([IDL.Text, IDL.Vec(IDL.Nat8)], [IDL.Variant({ 'Ok': IDL.Text, 'Err': IDL.Text })], []),
+    'upload_asset': IDL.Func([AssetInput], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'upload_file': IDL.Func([IDL.Text, IDL.Vec(IDL.Nat8), IDL.Opt(IDL.Text)], [IDL.Variant({ 'Ok': FileUpload, 'Err': AssetError })], []),
+    'file_exists': IDL.Func([IDL.Text], [IDL.Bool], ['query']),
     'get_file': IDL.Func([IDL.Text], [IDL.Opt(IDL.Vec(IDL.Nat8))], ['query']),
-    'upload_asset_with_file': IDL.Func([AssetInput, IDL.Vec(IDL.Nat8)], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'get_asset': IDL.Func([IDL.Nat64], [IDL.Opt(Asset)], ['query']),
-    'get_user_assets': IDL.Func([IDL.Principal], [IDL.Vec(Asset)], ['query']),
-    'get_all_assets': IDL.Func([], [IDL.Vec(Asset)], ['query']),
-    'get_assets_for_sale': IDL.Func([], [IDL.Vec(Asset)], ['query']),
-    'update_asset_price': IDL.Func([IDL.Nat64, IDL.Nat64], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'set_asset_for_sale': IDL.Func([IDL.Nat64, IDL.Bool], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'transfer_asset_ownership': IDL.Func([IDL.Nat64, IDL.Principal], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'marketplace_transfer_asset': IDL.Func([IDL.Nat64, IDL.Principal, IDL.Principal], [IDL.Variant({ 'Ok': Asset, 'Err': IDL.Text })], []),
-    'search_assets': IDL.Func([IDL.Text], [IDL.Vec(Asset)], ['query']),
-    'get_assets_by_category': IDL.Func([IDL.Text], [IDL.Vec(Asset)], ['query']),
+    'has_download_access': IDL.Func([IDL.Nat64, IDL.Principal], [IDL.Bool], ['query']),
+    'upload_asset_with_file': IDL.Func([AssetInput, IDL.Vec(IDL.Nat8)], [IDL.Variant({ 'Ok': AssetUploadV1Response, 'Err': IDL.Text })], []),
+    'set_asset_license': IDL.Func([IDL.Nat64, License], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'get_asset': IDL.Func([IDL.Nat64], [IDL.Opt(AssetV1Response)], ['composite_query']),
+    'get_asset_v2': IDL.Func([IDL.Nat64], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], ['composite_query']),
+    'get_asset_by_slug': IDL.Func([IDL.Text], [IDL.Opt(Asset)], ['composite_query']),
+    'reserve_asset': IDL.Func([IDL.Nat64], [IDL.Variant({ 'Ok': Reservation, 'Err': AssetError })], []),
+    'release_reservation': IDL.Func([IDL.Nat64], [IDL.Variant({ 'Ok': IDL.Null, 'Err': AssetError })], []),
+    'get_user_assets': IDL.Func([IDL.Principal, IDL.Opt(IDL.Nat64)], [AssetChunk], ['query']),
+    'get_all_assets': IDL.Func([IDL.Opt(IDL.Nat64)], [AssetChunk], ['query']),
+    'get_assets_for_sale': IDL.Func([IDL.Nat64, IDL.Nat64], [AssetPage], ['query']),
+    'get_assets_for_sale_after': IDL.Func([IDL.Opt(IDL.Text), IDL.Nat64], [IDL.Variant({ 'Ok': AssetCursorPageV1Response, 'Err': AssetError })], ['query']),
+    'get_featured_assets': IDL.Func([], [IDL.Vec(Asset)], ['query']),
+    'feature_asset': IDL.Func([IDL.Nat64, IDL.Opt(IDL.Nat32), IDL.Opt(IDL.Nat64)], [IDL.Variant({ 'Ok': IDL.Vec(FeaturedEntry), 'Err': IDL.Text })], []),
+    'unfeature_asset': IDL.Func([IDL.Nat64], [IDL.Variant({ 'Ok': IDL.Vec(FeaturedEntry), 'Err': IDL.Text })], []),
+    'update_asset_price': IDL.Func([IDL.Nat64, IDL.Nat64], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'set_asset_prices': IDL.Func([IDL.Nat64, IDL.Vec(PriceOption)], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'get_supported_ledgers': IDL.Func([], [IDL.Vec(SupportedLedger)], ['query']),
+    'get_ledger_canister_id': IDL.Func([], [IDL.Opt(IDL.Text)], ['query']),
+    'set_asset_for_sale': IDL.Func([IDL.Nat64, IDL.Bool], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'transfer_asset_ownership': IDL.Func([IDL.Nat64, IDL.Principal], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'transfer_all_assets': IDL.Func([IDL.Principal, IDL.Opt(IDL.Nat64), IDL.Bool], [IDL.Variant({ 'Ok': AccountMigration, 'Err': AssetError })], []),
+    'marketplace_transfer_asset': IDL.Func([IDL.Nat64, IDL.Principal, IDL.Principal, IDL.Nat64], [IDL.Variant({ 'Ok': Asset, 'Err': AssetError })], []),
+    'search_assets': IDL.Func([IDL.Text, IDL.Opt(IDL.Nat64)], [AssetChunk], ['query']),
+    'search_assets_ranked': IDL.Func([IDL.Text, IDL.Nat64, IDL.Nat64, IDL.Bool], [RankedSearchPage], ['query']),
+    'get_assets_by_category': IDL.Func([IDL.Nat64, IDL.Nat64, IDL.Nat64], [AssetPage], ['query']),
+    'get_categories': IDL.Func([], [IDL.Vec(CategoryNode)], ['query']),
+    'get_category_counts': IDL.Func([], [IDL.Vec(IDL.Tuple(IDL.Text, IDL.Nat64, IDL.Nat64))], ['query']),
+    'get_owner_stats': IDL.Func([IDL.Principal], [OwnerStats], ['query']),
+    'get_marketplace_stats': IDL.Func([], [MarketplaceStats], ['query']),
+    'get_assets_by_tag': IDL.Func([IDL.Text, IDL.Nat64, IDL.Nat64], [AssetPage], ['query']),
+    'get_similar_assets': IDL.Func([IDL.Nat64, IDL.Nat64, IDL.Bool], [IDL.Vec(AssetSummary)], ['query']),
+    'get_my_earnings': IDL.Func([TimeWindow], [EarningsSummary], ['query']),
+    'get_my_earnings_history': IDL.Func([IDL.Nat64, IDL.Nat64], [EarningsLegPage], ['query']),
+    'get_all_tags': IDL.Func([IDL.Nat64, IDL.Nat64], [TagPage], ['query']),
+    'suggest_tags': IDL.Func([IDL.Text, IDL.Nat64], [IDL.Vec(TagCount)], ['query']),
+    'set_my_profile': IDL.Func([IDL.Text, IDL.Text, IDL.Opt(IDL.Text), IDL.Opt(IDL.Text)], [IDL.Variant({ 'Ok': Profile, 'Err': AssetError })], []),
+    'get_profile': IDL.Func([IDL.Principal], [IDL.Opt(Profile)], ['query']),
+    'get_profile_by_name': IDL.Func([IDL.Text], [IDL.Opt(Profile)], ['query']),
+    'follow_creator': IDL.Func([IDL.Principal], [IDL.Variant({ 'Ok': FollowCounts, 'Err': AssetError })], []),
+    'unfollow_creator': IDL.Func([IDL.Principal], [IDL.Variant({ 'Ok': FollowCounts, 'Err': AssetError })], []),
+    'get_my_following': IDL.Func([], [FollowList], ['query']),
+    'get_followers': IDL.Func([IDL.Principal], [FollowList], ['query']),
+    'get_following_feed': IDL.Func([IDL.Nat64, IDL.Nat64], [AssetPage], ['query']),
+    'get_my_notifications': IDL.Func([IDL.Nat64, IDL.Nat64, IDL.Bool], [NotificationPage], ['query']),
+    'mark_notifications_read': IDL.Func([IDL.Vec(IDL.Nat64)], [IDL.Variant({ 'Ok': IDL.Nat64, 'Err': AssetError })], []),
+    'set_price_alert': IDL.Func([IDL.Nat64, IDL.Nat64], [IDL.Variant({ 'Ok': PriceAlert, 'Err': AssetError })], []),
+    'remove_price_alert': IDL.Func([IDL.Nat64], [IDL.Variant({ 'Ok': IDL.Null, 'Err': AssetError })], []),
+    'get_my_price_alerts': IDL.Func([], [IDL.Vec(PriceAlert)], ['query']),
+    'get_pause_status': IDL.Func([], [PauseStatus], ['query']),
     'get_total_assets': IDL.Func([], [IDL.Nat64], ['query']),
   })
 }
@@ -161,6 +544,7 @@ class CanisterService {
     this.authActor = null
     this.assetActor = null
     this.marketplaceActor = null
+    this.icpLedgerId = null
   }
 
   async initializeAgent(identity = null) {
@@ -285,6 +669,14 @@ class CanisterService {
 
     console.log('All actors initialized successfully')
 
+    // ICP prices are the prices entry for this ledger
+    try {
+      const [ledgerId] = await this.assetActor.get_ledger_canister_id()
+      this.icpLedgerId = ledgerId ?? null
+    } catch (error) {
+      console.warn('⚠️ Failed to load the ICP ledger id:', error)
+    }
+
     // Configure asset canister ID in marketplace for inter-canister calls
     if (this.marketplaceActor && CANISTER_IDS.asset) {
       try {
@@ -327,9 +719,29 @@ class CanisterService {
     return await this.assetActor.upload_asset(assetData)
   }
 
-  async uploadFile(fileHash, fileData) {
+  async uploadFile(fileHash, fileData, fileType = null) {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    return await this.assetActor.upload_file(fileHash, fileData)
+    return await this.assetActor.upload_file(fileHash, fileData, fileType ? [fileType] : [])
+  }
+
+  async fileExists(fileHash) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.file_exists(fileHash)
+  }
+
+  async setAssetLicense(assetId, license) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.set_asset_license(assetId, license)
+  }
+
+  async reserveAsset(assetId) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.reserve_asset(assetId)
+  }
+
+  async releaseReservation(assetId) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.release_reservation(assetId)
   }
 
   async getFile(fileHash) {
@@ -337,6 +749,11 @@ class CanisterService {
     return await this.assetActor.get_file(fileHash)
   }
 
+  async hasDownloadAccess(assetId, principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.has_download_access(assetId, principal)
+  }
+
   async uploadAssetWithFile(assetData, fileData) {
   if (!this.assetActor) throw new Error('Asset actor not initialized')
   
@@ -352,24 +769,71 @@ class CanisterService {
 
   async getAsset(assetId) {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    return await this.assetActor.get_asset(assetId)
+    // v2 carries the license, reservation and freeze that get_asset omits
+    const result = await this.assetActor.get_asset_v2(assetId)
+    return 'Ok' in result ? [result.Ok] : []
+  }
+
+  async getAssetBySlug(slug) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_asset_by_slug(slug)
+  }
+
+  // Large listings come back in size-capped chunks; keep following the
+  // cursor until the canister stops truncating
+  async collectChunks(fetchChunk) {
+    const assets = []
+    let cursor = []
+    for (;;) {
+      const chunk = await fetchChunk(cursor)
+      assets.push(...chunk.assets)
+      if (!chunk.truncated || chunk.next_cursor.length === 0) return assets
+      cursor = chunk.next_cursor
+    }
   }
 
   async getUserAssets(principal) {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    return await this.assetActor.get_user_assets(principal)
+    return await this.collectChunks((cursor) => this.assetActor.get_user_assets(principal, cursor))
   }
 
   async getAllAssets() {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    return await this.assetActor.get_all_assets()
+    return await this.collectChunks((cursor) => this.assetActor.get_all_assets(cursor))
   }
 
-  async getAssetsForSale() {
+  async getAssetsForSale(offset = 0, limit = 100) {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    const res =  await this.assetActor.get_assets_for_sale();
+    const res =  await this.assetActor.get_assets_for_sale(BigInt(offset), BigInt(limit));
     console.log("Console while calling function get asset = " , res);
-    return res;
+    return res.assets;
+  }
+
+  // Cursor-paged variant for infinite scrolling: pass the page's
+  // next_cursor back in to continue, or null to start over
+  // Curated order, for the landing page carousel
+  async getFeaturedAssets() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_featured_assets()
+  }
+
+  async featureAsset(assetId, position = null, until = null) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.feature_asset(
+      BigInt(assetId),
+      position === null ? [] : [position],
+      until === null ? [] : [BigInt(until)]
+    )
+  }
+
+  async unfeatureAsset(assetId) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.unfeature_asset(BigInt(assetId))
+  }
+
+  async getAssetsForSaleAfter(cursor = null, limit = 100) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_assets_for_sale_after(cursor === null ? [] : [cursor], BigInt(limit))
   }
 
   async setAssetForSale(assetId, forSale) {
@@ -382,9 +846,164 @@ class CanisterService {
     return await this.assetActor.update_asset_price(assetId, newPrice)
   }
 
-  async searchAssets(query) {
+  // One { ledger, amount } per token, replacing every price; leaving out the
+  // ICP ledger means the asset can no longer be bought in ICP
+  async setAssetPrices(assetId, prices) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.set_asset_prices(assetId, prices)
+  }
+
+  async getSupportedLedgers() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_supported_ledgers()
+  }
+
+  // The price in e8s, or null when the asset cannot be bought in ICP. v1
+  // records carry it as price; newer ones as the ICP ledger's prices entry.
+  icpPrice(asset) {
+    if ('price' in asset) return asset.price
+    const option = asset.prices.find((option) => option.ledger.toText() === this.icpLedgerId)
+    return option ? option.amount : null
+  }
+
+  // Best matches first; pass includeScores to see why an asset ranked where it did
+  async searchAssets(query, offset = 0, limit = 100, includeScores = false) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    const page = await this.assetActor.search_assets_ranked(query, BigInt(offset), BigInt(limit), includeScores)
+    return includeScores ? page.results : page.results.map((result) => result.asset)
+  }
+
+  async getCategories() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_categories()
+  }
+
+  async getCategoryCounts() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    const counts = await this.assetActor.get_category_counts()
+    return counts.map(([category, total, forSale]) => ({ category, total, forSale }))
+  }
+
+  // Call again with the returned next_cursor until the result is done
+  async transferAllAssets(to, cursor = null, dryRun = false) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.transfer_all_assets(to, cursor === null ? [] : [BigInt(cursor)], dryRun)
+  }
+
+  async getOwnerStats(principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_owner_stats(principal)
+  }
+
+  async getMarketplaceStats() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_marketplace_stats()
+  }
+
+  async getAssetsByTag(tag, offset = 0, limit = 20) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_assets_by_tag(tag, BigInt(offset), BigInt(limit))
+  }
+
+  async getSimilarAssets(assetId, limit = 12, excludeSameOwner = false) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_similar_assets(BigInt(assetId), BigInt(limit), excludeSameOwner)
+  }
+
+  // window is one of 'Last7Days', 'Last30Days' or 'AllTime'
+  async getMyEarnings(window = 'AllTime') {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_my_earnings({ [window]: null })
+  }
+
+  async getMyEarningsHistory(offset = 0, limit = 20) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_my_earnings_history(BigInt(offset), BigInt(limit))
+  }
+
+  async getAllTags(offset = 0, limit = 100) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_all_tags(BigInt(offset), BigInt(limit))
+  }
+
+  async suggestTags(prefix, limit = 10) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.suggest_tags(prefix, BigInt(limit))
+  }
+
+  async setMyProfile({ displayName, bio = '', avatarUrl = null, website = null }) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.set_my_profile(
+      displayName,
+      bio,
+      avatarUrl ? [avatarUrl] : [],
+      website ? [website] : []
+    )
+  }
+
+  async getProfile(principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_profile(principal)
+  }
+
+  async getProfileByName(name) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_profile_by_name(name)
+  }
+
+  async followCreator(principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.follow_creator(principal)
+  }
+
+  async unfollowCreator(principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.unfollow_creator(principal)
+  }
+
+  async getMyFollowing() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_my_following()
+  }
+
+  async getFollowers(principal) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_followers(principal)
+  }
+
+  async getFollowingFeed(offset = 0, limit = 20) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_following_feed(BigInt(offset), BigInt(limit))
+  }
+
+  async getMyNotifications(offset = 0, limit = 20, unreadOnly = false) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_my_notifications(BigInt(offset), BigInt(limit), unreadOnly)
+  }
+
+  async markNotificationsRead(ids) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.mark_notifications_read(ids.map((id) => BigInt(id)))
+  }
+
+  async setPriceAlert(assetId, targetPrice) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.set_price_alert(BigInt(assetId), BigInt(targetPrice))
+  }
+
+  async removePriceAlert(assetId) {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.remove_price_alert(BigInt(assetId))
+  }
+
+  async getMyPriceAlerts() {
+    if (!this.assetActor) throw new Error('Asset actor not initialized')
+    return await this.assetActor.get_my_price_alerts()
+  }
+
+  async getPauseStatus() {
     if (!this.assetActor) throw new Error('Asset actor not initialized')
-    return await this.assetActor.search_assets(query)
+    return await this.assetActor.get_pause_status()
   }
 
   // Marketplace methods
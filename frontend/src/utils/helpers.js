@@ -6,6 +6,12 @@ export const formatICP = (e8s) => {
   return icp.toFixed(4);
 };
 
+// An asset price from canisterService.icpPrice, or a dash when the asset
+// cannot be bought in ICP
+export const formatAssetPrice = (e8s) => {
+  return e8s === null ? '—' : `${formatICP(e8s)} ICP`;
+};
+
 // Convert ICP to e8s for canister calls
 export const icpToE8s = (icp) => {
   return Math.floor(Number(icp) * 100000000);
@@ -172,6 +178,17 @@ export const ASSET_CATEGORIES = [
   'Other'
 ];
 
+// Flattens the canister's category tree into select options, skipping
+// archived categories and their children
+export const flattenCategories = (nodes, depth = 0) => {
+  return nodes
+    .filter(node => !node.category.archived)
+    .flatMap(node => [
+      { id: node.category.id.toString(), name: node.category.name, depth },
+      ...flattenCategories(node.children, depth + 1)
+    ])
+};
+
 // Transaction status labels
 export const TRANSACTION_STATUS_LABELS = {
   Pending: 'Pending',